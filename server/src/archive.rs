@@ -0,0 +1,174 @@
+use std::{fs, io::Error as IoError, path::Path};
+
+use crate::history::HistoryEntry;
+
+const DAY_MILLIS: u64 = 24 * 60 * 60 * 1000;
+
+// The page templates. An operator can replace them with day.html and index.html in
+// ARCHIVE_TEMPLATES, which are read on every export. {{title}}, {{nav}} and {{body}}
+// are replaced; the body of a day page is a <ul> with one <li> per message.
+const DAY_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{{title}}</title>
+<style>
+body { font-family: sans-serif; max-width: 50em; margin: auto; }
+ul { list-style: none; padding: 0; }
+.time { color: #888; }
+.name { font-weight: bold; }
+</style>
+</head>
+<body>
+<h1>{{title}}</h1>
+<nav>{{nav}}</nav>
+<input id="search" type="search" placeholder="Search this day">
+{{body}}
+<script>
+document.getElementById("search").addEventListener("input", function (e) {
+    var needle = e.target.value.toLowerCase();
+    document.querySelectorAll("li").forEach(function (li) {
+        li.hidden = li.textContent.toLowerCase().indexOf(needle) < 0;
+    });
+});
+</script>
+</body>
+</html>
+"#;
+
+const INDEX_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{{title}}</title>
+<style>
+body { font-family: sans-serif; max-width: 50em; margin: auto; }
+</style>
+</head>
+<body>
+<h1>{{title}}</h1>
+{{body}}
+</body>
+</html>
+"#;
+
+// Writes 'entries' to 'dir' as one page per day (UTC), named like 2026-10-14.html, and an
+// index.html linking to them. Returns how many day pages were written.
+pub fn export(
+    room: &str,
+    entries: &[HistoryEntry],
+    dir: &Path,
+    templates: &Path,
+) -> Result<usize, IoError> {
+    let day_template = load_template(templates, "day.html", DAY_TEMPLATE);
+    let index_template = load_template(templates, "index.html", INDEX_TEMPLATE);
+    fs::create_dir_all(dir)?;
+
+    // The history is oldest first, so every day's messages are next to each other.
+    let mut days: Vec<(String, Vec<&HistoryEntry>)> = Vec::new();
+    for entry in entries {
+        let date = date(entry.timestamp);
+        match days.last_mut() {
+            Some((day, day_entries)) if *day == date => day_entries.push(entry),
+            _ => days.push((date, vec![entry])),
+        }
+    }
+
+    for (i, (date, day_entries)) in days.iter().enumerate() {
+        let mut nav = vec![String::from(r#"<a href="index.html">All days</a>"#)];
+        if let Some((previous, _)) = i.checked_sub(1).and_then(|i| days.get(i)) {
+            nav.push(format!(r#"<a href="{0}.html">&larr; {0}</a>"#, previous));
+        }
+        if let Some((next, _)) = days.get(i + 1) {
+            nav.push(format!(r#"<a href="{0}.html">{0} &rarr;</a>"#, next));
+        }
+
+        let mut body = String::from("<ul>\n");
+        for entry in day_entries {
+            body.push_str(&format!(
+                "<li id=\"{}\"><span class=\"time\">{}</span> <span class=\"name\">{}</span>: {}</li>\n",
+                entry.id,
+                time(entry.timestamp),
+                escape(&entry.src_name),
+                escape(&entry.text)
+            ));
+        }
+        body.push_str("</ul>");
+
+        let page = render(
+            &day_template,
+            &format!("{}, {}", escape(room), date),
+            &nav.join(" | "),
+            &body,
+        );
+        fs::write(dir.join(format!("{}.html", date)), page)?;
+    }
+
+    let mut body = String::from("<ul>\n");
+    for (date, day_entries) in days.iter().rev() {
+        body.push_str(&format!(
+            "<li><a href=\"{0}.html\">{0}</a> ({1} message(s))</li>\n",
+            date,
+            day_entries.len()
+        ));
+    }
+    body.push_str("</ul>");
+    fs::write(
+        dir.join("index.html"),
+        render(&index_template, &escape(room), "", &body),
+    )?;
+
+    Ok(days.len())
+}
+
+fn load_template(templates: &Path, name: &str, default: &str) -> String {
+    fs::read_to_string(templates.join(name)).unwrap_or_else(|_| default.to_string())
+}
+
+fn render(template: &str, title: &str, nav: &str, body: &str) -> String {
+    template
+        .replace("{{title}}", title)
+        .replace("{{nav}}", nav)
+        .replace("{{body}}", body)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// e.g. "2026-10-14"
+fn date(timestamp: u64) -> String {
+    let (year, month, day) = civil_from_days((timestamp / DAY_MILLIS) as i64);
+    format!("{}-{:02}-{:02}", year, month, day)
+}
+
+// e.g. "14:05"
+fn time(timestamp: u64) -> String {
+    let minutes = timestamp % DAY_MILLIS / 60_000;
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+// Turns days since the UNIX epoch into (year, month, day) of the proleptic
+// Gregorian calendar, after Howard Hinnant's `civil_from_days`. The same as in
+// test-client/src/clock.rs.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
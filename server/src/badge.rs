@@ -0,0 +1,37 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+// Marks an operator has given a peer through the console. Peers cannot set them
+// themselves: the server replaces whatever badges a message comes with.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Badge {
+    Staff,    // Runs this server.
+    Bot,      // An automated peer.
+    Verified, // The operator knows who is behind the name.
+}
+
+impl FromStr for Badge {
+    type Err = ();
+
+    fn from_str(badge: &str) -> Result<Self, Self::Err> {
+        match badge.to_lowercase().as_str() {
+            "staff" => Ok(Badge::Staff),
+            "bot" => Ok(Badge::Bot),
+            "verified" => Ok(Badge::Verified),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Badge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let badge = match self {
+            Badge::Staff => "staff",
+            Badge::Bot => "bot",
+            Badge::Verified => "verified",
+        };
+        write!(f, "{}", badge)
+    }
+}
@@ -0,0 +1,119 @@
+use std::time::{Duration, Instant};
+
+use async_std::future::timeout;
+use async_tungstenite::tungstenite::protocol::Message as TungMessage;
+use futures::{channel::mpsc::UnboundedReceiver, prelude::*};
+
+// The most messages sent in one Batch frame.
+const BATCH_MAX: usize = 64;
+
+// Writes what is queued for a peer to its socket. With a 'window', the Text messages
+// queued within it of the first one go out together, wrapped by 'batch' into a single
+// frame. That saves frames and syscalls in a busy room at the cost of holding each
+// message back for up to 'window'. Anything else, e.g. a Close, is sent as it is and in
+// order.
+pub async fn forward<S>(
+    mut receiver: UnboundedReceiver<TungMessage>,
+    mut outgoing: S,
+    window: Duration,
+    batch: impl Fn(Vec<String>) -> TungMessage,
+) -> Result<(), S::Error>
+where
+    S: Sink<TungMessage> + Unpin,
+{
+    if window.is_zero() {
+        return receiver.map(Ok).forward(outgoing).await;
+    }
+
+    while let Some(first) = receiver.next().await {
+        let first = match first {
+            TungMessage::Text(first) => first,
+            other => {
+                outgoing.send(other).await?;
+                continue;
+            }
+        };
+
+        let mut texts = vec![first];
+        let mut after_batch = None;
+        let send_at = Instant::now() + window;
+        while texts.len() < BATCH_MAX {
+            let wait = send_at.saturating_duration_since(Instant::now());
+            match timeout(wait, receiver.next()).await {
+                Ok(Some(TungMessage::Text(text))) => texts.push(text),
+                Ok(Some(other)) => {
+                    after_batch = Some(other);
+                    break;
+                }
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        let frame = match texts.len() {
+            1 => TungMessage::Text(texts.remove(0)),
+            _ => batch(texts),
+        };
+        outgoing.send(frame).await?;
+        if let Some(after_batch) = after_batch {
+            outgoing.send(after_batch).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task;
+    use futures::channel::mpsc::unbounded;
+
+    use super::*;
+
+    fn text(text: &str) -> TungMessage {
+        TungMessage::Text(text.to_string())
+    }
+
+    // Queues 'messages' and closes the queue, then returns what forward sent.
+    fn forwarded(messages: Vec<TungMessage>, window: Duration) -> Vec<TungMessage> {
+        let (sender, receiver) = unbounded();
+        for msg in messages {
+            sender.unbounded_send(msg).unwrap();
+        }
+        drop(sender);
+
+        let (outgoing, sent) = unbounded();
+        let batch = |texts: Vec<String>| TungMessage::Text(texts.join("+"));
+        task::block_on(forward(receiver, outgoing, window, batch)).unwrap();
+        task::block_on(sent.collect())
+    }
+
+    #[test]
+    fn without_a_window_messages_are_sent_as_they_are() {
+        let messages = vec![text("a"), text("b"), TungMessage::Close(None)];
+        assert_eq!(forwarded(messages.clone(), Duration::ZERO), messages);
+    }
+
+    #[test]
+    fn texts_are_batched_and_anything_else_follows_in_order() {
+        let messages = vec![text("a"), text("b"), TungMessage::Close(None), text("c")];
+        assert_eq!(
+            forwarded(messages, Duration::from_millis(50)),
+            vec![text("a+b"), TungMessage::Close(None), text("c")]
+        );
+    }
+
+    #[test]
+    fn a_batch_holds_batch_max_texts_at_most() {
+        let messages = (0..BATCH_MAX + 2).map(|i| text(&i.to_string())).collect();
+        let sent = forwarded(messages, Duration::from_millis(50));
+
+        let sizes: Vec<usize> = sent
+            .iter()
+            .map(|frame| match frame {
+                TungMessage::Text(frame) => frame.split('+').count(),
+                _ => 0,
+            })
+            .collect();
+        assert_eq!(sizes, vec![BATCH_MAX, 2]);
+    }
+}
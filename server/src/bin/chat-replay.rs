@@ -0,0 +1,350 @@
+// Replays an EVENT_LOG against a running server, best a fresh one without CHALLENGE or
+// INVITE_ONLY, to reproduce what led up to a bug:
+//
+//     chat-replay events.jsonl [--addr 127.0.0.1:8080] [--speed 2] [--expect <text>]...
+//
+// Every peer in the log gets a connection of its own that sends the peer's messages in
+// order, with the events as far apart as they were divided by --speed, 0 for no waiting.
+// The next event is only replayed once the server has acked the message before, so the
+// messages reach it in the order of the log however fast it is replayed. All connections
+// come from this machine's address and the server keeps spam scores by address, so turn
+// slow mode and the spam filter off for the replay, e.g. SLOW_MODE_SECS=0 and
+// SPAM_SLOW_MODE_AT/SPAM_MUTE_AT=1000000. A message the server refuses fails the replay.
+// The server gives out names of its own, so they differ from the ones in the log. What
+// only the console or an admin can do, like bans, badges and emoji, is skipped. With
+// --expect the replay fails unless every expected text reaches one of the connections,
+// e.g. the reply of a bot a bug is about. For more involved checks run scripted clients
+// like `test-client wait-for` alongside.
+
+use std::{
+    collections::HashMap,
+    env, fs, process,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_std::{future, net::TcpStream, task};
+use async_tungstenite::{client_async, tungstenite::Message as TungMessage};
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    StreamExt,
+};
+use serde_json::{json, Value};
+
+const PROTOCOL_VERSION: u32 = 3;
+const SERVER_NAME: &str = "Server"; // Who the server's own notices come from.
+
+// How long replies to the last events get to arrive before the expectations are checked.
+const SETTLE: Duration = Duration::from_secs(1);
+// How long the server gets to ack a message before the replay gives up.
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+const EXIT_OK: i32 = 0;
+const EXIT_UNMET: i32 = 1; // An expected text never arrived.
+const EXIT_USAGE: i32 = 2;
+const EXIT_DISCONNECTED: i32 = 3; // A connection could not be opened or the log not read.
+const EXIT_REFUSED: i32 = 4; // The server refused or never acked a message.
+
+const USAGE: &str =
+    "Usage: chat-replay <event log> [--addr <host:port>] [--speed <factor>] [--expect <text>]...";
+
+struct Options {
+    log: String,
+    addr: String,
+    speed: f64,
+    expect: Vec<String>,
+}
+
+// A connection standing in for a peer from the log.
+struct Peer {
+    name: String, // The name the server has given it.
+    sender: UnboundedSender<TungMessage>,
+    acks: UnboundedReceiver<Ack>,
+    last_msg_id: u64,
+}
+
+// The server's answer to a replayed message.
+struct Ack {
+    msg_id: u64,
+    refusal: Option<String>, // The notice that came before the Ack if it was refused.
+}
+
+fn main() {
+    dotenv::dotenv().ok();
+
+    let options = match parse_options(env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+
+    process::exit(task::block_on(replay(options)));
+}
+
+fn parse_options(args: Vec<String>) -> Result<Options, String> {
+    let default_addr = format!(
+        "{}:{}",
+        env::var("HOST").unwrap_or_else(|_| String::from("127.0.0.1")),
+        env::var("PORT").unwrap_or_else(|_| String::from("8080"))
+    );
+    let mut options = Options {
+        log: String::new(),
+        addr: default_addr,
+        speed: 1.0,
+        expect: Vec::new(),
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--addr" => options.addr = args.next().ok_or("--addr needs host:port.")?,
+            "--speed" => {
+                options.speed = args
+                    .next()
+                    .and_then(|speed| speed.parse::<f64>().ok())
+                    .filter(|speed| speed.is_finite() && *speed >= 0.0)
+                    .ok_or("--speed needs a factor like 2 or 0.5.")?;
+            }
+            "--expect" => options
+                .expect
+                .push(args.next().ok_or("--expect needs a text.")?),
+            option if option.starts_with("--") => {
+                return Err(format!("Unknown option {}.", option))
+            }
+            _ if options.log.is_empty() => options.log = arg,
+            _ => {
+                return Err(String::from(
+                    "Only one event log can be replayed at a time.",
+                ))
+            }
+        }
+    }
+
+    if options.log.is_empty() {
+        return Err(String::from("Which event log should be replayed?"));
+    }
+    Ok(options)
+}
+
+async fn replay(options: Options) -> i32 {
+    let log = match fs::read_to_string(&options.log) {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", options.log, e);
+            return EXIT_DISCONNECTED;
+        }
+    };
+    let records: Vec<Value> = match log
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect()
+    {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("{} is not an event log: {}", options.log, e);
+            return EXIT_DISCONNECTED;
+        }
+    };
+
+    let received = Arc::new(Mutex::new(Vec::new())); // The text of every Text and Private.
+    let mut peers: HashMap<String, Peer> = HashMap::new(); // Name in the log -> its stand-in.
+    let mut skipped = 0;
+    let started_at = records.first().map(at).unwrap_or_default();
+    let mut last_at = started_at;
+
+    for record in &records {
+        if options.speed > 0.0 {
+            let gap = at(record).saturating_sub(last_at) as f64 / options.speed;
+            task::sleep(Duration::from_millis(gap as u64)).await;
+        }
+        last_at = at(record).max(last_at);
+        let offset = (at(record).saturating_sub(started_at)) as f64 / 1000.0;
+
+        let payload = &record["payload"];
+        let name = payload["name"].as_str().unwrap_or_default();
+        match record["type"].as_str().unwrap_or_default() {
+            "Connected" => match connect(&options.addr, received.clone()).await {
+                Ok(peer) => {
+                    println!("[{:.1}s] {} connected as {}", offset, name, peer.name);
+                    peers.insert(name.to_string(), peer);
+                }
+                Err(e) => {
+                    eprintln!("[{:.1}s] Could not connect {}: {}", offset, name, e);
+                    return EXIT_DISCONNECTED;
+                }
+            },
+            "Disconnected" | "Kicked" => {
+                if let Some(peer) = peers.remove(name) {
+                    println!("[{:.1}s] {} left", offset, name);
+                    let _ = peer.sender.unbounded_send(TungMessage::Close(None));
+                }
+            }
+            "Message" => {
+                let src_name = payload["src_name"].as_str().unwrap_or_default();
+                let text = payload["text"].as_str().unwrap_or_default();
+                match peers.get_mut(src_name) {
+                    Some(peer) => {
+                        println!("[{:.1}s] {}: {}", offset, src_name, text);
+                        if let Err(e) = send_text(peer, text).await {
+                            eprintln!(
+                                "[{:.1}s] {}'s message was not taken: {}",
+                                offset, src_name, e
+                            );
+                            return EXIT_REFUSED;
+                        }
+                    }
+                    // E.g. a peer that connected before the log was started.
+                    None => skipped += 1,
+                }
+            }
+            _ => skipped += 1,
+        }
+    }
+
+    task::sleep(SETTLE).await;
+    for peer in peers.values() {
+        let _ = peer.sender.unbounded_send(TungMessage::Close(None));
+    }
+    println!(
+        "Replayed {} events, skipped {} that cannot be replayed by a peer.",
+        records.len() - skipped,
+        skipped
+    );
+
+    let received = received.lock().unwrap();
+    let unmet: Vec<&String> = options
+        .expect
+        .iter()
+        .filter(|expected| {
+            !received
+                .iter()
+                .any(|text: &String| text.contains(*expected))
+        })
+        .collect();
+    for expected in &unmet {
+        println!("Expected \"{}\", but it never arrived.", expected);
+    }
+    if unmet.is_empty() {
+        EXIT_OK
+    } else {
+        EXIT_UNMET
+    }
+}
+
+// Sends a Text and waits for its Ack. A ping goes ahead of it, so a refusal is told
+// apart from notices the server sent before it got to the message.
+async fn send_text(peer: &mut Peer, text: &str) -> Result<(), String> {
+    peer.last_msg_id += 1;
+    let msg_id = peer.last_msg_id;
+    let ping = json!({
+        "v": PROTOCOL_VERSION,
+        "type": "Ping",
+        "payload": { "nonce": msg_id, "sent_at": 0 },
+        "text": "",
+    });
+    let msg = json!({
+        "v": PROTOCOL_VERSION,
+        "type": "Text",
+        "text": text,
+        "msg_id": msg_id,
+    });
+    for msg in [ping, msg] {
+        let _ = peer
+            .sender
+            .unbounded_send(TungMessage::Text(msg.to_string()));
+    }
+
+    loop {
+        match future::timeout(ACK_TIMEOUT, peer.acks.next()).await {
+            Ok(Some(ack)) if ack.msg_id != msg_id => continue,
+            Ok(Some(Ack {
+                refusal: Some(notice),
+                ..
+            })) => return Err(notice),
+            Ok(Some(_)) => return Ok(()),
+            Ok(None) => return Err(String::from("the server closed the connection")),
+            Err(_) => return Err(String::from("the server never acked it")),
+        }
+    }
+}
+
+fn at(record: &Value) -> u64 {
+    record["at"].as_u64().unwrap_or_default()
+}
+
+// Connects a new peer and waits for its name. Whatever it receives from then on is
+// added to 'received'.
+async fn connect(addr: &str, received: Arc<Mutex<Vec<String>>>) -> Result<Peer, String> {
+    let stream = TcpStream::connect(addr).await.map_err(|e| e.to_string())?;
+    let (ws_stream, _) = client_async(format!("ws://{}/socket", addr), stream)
+        .await
+        .map_err(|e| e.to_string())?;
+    let (write, mut read) = ws_stream.split();
+
+    let name = loop {
+        let msg = match read.next().await {
+            Some(Ok(TungMessage::Text(msg))) => msg,
+            Some(Ok(TungMessage::Close(_))) | Some(Err(_)) | None => {
+                return Err(String::from("the server closed the connection"))
+            }
+            Some(Ok(_)) => continue,
+        };
+        let msg: Value = serde_json::from_str(&msg).map_err(|e| e.to_string())?;
+        match msg["type"].as_str() {
+            Some("PeerNameAssign") => {
+                break msg["payload"].as_str().unwrap_or_default().to_string()
+            }
+            Some("Challenge") => return Err(String::from("the server sends a challenge")),
+            _ => {}
+        }
+    };
+
+    let (sender, receiver) = unbounded();
+    let (ack_sender, acks) = unbounded();
+    task::spawn(receiver.map(Ok).forward(write));
+    task::spawn(async move {
+        let mut screening = None; // The msg_id of the message the server is screening.
+        let mut refusal = None;
+        while let Some(Ok(TungMessage::Text(msg))) = read.next().await {
+            // Batches are taken apart, like any peer does.
+            let msg: Value = serde_json::from_str(&msg).unwrap_or_default();
+            let msgs = match msg["type"].as_str() {
+                Some("Batch") => msg["payload"].as_array().cloned().unwrap_or_default(),
+                _ => vec![msg],
+            };
+            for msg in msgs {
+                let from_server = msg["src_name"] == SERVER_NAME;
+                match msg["type"].as_str() {
+                    Some("Pong") => screening = msg["payload"]["nonce"].as_u64(),
+                    Some("Ack") => {
+                        let msg_id = msg["payload"].as_u64().unwrap_or_default();
+                        let refusal = refusal.take().filter(|_| screening == Some(msg_id));
+                        let _ = ack_sender.unbounded_send(Ack { msg_id, refusal });
+                        screening = None;
+                    }
+                    Some("SlowModeWait") if screening.is_some() => {
+                        refusal = Some(String::from("slow mode"));
+                    }
+                    Some("Private") if screening.is_some() && from_server => {
+                        refusal = msg["text"].as_str().map(String::from);
+                    }
+                    _ => {}
+                }
+                if let Some("Text") | Some("Private") | Some("CodeSnippet") = msg["type"].as_str() {
+                    let text = msg["text"].as_str().unwrap_or_default().to_string();
+                    received.lock().unwrap().push(text);
+                }
+            }
+        }
+    });
+
+    Ok(Peer {
+        name,
+        sender,
+        acks,
+        last_msg_id: 0,
+    })
+}
@@ -0,0 +1,425 @@
+// Churns connections against a running server, best a fresh one without CHALLENGE or
+// INVITE_ONLY, to catch what is not cleaned up when peers leave:
+//
+//     chat-soak [--addr 127.0.0.1:8080] [--admin-key <key>] [--clients 4] [--duration 60]
+//
+// Each of the clients connects, chats a little and leaves again, over and over for
+// --duration seconds. It leaves in one of three ways: with a close frame, by vanishing
+// and reconnecting with its session token right away, or by being taken over by a new
+// connection presenting the token. Every ten seconds a census is taken through
+// CensusRequest, which needs the server's ADMIN_KEY, and once the clients are done and
+// the server has had a moment nothing may be leaking and everything kept per
+// connection, name and session, its memory included, has to be back where it started.
+// The rest of the memory grows with the history of what the clients said.
+
+use std::{
+    env, process,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_std::{future::timeout, net::TcpStream, task};
+use async_tungstenite::{
+    client_async,
+    tungstenite::{protocol::frame::coding::CloseCode, Message as TungMessage},
+    WebSocketStream,
+};
+use futures::{future, SinkExt, StreamExt};
+use rand::Rng;
+use serde_json::{json, Value};
+
+const PROTOCOL_VERSION: u32 = 3;
+
+const REPORT: Duration = Duration::from_secs(10);
+// How long the server gets to clean up after the last client before the final census.
+const SETTLE: Duration = Duration::from_secs(2);
+// How long a connection is held at most before it is left.
+const MAX_HOLD_MS: u64 = 300;
+// How long a reply, like the one to a close frame, is waited for.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const EXIT_OK: i32 = 0;
+const EXIT_LEAKED: i32 = 1; // The server did not return to the baseline.
+const EXIT_USAGE: i32 = 2;
+const EXIT_DISCONNECTED: i32 = 3; // The baseline could not be taken.
+
+const USAGE: &str =
+    "Usage: chat-soak [--addr <host:port>] [--admin-key <key>] [--clients <n>] [--duration <seconds>]";
+
+// What of the server's census is kept per connection, name or session.
+const KEPT: &[&str] = &[
+    "connections",
+    "peers",
+    "names",
+    "sessions",
+    "session_msg_ids",
+    "subscribers",
+    "locales",
+    "badges",
+    "accepted_rules",
+    "shadow_bans",
+    "admins",
+    "session_memory",
+];
+
+type Socket = WebSocketStream<TcpStream>;
+
+struct Options {
+    addr: String,
+    admin_key: String,
+    clients: usize,
+    duration: Duration,
+}
+
+// A connection that has been given a name.
+struct Session {
+    socket: Socket,
+    name: String,
+    token: String,
+    last_msg_id: u64, // Of the Texts sent on it, so the server keeps msg_ids for it.
+}
+
+#[derive(Default)]
+struct Counts {
+    connects: usize,
+    takeovers: usize,
+    renamed: usize, // Resumes and takeovers that were given a new name instead.
+    turned_away: usize,
+}
+
+// What CensusReply says.
+struct Census {
+    kept: Vec<u64>, // In the order of KEPT.
+    leaks: Vec<String>,
+}
+
+fn main() {
+    dotenv::dotenv().ok();
+
+    let options = match parse_options(env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+
+    process::exit(task::block_on(soak(options)));
+}
+
+fn parse_options(args: Vec<String>) -> Result<Options, String> {
+    let default_addr = format!(
+        "{}:{}",
+        env::var("HOST").unwrap_or_else(|_| String::from("127.0.0.1")),
+        env::var("PORT").unwrap_or_else(|_| String::from("8080"))
+    );
+    let mut options = Options {
+        addr: default_addr,
+        admin_key: env::var("ADMIN_KEY").unwrap_or_default(),
+        clients: 4,
+        duration: Duration::from_secs(60),
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--addr" => options.addr = args.next().ok_or("--addr needs host:port.")?,
+            "--admin-key" => options.admin_key = args.next().ok_or("--admin-key needs a key.")?,
+            "--clients" => {
+                options.clients = args
+                    .next()
+                    .and_then(|clients| clients.parse().ok())
+                    .filter(|clients| *clients > 0)
+                    .ok_or("--clients needs a number of at least 1.")?;
+            }
+            "--duration" => {
+                options.duration = args
+                    .next()
+                    .and_then(|secs| secs.parse().ok())
+                    .map(Duration::from_secs)
+                    .ok_or("--duration needs a number of seconds.")?;
+            }
+            _ => return Err(format!("Unknown option {}.", arg)),
+        }
+    }
+
+    if options.admin_key.is_empty() {
+        return Err(String::from(
+            "The census needs the server's ADMIN_KEY, set it or pass --admin-key.",
+        ));
+    }
+    Ok(options)
+}
+
+async fn soak(options: Options) -> i32 {
+    let baseline = match census(&options.addr, &options.admin_key).await {
+        Ok(baseline) => baseline,
+        Err(e) => {
+            eprintln!("Could not take the baseline census: {}", e);
+            return EXIT_DISCONNECTED;
+        }
+    };
+    println!("Baseline: {}", baseline.describe());
+
+    let counts = Arc::new(Mutex::new(Counts::default()));
+    let started_at = Instant::now();
+    let until = started_at + options.duration;
+    let clients = future::join_all(
+        (0..options.clients)
+            .map(|_| task::spawn(churn(options.addr.clone(), until, counts.clone()))),
+    );
+
+    let report = async {
+        while Instant::now() < until {
+            task::sleep(REPORT.min(until - Instant::now())).await;
+            let census = match census(&options.addr, &options.admin_key).await {
+                Ok(census) => census.describe(),
+                Err(e) => format!("no census, {}", e),
+            };
+            println!(
+                "[{}s] {}, {}",
+                started_at.elapsed().as_secs(),
+                counts.lock().unwrap().describe(),
+                census
+            );
+        }
+    };
+    future::join(clients, report).await;
+
+    task::sleep(SETTLE).await;
+    println!("Done: {}", counts.lock().unwrap().describe());
+    match census(&options.addr, &options.admin_key).await {
+        Ok(census) if census.leaks.is_empty() && census.kept == baseline.kept => {
+            println!("Back at the baseline: {}", census.describe());
+            EXIT_OK
+        }
+        Ok(census) => {
+            println!(
+                "LEAK: {}, but the baseline was {}",
+                census.describe(),
+                baseline.describe()
+            );
+            EXIT_LEAKED
+        }
+        // The baseline could be taken, so whatever turns the census away now is left over.
+        Err(e) => {
+            println!("LEAK: there is no census, {}", e);
+            EXIT_LEAKED
+        }
+    }
+}
+
+// Connects, chats and leaves until 'until'.
+async fn churn(addr: String, until: Instant, counts: Arc<Mutex<Counts>>) {
+    let mut session: Option<Session> = None;
+    let mut resume: Option<(String, String)> = None; // (name, token) to reconnect with.
+
+    while Instant::now() < until {
+        let hold = Duration::from_millis(rand::thread_rng().gen_range(0, MAX_HOLD_MS));
+        let leave = rand::thread_rng().gen_range(0, 3);
+
+        let mut current = match session.take() {
+            Some(current) => current,
+            None => {
+                let token = resume.as_ref().map(|(_, token)| token.as_str());
+                match connect(&addr, token, None).await {
+                    Ok(current) => {
+                        let mut counts = counts.lock().unwrap();
+                        counts.connects += 1;
+                        if let Some((name, _)) = resume.take() {
+                            // Fine if the old connection was cleaned up first.
+                            if current.name != name {
+                                counts.renamed += 1;
+                            }
+                        }
+                        current
+                    }
+                    Err(_) => {
+                        counts.lock().unwrap().turned_away += 1;
+                        resume = None;
+                        task::sleep(hold).await;
+                        continue;
+                    }
+                }
+            }
+        };
+
+        if rand::thread_rng().gen_range(0, 4) == 0 {
+            current.last_msg_id += 1;
+            let msg_id = current.last_msg_id;
+            let _ = current
+                .send("Text", json!(null), "Soaking", Some(msg_id))
+                .await;
+        }
+        task::sleep(hold).await;
+
+        match leave {
+            0 => {
+                let _ = timeout(REPLY_TIMEOUT, async {
+                    let _ = current.socket.close(None).await;
+                    while let Some(Ok(_)) = current.socket.next().await {}
+                })
+                .await;
+            }
+            // Dropping the socket ends the connection without a close frame.
+            1 => resume = Some((current.name, current.token)),
+            _ => match connect(&addr, Some(&current.token), None).await {
+                Ok(mut successor) => {
+                    successor.last_msg_id = current.last_msg_id; // The session goes on.
+                    let mut counts = counts.lock().unwrap();
+                    counts.connects += 1;
+                    counts.takeovers += 1;
+                    if successor.name != current.name {
+                        counts.renamed += 1;
+                    }
+                    session = Some(successor);
+                }
+                Err(_) => counts.lock().unwrap().turned_away += 1,
+            },
+        }
+    }
+}
+
+// Connects and waits for a name, taking over the session behind 'token' if there is one
+// and as an admin with 'admin_key'.
+async fn connect(
+    addr: &str,
+    token: Option<&str>,
+    admin_key: Option<&str>,
+) -> Result<Session, String> {
+    let stream = TcpStream::connect(addr).await.map_err(|e| e.to_string())?;
+    let url = match (token, admin_key) {
+        (Some(token), _) => format!("ws://{}/socket?token={}", addr, token),
+        (None, Some(admin_key)) => format!("ws://{}/socket?admin_key={}", addr, admin_key),
+        (None, None) => format!("ws://{}/socket", addr),
+    };
+    let (mut socket, _) = client_async(url, stream).await.map_err(|e| e.to_string())?;
+
+    let mut session_token = String::new();
+    loop {
+        let msg = match timeout(REPLY_TIMEOUT, socket.next()).await {
+            Ok(Some(Ok(TungMessage::Text(msg)))) => msg,
+            Ok(Some(Ok(TungMessage::Close(frame)))) => {
+                return Err(match frame {
+                    Some(frame) if frame.code != CloseCode::Normal => {
+                        format!("the server closed the connection: {}", frame.reason)
+                    }
+                    _ => String::from("the server closed the connection"),
+                })
+            }
+            Ok(Some(Ok(_))) => continue,
+            Ok(Some(Err(e))) => return Err(e.to_string()),
+            Ok(None) => return Err(String::from("the server closed the connection")),
+            Err(_) => return Err(String::from("no name was given in time")),
+        };
+        let msg: Value = serde_json::from_str(&msg).map_err(|e| e.to_string())?;
+        let payload = msg["payload"].as_str().unwrap_or_default().to_string();
+        match msg["type"].as_str() {
+            Some("SessionToken") => session_token = payload,
+            Some("PeerNameAssign") => {
+                return Ok(Session {
+                    socket,
+                    name: payload,
+                    token: session_token,
+                    last_msg_id: 0,
+                })
+            }
+            Some("Challenge") => return Err(String::from("the server sends a challenge")),
+            _ => {}
+        }
+    }
+}
+
+// Asks for the counts on an admin connection of its own, which is among them.
+async fn census(addr: &str, admin_key: &str) -> Result<Census, String> {
+    let mut session = connect(addr, None, Some(admin_key)).await?;
+    session.send("CensusRequest", json!(null), "", None).await?;
+
+    let census = loop {
+        let msg = match timeout(REPLY_TIMEOUT, session.socket.next()).await {
+            Ok(Some(Ok(TungMessage::Text(msg)))) => msg,
+            Ok(Some(Ok(TungMessage::Close(_)))) | Ok(Some(Err(_))) | Ok(None) => {
+                return Err(String::from("the server closed the connection"))
+            }
+            Ok(Some(Ok(_))) => continue,
+            Err(_) => {
+                return Err(String::from(
+                    "no CensusReply in time, is the admin key right?",
+                ))
+            }
+        };
+        // Batches are taken apart, like any peer does.
+        let msg: Value = serde_json::from_str(&msg).unwrap_or_default();
+        let msgs = match msg["type"].as_str() {
+            Some("Batch") => msg["payload"].as_array().cloned().unwrap_or_default(),
+            _ => vec![msg],
+        };
+        if let Some(reply) = msgs
+            .iter()
+            .find(|msg| msg["type"].as_str() == Some("CensusReply"))
+        {
+            let census = &reply["payload"]["census"];
+            break Census {
+                kept: KEPT
+                    .iter()
+                    .map(|what| census[*what].as_u64().unwrap_or_default())
+                    .collect(),
+                leaks: serde_json::from_value(reply["payload"]["leaks"].clone())
+                    .unwrap_or_default(),
+            };
+        }
+    };
+
+    let _ = timeout(REPLY_TIMEOUT, session.socket.close(None)).await;
+    Ok(census)
+}
+
+impl Session {
+    async fn send(
+        &mut self,
+        msg_type: &str,
+        payload: Value,
+        text: &str,
+        msg_id: Option<u64>,
+    ) -> Result<(), String> {
+        let mut msg = json!({
+            "v": PROTOCOL_VERSION,
+            "type": msg_type,
+            "text": text,
+        });
+        if !payload.is_null() {
+            msg["payload"] = payload;
+        }
+        if let Some(msg_id) = msg_id {
+            msg["msg_id"] = json!(msg_id);
+        }
+        self.socket
+            .send(TungMessage::Text(msg.to_string()))
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl Counts {
+    fn describe(&self) -> String {
+        format!(
+            "{} connects, {} takeovers, {} renamed, {} turned away",
+            self.connects, self.takeovers, self.renamed, self.turned_away
+        )
+    }
+}
+
+impl Census {
+    fn describe(&self) -> String {
+        let mut description = KEPT
+            .iter()
+            .zip(&self.kept)
+            .map(|(what, entries)| format!("{} {}", entries, what))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if !self.leaks.is_empty() {
+            description.push_str(&format!(" (leaking {})", self.leaks.join(", ")));
+        }
+        description
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_std::task;
+use serde::{Deserialize, Serialize};
+
+use crate::server::Server;
+
+// How many entries everything kept per connection, name or session has, for spotting
+// leaks: none of them should have more than there are connections, and once no
+// connection is left all of them should be back at 0. The console's census command
+// shows it, admins get it with CensusRequest, and with CENSUS_INTERVAL_SECS it is
+// logged periodically.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Census {
+    pub connections: usize, // Connection tasks still running, handshakes included.
+    pub peers: usize,
+    pub names: usize,
+    pub sessions: usize,
+    pub session_msg_ids: usize,
+    pub subscribers: usize,
+    pub locales: usize,
+    pub badges: usize,
+    pub accepted_rules: usize,
+    pub shadow_bans: usize, // Of sessions, those of IP addresses outlive them.
+    pub admins: usize,
+    // Addresses with spam state. It outlives their connections until it has cooled down,
+    // so it is no leak while there are entries left.
+    pub spam_filters: usize,
+    pub spam_warnings: usize, // Since the start, like the two below.
+    pub spam_slowed: usize,
+    pub spam_mutes: usize,
+    pub memory: usize, // In bytes, see memory::MemoryUsage.
+    // The part of 'memory' kept per session. The rest grows with the history.
+    pub session_memory: usize,
+}
+
+impl Census {
+    // What has more entries than there are connections, each of which keeps one at most.
+    pub fn leaks(&self) -> Vec<&'static str> {
+        [
+            ("peers", self.peers),
+            ("names", self.names),
+            ("sessions", self.sessions),
+            ("session msg_ids", self.session_msg_ids),
+            ("subscribers", self.subscribers),
+            ("locales", self.locales),
+            ("badges", self.badges),
+            ("accepted rules", self.accepted_rules),
+            ("shadow bans", self.shadow_bans),
+            ("admins", self.admins),
+        ]
+        .iter()
+        .filter(|(_, entries)| *entries > self.connections)
+        .map(|(what, _)| *what)
+        .collect()
+    }
+
+    pub fn describe(&self) -> String {
+        let mut description = format!(
+            "Connections: {}, peers: {}, names: {}, sessions: {}, session msg_ids: {}, subscribers: {}, locales: {}, badges: {}, accepted rules: {}, shadow bans: {}, admins: {}, spam filters: {}, memory: {} bytes, {} of them per session\nSpam: {} warnings, {} slowed down, {} muted",
+            self.connections,
+            self.peers,
+            self.names,
+            self.sessions,
+            self.session_msg_ids,
+            self.subscribers,
+            self.locales,
+            self.badges,
+            self.accepted_rules,
+            self.shadow_bans,
+            self.admins,
+            self.spam_filters,
+            self.memory,
+            self.session_memory,
+            self.spam_warnings,
+            self.spam_slowed,
+            self.spam_mutes
+        );
+        let leaks = self.leaks();
+        if !leaks.is_empty() {
+            description.push_str(&format!(
+                "\nLEAK: there are more {} than the {} connections left.",
+                leaks.join(", "),
+                self.connections
+            ));
+        }
+        description
+    }
+}
+
+// Counts a connection task for as long as it is alive.
+pub struct Counted(Arc<AtomicUsize>);
+
+impl Counted {
+    pub fn new(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(count.clone())
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Logs the census every 'interval'.
+pub async fn run(server: Server, interval: Duration) {
+    loop {
+        task::sleep(interval).await;
+        println!("\n[Census] {}", server.census().describe());
+    }
+}
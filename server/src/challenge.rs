@@ -0,0 +1,96 @@
+use std::{collections::HashSet, env, net::IpAddr, time::Duration};
+
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::config::{env_list, env_or};
+
+const NONCE_LEN: usize = 16;
+// Already billions of hashes on average, more would shut out every peer.
+const MAX_POW_DIFFICULTY: u32 = 32;
+
+pub struct ChallengeConfig {
+    pub difficulty: u32, // Leading zero bits a proof of work must have. Zero turns it off.
+    pub question: Option<String>, // Asked instead of a proof of work when set.
+    pub answer: String,  // The expected answer to 'question', ignoring case.
+    pub exempt_ips: HashSet<IpAddr>, // Addresses that never get a challenge.
+    pub timeout: Duration, // How long a peer has to answer.
+}
+
+impl ChallengeConfig {
+    pub fn from_env() -> Self {
+        let question = env::var("CHALLENGE_QUESTION").ok();
+        let answer = env::var("CHALLENGE_ANSWER").unwrap_or_default();
+        // Against an empty answer peers pass by sending nothing, so it is no challenge.
+        assert!(
+            question.is_none() || !answer.trim().is_empty(),
+            "CHALLENGE_QUESTION is set, but CHALLENGE_ANSWER is empty!"
+        );
+
+        let difficulty = env_or("POW_DIFFICULTY", 0);
+        assert!(
+            difficulty <= MAX_POW_DIFFICULTY,
+            "POW_DIFFICULTY can be {} at most!",
+            MAX_POW_DIFFICULTY
+        );
+
+        Self {
+            difficulty,
+            question,
+            answer,
+            exempt_ips: env_list("CHALLENGE_EXEMPT_IPS").into_iter().collect(),
+            timeout: Duration::from_secs(env_or("CHALLENGE_TIMEOUT_SECS", 30)),
+        }
+    }
+
+    // The challenge a peer connecting from 'ip' has to answer, if any.
+    pub fn challenge_for(&self, ip: &IpAddr) -> Option<Challenge> {
+        if self.exempt_ips.contains(ip) {
+            return None;
+        }
+
+        match &self.question {
+            Some(question) => Some(Challenge::Question(question.clone())),
+            None if self.difficulty > 0 => Some(Challenge::ProofOfWork {
+                nonce: rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(NONCE_LEN)
+                    .collect(),
+                difficulty: self.difficulty,
+            }),
+            None => None,
+        }
+    }
+
+    pub fn verify(&self, challenge: &Challenge, answer: &str) -> bool {
+        match challenge {
+            Challenge::Question(_) => answer.trim().eq_ignore_ascii_case(self.answer.trim()),
+            Challenge::ProofOfWork { nonce, difficulty } => {
+                leading_zero_bits(&Sha1::digest(format!("{}{}", nonce, answer).as_bytes()))
+                    >= *difficulty
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Challenge {
+    // Find an answer such that SHA-1(nonce + answer) starts with 'difficulty' zero bits.
+    ProofOfWork { nonce: String, difficulty: u32 },
+    // A question configured by the operator.
+    Question(String),
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+
+    bits
+}
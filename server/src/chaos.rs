@@ -0,0 +1,106 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use async_std::task;
+use async_tungstenite::tungstenite::protocol::Message as TungMessage;
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver},
+    StreamExt,
+};
+use rand::Rng;
+
+// How often a connection checks whether the console has cut it off.
+const CUT_POLL: Duration = Duration::from_millis(50);
+
+// Faults the console's chaos command injects into a server built with `--features
+// chaos`, so integration tests can exercise the clients' reconnect and resume logic.
+// Everything is off until the console turns it on.
+static FRAME_DELAY_MS: AtomicU64 = AtomicU64::new(0); // Each frame to a peer waits up to this long, at random.
+static DROP_PERCENT: AtomicU32 = AtomicU32::new(0); // Of the Text frames to peers, this many in 100 are never sent.
+static PERSIST_DELAY_MS: AtomicU64 = AtomicU64::new(0); // Every write to the event log takes this much longer.
+static CUT: Mutex<Vec<SocketAddr>> = Mutex::new(Vec::new()); // Connections to drop without a close frame.
+
+pub fn set_frame_delay(max: Duration) {
+    FRAME_DELAY_MS.store(max.as_millis() as u64, Ordering::Relaxed);
+}
+
+pub fn set_drop_percent(percent: u32) {
+    DROP_PERCENT.store(percent.min(100), Ordering::Relaxed);
+}
+
+pub fn set_persist_delay(delay: Duration) {
+    PERSIST_DELAY_MS.store(delay.as_millis() as u64, Ordering::Relaxed);
+}
+
+pub fn cut(peer_addr: SocketAddr) {
+    CUT.lock().unwrap().push(peer_addr);
+}
+
+pub fn clear() {
+    set_frame_delay(Duration::ZERO);
+    set_drop_percent(0);
+    set_persist_delay(Duration::ZERO);
+}
+
+pub fn describe() -> String {
+    format!(
+        "Frame delay up to {} ms, {}% of Text frames dropped, event log writes {} ms slower",
+        FRAME_DELAY_MS.load(Ordering::Relaxed),
+        DROP_PERCENT.load(Ordering::Relaxed),
+        PERSIST_DELAY_MS.load(Ordering::Relaxed)
+    )
+}
+
+// Passes on what is queued for a peer, delayed and thinned out as set. Only Text frames
+// are dropped, a lost Close would leave the peer hanging rather than reconnecting.
+pub fn faulty(mut receiver: UnboundedReceiver<TungMessage>) -> UnboundedReceiver<TungMessage> {
+    let (sender, faulty) = unbounded();
+
+    task::spawn(async move {
+        while let Some(msg) = receiver.next().await {
+            let max_delay = FRAME_DELAY_MS.load(Ordering::Relaxed);
+            if max_delay > 0 {
+                let delay = rand::thread_rng().gen_range(0, max_delay + 1);
+                task::sleep(Duration::from_millis(delay)).await;
+            }
+            let dropped =
+                rand::thread_rng().gen_range(0, 100) < DROP_PERCENT.load(Ordering::Relaxed);
+            if msg.is_text() && dropped {
+                continue;
+            }
+            if sender.unbounded_send(msg).is_err() {
+                break;
+            }
+        }
+    });
+    faulty
+}
+
+// Returns once the console has cut off 'peer_addr'.
+pub async fn cut_off(peer_addr: SocketAddr) {
+    loop {
+        {
+            let mut cut = CUT.lock().unwrap();
+            if let Some(i) = cut.iter().position(|addr| *addr == peer_addr) {
+                cut.remove(i);
+                return;
+            }
+        }
+        task::sleep(CUT_POLL).await;
+    }
+}
+
+// Blocks like a slow disk would, before the event log is written to.
+pub fn slow_persistence() {
+    let delay = PERSIST_DELAY_MS.load(Ordering::Relaxed);
+    if delay > 0 {
+        thread::sleep(Duration::from_millis(delay));
+    }
+}
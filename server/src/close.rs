@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use async_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+
+// Why the server closed a connection, sent as the code and reason of the close frame.
+// The codes live in the 4000-4999 range WebSocket leaves to applications, and clients
+// decide from them whether to reconnect. Keep in sync with test-client/src/close.rs.
+#[allow(dead_code)] // Idle is reserved for a feature that will send it.
+#[derive(Debug, Clone, Copy)]
+pub enum CloseReason {
+    Kicked,          // An admin has kicked the peer.
+    Banned,          // The peer is banned.
+    Idle,            // The peer has been idle for too long.
+    ServerFull,      // There are no names left to give out.
+    ProtocolError,   // The peer has sent something that is not a valid message.
+    Shutdown,        // The server is shutting down.
+    ChallengeFailed, // The peer did not pass the connect challenge in time.
+    InviteRequired,  // The server is invite-only and the peer presented no valid invite.
+    Replaced,        // A newer connection has taken over the peer's session token.
+    RetryAfter,      // The server is taking too many connections, see retry_after_frame.
+}
+
+impl CloseReason {
+    pub fn code(self) -> u16 {
+        match self {
+            CloseReason::Kicked => 4000,
+            CloseReason::Banned => 4001,
+            CloseReason::Idle => 4002,
+            CloseReason::ServerFull => 4003,
+            CloseReason::ProtocolError => 4004,
+            CloseReason::Shutdown => 4005,
+            CloseReason::ChallengeFailed => 4006,
+            CloseReason::InviteRequired => 4007,
+            CloseReason::Replaced => 4008,
+            CloseReason::RetryAfter => 4009,
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            CloseReason::Kicked => "kicked",
+            CloseReason::Banned => "banned",
+            CloseReason::Idle => "idle",
+            CloseReason::ServerFull => "server-full",
+            CloseReason::ProtocolError => "protocol-error",
+            CloseReason::Shutdown => "shutdown",
+            CloseReason::ChallengeFailed => "challenge-failed",
+            CloseReason::InviteRequired => "invite-required",
+            CloseReason::Replaced => "replaced",
+            CloseReason::RetryAfter => "retry-after",
+        }
+    }
+
+    pub fn frame(self) -> CloseFrame<'static> {
+        CloseFrame {
+            code: CloseCode::from(self.code()),
+            reason: self.reason().into(),
+        }
+    }
+}
+
+// Turns a connection away for 'after', which the reason carries in whole seconds,
+// e.g. "retry-after 3".
+pub fn retry_after_frame(after: Duration) -> CloseFrame<'static> {
+    let secs = after.as_secs() + u64::from(after.subsec_nanos() > 0);
+
+    CloseFrame {
+        code: CloseCode::from(CloseReason::RetryAfter.code()),
+        reason: format!("{} {}", CloseReason::RetryAfter.reason(), secs).into(),
+    }
+}
@@ -0,0 +1,90 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use serde::{Deserialize, Serialize};
+
+// A slash command registered by a bot, as peers get to see it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommandInfo {
+    pub name: String, // Without the slash, e.g. "deploy" for `/deploy`.
+    pub description: String,
+    pub bot: String, // The name of the bot the command is routed to.
+}
+
+// Slash commands bots have registered, each bound to the connection of its bot.
+pub struct Commands {
+    commands: HashMap<String, (SocketAddr, CommandInfo)>,
+}
+
+impl Commands {
+    pub fn new() -> Self {
+        Self {
+            commands: HashMap::new(),
+        }
+    }
+
+    // Registers or re-describes 'name'. Fails with the name of the bot that owns
+    // 'name' if that is another connection.
+    pub fn register(
+        &mut self,
+        name: &str,
+        description: &str,
+        bot: &str,
+        bot_addr: &SocketAddr,
+    ) -> Result<(), String> {
+        if let Some((owner_addr, info)) = self.commands.get(name) {
+            if owner_addr != bot_addr {
+                return Err(info.bot.clone());
+            }
+        }
+
+        let info = CommandInfo {
+            name: name.to_string(),
+            description: description.to_string(),
+            bot: bot.to_string(),
+        };
+        self.commands.insert(name.to_string(), (*bot_addr, info));
+        Ok(())
+    }
+
+    // Drops every command of the bot at 'bot_addr'. Returns whether there were any.
+    pub fn unregister_all(&mut self, bot_addr: &SocketAddr) -> bool {
+        let registered = self.commands.len();
+        self.commands
+            .retain(|_, (owner_addr, _)| owner_addr != bot_addr);
+        self.commands.len() != registered
+    }
+
+    // The bot connection 'name' is routed to.
+    pub fn owner(&self, name: &str) -> Option<SocketAddr> {
+        self.commands.get(name).map(|(owner_addr, _)| *owner_addr)
+    }
+
+    // Every registered command, sorted by name.
+    pub fn list(&self) -> Vec<CommandInfo> {
+        let mut commands: Vec<CommandInfo> = self
+            .commands
+            .values()
+            .map(|(_, info)| info.clone())
+            .collect();
+        commands.sort_by(|a, b| a.name.cmp(&b.name));
+        commands
+    }
+}
+
+// Splits "/deploy prod now" into ("deploy", "prod now").
+pub fn parse_invocation(text: &str) -> Option<(&str, &str)> {
+    let invocation = text.trim().strip_prefix('/')?;
+
+    match invocation.split_once(char::is_whitespace) {
+        Some((name, args)) => Some((name, args.trim())),
+        None => Some((invocation, "")),
+    }
+}
+
+// Command names are what follows the slash, so they may not hold whitespace or slashes.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
@@ -0,0 +1,98 @@
+use std::{env, str::FromStr, time::Duration};
+
+use crate::{
+    challenge::ChallengeConfig,
+    content::ContentConfig,
+    digest::DigestConfig,
+    filter::FilterConfig,
+    help::{self, HelpEntry},
+    locale::Catalog,
+    spam::SpamConfig,
+    welcome::WelcomeConfig,
+};
+
+// Optional server settings. Each part reads its own environment variables
+// and falls back to a default when a variable is missing or malformed.
+pub struct Config {
+    pub admin_key: Option<String>, // Connections presenting it as `?admin_key=` are admins.
+    pub invite_only: bool,         // Whether new peers need an invite token from an admin.
+    pub msg_id_window: usize,      // How many msg_ids per peer are kept to spot resends.
+    pub backfill_size: usize,      // How many of the latest messages a new peer is sent.
+    pub history_size: usize,       // How many messages are kept for Backfill and FetchContext.
+    pub history_budget: usize,     // How many bytes of messages are kept at most, 0 for no limit.
+    pub accept_rate: u32,          // How many connections a second are accepted, 0 for no limit.
+    pub reuse_port: bool, // Whether to bind with SO_REUSEPORT, for handing over to a new binary.
+    pub batch_window: Duration, // How long messages to a peer are collected into one Batch, zero for never.
+    // Once the tracked memory (see memory::MemoryUsage) is over this many bytes, new
+    // peers are not sent a Backfill. 0 for no limit.
+    pub memory_budget: usize,
+    // The address peers reach the server under, for permalinks. Defaults to HOST:PORT.
+    pub public_addr: Option<String>,
+    // Where the console's archive command looks for day.html and index.html templates.
+    pub archive_templates: String,
+    // The custom emoji the server starts with, see emoji::Emoji::load.
+    pub emoji_file: String,
+    // Where every change to the state is recorded and replayed from on startup, see
+    // events::EventLog. None to keep no log.
+    pub event_log: Option<String>,
+    // How often the census (see census::Census) is logged, None for never.
+    pub census_interval: Option<Duration>,
+    // The operator's own help topics, read from HELP_FILE.
+    pub help_topics: Vec<HelpEntry>,
+    // Translations of the texts the server sends, read from LOCALE_DIR.
+    pub catalog: Catalog,
+    pub spam: SpamConfig,
+    pub challenge: ChallengeConfig,
+    pub filter: FilterConfig,
+    pub content: ContentConfig,
+    pub welcome: WelcomeConfig,
+    pub digest: DigestConfig,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self {
+            admin_key: env::var("ADMIN_KEY").ok().filter(|key| !key.is_empty()),
+            invite_only: env_or("INVITE_ONLY", false),
+            msg_id_window: env_or("MSG_ID_WINDOW", 256),
+            backfill_size: env_or("BACKFILL_SIZE", 20),
+            history_size: env_or("HISTORY_SIZE", 500),
+            history_budget: env_or("HISTORY_BUDGET", 0),
+            memory_budget: env_or("MEMORY_BUDGET", 0),
+            accept_rate: env_or("ACCEPT_RATE", 0),
+            reuse_port: env_or("REUSE_PORT", false),
+            batch_window: Duration::from_millis(env_or("BATCH_WINDOW_MS", 0)),
+            public_addr: env::var("PUBLIC_ADDR").ok().filter(|addr| !addr.is_empty()),
+            archive_templates: env_or("ARCHIVE_TEMPLATES", String::from("archive-templates")),
+            emoji_file: env_or("EMOJI_FILE", String::from("emoji.txt")),
+            event_log: env::var("EVENT_LOG").ok().filter(|path| !path.is_empty()),
+            census_interval: Some(env_or("CENSUS_INTERVAL_SECS", 0))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            help_topics: help::load_topics(&env_or("HELP_FILE", String::from("help.txt"))),
+            catalog: Catalog::load(&env_or("LOCALE_DIR", String::from("locales"))),
+            spam: SpamConfig::from_env(),
+            challenge: ChallengeConfig::from_env(),
+            filter: FilterConfig::from_env(),
+            content: ContentConfig::from_env(),
+            welcome: WelcomeConfig::from_env(),
+            digest: DigestConfig::from_env(),
+        }
+    }
+}
+
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+// Parses a comma separated list, skipping entries that fail to parse.
+pub fn env_list<T: FromStr>(key: &str) -> Vec<T> {
+    env::var(key)
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|value| value.trim().parse().ok())
+        .collect()
+}
@@ -0,0 +1,112 @@
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+};
+
+// Where a connection is in its life. Connections only ever move forward through these,
+// and which messages a peer may send depends on where its connection is, see check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum State {
+    Accepted,       // The TCP connection is in, the WebSocket handshake under way.
+    Authenticating, // Being checked for bans, a connect challenge and an invite.
+    Named,          // Given a name, the greeting up to Backfill is queued. Nothing is read.
+    Active,         // Chatting.
+    // The server no longer accepts connections, it is handed over or drained. Chatting
+    // goes on until the peer is closed.
+    Draining,
+    Closed, // Gone, what belonged to the connection is cleaned up.
+}
+
+// The State of a connection that moves on to Closed when it is dropped, so the connection
+// ends up Closed whichever way on_peer_connect returns, turned away during the handshake
+// or not.
+pub struct Lifecycle(State);
+
+// Who sends a message type and when, as far as the state machine is concerned. Whether
+// the peer is an admin is checked on top of this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    Handshake, // A reply to the connect challenge, only while Authenticating.
+    Chat,      // Anything peers send while chatting, also while Draining.
+    // Sets up something meant to last, like a bot's command or an invite. Not while
+    // Draining, it would go down with the server.
+    Setup,
+    Server, // Only ever sent by the server.
+}
+
+// Why a message was refused. Peers are told in the text behind 'key'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IllegalMessage {
+    pub state: State,
+    pub origin: Origin,
+}
+
+impl State {
+    pub fn advance(&mut self, next: State) {
+        debug_assert!(next > *self, "{:?} cannot follow {:?}", next, self);
+        *self = next;
+    }
+
+    pub fn check(self, origin: Origin) -> Result<(), IllegalMessage> {
+        let legal = match origin {
+            Origin::Handshake => self == State::Authenticating,
+            Origin::Chat => self == State::Active || self == State::Draining,
+            Origin::Setup => self == State::Active,
+            Origin::Server => false,
+        };
+
+        if legal {
+            Ok(())
+        } else {
+            Err(IllegalMessage {
+                state: self,
+                origin,
+            })
+        }
+    }
+}
+
+impl Lifecycle {
+    pub fn accepted() -> Self {
+        Self(State::Accepted)
+    }
+}
+
+impl Deref for Lifecycle {
+    type Target = State;
+
+    fn deref(&self) -> &State {
+        &self.0
+    }
+}
+
+impl DerefMut for Lifecycle {
+    fn deref_mut(&mut self) -> &mut State {
+        &mut self.0
+    }
+}
+
+impl Drop for Lifecycle {
+    fn drop(&mut self) {
+        self.0.advance(State::Closed);
+    }
+}
+
+impl IllegalMessage {
+    pub fn key(&self) -> &'static str {
+        match (self.origin, self.state) {
+            (Origin::Server, _) => "message-server-only",
+            (Origin::Setup, State::Draining) => "message-draining",
+            _ => "message-out-of-order",
+        }
+    }
+}
+
+impl fmt::Display for IllegalMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.origin {
+            Origin::Server => write!(f, "only the server sends it"),
+            _ => write!(f, "not legal while {:?}", self.state),
+        }
+    }
+}
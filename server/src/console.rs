@@ -0,0 +1,359 @@
+use std::time::Duration;
+
+use async_std::{
+    io::{self, BufReader},
+    prelude::*,
+};
+
+#[cfg(feature = "chaos")]
+use crate::chaos;
+use crate::{
+    badge::Badge,
+    server::{Server, ROOM_NAME},
+};
+
+// How long a drain gives peers to leave unless a duration is given.
+const DRAIN_DEFAULT: Duration = Duration::from_secs(60);
+// How long a handover keeps serving the peers before moving them over.
+const HANDOVER_DEFAULT: Duration = Duration::from_secs(30);
+
+const HELP: &str = "Commands: peers, rooms, kick <name>, ban <name> [duration, e.g. 30m, 1h, 2d], \
+broadcast <text>, badge <name> <staff|bot|verified>, unbadge <name> <badge>, digest, slowmode [room] [duration|off], archive [dir], emoji [add <name> <value> | remove <name>], reports, resolve <id>, dismiss <id>, stats, memory, census, handover [duration], drain [duration] [address], help";
+
+// Reads admin commands from the server's stdin until it is closed.
+pub async fn run(server: Server) {
+    let mut lines = BufReader::new(io::stdin()).lines();
+
+    while let Some(Ok(line)) = lines.next().await {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (command, args) = match line.split_once(' ') {
+            Some((command, args)) => (command, args.trim()),
+            None => (line, ""),
+        };
+
+        let reply = match command {
+            "peers" => {
+                let peers = server.peers();
+                let mut reply = format!("{} peer(s) online", peers.len());
+                for (name, addr, shadow_banned) in peers {
+                    reply.push_str(&format!(
+                        "\n    {} ({}){}",
+                        name,
+                        addr,
+                        if shadow_banned {
+                            " [shadow banned]"
+                        } else {
+                            ""
+                        }
+                    ));
+                }
+                reply
+            }
+            "rooms" => String::from("There are no rooms, everyone shares the one channel."),
+            "kick" if !args.is_empty() => {
+                if server.kick(args) {
+                    format!("Kicked {}.", args)
+                } else {
+                    format!("{} is not connected.", args)
+                }
+            }
+            "ban" if !args.is_empty() => {
+                let (name, duration) = match args.split_once(' ') {
+                    Some((name, duration)) => (name, Some(duration.trim())),
+                    None => (args, None),
+                };
+
+                match duration.map(|duration| (duration, parse_duration(duration))) {
+                    Some((_, None)) => String::from("Durations look like 45s, 30m, 1h or 2d."),
+                    Some((text, Some(duration))) => match server.ban(name, Some(duration)) {
+                        Ok(ip) => format!("Banned {} ({}) for {}.", name, ip, text),
+                        Err(e) => e,
+                    },
+                    None => match server.ban(name, None) {
+                        Ok(ip) => format!("Banned {} ({}).", name, ip),
+                        Err(e) => e,
+                    },
+                }
+            }
+            "slowmode" => {
+                let (room, interval) = match args.split_once(' ') {
+                    Some((room, interval)) => (room, interval.trim()),
+                    None => (ROOM_NAME, args),
+                };
+                let interval = match interval {
+                    "" => None,
+                    "off" => Some(Some(Duration::ZERO)),
+                    interval => Some(parse_duration(interval)),
+                };
+
+                match interval {
+                    None => match server.slow_mode() {
+                        Duration::ZERO => String::from("Slow mode is off."),
+                        interval => format!("Slow mode is {}s.", interval.as_secs()),
+                    },
+                    Some(None) => String::from("Durations look like 45s, 30m, 1h or 2d."),
+                    Some(Some(interval)) => match server.set_slow_mode(room, interval) {
+                        Ok(()) if interval.is_zero() => format!("Slow mode in {} is off.", room),
+                        Ok(()) => format!("Slow mode in {} is {}s.", room, interval.as_secs()),
+                        Err(e) => e,
+                    },
+                }
+            }
+            "broadcast" if !args.is_empty() => {
+                server.broadcast(args);
+                format!("Broadcast: {}", args)
+            }
+            "badge" | "unbadge" if !args.is_empty() => {
+                let (name, badge) = args.split_once(' ').unwrap_or((args, ""));
+                match badge.trim().parse::<Badge>() {
+                    Ok(badge) if server.set_badge(name, badge, command == "badge") => {
+                        if command == "badge" {
+                            format!("{} now has the {} badge.", name, badge)
+                        } else {
+                            format!("{} no longer has the {} badge.", name, badge)
+                        }
+                    }
+                    Ok(_) => format!("{} is not connected.", name),
+                    Err(_) => String::from("Badges are staff, bot and verified."),
+                }
+            }
+            "digest" => match server.post_digest() {
+                Some(digest) => format!("Posted the digest: {}", digest),
+                None => String::from(
+                    "No digest, DIGEST is off or nothing has been said since the last one.",
+                ),
+            },
+            "emoji" => {
+                let mut args = args.split_whitespace();
+                match (args.next(), args.next(), args.next()) {
+                    (None, _, _) => {
+                        let list = server.emoji();
+                        let mut reply = format!("{} custom emoji", list.emoji.len());
+                        for emoji in list.emoji {
+                            reply.push_str(&format!("\n    :{}: {}", emoji.name, emoji.value));
+                        }
+                        reply
+                    }
+                    (Some("add"), Some(name), Some(value)) => match server.add_emoji(name, value) {
+                        Ok(()) => format!("Added :{}: {}.", name.trim_matches(':'), value),
+                        Err(e) => format!("Could not add the emoji, {}.", e),
+                    },
+                    (Some("remove"), Some(name), None) => {
+                        if server.remove_emoji(name) {
+                            format!("Removed :{}:.", name.trim_matches(':'))
+                        } else {
+                            format!("There is no emoji :{}:.", name.trim_matches(':'))
+                        }
+                    }
+                    _ => String::from("Usage: emoji [add <name> <value> | remove <name>]"),
+                }
+            }
+            "reports" => {
+                let reports = server.reports();
+                let mut reply = format!("{} open report(s)", reports.len());
+                for report in reports {
+                    reply.push_str(&format!(
+                        "\n    #{} by {} on message {} by {}, \"{}\": {}",
+                        report.id,
+                        report.reporter,
+                        report.msg_id,
+                        report.author,
+                        report.text,
+                        report.reason
+                    ));
+                }
+                reply
+            }
+            "resolve" | "dismiss" if !args.is_empty() => match args.parse() {
+                Ok(id) => match server.close_report(id, command == "resolve") {
+                    Ok(report) if command == "resolve" => {
+                        format!("Resolved report #{}.", report.id)
+                    }
+                    Ok(report) => format!("Dismissed report #{}.", report.id),
+                    Err(e) => e,
+                },
+                Err(_) => String::from("Reports go by their number, see reports."),
+            },
+            "stats" => {
+                let stats = server.stats();
+                format!(
+                    "Peers online: {}, spots left: {}, bans: {}, shadow bans: {} ({} by IP), invites: {}, uptime: {} seconds",
+                    stats.peers_online,
+                    stats.peer_spots_left,
+                    stats.bans,
+                    stats.shadow_bans + stats.ip_shadow_bans,
+                    stats.ip_shadow_bans,
+                    stats.invites,
+                    stats.uptime.as_secs()
+                )
+            }
+            "archive" => {
+                let dir = match args {
+                    "" => "archive",
+                    dir => dir,
+                };
+                match server.archive(dir) {
+                    Ok(days) => format!("Archived {} day(s) of history to {}.", days, dir),
+                    Err(e) => format!("Could not write the archive to {}: {}", dir, e),
+                }
+            }
+            "memory" => {
+                let memory = server.memory();
+                format!(
+                    "History: {} bytes, msg_ids: {} bytes, digest activity: {} bytes, total: {} bytes{}",
+                    memory.history,
+                    memory.msg_ids,
+                    memory.activity,
+                    memory.total(),
+                    match memory.budget {
+                        0 => String::new(),
+                        budget => format!(" of the {} byte budget", budget),
+                    }
+                )
+            }
+            "census" => server.census().describe(),
+            "handover" => {
+                let duration = match args {
+                    "" => Some(HANDOVER_DEFAULT),
+                    duration => parse_duration(duration),
+                };
+
+                match duration {
+                    None => String::from("Durations look like 45s, 30m, 1h or 2d."),
+                    Some(duration) => match server.hand_over(duration) {
+                        Ok(()) => format!(
+                            "No longer accepting connections. Peers still connected in {} seconds \
+                            are moved over, and the server exits once they are gone.",
+                            duration.as_secs()
+                        ),
+                        Err(e) => e,
+                    },
+                }
+            }
+            "drain" => {
+                let (duration, redirect) = match args.split_once(' ') {
+                    Some((duration, redirect)) => (duration, Some(redirect.trim())),
+                    None => (args, None),
+                };
+                let duration = match duration {
+                    "" => Some(DRAIN_DEFAULT),
+                    duration => parse_duration(duration),
+                };
+
+                match duration {
+                    None => String::from("Durations look like 45s, 30m, 1h or 2d."),
+                    Some(duration) => match server.drain(duration, redirect.map(str::to_string)) {
+                        Ok(()) => format!(
+                            "Draining, the server goes down in {} seconds{}.",
+                            duration.as_secs(),
+                            redirect
+                                .map(|addr| format!(" and peers are sent to {}", addr))
+                                .unwrap_or_default()
+                        ),
+                        Err(e) => e,
+                    },
+                }
+            }
+            #[cfg(feature = "chaos")]
+            "chaos" => chaos_command(&server, args),
+            _ => String::from(HELP),
+        };
+
+        println!("\n[Console] {}", reply);
+    }
+}
+
+#[cfg(feature = "chaos")]
+fn chaos_command(server: &Server, args: &str) -> String {
+    let (fault, arg) = match args.split_once(' ') {
+        Some((fault, arg)) => (fault, arg.trim()),
+        None => (args, ""),
+    };
+
+    match (fault, arg.parse::<u64>()) {
+        ("", _) => chaos::describe(),
+        ("delay", Ok(max)) => {
+            chaos::set_frame_delay(Duration::from_millis(max));
+            format!("Frames to peers now wait up to {} ms.", max)
+        }
+        ("drop", Ok(percent)) if percent <= 100 => {
+            chaos::set_drop_percent(percent as u32);
+            format!("{}% of Text frames to peers are now dropped.", percent)
+        }
+        ("persist", Ok(delay)) => {
+            chaos::set_persist_delay(Duration::from_millis(delay));
+            format!("Event log writes now take {} ms longer.", delay)
+        }
+        ("disconnect", _) if !arg.is_empty() => {
+            if server.cut_off(arg) {
+                format!("Dropped the connection of {}.", arg)
+            } else {
+                format!("{} is not connected.", arg)
+            }
+        }
+        ("off", _) => {
+            chaos::clear();
+            String::from("No more faults are injected.")
+        }
+        _ => String::from(
+            "Usage: chaos [delay <ms> | drop <percent> | persist <ms> | disconnect <name> | off]",
+        ),
+    }
+}
+
+// Parses durations like "45s", "30m", "1h" and "2d".
+fn parse_duration(text: &str) -> Option<Duration> {
+    // The unit is the last character, which need not be a single byte when mistyped.
+    let (split, _) = text.char_indices().last()?;
+    let (amount, unit) = text.split_at(split);
+    let amount: u64 = amount.parse().ok()?;
+
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => return None,
+    };
+
+    amount.checked_mul(unit_secs).map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_are_parsed_by_unit() {
+        assert_eq!(parse_duration("45s"), Some(Duration::from_secs(45)));
+        assert_eq!(parse_duration("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(60 * 60)));
+        assert_eq!(
+            parse_duration("2d"),
+            Some(Duration::from_secs(2 * 24 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn malformed_durations_are_refused() {
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration("10w"), None);
+        assert_eq!(parse_duration("5é"), None);
+        assert_eq!(parse_duration("é"), None);
+    }
+
+    #[test]
+    fn durations_too_long_to_count_are_refused() {
+        assert_eq!(parse_duration(&format!("{}d", u64::MAX / 60)), None);
+        assert_eq!(
+            parse_duration(&format!("{}s", u64::MAX)),
+            Some(Duration::from_secs(u64::MAX))
+        );
+    }
+}
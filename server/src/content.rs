@@ -0,0 +1,108 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Error as IoError, ErrorKind, Read, Write},
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use async_std::{future::timeout, task};
+
+use crate::config::env_or;
+
+// The most of a lookup's output that is posted.
+const RESULT_MAX_LEN: usize = 2000;
+const WAIT_POLL: Duration = Duration::from_millis(10);
+
+// Slash commands the server answers itself by running an operator's script, e.g. `/gif
+// cats` looking up a GIF, so that API keys stay on the server. CONTENT_FILE has one
+// "name: command" per line, lines starting with # are skipped. The command is run
+// through `sh -c exec` with the query on its stdin and has CONTENT_TIMEOUT_MS to print
+// what is posted, e.g. a link.
+pub struct ContentConfig {
+    pub commands: HashMap<String, String>, // Name without the slash -> command.
+    pub timeout: Duration,
+}
+
+impl ContentConfig {
+    pub fn from_env() -> Self {
+        let path = env_or("CONTENT_FILE", String::from("content.txt"));
+        let commands = fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .filter(|line| !line.trim_start().starts_with('#'))
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, command)| (name.trim().to_lowercase(), command.trim().to_string()))
+            .filter(|(name, command)| !name.is_empty() && !command.is_empty())
+            .collect();
+
+        Self {
+            commands,
+            timeout: Duration::from_millis(env_or("CONTENT_TIMEOUT_MS", 3000)),
+        }
+    }
+
+    pub fn handles(&self, name: &str) -> bool {
+        self.commands.contains_key(&name.to_lowercase())
+    }
+
+    // Runs the command behind 'name' for 'query'. Fails if there is no such command
+    // or it fails, prints nothing or does not finish in time.
+    pub async fn look_up(&self, name: &str, query: &str) -> Result<String, IoError> {
+        let command = self
+            .commands
+            .get(&name.to_lowercase())
+            .ok_or_else(|| IoError::new(ErrorKind::NotFound, "no such content command"))?;
+
+        let mut process = Command::new("sh")
+            .arg("-c")
+            // exec, so that killing the child kills the script rather than just the shell.
+            .arg(format!("exec {}", command))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut stdin = process.stdin.take().unwrap();
+        let mut stdout = process.stdout.take().unwrap();
+        let process = Arc::new(Mutex::new(process));
+
+        let query = format!("{}\n", query);
+        let waiting = process.clone();
+        let output = task::spawn_blocking(move || {
+            // Dropping stdin afterwards tells the script the query is complete. A script
+            // that does not read its stdin may be gone already, which its status tells.
+            let _ = stdin.write_all(query.as_bytes());
+            drop(stdin);
+            let mut output = String::new();
+            stdout.read_to_string(&mut output)?;
+            wait(&waiting).map(|_| output)
+        });
+
+        match timeout(self.timeout, output).await {
+            Ok(Ok(output)) if !output.trim().is_empty() => {
+                Ok(output.trim().chars().take(RESULT_MAX_LEN).collect())
+            }
+            Ok(Ok(_)) => Err(IoError::new(ErrorKind::UnexpectedEof, "it printed nothing")),
+            Ok(Err(e)) => Err(e),
+            Err(_) => {
+                // Killing it ends the blocked read, and the task reaps it.
+                let _ = process.lock().unwrap().kill();
+                Err(IoError::new(ErrorKind::TimedOut, "it timed out"))
+            }
+        }
+    }
+}
+
+// Waits for the script to exit, failing unless it succeeded. Polled, so the process is
+// never locked for long and look_up can kill it when it runs out of time.
+fn wait(process: &Mutex<Child>) -> Result<(), IoError> {
+    loop {
+        match process.lock().unwrap().try_wait()? {
+            Some(status) if status.success() => return Ok(()),
+            Some(status) => return Err(IoError::other(format!("it exited with {}", status))),
+            None => {}
+        }
+        thread::sleep(WAIT_POLL);
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    mem::size_of,
+};
+
+// The msg_ids a single peer has sent recently. A peer that resends a message
+// because it missed the Ack reuses the msg_id, so repeats can be told apart
+// from new messages as long as they fall within the window.
+pub struct SeenMsgIds {
+    window: usize,
+    order: VecDeque<u64>,
+    seen: HashSet<u64>,
+}
+
+impl SeenMsgIds {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window: window.max(1),
+            order: VecDeque::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    // Records 'msg_id'. Returns false if it has already been seen.
+    pub fn insert(&mut self, msg_id: u64) -> bool {
+        if !self.seen.insert(msg_id) {
+            return false;
+        }
+
+        self.order.push_back(msg_id);
+        if self.order.len() > self.window {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+
+        true
+    }
+
+    // Roughly how many bytes the window takes up, each msg_id being kept twice.
+    pub fn bytes(&self) -> usize {
+        self.order.len() * 2 * size_of::<u64>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_are_refused() {
+        let mut seen = SeenMsgIds::new(4);
+        assert!(seen.insert(1));
+        assert!(seen.insert(2));
+        assert!(!seen.insert(1));
+        assert!(!seen.insert(2));
+    }
+
+    #[test]
+    fn the_oldest_is_evicted_past_the_window() {
+        let mut seen = SeenMsgIds::new(3);
+        for msg_id in 1..=4 {
+            assert!(seen.insert(msg_id));
+        }
+        // 1 fell out of the window, 2 to 4 are still in it.
+        assert_eq!(seen.bytes(), 3 * 2 * size_of::<u64>());
+        for msg_id in 2..=4 {
+            assert!(!seen.insert(msg_id));
+        }
+    }
+
+    #[test]
+    fn an_evicted_msg_id_is_admitted_again() {
+        let mut seen = SeenMsgIds::new(2);
+        assert!(seen.insert(1));
+        assert!(seen.insert(2));
+        assert!(seen.insert(3));
+        assert!(seen.insert(1));
+        // Admitting 1 again evicted 2.
+        assert!(!seen.insert(1));
+        assert!(seen.insert(2));
+        assert!(!seen.insert(1));
+    }
+
+    #[test]
+    fn a_window_of_zero_still_holds_one() {
+        let mut seen = SeenMsgIds::new(0);
+        assert!(seen.insert(1));
+        assert!(!seen.insert(1));
+        assert!(seen.insert(2));
+        assert!(seen.insert(1));
+    }
+}
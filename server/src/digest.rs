@@ -0,0 +1,157 @@
+use std::{collections::HashMap, env, mem::size_of, time::Duration};
+
+use async_std::task;
+
+use crate::{config::env_or, locale::Texts, server::Server};
+
+// Opt-in, through DIGEST=daily or DIGEST=weekly. DIGEST_TOP is how many peers and
+// links are named.
+pub struct DigestConfig {
+    pub period: Option<Period>,
+    pub top: usize,
+}
+
+impl DigestConfig {
+    pub fn from_env() -> Self {
+        let period = match env::var("DIGEST")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "daily" => Some(Period::Day),
+            "weekly" => Some(Period::Week),
+            _ => None,
+        };
+
+        Self {
+            period,
+            top: env_or("DIGEST_TOP", 3),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Period {
+    Day,
+    Week,
+}
+
+impl Period {
+    fn duration(self) -> Duration {
+        match self {
+            Period::Day => Duration::from_secs(24 * 60 * 60),
+            Period::Week => Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+}
+
+// The broadcast Text messages since the last digest. Counted as they are sent,
+// since the history only keeps the latest HISTORY_SIZE of them.
+pub struct Activity {
+    messages: usize,
+    by_peer: HashMap<String, usize>,
+    links: HashMap<String, usize>,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            messages: 0,
+            by_peer: HashMap::new(),
+            links: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, peer_name: &str, text: &str) {
+        self.messages += 1;
+        *self.by_peer.entry(peer_name.to_string()).or_default() += 1;
+
+        for word in text.split_whitespace() {
+            if word.starts_with("http://")
+                || word.starts_with("https://")
+                || word.starts_with("www.")
+            {
+                let link = word.trim_end_matches(['.', ',', ';', ':', '!', '?', ')']);
+                *self.links.entry(link.to_string()).or_default() += 1;
+            }
+        }
+    }
+
+    // Roughly how many bytes the counts take up.
+    pub fn bytes(&self) -> usize {
+        self.by_peer
+            .keys()
+            .chain(self.links.keys())
+            .map(|key| size_of::<(String, usize)>() + key.len())
+            .sum()
+    }
+
+    // Sums up the activity so far and starts counting again.
+    pub fn take(&mut self, period: Period, top: usize) -> Digest {
+        let activity = std::mem::replace(self, Activity::new());
+
+        Digest {
+            period,
+            messages: activity.messages,
+            peers: activity.by_peer.len(),
+            most_active: most_counted(activity.by_peer, top),
+            top_links: most_counted(activity.links, top),
+        }
+    }
+}
+
+pub struct Digest {
+    period: Period,
+    pub messages: usize,
+    peers: usize,
+    most_active: Vec<(String, usize)>,
+    top_links: Vec<(String, usize)>,
+}
+
+impl Digest {
+    pub fn render(&self, texts: Texts) -> String {
+        let key = match self.period {
+            Period::Day => "digest-day",
+            Period::Week => "digest-week",
+        };
+        let mut text = texts.get(key, &[&self.messages, &self.peers]);
+
+        if !self.most_active.is_empty() {
+            text.push(' ');
+            text.push_str(&texts.get("digest-active", &[&list(&self.most_active)]));
+        }
+        if !self.top_links.is_empty() {
+            text.push(' ');
+            text.push_str(&texts.get("digest-links", &[&list(&self.top_links)]));
+        }
+
+        text
+    }
+}
+
+// Posts a digest every period, skipping periods without any messages.
+pub async fn run(server: Server, period: Period) {
+    loop {
+        task::sleep(period.duration()).await;
+        if let Some(digest) = server.post_digest() {
+            println!("\n[Digest] {}", digest);
+        }
+    }
+}
+
+// The 'top' most counted, most first and by name on a tie.
+fn most_counted(counts: HashMap<String, usize>, top: usize) -> Vec<(String, usize)> {
+    let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(top);
+    counts
+}
+
+// e.g. "Tanya (12), Elle (9)"
+fn list(counts: &[(String, usize)]) -> String {
+    counts
+        .iter()
+        .map(|(name, count)| format!("{} ({})", name, count))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
@@ -0,0 +1,192 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+const NAME_MAX_LEN: usize = 32;
+const VALUE_MAX_LEN: usize = 16; // In chars, enough for the longest ZWJ sequences.
+
+// A custom emoji, written `:name:` in a message.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmojiInfo {
+    pub name: String,  // Without the colons, e.g. "shipit".
+    pub value: String, // The Unicode sequence it stands for, e.g. "🐿️".
+}
+
+// Every custom emoji, as peers get to see it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmojiList {
+    pub version: u64, // Goes up whenever the emoji change, so a client can keep what it has.
+    pub emoji: Vec<EmojiInfo>, // Sorted by name.
+}
+
+// The custom emoji the operator has set up, from EMOJI_FILE and the console.
+pub struct Emoji {
+    emoji: BTreeMap<String, String>,
+    // Bumped on every change. It starts at the time the server started, in milliseconds,
+    // so a client reconnecting to a restarted server never finds a version it has seen.
+    version: u64,
+}
+
+impl Emoji {
+    // One "name: value" per line. Lines starting with # and invalid emoji are skipped.
+    pub fn load(path: &str) -> Self {
+        let mut emoji = Self {
+            emoji: BTreeMap::new(),
+            version: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        };
+        for line in fs::read_to_string(path).unwrap_or_default().lines() {
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if let Err(e) = emoji.add(name.trim(), value.trim()) {
+                    println!("Skipped emoji {} in {}: {}", name.trim(), path, e);
+                }
+            }
+        }
+        emoji
+    }
+
+    // Adds or replaces ':name:'. Fails with why if the name or value is not allowed.
+    pub fn add(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let name = name.trim_matches(':').to_lowercase();
+        if name.is_empty()
+            || name.len() > NAME_MAX_LEN
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '+')
+        {
+            return Err(format!(
+                "names are 1 to {} letters, digits, _, - or +",
+                NAME_MAX_LEN
+            ));
+        }
+        if value.is_empty()
+            || value.chars().count() > VALUE_MAX_LEN
+            || value.chars().any(|c| c.is_whitespace() || c.is_control())
+        {
+            return Err(format!(
+                "an emoji stands for 1 to {} characters without spaces",
+                VALUE_MAX_LEN
+            ));
+        }
+
+        self.emoji.insert(name, value.to_string());
+        self.version += 1;
+        Ok(())
+    }
+
+    // Returns whether there was such an emoji.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let removed = self
+            .emoji
+            .remove(&name.trim_matches(':').to_lowercase())
+            .is_some();
+        if removed {
+            self.version += 1;
+        }
+        removed
+    }
+
+    // Writes every `:name:` of a known emoji in 'text' the way the EmojiList has it, since
+    // names are matched regardless of case, e.g. `:ShipIt:` becomes `:shipit:`. Anything
+    // else between colons, e.g. a time like 12:30:45, is left as it is.
+    pub fn normalize(&self, text: &str) -> String {
+        if self.emoji.is_empty() {
+            return text.to_string();
+        }
+
+        let mut normalized = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find(':') {
+            normalized.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let name = after.find(':').and_then(|end| {
+                let name = after[..end].to_lowercase();
+                self.emoji.contains_key(&name).then_some((end, name))
+            });
+            match name {
+                Some((end, name)) => {
+                    normalized.push(':');
+                    normalized.push_str(&name);
+                    normalized.push(':');
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    normalized.push(':');
+                    rest = after;
+                }
+            }
+        }
+        normalized.push_str(rest);
+        normalized
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.emoji.is_empty()
+    }
+
+    pub fn list(&self) -> EmojiList {
+        EmojiList {
+            version: self.version,
+            emoji: self
+                .emoji
+                .iter()
+                .map(|(name, value)| EmojiInfo {
+                    name: name.clone(),
+                    value: value.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emoji() -> Emoji {
+        let mut emoji = Emoji::load("");
+        emoji.add("shipit", "🐿️").unwrap();
+        emoji
+    }
+
+    #[test]
+    fn every_change_bumps_the_version() {
+        let mut emoji = emoji();
+        let version = emoji.list().version;
+        emoji.add("tada", "🎉").unwrap();
+        assert!(emoji.list().version > version);
+
+        let version = emoji.list().version;
+        assert!(emoji.add("bad name", "🎉").is_err());
+        assert!(!emoji.remove("nope"));
+        assert_eq!(emoji.list().version, version);
+        assert!(emoji.remove(":tada:"));
+        assert!(emoji.list().version > version);
+    }
+
+    #[test]
+    fn known_names_are_normalized() {
+        let emoji = emoji();
+        assert_eq!(emoji.normalize("Ship :ShipIt: now"), "Ship :shipit: now");
+        assert_eq!(emoji.normalize(":SHIPIT::shipit:"), ":shipit::shipit:");
+    }
+
+    #[test]
+    fn anything_else_between_colons_is_kept() {
+        let emoji = emoji();
+        assert_eq!(
+            emoji.normalize("At 12:30:45 :Nope: :"),
+            "At 12:30:45 :Nope: :"
+        );
+        assert_eq!(emoji.normalize("a:b:ShipIt:"), "a:b:shipit:");
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Error as IoError, ErrorKind, Write},
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{badge::Badge, report::Report};
+
+// A change to the server's state, as the event log records it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", content = "payload")]
+pub enum Event {
+    // A new peer, not one taking over its session.
+    Connected {
+        name: String,
+        addr: SocketAddr,
+    },
+    Disconnected {
+        name: String,
+    },
+    Kicked {
+        name: String,
+    },
+    // A message added to the history, which gives it the next id.
+    Message {
+        src_name: String,
+        text: String,
+        timestamp: u64,
+    },
+    // 'until' is in milliseconds since the UNIX epoch, None if the ban never ends.
+    Ban {
+        ip: IpAddr,
+        until: Option<u64>,
+    },
+    // A session's shadow ban, which ends with the session.
+    ShadowBan {
+        name: String,
+    },
+    ShadowBanIp {
+        ip: IpAddr,
+    },
+    Badge {
+        name: String,
+        badge: Badge,
+        on: bool,
+    },
+    EmojiAdded {
+        name: String,
+        value: String,
+    },
+    EmojiRemoved {
+        name: String,
+    },
+    Reported(Report),
+    // An admin has dealt with the report, or has dismissed it if not 'resolved'.
+    ReportClosed {
+        id: u64,
+        resolved: bool,
+    },
+}
+
+// One line of the event log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Record {
+    pub at: u64, // Milliseconds since the UNIX epoch.
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+// EVENT_LOG, an append-only file with one Record per line. The server replays it on
+// startup to rebuild the history, bans, IP shadow bans, emoji and open reports.
+// Connections do not survive a restart, so neither does what belongs to them, like
+// names, badges and the shadow bans of sessions: those events are there for the audit
+// trail. Without EVENT_LOG nothing is recorded.
+pub struct EventLog {
+    file: Option<Mutex<File>>,
+}
+
+impl EventLog {
+    // Opens 'path' for appending and returns what was recorded in it before. A last line
+    // that does not parse was torn by a crash while it was being written: it is cut off
+    // with a warning. Anything before it that does not parse is an error.
+    pub fn open(path: Option<&str>) -> Result<(Self, Vec<Record>), IoError> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok((Self { file: None }, Vec::new())),
+        };
+
+        let log = match fs::read(path) {
+            Ok(log) => log,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let (records, intact) = parse(&log, path)?;
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if intact < log.len() {
+            println!(
+                "\n[Events] Cut off the torn last line of {} ({} bytes).",
+                path,
+                log.len() - intact
+            );
+            file.set_len(intact as u64)?;
+        }
+        // A line written in full but for its newline would run into the next one.
+        if intact > 0 && log[intact - 1] != b'\n' {
+            file.write_all(b"\n")?;
+        }
+
+        Ok((
+            Self {
+                file: Some(Mutex::new(file)),
+            },
+            records,
+        ))
+    }
+
+    pub fn record(&self, at: u64, event: Event) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+
+        #[cfg(feature = "chaos")]
+        crate::chaos::slow_persistence();
+        let line = serde_json::to_string(&Record { at, event }).unwrap() + "\n";
+        if let Err(e) = file.lock().unwrap().write_all(line.as_bytes()) {
+            println!("\n[Events] Could not record an event: {}", e);
+        }
+    }
+}
+
+// The records in 'log' and how many of its bytes hold them, which is all of them
+// unless the last line is torn.
+fn parse(log: &[u8], path: &str) -> Result<(Vec<Record>, usize), IoError> {
+    let mut records = Vec::new();
+    let mut start = 0;
+
+    for (i, line) in log.split_inclusive(|byte| *byte == b'\n').enumerate() {
+        let end = start + line.len();
+        if !line.iter().all(u8::is_ascii_whitespace) {
+            match serde_json::from_slice(line) {
+                Ok(record) => records.push(record),
+                Err(_) if log[end..].iter().all(u8::is_ascii_whitespace) => {
+                    return Ok((records, start))
+                }
+                Err(e) => {
+                    return Err(IoError::new(
+                        ErrorKind::InvalidData,
+                        format!("line {} of {}: {}", i + 1, path, e),
+                    ))
+                }
+            }
+        }
+        start = end;
+    }
+
+    Ok((records, log.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    const KICKED: &str = r#"{"at":1,"type":"Kicked","payload":{"name":"Alice"}}"#;
+
+    // A log in the temp directory holding 'contents', unique to the test.
+    fn log_with(test: &str, contents: &[u8]) -> String {
+        let path = env::temp_dir().join(format!("event-log-{}-{}.jsonl", test, process::id()));
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn kicked(name: &str) -> Event {
+        Event::Kicked {
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn a_torn_last_line_is_cut_off() {
+        // Torn in the middle of a multi-byte character, too.
+        let mut contents = format!("{}\n", KICKED).into_bytes();
+        contents.extend_from_slice(
+            &"{\"at\":2,\"type\":\"Kicked\",\"payload\":{\"name\":\"Zoë".as_bytes()[..46],
+        );
+        let path = log_with("torn", &contents);
+
+        let (log, records) = EventLog::open(Some(&path)).unwrap();
+        assert_eq!(records.len(), 1);
+        log.record(3, kicked("Bob"));
+        drop(log);
+
+        let (_, records) = EventLog::open(Some(&path)).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].at, 3);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_last_line_without_its_newline_is_kept() {
+        let path = log_with("unterminated", KICKED.as_bytes());
+
+        let (log, records) = EventLog::open(Some(&path)).unwrap();
+        assert_eq!(records.len(), 1);
+        log.record(2, kicked("Bob"));
+        drop(log);
+
+        let (_, records) = EventLog::open(Some(&path)).unwrap();
+        assert_eq!(records.len(), 2);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corruption_before_the_last_line_is_an_error() {
+        let contents = format!("{}\n{{\"at\n{}\n", KICKED, KICKED);
+        let path = log_with("corrupt", contents.as_bytes());
+
+        let e = EventLog::open(Some(&path)).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        // Nothing has been cut off.
+        assert_eq!(fs::read(&path).unwrap(), contents.as_bytes());
+        fs::remove_file(path).unwrap();
+    }
+}
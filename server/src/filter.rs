@@ -0,0 +1,182 @@
+use std::{
+    env,
+    io::{BufRead, BufReader, Error as IoError, ErrorKind, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use async_std::future::timeout;
+use futures::channel::oneshot;
+use serde::{Deserialize, Serialize};
+
+use crate::config::env_or;
+
+// An external moderation script, e.g. FILTER_COMMAND="python3 filter.py". It is sent
+// every Text and Private message as a FilterRequest on one line of its stdin and has to
+// answer each with a FilterVerdict on one line of its stdout.
+pub struct FilterConfig {
+    pub command: Option<String>, // Run through `sh -c exec`. Unset turns the filter off.
+    pub timeout: Duration,       // How long the filter has to answer a message.
+    pub fail_open: bool,         // Deliver messages when the filter fails or times out.
+}
+
+impl FilterConfig {
+    pub fn from_env() -> Self {
+        Self {
+            command: env::var("FILTER_COMMAND")
+                .ok()
+                .filter(|command| !command.trim().is_empty()),
+            timeout: Duration::from_millis(env_or("FILTER_TIMEOUT_MS", 500)),
+            fail_open: env_or("FILTER_FAIL_OPEN", true),
+        }
+    }
+}
+
+// E.g. {"kind":"text","src_name":"Alice","src_addr":"127.0.0.1:50123","to":null,"text":"hi"}
+#[derive(Serialize, Debug)]
+pub struct FilterRequest<'a> {
+    pub kind: &'a str, // "text" or "private".
+    pub src_name: &'a str,
+    pub src_addr: String,
+    pub to: Option<&'a str>, // The receiving peer of a private message.
+    pub text: &'a str,
+}
+
+// E.g. {"action":"allow"}, {"action":"modify","text":"***"} or {"action":"reject","reason":"..."}
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum FilterVerdict {
+    Allow,
+    Modify {
+        text: String, // Delivered instead of the original text.
+    },
+    Reject {
+        #[serde(default)]
+        reason: Option<String>, // Shown to the sender.
+    },
+}
+
+type Job = (String, oneshot::Sender<Option<FilterVerdict>>);
+
+// The filter process shared by every connection. Pipes block, so a thread of its own
+// hands the process one message at a time and restarts it after any failure.
+pub struct Filter {
+    jobs: Option<mpsc::Sender<Job>>,
+    child: Arc<Mutex<Option<Child>>>,
+    timeout: Duration,
+    fail_open: bool,
+}
+
+impl Filter {
+    pub fn new(config: &FilterConfig) -> Self {
+        let child = Arc::new(Mutex::new(None));
+
+        let jobs = config.command.clone().map(|command| {
+            let (jobs, queue) = mpsc::channel();
+            let child = child.clone();
+            thread::spawn(move || run_filter(&command, &child, queue));
+            jobs
+        });
+
+        Self {
+            jobs,
+            child,
+            timeout: config.timeout,
+            fail_open: config.fail_open,
+        }
+    }
+
+    // Asks the filter about a message. A filter that fails or does not answer in
+    // time decides nothing, and the failure policy picks the verdict instead.
+    pub async fn check(&self, request: &FilterRequest<'_>) -> FilterVerdict {
+        let jobs = match &self.jobs {
+            Some(jobs) => jobs,
+            None => return FilterVerdict::Allow,
+        };
+
+        let (reply, verdict) = oneshot::channel();
+        let line = serde_json::to_string(request).unwrap();
+        if jobs.send((line, reply)).is_ok() {
+            match timeout(self.timeout, verdict).await {
+                Ok(Ok(Some(verdict))) => return verdict,
+                Ok(_) => println!("\n[Filter] The filter failed on {:?}", request),
+                Err(_) => {
+                    println!("\n[Filter] The filter timed out on {:?}", request);
+                    // Killing it unblocks the filter thread, which starts a fresh process.
+                    if let Some(child) = self.child.lock().unwrap().as_mut() {
+                        let _ = child.kill();
+                    }
+                }
+            }
+        }
+
+        if self.fail_open {
+            FilterVerdict::Allow
+        } else {
+            FilterVerdict::Reject { reason: None }
+        }
+    }
+}
+
+fn run_filter(command: &str, child: &Mutex<Option<Child>>, queue: mpsc::Receiver<Job>) {
+    let mut pipes: Option<(ChildStdin, BufReader<ChildStdout>)> = None;
+
+    for (line, reply) in queue {
+        if pipes.is_none() {
+            match spawn_filter(command) {
+                Ok((process, stdin, stdout)) => {
+                    println!("\n[Filter] Started `{}`", command);
+                    *child.lock().unwrap() = Some(process);
+                    pipes = Some((stdin, stdout));
+                }
+                Err(e) => println!("\n[Filter] Failed to start `{}`: {}", command, e),
+            }
+        }
+
+        let verdict = pipes
+            .as_mut()
+            .and_then(|(stdin, stdout)| ask_filter(stdin, stdout, &line).ok());
+
+        // A process that has failed once may be out of step with its replies.
+        if verdict.is_none() && pipes.take().is_some() {
+            if let Some(mut process) = child.lock().unwrap().take() {
+                let _ = process.kill();
+                let _ = process.wait();
+            }
+        }
+
+        let _ = reply.send(verdict);
+    }
+}
+
+fn spawn_filter(command: &str) -> Result<(Child, ChildStdin, BufReader<ChildStdout>), IoError> {
+    let mut process = Command::new("sh")
+        .arg("-c")
+        // exec, so that killing the child kills the script rather than just the shell.
+        .arg(format!("exec {}", command))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let stdin = process.stdin.take().unwrap();
+    let stdout = BufReader::new(process.stdout.take().unwrap());
+    Ok((process, stdin, stdout))
+}
+
+fn ask_filter(
+    stdin: &mut ChildStdin,
+    stdout: &mut BufReader<ChildStdout>,
+    line: &str,
+) -> Result<FilterVerdict, IoError> {
+    writeln!(stdin, "{}", line)?;
+    stdin.flush()?;
+
+    let mut reply = String::new();
+    if stdout.read_line(&mut reply)? == 0 {
+        return Err(IoError::new(ErrorKind::UnexpectedEof, "the filter exited"));
+    }
+
+    serde_json::from_str(&reply).map_err(|e| IoError::new(ErrorKind::InvalidData, e))
+}
@@ -0,0 +1,148 @@
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+
+use crate::commands::CommandInfo;
+
+// One entry of a HelpReply.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HelpEntry {
+    pub topic: String,
+    pub usage: String, // How to use it in the test client. Empty for custom topics.
+    pub description: String,
+}
+
+// The operator's own topics, one "topic: text" per line. Lines starting with # are skipped.
+pub fn load_topics(path: &str) -> Vec<HelpEntry> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .map(|(topic, text)| HelpEntry {
+            topic: topic.trim().to_lowercase(),
+            usage: String::new(),
+            description: text.trim().to_string(),
+        })
+        .filter(|entry| !entry.topic.is_empty())
+        .collect()
+}
+
+// Everything this server supports for the asking peer: the built-in features, the
+// commands bots have registered and the operator's topics, in that order.
+pub fn entries(
+    is_admin: bool,
+    invite_only: bool,
+    commands: &[CommandInfo],
+    topics: &[HelpEntry],
+) -> Vec<HelpEntry> {
+    let mut entries = vec![
+        entry(
+            "help",
+            "/help [topic]",
+            "Shows what this server supports, or one topic of it.",
+        ),
+        entry(
+            "pm",
+            "pm: <name> <text>",
+            "Sends a private message to a peer.",
+        ),
+        entry(
+            "ping",
+            "/ping",
+            "Measures the round trip time to the server.",
+        ),
+        entry(
+            "roster",
+            "roster",
+            "Lists the peers online and who has been active lately.",
+        ),
+        entry(
+            "peerdatarequest",
+            "peerdatarequest [offset] [limit] [filter]",
+            "Asks the server how many peers are online and, given a limit, for their names.",
+        ),
+        entry(
+            "commands",
+            "/commands",
+            "Lists the commands bots have registered.",
+        ),
+        entry(
+            "permalink",
+            "/permalink [id]",
+            "Gets a stable link to a message, by default the one /find is on or else the latest.",
+        ),
+        entry(
+            "report",
+            "/report [id] <reason>",
+            "Flags a message for the admins, by default the one /find is on or else the latest.",
+        ),
+        entry(
+            "context",
+            "/context [id] [size]",
+            "Shows the messages around a message, by default the one /find is on or else the latest.",
+        ),
+        entry(
+            "history",
+            "/history [count]",
+            "Pages back through older messages, further each time.",
+        ),
+    ];
+
+    if is_admin {
+        entries.extend(vec![
+            entry(
+                "invite",
+                "invite: [uses] [expires in secs]",
+                if invite_only {
+                    "Creates an invite token. This server is invite-only."
+                } else {
+                    "Creates an invite token. This server is open, so nobody needs one yet."
+                },
+            ),
+            entry("invites", "invites", "Lists the invite tokens still valid."),
+            entry(
+                "revokeinvite",
+                "revokeinvite: <token>",
+                "Revokes an invite token.",
+            ),
+            entry(
+                "shadowban",
+                "shadowban: <name>",
+                "Silently drops every message of a peer until its session ends.",
+            ),
+            entry(
+                "shadowban-ip",
+                "shadowban-ip: <name>",
+                "Silently drops every message from the peer's IP address, for every connection.",
+            ),
+            entry(
+                "slowmode",
+                "slowmode: <seconds>",
+                "Makes everyone wait that long between two messages, 0 turns it off.",
+            ),
+            entry(
+                "register",
+                "register: <name> <description>",
+                "Registers /name as a command of this connection, for bots.",
+            ),
+        ]);
+    }
+
+    entries.extend(commands.iter().map(|command| HelpEntry {
+        topic: command.name.clone(),
+        usage: format!("/{} ...", command.name),
+        description: format!("{} (handled by {})", command.description, command.bot),
+    }));
+    entries.extend(topics.iter().cloned());
+
+    entries
+}
+
+fn entry(topic: &str, usage: &str, description: &str) -> HelpEntry {
+    HelpEntry {
+        topic: topic.to_string(),
+        usage: usage.to_string(),
+        description: description.to_string(),
+    }
+}
@@ -0,0 +1,204 @@
+use std::{collections::VecDeque, mem::size_of};
+
+use serde::{Deserialize, Serialize};
+
+// A message as it is kept in the history.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    pub id: u64, // Counts up from 0 in the order messages were sent. Permalinks point at it.
+    pub src_name: String,
+    pub text: String,
+    pub timestamp: u64,
+}
+
+// Which way a HistoryRequest pages from its cursor.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    #[default]
+    Older,
+    Newer,
+}
+
+// Cursors are opaque to peers, so what they hold can change without breaking them. For
+// now they hold the id of the message a page ends at.
+pub fn cursor(id: u64) -> String {
+    format!("c{:x}", id)
+}
+
+pub fn parse_cursor(cursor: &str) -> Option<u64> {
+    u64::from_str_radix(cursor.strip_prefix('c')?, 16).ok()
+}
+
+impl HistoryEntry {
+    // Roughly how many bytes the entry takes up.
+    fn size(&self) -> usize {
+        size_of::<HistoryEntry>() + self.src_name.len() + self.text.len()
+    }
+}
+
+// The latest broadcast Text messages, oldest first. Only lives in memory.
+pub struct History {
+    capacity: usize,
+    budget: usize, // At most this many bytes are kept, 0 for no limit besides 'capacity'.
+    bytes: usize,
+    entries: VecDeque<HistoryEntry>,
+    next_id: u64,
+}
+
+impl History {
+    pub fn new(capacity: usize, budget: usize) -> Self {
+        Self {
+            capacity,
+            budget,
+            bytes: 0,
+            entries: VecDeque::new(),
+            next_id: 0,
+        }
+    }
+
+    // Records a message and returns its id. Every message gets one, even if
+    // none are kept.
+    pub fn push(&mut self, src_name: &str, text: &str, timestamp: u64) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        if self.capacity == 0 {
+            return id;
+        }
+
+        let entry = HistoryEntry {
+            id,
+            src_name: src_name.to_string(),
+            text: text.to_string(),
+            timestamp,
+        };
+        self.bytes += entry.size();
+        self.entries.push_back(entry);
+
+        // The newest entry is always kept, even on its own over the budget.
+        while self.entries.len() > self.capacity
+            || (self.budget > 0 && self.bytes > self.budget && self.entries.len() > 1)
+        {
+            if let Some(oldest) = self.entries.pop_front() {
+                self.bytes -= oldest.size();
+            }
+        }
+        id
+    }
+
+    // Roughly how many bytes the kept entries take up.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    // Up to 'before' entries before the one with 'id', that entry and up to 'after' entries
+    // after it, oldest first. None if that entry is not kept (anymore).
+    pub fn context(&self, id: u64, before: usize, after: usize) -> Option<Vec<HistoryEntry>> {
+        let i = self.entries.iter().position(|entry| entry.id == id)?;
+        let start = i.saturating_sub(before);
+        let end = (i + after + 1).min(self.entries.len());
+
+        Some(self.entries.range(start..end).cloned().collect())
+    }
+
+    // Up to 'limit' entries older or newer than the one with id 'from', or else starting at
+    // the newest (Older) or oldest (Newer) entry, oldest first. Pages go by id rather than
+    // position, so 'from' need not be kept anymore. Also returns whether more are kept
+    // beyond the page.
+    pub fn page(
+        &self,
+        from: Option<u64>,
+        direction: Direction,
+        limit: usize,
+    ) -> (Vec<HistoryEntry>, bool) {
+        let (start, end) = match direction {
+            Direction::Older => {
+                let end = match from {
+                    Some(id) => self.entries.partition_point(|entry| entry.id < id),
+                    None => self.entries.len(),
+                };
+                (end.saturating_sub(limit), end)
+            }
+            Direction::Newer => {
+                let start = match from {
+                    Some(id) => self.entries.partition_point(|entry| entry.id <= id),
+                    None => 0,
+                };
+                (start, (start + limit).min(self.entries.len()))
+            }
+        };
+
+        let more = match direction {
+            Direction::Older => start > 0,
+            Direction::Newer => end < self.entries.len(),
+        };
+        (self.entries.range(start..end).cloned().collect(), more)
+    }
+
+    // Whether 'id' has been given to a message, whether or not it is still kept.
+    pub fn has_assigned(&self, id: u64) -> bool {
+        id < self.next_id
+    }
+
+    // The latest 'n' entries, oldest first.
+    pub fn latest(&self, n: usize) -> Vec<HistoryEntry> {
+        let skip = self.entries.len().saturating_sub(n);
+        self.entries.iter().skip(skip).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(entries: &[HistoryEntry]) -> Vec<u64> {
+        entries.iter().map(|entry| entry.id).collect()
+    }
+
+    fn history(capacity: usize, messages: u64) -> History {
+        let mut history = History::new(capacity, 0);
+        for i in 0..messages {
+            history.push("Alice", "hi", i);
+        }
+        history
+    }
+
+    #[test]
+    fn pages_tell_whether_more_are_kept_at_both_ends() {
+        let history = history(10, 5);
+
+        let (newest, more) = history.page(None, Direction::Older, 2);
+        assert_eq!((ids(&newest), more), (vec![3, 4], true));
+        let (oldest, more) = history.page(Some(1), Direction::Older, 2);
+        assert_eq!((ids(&oldest), more), (vec![0], false));
+
+        let (oldest, more) = history.page(None, Direction::Newer, 2);
+        assert_eq!((ids(&oldest), more), (vec![0, 1], true));
+        let (newest, more) = history.page(Some(2), Direction::Newer, 2);
+        assert_eq!((ids(&newest), more), (vec![3, 4], false));
+    }
+
+    #[test]
+    fn newer_than_the_newest_entry_is_an_empty_page() {
+        let history = history(10, 5);
+
+        let (page, more) = history.page(Some(4), Direction::Newer, 10);
+        assert!(page.is_empty());
+        assert!(!more);
+    }
+
+    #[test]
+    fn a_page_goes_on_from_a_cursor_that_has_been_evicted() {
+        // Only 3..=5 are still kept.
+        let history = history(3, 6);
+
+        let (page, more) = history.page(Some(1), Direction::Newer, 2);
+        assert_eq!((ids(&page), more), (vec![3, 4], true));
+        let (page, more) = history.page(Some(1), Direction::Older, 2);
+        assert!(page.is_empty());
+        assert!(!more);
+        let (page, more) = history.page(Some(4), Direction::Older, 10);
+        assert_eq!((ids(&page), more), (vec![3], false));
+    }
+}
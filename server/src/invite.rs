@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+
+const INVITE_TOKEN_LEN: usize = 12;
+
+struct Invite {
+    uses_left: u32,
+    expires_at: Option<Instant>,
+}
+
+// What an admin gets to see about an invite.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InviteInfo {
+    pub token: String,
+    pub uses_left: u32,
+    pub expires_in_secs: Option<u64>, // None if the invite never expires.
+}
+
+// Invite tokens a peer has to present as `?invite=` while the server is invite-only.
+pub struct Invites {
+    invites: HashMap<String, Invite>,
+}
+
+impl Invites {
+    pub fn new() -> Self {
+        Self {
+            invites: HashMap::new(),
+        }
+    }
+
+    // None if 'expires_in' is too far off to count down to. An invite meant to last that
+    // long is created without one.
+    pub fn create(&mut self, uses: u32, expires_in: Option<Duration>) -> Option<InviteInfo> {
+        let now = Instant::now();
+        let expires_at = match expires_in {
+            Some(expires_in) => Some(now.checked_add(expires_in)?),
+            None => None,
+        };
+
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(INVITE_TOKEN_LEN)
+            .collect();
+
+        let invite = Invite {
+            uses_left: uses.max(1),
+            expires_at,
+        };
+
+        let info = invite_info(&token, &invite, now);
+        self.invites.insert(token, invite);
+        Some(info)
+    }
+
+    // Uses up one use of 'token'. Returns whether the token was valid.
+    pub fn redeem(&mut self, token: &str) -> bool {
+        self.prune();
+
+        match self.invites.get_mut(token) {
+            Some(invite) => {
+                invite.uses_left -= 1;
+                if invite.uses_left == 0 {
+                    self.invites.remove(token);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn revoke(&mut self, token: &str) -> bool {
+        self.invites.remove(token).is_some()
+    }
+
+    pub fn list(&mut self) -> Vec<InviteInfo> {
+        self.prune();
+
+        let now = Instant::now();
+        self.invites
+            .iter()
+            .map(|(token, invite)| invite_info(token, invite, now))
+            .collect()
+    }
+
+    fn prune(&mut self) {
+        let now = Instant::now();
+        self.invites
+            .retain(|_, invite| invite.expires_at.is_none_or(|expires_at| expires_at > now));
+    }
+}
+
+fn invite_info(token: &str, invite: &Invite, now: Instant) -> InviteInfo {
+    InviteInfo {
+        token: token.to_string(),
+        uses_left: invite.uses_left,
+        expires_in_secs: invite
+            .expires_at
+            .map(|expires_at| expires_at.saturating_duration_since(now).as_secs()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_invite_is_used_up() {
+        let mut invites = Invites::new();
+        let invite = invites.create(2, None).unwrap();
+
+        assert!(invites.redeem(&invite.token));
+        assert!(invites.redeem(&invite.token));
+        assert!(!invites.redeem(&invite.token));
+    }
+
+    #[test]
+    fn an_expiry_too_far_off_is_refused() {
+        let mut invites = Invites::new();
+
+        assert!(invites
+            .create(1, Some(Duration::from_secs(u64::MAX)))
+            .is_none());
+        assert!(invites.list().is_empty());
+        let invite = invites.create(1, Some(Duration::from_secs(60))).unwrap();
+        assert_eq!(invite.expires_in_secs, Some(60));
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    io::{Error as IoError, ErrorKind},
+    net::{SocketAddr, TcpListener as StdTcpListener, ToSocketAddrs},
+};
+
+use async_std::net::TcpListener;
+
+// Binds the listening socket. With REUSE_PORT it is bound with SO_REUSEPORT, so a
+// newer server binary can bind the same address while this one is still running
+// and take over new connections once this one stops accepting (see handover).
+pub async fn bind(addr: &str, reuse_port: bool) -> Result<TcpListener, IoError> {
+    if !reuse_port {
+        return TcpListener::bind(addr).await;
+    }
+
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| IoError::new(ErrorKind::InvalidInput, "no address to bind to"))?;
+    Ok(TcpListener::from(bind_reuse_port(addr)?))
+}
+
+#[cfg(unix)]
+fn bind_reuse_port(addr: SocketAddr) -> Result<StdTcpListener, IoError> {
+    use std::{mem, os::unix::io::FromRawFd};
+
+    // std has no way to set socket options before bind, so the socket is set up by hand.
+    unsafe {
+        let (family, sockaddr, len) = match addr {
+            SocketAddr::V4(addr) => {
+                let mut sockaddr: libc::sockaddr_in = mem::zeroed();
+                sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+                sockaddr.sin_port = addr.port().to_be();
+                sockaddr.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                let mut storage: libc::sockaddr_storage = mem::zeroed();
+                std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, sockaddr);
+                (libc::AF_INET, storage, mem::size_of::<libc::sockaddr_in>())
+            }
+            SocketAddr::V6(addr) => {
+                let mut sockaddr: libc::sockaddr_in6 = mem::zeroed();
+                sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sockaddr.sin6_port = addr.port().to_be();
+                sockaddr.sin6_addr.s6_addr = addr.ip().octets();
+                sockaddr.sin6_scope_id = addr.scope_id();
+                let mut storage: libc::sockaddr_storage = mem::zeroed();
+                std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, sockaddr);
+                (
+                    libc::AF_INET6,
+                    storage,
+                    mem::size_of::<libc::sockaddr_in6>(),
+                )
+            }
+        };
+
+        let fd = libc::socket(family, libc::SOCK_STREAM, 0);
+        if fd < 0 {
+            return Err(IoError::last_os_error());
+        }
+        // Owned from here on, so the socket is closed on an early return.
+        let listener = StdTcpListener::from_raw_fd(fd);
+
+        let on: libc::c_int = 1;
+        for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+            if libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &on as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            ) < 0
+            {
+                return Err(IoError::last_os_error());
+            }
+        }
+        if libc::bind(
+            fd,
+            &sockaddr as *const _ as *const libc::sockaddr,
+            len as libc::socklen_t,
+        ) < 0
+            || libc::listen(fd, 1024) < 0
+        {
+            return Err(IoError::last_os_error());
+        }
+
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    }
+}
+
+#[cfg(not(unix))]
+fn bind_reuse_port(_addr: SocketAddr) -> Result<StdTcpListener, IoError> {
+    Err(IoError::new(
+        ErrorKind::Unsupported,
+        "REUSE_PORT is only supported on unix",
+    ))
+}
@@ -0,0 +1,171 @@
+use std::{collections::HashMap, fmt::Display, fs, path::Path};
+
+// Every text the server sends to peers, in English. "{0}", "{1}", ... are replaced by
+// the arguments, so a translation may put them in any order.
+const ENGLISH: &[(&str, &str)] = &[
+    ("peer-connected", "{0} ({1}) has connected."),
+    ("peer-disconnected", "{0} ({1}) has disconnected."),
+    ("peer-not-connected", "{0} is not connected."),
+    (
+        "shadow-banned",
+        "{0} is now shadow banned until the session ends.",
+    ),
+    (
+        "shadow-banned-ip",
+        "{0} ({1}) is now shadow banned, and so is every connection from {1}.",
+    ),
+    ("invite-revoked", "Invite {0} has been revoked."),
+    ("invite-unknown", "There is no invite {0}."),
+    (
+        "invite-expiry-too-far",
+        "That invite would never expire, leave the expiry out instead.",
+    ),
+    (
+        "spam-warning",
+        "You are sending messages too fast. Slow down or you will be muted.",
+    ),
+    ("spam-slow-mode", "Slow mode: your message was not sent."),
+    (
+        "spam-muted",
+        "You are muted for spamming. Try again in {0} seconds.",
+    ),
+    ("filter-rejected", "Your message was not sent."),
+    ("filter-rejected-reason", "Your message was not sent: {0}"),
+    ("command-invalid", "/{0} is not a valid command name."),
+    ("command-taken", "/{0} is already registered by {1}."),
+    ("help-all", "This server supports:"),
+    ("help-topic", "Help on {0}:"),
+    ("help-unknown", "There is no help on {0}."),
+    ("message-unknown", "There is no message {0}."),
+    ("message-gone", "Message {0} is no longer kept."),
+    (
+        "report-filed",
+        "Thanks, the admins will look into it (report #{0}).",
+    ),
+    (
+        "report-notice",
+        "Report #{0}: {1} reported message {2} by {3}, \"{4}\". Reason: {5}",
+    ),
+    ("room-unknown", "There is no room {0}."),
+    (
+        "slow-mode-set",
+        "Slow mode in {0}: everyone waits {1} seconds between messages.",
+    ),
+    ("slow-mode-off", "Slow mode in {0} is off."),
+    ("history-bad-cursor", "That is not a valid history cursor."),
+    (
+        "message-server-only",
+        "{0} is only ever sent by the server.",
+    ),
+    ("message-out-of-order", "{0} cannot be sent at this point."),
+    (
+        "message-draining",
+        "{0} is not accepted while the server shuts down.",
+    ),
+    (
+        "rules-required",
+        "Send /accept to agree to the rules of this server before you post.",
+    ),
+    (
+        "rules-accepted",
+        "Thanks for accepting the rules, you can post now.",
+    ),
+    (
+        "content-failed",
+        "/{0} did not come up with anything, try again later.",
+    ),
+    (
+        "digest-day",
+        "Over the last day, {1} peers have sent {0} messages.",
+    ),
+    (
+        "digest-week",
+        "Over the last week, {1} peers have sent {0} messages.",
+    ),
+    ("digest-active", "Most active: {0}."),
+    ("digest-links", "Top links: {0}."),
+    (
+        "drain-notice",
+        "The server goes down for maintenance in {0} seconds.",
+    ),
+    (
+        "drain-notice-redirect",
+        "The server goes down for maintenance in {0} seconds. Reconnect to {1} to keep chatting.",
+    ),
+    (
+        "handover-notice",
+        "The server is being upgraded. You are moved over to the new version in {0} seconds.",
+    ),
+];
+
+// Translations of ENGLISH, read from <LOCALE_DIR>/<locale>.txt with one "key = text" per
+// line, e.g. locales/de.txt. Missing keys and locales fall back to English.
+pub struct Catalog {
+    locales: HashMap<String, HashMap<String, String>>,
+}
+
+impl Catalog {
+    pub fn load(dir: &str) -> Self {
+        let mut locales = HashMap::new();
+
+        for file in fs::read_dir(Path::new(dir)).into_iter().flatten().flatten() {
+            let path = file.path();
+            if path.extension().is_none_or(|extension| extension != "txt") {
+                continue;
+            }
+            let locale = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(locale) => locale.to_lowercase(),
+                None => continue,
+            };
+
+            let texts = fs::read_to_string(&path)
+                .unwrap_or_default()
+                .lines()
+                .filter(|line| !line.trim_start().starts_with('#'))
+                .filter_map(|line| line.split_once('='))
+                .map(|(key, text)| (key.trim().to_string(), text.trim().to_string()))
+                .collect();
+            locales.insert(locale, texts);
+        }
+
+        Self { locales }
+    }
+
+    // The text for 'key' in 'locale', trying "de" for "de-AT" before falling back to English.
+    pub fn text(&self, locale: &str, key: &str, args: &[&dyn Display]) -> String {
+        let locale = locale.to_lowercase();
+        let language = locale.split(['-', '_']).next().unwrap_or_default();
+
+        let template = [locale.as_str(), language]
+            .iter()
+            .filter_map(|locale| self.locales.get(*locale)?.get(key))
+            .map(String::as_str)
+            .chain(
+                ENGLISH
+                    .iter()
+                    .filter(|(english_key, _)| *english_key == key)
+                    .map(|(_, text)| *text),
+            )
+            .next()
+            .unwrap_or(key);
+
+        args.iter()
+            .enumerate()
+            .fold(template.to_string(), |text, (i, arg)| {
+                text.replace(&format!("{{{}}}", i), &arg.to_string())
+            })
+    }
+}
+
+// The catalog together with the locale of one peer.
+#[derive(Clone, Copy)]
+pub struct Texts<'a> {
+    pub catalog: &'a Catalog,
+    pub locale: &'a str,
+}
+
+impl Texts<'_> {
+    pub fn get(&self, key: &str, args: &[&dyn Display]) -> String {
+        self.catalog.text(self.locale, key, args)
+    }
+}
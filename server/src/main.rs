@@ -1,20 +1,56 @@
 use async_std::task;
+use config::Config;
 use dotenv::dotenv;
 use server::Server;
 use std::{env, io::Error as IoError};
 
+mod archive;
+mod badge;
+mod batch;
+mod census;
+mod challenge;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod close;
+mod commands;
+mod config;
+mod connection;
+mod console;
+mod content;
+mod dedup;
+mod digest;
+mod emoji;
+mod events;
+mod filter;
+mod help;
+mod history;
+mod invite;
+mod listener;
+mod locale;
+mod memory;
+mod report;
 mod server;
+mod spam;
+mod throttle;
+mod welcome;
 
 fn main() -> Result<(), IoError> {
     dotenv().ok();
 
-    let host = env::var("HOST")
-        .ok()
-        .expect("Failed to parse HOST environment variable!");
-    let port = env::var("PORT")
-        .ok()
-        .expect("Failed to parse PORT environment variable!");
+    let host = env::var("HOST").expect("Failed to parse HOST environment variable!");
+    let port = env::var("PORT").expect("Failed to parse PORT environment variable!");
 
-    let server = Server::new(format!("{}:{}", host, port));
+    let config = Config::from_env();
+    let digest_period = config.digest.period;
+    let census_interval = config.census_interval;
+
+    let server = Server::new(format!("{}:{}", host, port), config);
+    task::spawn(console::run(server.clone()));
+    if let Some(period) = digest_period {
+        task::spawn(digest::run(server.clone(), period));
+    }
+    if let Some(interval) = census_interval {
+        task::spawn(census::run(server.clone(), interval));
+    }
     task::block_on(server.run())
 }
@@ -0,0 +1,19 @@
+// Rough sizes in bytes of what the server keeps in memory, for the console's memory
+// command and MEMORY_BUDGET. Only the entries' own fields and strings are counted, not
+// allocator overhead or spare capacity, so the process itself uses somewhat more.
+pub struct MemoryUsage {
+    pub history: usize,  // The History entries.
+    pub msg_ids: usize,  // The msg_ids kept per session to spot resends.
+    pub activity: usize, // What has been counted for the next digest.
+    pub budget: usize,   // MEMORY_BUDGET, 0 if there is none.
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.history + self.msg_ids + self.activity
+    }
+
+    pub fn over_budget(&self) -> bool {
+        self.budget > 0 && self.total() > self.budget
+    }
+}
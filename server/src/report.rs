@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+// A message a peer has flagged for the admins, with what it said when it was reported,
+// since the history may drop it before anyone gets to look.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub id: u64,
+    pub msg_id: u64,
+    pub reporter: String,
+    pub author: String,
+    pub text: String,
+    pub reason: String,
+    pub at: u64, // Milliseconds since the UNIX epoch.
+}
+
+// The moderation queue: reports stay open until an admin resolves or dismisses them on
+// the console. EVENT_LOG records both, so open reports survive a restart.
+pub struct Reports {
+    open: BTreeMap<u64, Report>,
+    last_id: u64, // Ids are never given out twice, closed reports keep theirs.
+}
+
+impl Reports {
+    pub fn new() -> Self {
+        Self {
+            open: BTreeMap::new(),
+            last_id: 0,
+        }
+    }
+
+    // Files a report under the next id and returns it.
+    pub fn file(
+        &mut self,
+        msg_id: u64,
+        reporter: &str,
+        author: &str,
+        text: &str,
+        reason: &str,
+        at: u64,
+    ) -> Report {
+        self.last_id += 1;
+        let report = Report {
+            id: self.last_id,
+            msg_id,
+            reporter: reporter.to_string(),
+            author: author.to_string(),
+            text: text.to_string(),
+            reason: reason.to_string(),
+            at,
+        };
+        self.restore(report.clone());
+        report
+    }
+
+    // Puts a report replayed from the event log back.
+    pub fn restore(&mut self, report: Report) {
+        self.last_id = self.last_id.max(report.id);
+        self.open.insert(report.id, report);
+    }
+
+    // Takes the report off the queue, None if there is no open report 'id'.
+    pub fn close(&mut self, id: u64) -> Option<Report> {
+        self.open.remove(&id)
+    }
+
+    // The open reports, oldest first.
+    pub fn open(&self) -> Vec<Report> {
+        self.open.values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_not_given_out_again_after_a_close_or_a_replay() {
+        let mut reports = Reports::new();
+        let first = reports.file(4, "Alice", "Bob", "hi", "rude", 10);
+        assert_eq!(reports.close(first.id), Some(first));
+        assert_eq!(reports.close(1), None);
+        assert_eq!(reports.file(4, "Alice", "Bob", "hi", "rude", 11).id, 2);
+
+        let mut replayed = Reports::new();
+        let later = reports.file(5, "Carol", "Bob", "yo", "spam", 12);
+        replayed.restore(later.clone());
+        assert_eq!(replayed.open(), vec![later]);
+        assert_eq!(replayed.file(6, "Carol", "Bob", "yo", "spam", 13).id, 4);
+    }
+}
@@ -3,165 +3,1441 @@ use std::{
     fs::File,
     io::{BufRead, BufReader, Error as IoError},
     iter::FromIterator,
-    net::SocketAddr,
-    sync::{Arc, Mutex},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use async_std::{
-    net::{TcpListener, TcpStream},
-    task,
-};
+use async_std::{future::timeout, net::TcpStream, task};
 
 use futures::{
     channel::mpsc::{unbounded, UnboundedSender},
-    future, pin_mut,
+    future::{self, Either},
+    pin_mut,
     prelude::*,
 };
 
-use async_tungstenite::tungstenite::protocol::Message as TungMessage;
-use rand::seq::SliceRandom;
+use crate::{
+    archive,
+    badge::Badge,
+    batch,
+    census::{Census, Counted},
+    challenge::{Challenge, ChallengeConfig},
+    close::{self, CloseReason},
+    commands::{self, CommandInfo, Commands},
+    config::Config,
+    connection::{IllegalMessage, Lifecycle, Origin, State},
+    content::ContentConfig,
+    dedup::SeenMsgIds,
+    digest::Activity,
+    emoji::{Emoji, EmojiList},
+    events::{Event, EventLog, Record},
+    filter::{Filter, FilterRequest, FilterVerdict},
+    help::{self, HelpEntry},
+    history::{self, Direction, History, HistoryEntry},
+    invite::{InviteInfo, Invites},
+    listener,
+    locale::{Catalog, Texts},
+    memory::MemoryUsage,
+    report::{Report, Reports},
+    spam::{SpamConfig, SpamFilters, SpamVerdict},
+    throttle::AcceptThrottle,
+    welcome::WelcomeConfig,
+};
+
+#[cfg(feature = "chaos")]
+use crate::chaos;
+
+use async_tungstenite::{
+    tungstenite::{handshake::server::Request, protocol::Message as TungMessage},
+    WebSocketStream,
+};
+use rand::{distributions::Alphanumeric, seq::SliceRandom, Rng};
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
 
 type Sender = UnboundedSender<TungMessage>;
 type PeerMap = Arc<Mutex<HashMap<SocketAddr, Sender>>>;
 type PeerNameMap = Arc<Mutex<HashMap<String, SocketAddr>>>;
+type PeerTokenMap = Arc<Mutex<HashMap<String, String>>>;
+type PeerBadges = Arc<Mutex<HashMap<String, Vec<Badge>>>>; // Name -> badges, sorted. Only peers that have any.
+type SessionMsgIds = Arc<Mutex<HashMap<String, Arc<Mutex<SeenMsgIds>>>>>; // Session token -> its msg_ids, shared with a connection taking it over.
+type ShadowBans = Arc<Mutex<ShadowBanList>>;
+type InviteMap = Arc<Mutex<Invites>>;
+type PeerListSubscribers = Arc<Mutex<HashSet<SocketAddr>>>;
+type PeerLocales = Arc<Mutex<HashMap<SocketAddr, String>>>; // Only peers that announced a locale.
+type HistoryBuffer = Arc<Mutex<History>>;
+type CommandMap = Arc<Mutex<Commands>>;
+type EmojiMap = Arc<Mutex<Emoji>>;
+type ActivityLog = Arc<Mutex<Activity>>;
+type Events = Arc<EventLog>;
+type Connections = Arc<AtomicUsize>; // How many connection tasks are running.
+type SpamState = Arc<Mutex<SpamFilters>>;
+type AcceptedRules = Arc<Mutex<HashSet<String>>>; // Session tokens of the peers that have sent /accept.
+type Bans = Arc<Mutex<HashMap<IpAddr, Option<Instant>>>>; // IP -> when the ban ends, None if never.
+type ReportQueue = Arc<Mutex<Reports>>;
+type Admins = Arc<Mutex<HashSet<SocketAddr>>>; // Connections that presented ADMIN_KEY.
+type Listening = Arc<Mutex<Option<UnboundedSender<()>>>>; // Some while run() accepts connections. Dropping the sender stops it.
 
 const LOCAL_NAME: &str = "Server";
-
+const PROTOCOL_VERSION: u32 = 3; // 2 introduced the { v, type, payload } envelope, 3 has the server stamp the sender.
+const SESSION_TOKEN_LEN: usize = 32;
+const PEER_INFO_MAX_LIMIT: usize = 100;
+pub const ROOM_NAME: &str = "main"; // Every peer is in this room, there are no others yet.
+const CONTEXT_MAX_SIZE: usize = 50;
+const HISTORY_PAGE_MAX: usize = 100;
+const DRAIN_NOTICES: [u64; 6] = [600, 300, 60, 30, 10, 5]; // Seconds left at which a drain is announced again.
+
+// On the wire a message is an envelope: { "v", "type", "payload", ... } where "type" and
+// "payload" come from MessageType. Unknown fields are ignored and unknown types end up as
+// MessageType::Unknown, so a peer on an older version keeps working when new types are added.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Message<'a> {
-    src_name: &'a str,
-    src_addr: &'a str,
+    v: u32, // The PROTOCOL_VERSION of the sender.
+    #[serde(default)]
+    src_name: &'a str, // Who sent the message, stamped by the server on everything it sends or relays. Since 3 peers leave it out, and whatever they send is replaced.
+    #[serde(default)]
+    src_addr: &'a str, // The server's own address, also on what it relays: peer addresses stay with the server. Stamped like 'src_name'.
+    #[serde(flatten)]
     msg_type: MessageType<'a>,
     text: String,
+    #[serde(default)]
+    timestamp: u64, // Milliseconds since the UNIX epoch by the server's clock. The server stamps every message it sends or relays.
+    #[serde(default)]
+    msg_id: Option<u64>, // Set by peers on Text and Private messages, unique per session. A message resent with the same msg_id, also from a connection that has taken over the session, is acked but not delivered again. On a broadcast Text it is the message's id in the history instead.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    badges: Vec<Badge>, // The sender's badges, stamped by the server on every Text, Private and CodeSnippet message it relays. Whatever a peer sends is replaced.
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", content = "payload")]
 enum MessageType<'a> {
+    Challenge(Challenge), // Sent before anything else when the server wants a new peer to prove itself. The peer has to reply with ChallengeAnswer or it is disconnected.
+    ChallengeAnswer(&'a str), // A peer's answer to a Challenge. The parameter is the answer.
+    TimeSync(u64), // Sent right before SessionToken. The parameter is the server's clock in milliseconds since the UNIX epoch, so peers can correct for their own clock when rendering timestamps.
     NewPeer(&'a str), // Broadcast this message to all peers when a new peer has connected. The parameter is the name of the new peer that has connected.
     DisconPeer(&'a str), // Broadcast this message to all peers when a peer has disconnected. The parameter is the name of the peer that has disconnected.
     PeerNameAssign(&'a str), // The server sends this message to a peer when it has first connected, giving it a random name. The name is the parameter.
-    PeerInfoRequest, // A peer sends this message to the server if the peer wants to retrieve peer info (PeerDataReply message is sent back to the peer).
+    Backfill(Vec<HistoryEntry>), // Sent right after PeerNameAssign with the latest BACKFILL_SIZE Text messages, oldest first, so a new peer has context. Render it as history, not as new messages.
+    SessionToken(&'a str), // Sent right before PeerNameAssign. Presenting the token as `?token=` on a new connection takes over the name while the old connection is still bound to it.
+    SubscribePeerList, // A peer sends this message to keep a roster in sync. The server replies with a PeerListDelta of everyone online and sends further deltas as peers come and go.
+    PeerListDelta(PeerListDelta), // The changes to the peer list since the last delta. Only sent to peers that have sent SubscribePeerList.
+    // A peer sends this message to the server if the peer wants to retrieve peer info (PeerDataReply message is sent back to the peer).
+    // Only the counts are filled in unless 'limit' is set, in which case up to 'limit' names matching 'filter' are included, starting at 'offset'.
+    PeerInfoRequest {
+        #[serde(default)]
+        offset: usize,
+        #[serde(default)]
+        limit: usize,
+        #[serde(default)]
+        filter: Option<String>,
+    },
     PeerInfoReply(PeerInfo), // If the server has received a PeerDataRequest message, a peer is asking to retrieve data about all connected peers. This resides in the PeerInfo struct.
     Private(&'a str), // A private message to the given peer. The parameter is the name of the peer receiving the message.
-    Text,             // Standard broadcasted text message to all peers.
+    // A snippet of code, broadcast like a Text with the code as 'text'. 'language' names what it is written in, e.g. "rust", for the client to render it by. It is kept in the history as a Markdown fenced code block.
+    CodeSnippet {
+        #[serde(default)]
+        language: Option<String>,
+    },
+    // An admin sends this message to create an invite token. The server replies with an InviteList holding only the new invite.
+    CreateInvite {
+        uses: u32,
+        expires_in_secs: Option<u64>,
+    },
+    ListInvites, // An admin sends this message to list all valid invite tokens. The server replies with an InviteList.
+    RevokeInvite(&'a str), // An admin sends this message to revoke the given invite token.
+    InviteList(Vec<InviteInfo>), // The server's reply to CreateInvite and ListInvites.
+    ShadowBan(&'a str), // An admin sends this message to shadow ban the given peer's session. Its messages are silently dropped from then on and it is left out of PeerInfoReply, until the session ends.
+    ShadowBanIp(&'a str), // An admin sends this message to shadow ban the given peer's IP address, every connection from it and for as long as the server keeps its state.
+    Ack(u64), // The server's reply to every Text, Private and CodeSnippet message carrying a msg_id, including repeats. The parameter is the msg_id. It comes after any notice that the message was refused and before anything else about it. A peer that has not seen the Ack may resend the message.
+    // An admin sends this message to set the slow mode of 'room': every peer has to wait 'interval' seconds between two messages there, 0 turns it off. SLOW_MODE_SECS is what the server starts with.
+    SetSlowMode {
+        room: String,
+        interval: u64,
+    },
+    SlowModeWait(u64), // The server sends this message to a peer whose message was dropped by slow mode. The parameter is how many seconds the peer has to wait.
+    // A peer sends this message to flag the Text with id 'msg_id' for the admins, saying why in 'reason'. The report is queued for the console and every admin online is told with a Private.
+    Report {
+        msg_id: u64,
+        reason: String,
+    },
+    CensusRequest, // An admin sends this message to see how much the server keeps per connection, name and session, e.g. to spot leaks. The server replies with a CensusReply.
+    // The server's reply to CensusRequest. 'leaks' names what has more entries than there are connections, the admin's own included.
+    CensusReply {
+        census: Census,
+        leaks: Vec<String>,
+    },
+    // A bot (a peer connected with the admin key) sends this message to register `/name`. Text messages starting with it are routed to the bot as CommandInvocation instead of being broadcast.
+    RegisterCommand {
+        name: String,
+        description: String,
+    },
+    CommandsList(Vec<CommandInfo>), // Every registered command, sent after Backfill when there are any and to everyone whenever the list changes.
+    EmojiList(EmojiList), // The server's custom emoji, sent before Backfill when there are any and to everyone whenever they change. Render `:name:` in a message as the emoji's value.
+    // A peer sends this message to learn what this server supports. The server replies with a HelpReply of every entry, or only those on 'topic' when it is set.
+    HelpRequest {
+        #[serde(default)]
+        topic: Option<String>,
+    },
+    HelpReply(Vec<HelpEntry>), // The server's reply to HelpRequest. Only lists admin features to admins and includes the commands bots have registered.
+    // A peer sends this message to get a stable reference to the Text with id 'msg_id', as seen in the msg_id of broadcast Texts and in Backfill.
+    GetPermalink {
+        msg_id: u64,
+    },
+    Permalink(Permalink), // The server's reply to GetPermalink, unless no message has that id.
+    // A peer sends this message to load the Texts around the one with id 'msg_id', e.g. to jump to a permalink. At most CONTEXT_MAX_SIZE are sent on either side.
+    FetchContext {
+        msg_id: u64,
+        #[serde(default)]
+        before: usize,
+        #[serde(default)]
+        after: usize,
+    },
+    // The server's reply to FetchContext, unless the message is no longer kept. 'entries' are oldest first and include the message itself.
+    Context {
+        msg_id: u64,
+        entries: Vec<HistoryEntry>,
+    },
+    // A peer sends this message to page through the history, e.g. for infinite scroll. Without a 'cursor' it starts at the newest message going "older" or the oldest going "newer", otherwise pass the 'next_cursor' of the previous HistoryReply. Pages go by message, so they do not shift as new messages arrive. At most HISTORY_PAGE_MAX are sent, and that many if 'limit' is 0.
+    HistoryRequest {
+        #[serde(default)]
+        room: Option<String>,
+        #[serde(default)]
+        cursor: Option<String>,
+        #[serde(default)]
+        direction: Direction,
+        #[serde(default)]
+        limit: usize,
+    },
+    // The server's reply to HistoryRequest. 'messages' are oldest first. 'next_cursor' continues in the same direction. Going older it is None once the oldest message kept has been sent, going newer it is always set, so it can be used to poll for messages sent since.
+    HistoryReply {
+        messages: Vec<HistoryEntry>,
+        next_cursor: Option<String>,
+    },
+    // The server sends this message to every peer when it is drained for maintenance with an alternate address. Reconnect to 'addr' to keep chatting, presenting 'token' as `?token=` there when set and the current session token otherwise, unless 'addr' is on another host: that gets neither the session token nor an admin key or invite.
+    Redirect {
+        addr: String,
+        #[serde(default)]
+        token: Option<String>,
+    },
+    // The server sends this message to the bot that registered 'name' when a peer invokes it. 'src_name' is the invoking peer and 'args' is the rest of the line.
+    CommandInvocation {
+        name: String,
+        args: String,
+    },
+    // A peer sends this message to measure its round trip time to the server. 'sent_at' is the peer's own clock in milliseconds and is never read by the server.
+    Ping {
+        nonce: u64,
+        sent_at: u64,
+    },
+    // The server's reply to Ping, echoing its 'nonce' and 'sent_at'.
+    Pong {
+        nonce: u64,
+        sent_at: u64,
+    },
+    Batch(Vec<Box<RawValue>>), // Several messages to the same peer in one frame, oldest first, when BATCH_WINDOW_MS is set. Handle each as if it had arrived on its own.
+    Text,                      // Standard broadcasted text message to all peers.
+    #[serde(untagged)]
+    Unknown(serde_json::Value), // Any type this version does not know about, e.g. one added later. The parameter is the raw type and payload.
+}
+
+impl MessageType<'_> {
+    // Which connection states the type is legal in, see connection::State::check.
+    fn origin(&self) -> Origin {
+        match self {
+            MessageType::ChallengeAnswer(_) => Origin::Handshake,
+            MessageType::SubscribePeerList
+            | MessageType::PeerInfoRequest { .. }
+            | MessageType::Private(_)
+            | MessageType::CodeSnippet { .. }
+            | MessageType::ListInvites
+            | MessageType::RevokeInvite(_)
+            | MessageType::ShadowBan(_)
+            | MessageType::ShadowBanIp(_)
+            | MessageType::SetSlowMode { .. }
+            | MessageType::CensusRequest
+            | MessageType::Report { .. }
+            | MessageType::HelpRequest { .. }
+            | MessageType::GetPermalink { .. }
+            | MessageType::FetchContext { .. }
+            | MessageType::HistoryRequest { .. }
+            | MessageType::Ping { .. }
+            | MessageType::Text => Origin::Chat,
+            // Left to handle_unknown_msg, a newer peer may send types this version lacks.
+            MessageType::Unknown(_) => Origin::Chat,
+            MessageType::CreateInvite { .. } | MessageType::RegisterCommand { .. } => Origin::Setup,
+            MessageType::Challenge(_)
+            | MessageType::TimeSync(_)
+            | MessageType::NewPeer(_)
+            | MessageType::DisconPeer(_)
+            | MessageType::PeerNameAssign(_)
+            | MessageType::Backfill(_)
+            | MessageType::SessionToken(_)
+            | MessageType::PeerListDelta(_)
+            | MessageType::PeerInfoReply(_)
+            | MessageType::InviteList(_)
+            | MessageType::Ack(_)
+            | MessageType::SlowModeWait(_)
+            | MessageType::CensusReply { .. }
+            | MessageType::CommandsList(_)
+            | MessageType::EmojiList(_)
+            | MessageType::HelpReply(_)
+            | MessageType::Permalink(_)
+            | MessageType::Context { .. }
+            | MessageType::HistoryReply { .. }
+            | MessageType::Redirect { .. }
+            | MessageType::CommandInvocation { .. }
+            | MessageType::Pong { .. }
+            | MessageType::Batch(_) => Origin::Server,
+        }
+    }
+
+    // The type as it goes over the wire, e.g. "Text".
+    fn name(&self) -> String {
+        serde_json::to_value(self)
+            .ok()
+            .and_then(|value| value["type"].as_str().map(String::from))
+            .unwrap_or_default()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct PeerInfo {
-    peers_online: i32,           // How many peers are currently online?
-    peer_spots_left: i32,        // How many available spots are left for connections?
-    peer_names: HashSet<String>, // What are the names of the connected peers? excluding the requesting peers name.
+    peers_online: i32,       // How many peers are currently online?
+    peer_spots_left: i32,    // How many available spots are left for connections?
+    peers_matching: i32,     // How many of the other peers match the request's filter?
+    peer_names: Vec<String>, // The requested page of matching peer names, sorted and excluding the requesting peers name.
+    #[serde(default)]
+    peer_badges: HashMap<String, Vec<Badge>>, // The badges of the peers in 'peer_names' that have any.
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Permalink {
+    msg_id: u64,
+    uri: String,         // chat://<server>/<room>/<msg_id>
+    url: Option<String>, // Where a web UI shows the message. Always None, the server has no web UI yet.
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PeerListDelta {
+    joined: Vec<String>,            // Names of the peers that have connected.
+    left: Vec<String>,              // Names of the peers that have disconnected.
+    renamed: Vec<(String, String)>, // (old, new) names. Names never change yet, so this is always empty.
+}
+
+// Who is shadow banned. A session is by the name bound to it, which is lifted again when
+// the session ends, so the next peer given the name is not. An address is only ever
+// banned as a whole with ShadowBanIp, which also hides every other connection from it.
+#[derive(Default)]
+struct ShadowBanList {
+    names: HashSet<String>,
+    ips: HashSet<IpAddr>,
+}
+
+impl ShadowBanList {
+    fn covers(&self, peer_name: &str, peer_addr: &SocketAddr) -> bool {
+        self.names.contains(peer_name) || self.ips.contains(&peer_addr.ip())
+    }
 }
 
+// What a drain tells the peers.
+enum Drain {
+    Maintenance(Option<String>), // With the address the peers have been redirected to, if any.
+    Handover, // A newer binary has the port, peers closed at the end reconnect to it.
+}
+
+#[derive(Clone)]
 pub struct Server {
     addr: String,
     peer_map: PeerMap,
     peer_name_map: PeerNameMap,
+    peer_token_map: PeerTokenMap,
+    session_msg_ids: SessionMsgIds,
+    peer_badges: PeerBadges,
+    shadow_bans: ShadowBans,
+    bans: Bans,
+    invites: InviteMap,
+    peer_list_subscribers: PeerListSubscribers,
+    peer_locales: PeerLocales,
+    history: HistoryBuffer,
+    activity: ActivityLog,
+    commands: CommandMap,
+    emoji: EmojiMap,
+    accepted_rules: AcceptedRules,
+    spam_filters: SpamState,
+    events: Events,
+    reports: ReportQueue,
+    admins: Admins,
+    connections: Connections,
+    names: Arc<HashSet<String>>,
+    filter: Arc<Filter>,
+    config: Arc<Config>,
+    listening: Listening,
+    started_at: Instant,
+}
+
+// What the admin console's `stats` command shows.
+pub struct Stats {
+    pub peers_online: usize,
+    pub peer_spots_left: usize,
+    pub bans: usize,
+    pub shadow_bans: usize,
+    pub ip_shadow_bans: usize,
+    pub invites: usize,
+    pub uptime: Duration,
 }
 
 impl Server {
-    pub fn new(addr: String) -> Self {
-        Self {
+    pub fn new(addr: String, config: Config) -> Self {
+        let (events, recorded) =
+            EventLog::open(config.event_log.as_deref()).expect("Failed to open EVENT_LOG");
+
+        let server = Self {
             addr,
             peer_map: PeerMap::new(Mutex::new(HashMap::new())),
             peer_name_map: PeerNameMap::new(Mutex::new(HashMap::new())),
+            peer_token_map: PeerTokenMap::new(Mutex::new(HashMap::new())),
+            session_msg_ids: SessionMsgIds::new(Mutex::new(HashMap::new())),
+            peer_badges: PeerBadges::new(Mutex::new(HashMap::new())),
+            shadow_bans: ShadowBans::default(),
+            bans: Bans::new(Mutex::new(HashMap::new())),
+            invites: InviteMap::new(Mutex::new(Invites::new())),
+            peer_list_subscribers: PeerListSubscribers::new(Mutex::new(HashSet::new())),
+            peer_locales: PeerLocales::new(Mutex::new(HashMap::new())),
+            history: HistoryBuffer::new(Mutex::new(History::new(
+                config.history_size.max(config.backfill_size),
+                config.history_budget,
+            ))),
+            activity: ActivityLog::new(Mutex::new(Activity::new())),
+            commands: CommandMap::new(Mutex::new(Commands::new())),
+            emoji: EmojiMap::new(Mutex::new(Emoji::load(&config.emoji_file))),
+            accepted_rules: AcceptedRules::new(Mutex::new(HashSet::new())),
+            spam_filters: SpamState::new(Mutex::new(SpamFilters::new(config.spam.slow_mode))),
+            events: Events::new(events),
+            reports: ReportQueue::new(Mutex::new(Reports::new())),
+            admins: Admins::new(Mutex::new(HashSet::new())),
+            connections: Connections::new(AtomicUsize::new(0)),
+            names: Arc::new(parse_peer_names()),
+            filter: Arc::new(Filter::new(&config.filter)),
+            config: Arc::new(config),
+            listening: Listening::new(Mutex::new(None)),
+            started_at: Instant::now(),
+        };
+        server.replay(recorded);
+        server
+    }
+
+    // Rebuilds what outlives connections from the records of the event log.
+    fn replay(&self, records: Vec<Record>) {
+        if records.is_empty() {
+            return;
+        }
+
+        let now = now_millis();
+        let mut history = self.history.lock().unwrap();
+        let mut bans = self.bans.lock().unwrap();
+        let mut shadow_bans = self.shadow_bans.lock().unwrap();
+        let mut emoji = self.emoji.lock().unwrap();
+        let mut reports = self.reports.lock().unwrap();
+        for record in &records {
+            match &record.event {
+                Event::Message {
+                    src_name,
+                    text,
+                    timestamp,
+                } => {
+                    history.push(src_name, text, *timestamp);
+                }
+                Event::Ban { ip, until: None } => {
+                    bans.insert(*ip, None);
+                }
+                Event::Ban {
+                    ip,
+                    until: Some(until),
+                } if *until > now => {
+                    // One too far off to count down to never ends for all intents.
+                    let ends = Instant::now().checked_add(Duration::from_millis(until - now));
+                    bans.insert(*ip, ends);
+                }
+                Event::Ban { ip, .. } => {
+                    bans.remove(ip);
+                }
+                Event::ShadowBanIp { ip } => {
+                    shadow_bans.ips.insert(*ip);
+                }
+                Event::EmojiAdded { name, value } => {
+                    let _ = emoji.add(name, value);
+                }
+                Event::EmojiRemoved { name } => {
+                    emoji.remove(name);
+                }
+                Event::Reported(report) => {
+                    reports.restore(report.clone());
+                }
+                Event::ReportClosed { id, .. } => {
+                    reports.close(*id);
+                }
+                Event::Connected { .. }
+                | Event::Disconnected { .. }
+                | Event::Kicked { .. }
+                | Event::ShadowBan { .. }
+                | Event::Badge { .. } => {}
+            }
+        }
+        println!("Replayed {} events from the event log.", records.len());
+    }
+
+    // Every connected peer as (name, address, whether it is shadow banned), sorted by name.
+    pub fn peers(&self) -> Vec<(String, SocketAddr, bool)> {
+        let mut peers: Vec<(String, SocketAddr, bool)> = self
+            .peer_name_map
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, addr)| {
+                (
+                    name.clone(),
+                    *addr,
+                    is_shadow_banned(&self.shadow_bans, name, addr),
+                )
+            })
+            .collect();
+        peers.sort();
+        peers
+    }
+
+    // Returns false if no peer goes by 'peer_name'.
+    pub fn kick(&self, peer_name: &str) -> bool {
+        let peer_addr = self.peer_name_map.lock().unwrap().get(peer_name).copied();
+
+        match peer_addr {
+            Some(peer_addr) => {
+                close_peer(&self.peer_map, &peer_addr, CloseReason::Kicked);
+                self.events.record(
+                    now_millis(),
+                    Event::Kicked {
+                        name: peer_name.to_string(),
+                    },
+                );
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Drops the connection of 'peer_name' without closing it. Returns false if no peer
+    // goes by that name.
+    #[cfg(feature = "chaos")]
+    pub fn cut_off(&self, peer_name: &str) -> bool {
+        let peer_addr = self.peer_name_map.lock().unwrap().get(peer_name).copied();
+
+        match peer_addr {
+            Some(peer_addr) => {
+                chaos::cut(peer_addr);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Bans the IP address of 'peer_name' for 'duration', forever if None, and
+    // closes its connection. Returns the banned address, or why nobody was banned.
+    pub fn ban(&self, peer_name: &str, duration: Option<Duration>) -> Result<IpAddr, String> {
+        let peer_addr = self
+            .peer_name_map
+            .lock()
+            .unwrap()
+            .get(peer_name)
+            .copied()
+            .ok_or_else(|| format!("{} is not connected.", peer_name))?;
+        let until = duration
+            .map(|duration| {
+                Instant::now().checked_add(duration).ok_or_else(|| {
+                    String::from("That ban would never end, leave the duration out instead.")
+                })
+            })
+            .transpose()?;
+
+        self.bans.lock().unwrap().insert(peer_addr.ip(), until);
+        close_peer(&self.peer_map, &peer_addr, CloseReason::Banned);
+        let now = now_millis();
+        self.events.record(
+            now,
+            Event::Ban {
+                ip: peer_addr.ip(),
+                until: duration.map(|duration| {
+                    now.saturating_add(duration.as_millis().min(u128::from(u64::MAX)) as u64)
+                }),
+            },
+        );
+
+        Ok(peer_addr.ip())
+    }
+
+    // Sets the interval every peer in 'room' has to keep between messages, zero for none.
+    pub fn set_slow_mode(&self, room: &str, interval: Duration) -> Result<(), String> {
+        if set_slow_mode(&self.spam_filters, room, interval) {
+            Ok(())
+        } else {
+            Err(format!("There is no room {}.", room))
+        }
+    }
+
+    pub fn slow_mode(&self) -> Duration {
+        self.spam_filters.lock().unwrap().slow_mode
+    }
+
+    // The reports no admin has dealt with yet, oldest first.
+    pub fn reports(&self) -> Vec<Report> {
+        self.reports.lock().unwrap().open()
+    }
+
+    // Takes report 'id' off the queue as resolved, or as dismissed if not 'resolved'.
+    pub fn close_report(&self, id: u64, resolved: bool) -> Result<Report, String> {
+        let report = self
+            .reports
+            .lock()
+            .unwrap()
+            .close(id)
+            .ok_or_else(|| format!("There is no open report {}.", id))?;
+        self.events
+            .record(now_millis(), Event::ReportClosed { id, resolved });
+        Ok(report)
+    }
+
+    // Gives 'peer_name' 'badge', or takes it away. Badges belong to the name and are
+    // dropped once it disconnects. Returns false if the peer is not connected.
+    pub fn set_badge(&self, peer_name: &str, badge: Badge, on: bool) -> bool {
+        if !self.peer_name_map.lock().unwrap().contains_key(peer_name) {
+            return false;
+        }
+
+        let mut peer_badges = self.peer_badges.lock().unwrap();
+        let badges = peer_badges.entry(peer_name.to_string()).or_default();
+        badges.retain(|b| *b != badge);
+        if on {
+            badges.push(badge);
+            badges.sort();
+        }
+        if badges.is_empty() {
+            peer_badges.remove(peer_name);
+        }
+        self.events.record(
+            now_millis(),
+            Event::Badge {
+                name: peer_name.to_string(),
+                badge,
+                on,
+            },
+        );
+        true
+    }
+
+    // Stops accepting connections, so a newer binary bound to the same port with
+    // REUSE_PORT gets the new ones, and tells the peers they are moved over in 'after'.
+    // Chatting goes on until then, and whoever is still connected is closed with Shutdown
+    // and reconnects to the newer binary. run() returns once the last peer is gone.
+    // Fails if the server had already stopped or 'after' is too far off to count down to.
+    pub fn hand_over(&self, after: Duration) -> Result<(), String> {
+        let ends_at = self.stop_listening(after)?;
+        task::spawn(drain_countdown(self.clone(), ends_at, Drain::Handover));
+        Ok(())
+    }
+
+    // Stops accepting connections, announces that the server goes down in 'after' and
+    // closes whoever is still connected then. With 'redirect' every peer is also sent a
+    // Redirect to that address right away. Like hand_over, run() returns once the last
+    // peer is gone, and fails the same way.
+    pub fn drain(&self, after: Duration, redirect: Option<String>) -> Result<(), String> {
+        let ends_at = self.stop_listening(after)?;
+
+        if let Some(addr) = &redirect {
+            let msg = Message {
+                v: PROTOCOL_VERSION,
+                src_addr: &self.addr,
+                src_name: LOCAL_NAME,
+                msg_type: MessageType::Redirect {
+                    addr: addr.clone(),
+                    token: None, // Sessions are not shared between servers yet.
+                },
+                text: String::from(""),
+                timestamp: now_millis(),
+                msg_id: None,
+                badges: Vec::new(),
+            };
+            let msg = TungMessage::Text(serde_json::to_string(&msg).unwrap());
+
+            for recp in self.peer_map.lock().unwrap().values() {
+                let _ = recp.unbounded_send(msg.clone());
+            }
+        }
+
+        task::spawn(drain_countdown(
+            self.clone(),
+            ends_at,
+            Drain::Maintenance(redirect),
+        ));
+        Ok(())
+    }
+
+    // Stops accepting connections for a drain that ends in 'after', returning when. The
+    // duration is checked first, so a refused one leaves the server as it was.
+    fn stop_listening(&self, after: Duration) -> Result<Instant, String> {
+        let ends_at = Instant::now()
+            .checked_add(after)
+            .ok_or_else(|| String::from("That would never end, give a shorter duration."))?;
+        match self.listening.lock().unwrap().take() {
+            Some(_) => Ok(ends_at),
+            None => Err(String::from(
+                "The server is not accepting connections anymore.",
+            )),
+        }
+    }
+
+    // Tells every peer how many seconds are left until the drain ends.
+    fn announce_drain(&self, secs_left: u64, drain: &Drain) {
+        let msg = Message {
+            v: PROTOCOL_VERSION,
+            src_addr: &self.addr,
+            src_name: LOCAL_NAME,
+            msg_type: MessageType::Text,
+            text: String::from(""),
+            timestamp: now_millis(),
+            msg_id: None,
+            badges: Vec::new(),
+        };
+
+        match drain {
+            Drain::Maintenance(Some(addr)) => broadcast_localized_msg(
+                &self.peer_map,
+                &self.peer_locales,
+                &self.config.catalog,
+                None,
+                msg,
+                "drain-notice-redirect",
+                &[&secs_left, &addr],
+            ),
+            Drain::Maintenance(None) => broadcast_localized_msg(
+                &self.peer_map,
+                &self.peer_locales,
+                &self.config.catalog,
+                None,
+                msg,
+                "drain-notice",
+                &[&secs_left],
+            ),
+            Drain::Handover => broadcast_localized_msg(
+                &self.peer_map,
+                &self.peer_locales,
+                &self.config.catalog,
+                None,
+                msg,
+                "handover-notice",
+                &[&secs_left],
+            ),
+        }
+    }
+
+    fn close_all(&self, reason: CloseReason) {
+        for recp in self.peer_map.lock().unwrap().values() {
+            let _ = recp.unbounded_send(TungMessage::Close(Some(reason.frame())));
+        }
+    }
+
+    // Sends 'text' to every peer as a Text message from the server.
+    pub fn broadcast(&self, text: &str) {
+        let msg = Message {
+            v: PROTOCOL_VERSION,
+            src_addr: &self.addr,
+            src_name: LOCAL_NAME,
+            msg_type: MessageType::Text,
+            text: text.to_string(),
+            timestamp: now_millis(),
+            msg_id: None,
+            badges: Vec::new(),
+        };
+        let msg = TungMessage::Text(serde_json::to_string(&msg).unwrap());
+
+        for recp in self.peer_map.lock().unwrap().values() {
+            let _ = recp.unbounded_send(msg.clone());
+        }
+    }
+
+    // Every custom emoji, for the console.
+    pub fn emoji(&self) -> EmojiList {
+        self.emoji.lock().unwrap().list()
+    }
+
+    // Adds or replaces ':name:' and sends everyone the new EmojiList. Fails with why
+    // if the name or value is not allowed.
+    pub fn add_emoji(&self, name: &str, value: &str) -> Result<(), String> {
+        self.emoji.lock().unwrap().add(name, value)?;
+        self.events.record(
+            now_millis(),
+            Event::EmojiAdded {
+                name: name.to_string(),
+                value: value.to_string(),
+            },
+        );
+        self.broadcast_emoji_list();
+        Ok(())
+    }
+
+    // Returns whether there was such an emoji.
+    pub fn remove_emoji(&self, name: &str) -> bool {
+        let removed = self.emoji.lock().unwrap().remove(name);
+        if removed {
+            self.events.record(
+                now_millis(),
+                Event::EmojiRemoved {
+                    name: name.to_string(),
+                },
+            );
+            self.broadcast_emoji_list();
+        }
+        removed
+    }
+
+    // Sent even when the list has become empty, so peers drop what they have.
+    fn broadcast_emoji_list(&self) {
+        let msg = Message {
+            v: PROTOCOL_VERSION,
+            src_addr: &self.addr,
+            src_name: LOCAL_NAME,
+            msg_type: MessageType::EmojiList(self.emoji()),
+            text: String::from("EmojiList"),
+            timestamp: now_millis(),
+            msg_id: None,
+            badges: Vec::new(),
+        };
+        let msg = TungMessage::Text(serde_json::to_string(&msg).unwrap());
+
+        for recp in self.peer_map.lock().unwrap().values() {
+            let _ = recp.unbounded_send(msg.clone());
+        }
+    }
+
+    // Posts the activity since the last digest to everyone, in their own locale.
+    // Returns the digest, unless digests are off or nothing has been said since.
+    pub fn post_digest(&self) -> Option<String> {
+        let period = self.config.digest.period?;
+        let digest = self
+            .activity
+            .lock()
+            .unwrap()
+            .take(period, self.config.digest.top);
+        if digest.messages == 0 {
+            return None;
+        }
+
+        let locales = self.peer_locales.lock().unwrap().clone();
+        let peers = self.peer_map.lock().unwrap();
+        let mut rendered: HashMap<&str, TungMessage> = HashMap::new();
+
+        for (addr, recp) in peers.iter() {
+            let locale = locales.get(addr).map(String::as_str).unwrap_or_default();
+            let rendered = rendered.entry(locale).or_insert_with(|| {
+                let texts = Texts {
+                    catalog: &self.config.catalog,
+                    locale,
+                };
+                let msg = Message {
+                    v: PROTOCOL_VERSION,
+                    src_addr: &self.addr,
+                    src_name: LOCAL_NAME,
+                    msg_type: MessageType::Text,
+                    text: digest.render(texts),
+                    timestamp: now_millis(),
+                    msg_id: None,
+                    badges: Vec::new(),
+                };
+                TungMessage::Text(serde_json::to_string(&msg).unwrap())
+            });
+            let _ = recp.unbounded_send(rendered.clone());
+        }
+
+        let texts = Texts {
+            catalog: &self.config.catalog,
+            locale: "",
+        };
+        Some(digest.render(texts))
+    }
+
+    pub fn stats(&self) -> Stats {
+        // Each lock is taken on its own, available_peer_names locks the name map too.
+        let peers_online = self.peer_name_map.lock().unwrap().len();
+        let peer_spots_left = available_peer_names(&self.peer_name_map, &self.names).len();
+        let now = Instant::now();
+        let bans = self
+            .bans
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|until| until.is_none_or(|until| until > now))
+            .count();
+        let (shadow_bans, ip_shadow_bans) = {
+            let shadow_bans = self.shadow_bans.lock().unwrap();
+            (shadow_bans.names.len(), shadow_bans.ips.len())
+        };
+        let invites = self.invites.lock().unwrap().list().len();
+
+        Stats {
+            peers_online,
+            peer_spots_left,
+            bans,
+            shadow_bans,
+            ip_shadow_bans,
+            invites,
+            uptime: self.started_at.elapsed(),
+        }
+    }
+
+    // Writes the history kept to 'dir' as a static HTML archive, one page per day.
+    // Returns how many days it covers.
+    pub fn archive(&self, dir: &str) -> Result<usize, IoError> {
+        let entries = self.history.lock().unwrap().latest(usize::MAX);
+
+        archive::export(
+            ROOM_NAME,
+            &entries,
+            Path::new(dir),
+            Path::new(&self.config.archive_templates),
+        )
+    }
+
+    pub fn memory(&self) -> MemoryUsage {
+        let msg_ids = self
+            .session_msg_ids
+            .lock()
+            .unwrap()
+            .values()
+            .map(|seen| seen.lock().unwrap().bytes())
+            .sum();
+
+        MemoryUsage {
+            history: self.history.lock().unwrap().bytes(),
+            msg_ids,
+            activity: self.activity.lock().unwrap().bytes(),
+            budget: self.config.memory_budget,
+        }
+    }
+
+    pub fn census(&self) -> Census {
+        // Each lock is taken on its own, like in stats: temporaries in the struct literal
+        // would hold every lock at once, session_msg_ids included, which memory locks too.
+        let peers = self.peer_map.lock().unwrap().len();
+        let names = self.peer_name_map.lock().unwrap().len();
+        let sessions = self.peer_token_map.lock().unwrap().len();
+        let session_msg_ids = self.session_msg_ids.lock().unwrap().len();
+        let subscribers = self.peer_list_subscribers.lock().unwrap().len();
+        let locales = self.peer_locales.lock().unwrap().len();
+        let badges = self.peer_badges.lock().unwrap().len();
+        let accepted_rules = self.accepted_rules.lock().unwrap().len();
+        let shadow_bans = self.shadow_bans.lock().unwrap().names.len();
+        let admins = self.admins.lock().unwrap().len();
+        let memory = self.memory();
+        let (spam_filters, spam_warnings, spam_slowed, spam_mutes) = {
+            let spam_filters = self.spam_filters.lock().unwrap();
+            (
+                spam_filters.len(),
+                spam_filters.warnings,
+                spam_filters.slowed,
+                spam_filters.mutes,
+            )
+        };
+
+        Census {
+            connections: self.connections.load(Ordering::Relaxed),
+            peers,
+            names,
+            sessions,
+            session_msg_ids,
+            subscribers,
+            locales,
+            badges,
+            accepted_rules,
+            shadow_bans,
+            admins,
+            spam_filters,
+            spam_warnings,
+            spam_slowed,
+            spam_mutes,
+            memory: memory.total(),
+            session_memory: memory.msg_ids,
         }
     }
 
     pub async fn run(&self) -> Result<(), IoError> {
         // Create the event loop and TCP listener we'll accept connections on.
-        let try_socket = TcpListener::bind(&self.addr).await;
+        let try_socket = listener::bind(&self.addr, self.config.reuse_port).await;
         let listener = try_socket.expect("Failed to bind");
         println!("Listening on: {}", &self.addr);
 
-        let names = parse_peer_names();
+        let (listening, mut stopped) = unbounded::<()>();
+        *self.listening.lock().unwrap() = Some(listening);
 
         // Let's spawn the handling of each connection in a separate task.
-        while let Ok((stream, peer_addr)) = listener.accept().await {
-            task::spawn(on_peer_connect(
-                self.peer_map.clone(),
-                self.peer_name_map.clone(),
-                stream,
-                self.addr.clone(),
-                peer_addr,
-                names.clone(),
-            ));
+        let mut throttle = AcceptThrottle::new(self.config.accept_rate);
+        loop {
+            let accept = listener.accept();
+            pin_mut!(accept);
+            let (stream, peer_addr) = match future::select(accept, stopped.next()).await {
+                Either::Left((Ok(accepted), _)) => accepted,
+                _ => break,
+            };
+
+            match throttle.admit() {
+                Ok(()) => task::spawn(on_peer_connect(self.clone(), stream, peer_addr)),
+                Err(retry_after) => task::spawn(turn_away(stream, retry_after)),
+            };
         }
 
+        // Give up the port right away, then wait for the peers still connected.
+        drop(listener);
+        println!("\nNo longer accepting connections.");
+        while !self.peer_map.lock().unwrap().is_empty() {
+            task::sleep(Duration::from_secs(1)).await;
+        }
+        println!("\nEvery peer has disconnected, the server is empty.");
+
         Ok(())
     }
 }
 
-async fn on_peer_connect(
-    peer_map: PeerMap,
-    peer_name_map: PeerNameMap,
-    raw_stream: TcpStream,
-    local_addr: String,
-    peer_addr: SocketAddr,
-    names: HashSet<String>,
-) {
+// Counts down a drain, announcing it again at each of DRAIN_NOTICES, and closes the
+// peers still connected at the end. Stops early once everyone has left.
+async fn drain_countdown(server: Server, ends_at: Instant, drain: Drain) {
+    let after = ends_at.saturating_duration_since(Instant::now());
+    server.announce_drain(after.as_secs(), &drain);
+
+    for secs_left in DRAIN_NOTICES.iter().filter(|secs| **secs < after.as_secs()) {
+        let notice_at = ends_at - Duration::from_secs(*secs_left);
+        task::sleep(notice_at.saturating_duration_since(Instant::now())).await;
+        if server.peer_map.lock().unwrap().is_empty() {
+            return;
+        }
+        server.announce_drain(*secs_left, &drain);
+    }
+
+    task::sleep(ends_at.saturating_duration_since(Instant::now())).await;
+    server.close_all(CloseReason::Shutdown);
+}
+
+// Closes a connection the accept throttle has no room for, doing no more than the
+// handshake needed to tell it when to come back.
+async fn turn_away(raw_stream: TcpStream, retry_after: Duration) {
+    if let Ok(mut ws_stream) = async_tungstenite::accept_async(raw_stream).await {
+        let _ = ws_stream
+            .close(Some(close::retry_after_frame(retry_after)))
+            .await;
+    }
+}
+
+// The handshake callback has to return tungstenite's ErrorResponse.
+#[allow(clippy::result_large_err)]
+async fn on_peer_connect(server: Server, raw_stream: TcpStream, peer_addr: SocketAddr) {
+    let _counted = Counted::new(&server.connections);
+    let shed_backfill = server.memory().over_budget();
+    let Server {
+        addr: local_addr,
+        peer_map,
+        peer_name_map,
+        peer_token_map,
+        session_msg_ids,
+        peer_badges,
+        shadow_bans,
+        bans,
+        invites,
+        peer_list_subscribers,
+        peer_locales,
+        history,
+        activity,
+        commands,
+        emoji,
+        accepted_rules,
+        spam_filters,
+        events,
+        reports,
+        admins,
+        names,
+        filter,
+        config,
+        listening,
+        ..
+    } = server.clone(); // The whole server is still needed for the census.
+
     println!("\nIncoming TCP connection from: {}", peer_addr);
+    let mut state = Lifecycle::accepted(); // Closed however this returns.
     let local_addr = local_addr.as_str();
 
-    let ws_stream = async_tungstenite::accept_async(raw_stream)
-        .await
-        .expect("Error during the websocket handshake occurred");
-
-    let available_peer_names = available_peer_names(&peer_name_map, &names);
-    let peer_name = random_peer_name(&available_peer_names);
-    let peer_spots_left: i32 = (available_peer_names.len() - 1) as i32;
+    let mut presented_token = None;
+    let mut presented_admin_key = None;
+    let mut presented_invite = None;
+    let mut presented_locale = None;
+    let handshake = async_tungstenite::accept_hdr_async(raw_stream, |req: &Request, resp| {
+        presented_token = query_param(req.uri().query(), "token");
+        presented_admin_key = query_param(req.uri().query(), "admin_key");
+        presented_invite = query_param(req.uri().query(), "invite");
+        presented_locale = query_param(req.uri().query(), "locale");
+        Ok(resp)
+    })
+    .await;
+    let mut ws_stream = match handshake {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            println!("The websocket handshake with {} failed: {}", peer_addr, e);
+            return;
+        }
+    };
+    state.advance(State::Authenticating);
 
-    if peer_spots_left < 0 {
-        println!("NO ROOM FOR MORE PEERS!");
+    if is_banned(&bans, &peer_addr.ip()) {
+        println!("{} is banned.", peer_addr);
+        let _ = ws_stream.close(Some(CloseReason::Banned.frame())).await;
         return;
     }
 
-    // bind the peer name to the given peer address such that we can remove it
-    // later, but not re-use the name while the peer is still active.
-    peer_name_map
-        .lock()
-        .unwrap()
-        .insert(peer_name.to_string(), peer_addr);
+    if let Some(challenge) = config.challenge.challenge_for(&peer_addr.ip()) {
+        if !pass_challenge(&mut ws_stream, &config.challenge, challenge, local_addr).await {
+            println!("{} failed the connect challenge.", peer_addr);
+            let _ = ws_stream
+                .close(Some(CloseReason::ChallengeFailed.frame()))
+                .await;
+            return;
+        }
+    }
 
-    broadcast_new_peer_msg(&peer_map, &local_addr, &peer_addr, &peer_name);
-    println!("{} ({}) has connected.", peer_name, peer_addr);
-    println!("Peer spots left: {}", peer_spots_left);
+    let is_admin = config.admin_key.is_some() && presented_admin_key == config.admin_key;
 
     let (sender, receiver) = unbounded();
+    let takeover = presented_token.and_then(|token| {
+        take_over_peer(
+            &peer_map,
+            &peer_name_map,
+            &peer_token_map,
+            &token,
+            &peer_addr,
+            &sender,
+        )
+        .map(|peer_name| (peer_name, token))
+    });
+
+    let is_new = takeover.is_none();
+    let (peer_name, session_token) = match takeover {
+        Some((peer_name, session_token)) => {
+            println!(
+                "{} ({}) has taken over its previous connection.",
+                peer_name, peer_addr
+            );
+            (peer_name, session_token)
+        }
+        None => {
+            // Taking over a connection does not need an invite, the old one already used it.
+            if config.invite_only
+                && !is_admin
+                && !presented_invite.is_some_and(|invite| invites.lock().unwrap().redeem(&invite))
+            {
+                println!("{} did not present a valid invite.", peer_addr);
+                let _ = ws_stream
+                    .close(Some(CloseReason::InviteRequired.frame()))
+                    .await;
+                return;
+            }
+
+            let available_peer_names = available_peer_names(&peer_name_map, &names);
+            if available_peer_names.is_empty() {
+                println!("NO ROOM FOR MORE PEERS!");
+                let _ = ws_stream.close(Some(CloseReason::ServerFull.frame())).await;
+                return;
+            }
+
+            let peer_name = random_peer_name(&available_peer_names);
+            let peer_spots_left: i32 = (available_peer_names.len() - 1) as i32;
+
+            // bind the peer name to the given peer address such that we can remove it
+            // later, but not re-use the name while the peer is still active.
+            peer_name_map
+                .lock()
+                .unwrap()
+                .insert(peer_name.to_string(), peer_addr);
+
+            let session_token = new_session_token();
+            peer_token_map
+                .lock()
+                .unwrap()
+                .insert(session_token.clone(), peer_name.clone());
+
+            // A shadow banned peer is kept out of everyone else's view of the peer list.
+            if !is_shadow_banned(&shadow_bans, &peer_name, &peer_addr) {
+                broadcast_new_peer_msg(
+                    &peer_map,
+                    &peer_locales,
+                    &config.catalog,
+                    local_addr,
+                    &peer_addr,
+                    &peer_name,
+                );
+                broadcast_peer_list_delta(
+                    &peer_map,
+                    &peer_list_subscribers,
+                    local_addr,
+                    &peer_addr,
+                    PeerListDelta {
+                        joined: vec![peer_name.clone()],
+                        left: Vec::new(),
+                        renamed: Vec::new(),
+                    },
+                );
+            }
+            println!(
+                "{} ({}) has connected{}.",
+                peer_name,
+                peer_addr,
+                if is_admin { " as an admin" } else { "" }
+            );
+            events.record(
+                now_millis(),
+                Event::Connected {
+                    name: peer_name.clone(),
+                    addr: peer_addr,
+                },
+            );
+            println!("Peer spots left: {}", peer_spots_left);
+
+            (peer_name, session_token)
+        }
+    };
+    state.advance(State::Named);
 
     // assign the new peer the name of 'peer_name'
-    send_name_assignment_msg(&sender, &local_addr, &peer_name);
+    send_time_sync_msg(&sender, local_addr);
+    send_session_token_msg(&sender, local_addr, &session_token);
+    send_name_assignment_msg(&sender, local_addr, &peer_name);
+    // Before the Backfill, which may use them.
+    send_emoji_list_msg(&sender, &emoji, local_addr);
+    // A peer does fine without a backfill, so it is the first thing shed.
+    if shed_backfill {
+        println!("Over MEMORY_BUDGET, {} is not sent a backfill.", peer_name);
+    } else {
+        send_backfill_msg(&sender, &history, config.backfill_size, local_addr);
+    }
+    send_commands_list_msg(&sender, &commands, local_addr);
+
+    // Insert the write part of this peer to the peer map. A takeover already did, and
+    // inserting it again could bring it back after yet another connection took over.
+    if is_new {
+        peer_map.lock().unwrap().insert(peer_addr, sender);
+    } else {
+        drop(sender);
+    }
+    if is_admin {
+        admins.lock().unwrap().insert(peer_addr);
+    }
+
+    let locale = presented_locale.unwrap_or_default();
+    if !locale.is_empty() {
+        peer_locales
+            .lock()
+            .unwrap()
+            .insert(peer_addr, locale.clone());
+    }
+    let texts = Texts {
+        catalog: &config.catalog,
+        locale: &locale,
+    };
+    // Once, not again when the peer takes its name over after reconnecting.
+    if is_new {
+        send_welcome_msg(
+            &peer_map,
+            &config.welcome,
+            local_addr,
+            texts,
+            &peer_name,
+            &peer_addr,
+        );
+    }
 
-    // Insert the write part of this peer to the peer map.
-    peer_map.lock().unwrap().insert(peer_addr, sender);
+    state.advance(State::Active);
 
     let (outgoing, incoming) = ws_stream.split();
+    let seen_msg_ids = session_seen_msg_ids(&session_msg_ids, &session_token, config.msg_id_window);
 
-    let broadcast_incoming = incoming
-        .try_filter(|msg| {
+    let broadcast_incoming = async {
+        let mut incoming = incoming.try_filter(|msg| {
             // Broadcasting a Close message from one client
-            // will close the other clients.
-            future::ready(!msg.is_close())
-        })
-        .try_for_each(|msg| {
-            let msg: Message = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+            // will close the other clients. Pings are answered by tungstenite itself.
+            future::ready(msg.is_text() || msg.is_binary())
+        });
+
+        // A loop rather than try_for_each, since the filter process is awaited.
+        while let Some(Ok(raw_msg)) = incoming.next().await {
+            let mut msg: Message = match raw_msg
+                .to_text()
+                .ok()
+                .and_then(|msg| serde_json::from_str(msg).ok())
+            {
+                Some(msg) => msg,
+                None => {
+                    println!("\n{} ({}) sent an invalid message.", peer_name, peer_addr);
+                    close_peer(&peer_map, &peer_addr, CloseReason::ProtocolError);
+                    continue;
+                }
+            };
+            // The server stops accepting connections once it is handed over or drained.
+            if *state == State::Active && listening.lock().unwrap().is_none() {
+                state.advance(State::Draining);
+            }
+            if let Err(illegal) = state.check(msg.msg_type.origin()) {
+                refuse_illegal_msg(
+                    &peer_map,
+                    local_addr,
+                    texts,
+                    &peer_name,
+                    &peer_addr,
+                    &msg.msg_type.name(),
+                    illegal,
+                );
+                continue;
+            }
+            // Peers don't get to say who they are, the server knows. Where they connect
+            // from is not passed on.
+            msg.src_name = &peer_name;
+            msg.src_addr = local_addr;
+            // Peer clocks can't be trusted, so whatever time the peer stamped is replaced.
+            msg.timestamp = now_millis();
+            // Neither can badges a peer claims for itself.
+            msg.badges = peer_badges
+                .lock()
+                .unwrap()
+                .get(&peer_name)
+                .cloned()
+                .unwrap_or_default();
             let msg_type = msg.msg_type.clone();
 
+            if let MessageType::Text | MessageType::Private(_) | MessageType::CodeSnippet { .. } =
+                msg_type
+            {
+                // A repeat was screened when it first came, it is only acked again.
+                if let Some(msg_id) = msg.msg_id {
+                    if !first_seen_msg_id(&seen_msg_ids, &peer_name, &peer_addr, msg_id) {
+                        send_ack_msg(&peer_map, &peer_addr, local_addr, msg_id);
+                        continue;
+                    }
+                }
+
+                // Why a message is refused is told before its Ack and anything else after,
+                // so a peer knows from what comes first whether it was taken.
+                let mut spam_verdict = SpamVerdict::Deliver;
+                let taken = (is_admin
+                    || screen_rules(
+                        &peer_map,
+                        &config.welcome,
+                        &accepted_rules,
+                        local_addr,
+                        texts,
+                        &session_token,
+                        &peer_name,
+                        &peer_addr,
+                        &msg.text,
+                    ))
+                    && {
+                        spam_verdict = screen_spam(
+                            &peer_map,
+                            &config.spam,
+                            &spam_filters,
+                            local_addr,
+                            texts,
+                            &peer_name,
+                            &peer_addr,
+                            &msg.text,
+                        );
+                        !matches!(
+                            spam_verdict,
+                            SpamVerdict::SlowMode(_) | SpamVerdict::Muted(_)
+                        )
+                    }
+                    && screen_filter(
+                        &peer_map, &filter, local_addr, texts, &peer_name, &peer_addr, &mut msg,
+                    )
+                    .await;
+                if let Some(msg_id) = msg.msg_id {
+                    send_ack_msg(&peer_map, &peer_addr, local_addr, msg_id);
+                }
+                if !taken {
+                    continue;
+                }
+                if let SpamVerdict::Warn = spam_verdict {
+                    send_spam_warning_msg(&peer_map, local_addr, texts, &peer_name, &peer_addr);
+                }
+
+                // Shadow banned peers are never told that nobody receives their messages.
+                if !is_admin && is_shadow_banned(&shadow_bans, &peer_name, &peer_addr) {
+                    println!(
+                        "\n[ShadowBan] Dropped message from {} ({}): {}",
+                        peer_name, peer_addr, msg.text
+                    );
+                    continue;
+                }
+
+                // Code is passed on as it is.
+                if !matches!(msg_type, MessageType::CodeSnippet { .. }) {
+                    msg.text = emoji.lock().unwrap().normalize(&msg.text);
+                }
+            }
+
             match msg_type {
-                MessageType::Text => handle_text_msg(&peer_map, &peer_addr, msg),
-                MessageType::PeerInfoRequest => handle_peer_info_request_msg(
+                MessageType::Text if is_command_invocation(&commands, &msg.text) => {
+                    handle_command_invocation_msg(
+                        &peer_map, &commands, local_addr, &peer_name, &peer_addr, msg,
+                    )
+                }
+                MessageType::Text if is_content_command(&config.content, &msg.text) => {
+                    handle_content_command_msg(
+                        &peer_map,
+                        &config.content,
+                        &history,
+                        &events,
+                        &activity,
+                        local_addr,
+                        texts,
+                        &peer_addr,
+                        msg,
+                    )
+                    .await
+                }
+                MessageType::Text => {
+                    handle_text_msg(&peer_map, &history, &events, &activity, &peer_addr, msg)
+                }
+                MessageType::CodeSnippet { language } => handle_code_snippet_msg(
+                    &peer_map, &history, &events, &activity, &peer_addr, language, msg,
+                ),
+                MessageType::Ping { nonce, sent_at } => {
+                    handle_ping_msg(&peer_map, &peer_addr, local_addr, nonce, sent_at)
+                }
+                MessageType::SubscribePeerList => handle_subscribe_peer_list_msg(
+                    &peer_map,
+                    &peer_name_map,
+                    &shadow_bans,
+                    &peer_list_subscribers,
+                    local_addr,
+                    &peer_name,
+                    &peer_addr,
+                ),
+                MessageType::PeerInfoRequest { .. } => handle_peer_info_request_msg(
                     &peer_map,
                     &peer_name_map,
+                    &shadow_bans,
+                    &peer_badges,
                     &names,
                     local_addr,
                     &peer_name,
                     &peer_addr,
                     msg,
                 ),
-                MessageType::PeerInfoReply(peer_info) => {
-                    handle_peer_info_reply_msg(&peer_addr, peer_info, msg)
-                }
                 MessageType::Private(recv_peer_name) => handle_private_msg(
                     &peer_map,
                     &peer_name_map,
@@ -169,39 +1445,243 @@ async fn on_peer_connect(
                     &peer_name,
                     &peer_addr,
                     local_addr,
+                    texts,
                     msg,
                 ),
-                _ => handle_unknown_msg(&peer_addr, msg),
-            }
-
-            future::ok(())
-        });
-
-    let receive_from_others = receiver.map(Ok).forward(outgoing);
-
-    pin_mut!(broadcast_incoming, receive_from_others);
-    future::select(broadcast_incoming, receive_from_others).await;
-
-    let discon_peer_name = discon_peer_name(&peer_name_map, &peer_addr).unwrap();
-    peer_name_map.lock().unwrap().remove(&discon_peer_name);
-    peer_map.lock().unwrap().remove(&peer_addr);
-
-    broadcast_lost_peer_msg(&peer_map, &local_addr, &peer_addr, &discon_peer_name);
-    println!("\n[Chat] {} ({}) has disconnected.", peer_name, peer_addr);
-}
-
-fn broadcast_msg(peers: &PeerMap, peer_addr: &SocketAddr, msg: Message) {
-    let peers = peers.lock().unwrap();
-    let msg = TungMessage::Text(serde_json::to_string(&msg).unwrap());
-
-    // We want to broadcast the message to everyone except ourselves.
-    let broadcast_recipients = peers
-        .iter()
-        .filter(|(addr, _)| addr != &peer_addr)
-        .map(|(_, ws_sink)| ws_sink);
-
+                MessageType::CreateInvite { .. }
+                | MessageType::ListInvites
+                | MessageType::RevokeInvite(_)
+                    if is_admin =>
+                {
+                    handle_invite_msg(
+                        &peer_map, &invites, &peer_name, &peer_addr, local_addr, texts, msg,
+                    )
+                }
+                MessageType::ShadowBan(banned_name) if is_admin => handle_shadow_ban_msg(
+                    &peer_map,
+                    &peer_name_map,
+                    &shadow_bans,
+                    &events,
+                    &peer_list_subscribers,
+                    banned_name,
+                    false,
+                    &peer_name,
+                    &peer_addr,
+                    local_addr,
+                    texts,
+                ),
+                MessageType::ShadowBanIp(banned_name) if is_admin => handle_shadow_ban_msg(
+                    &peer_map,
+                    &peer_name_map,
+                    &shadow_bans,
+                    &events,
+                    &peer_list_subscribers,
+                    banned_name,
+                    true,
+                    &peer_name,
+                    &peer_addr,
+                    local_addr,
+                    texts,
+                ),
+                MessageType::SetSlowMode { room, interval } if is_admin => {
+                    handle_set_slow_mode_msg(
+                        &peer_map,
+                        &spam_filters,
+                        &room,
+                        interval,
+                        &peer_name,
+                        &peer_addr,
+                        local_addr,
+                        texts,
+                    )
+                }
+                MessageType::Report { msg_id, reason } => handle_report_msg(
+                    &peer_map,
+                    &peer_name_map,
+                    &peer_locales,
+                    &admins,
+                    &history,
+                    &reports,
+                    &events,
+                    &config.catalog,
+                    local_addr,
+                    texts,
+                    &peer_name,
+                    &peer_addr,
+                    msg_id,
+                    &reason,
+                ),
+                MessageType::CensusRequest if is_admin => {
+                    handle_census_request_msg(&peer_map, server.census(), &peer_addr, local_addr)
+                }
+                MessageType::HelpRequest { .. } => handle_help_request_msg(
+                    &peer_map, &commands, &config, is_admin, local_addr, texts, &peer_addr, msg,
+                ),
+                MessageType::GetPermalink { msg_id } => handle_get_permalink_msg(
+                    &peer_map, &history, &config, local_addr, texts, &peer_addr, msg_id,
+                ),
+                MessageType::FetchContext {
+                    msg_id,
+                    before,
+                    after,
+                } => handle_fetch_context_msg(
+                    &peer_map, &history, local_addr, texts, &peer_addr, msg_id, before, after,
+                ),
+                MessageType::HistoryRequest {
+                    room,
+                    cursor,
+                    direction,
+                    limit,
+                } => handle_history_request_msg(
+                    &peer_map, &history, local_addr, texts, &peer_addr, room, cursor, direction,
+                    limit,
+                ),
+                MessageType::RegisterCommand { .. } if is_admin => handle_register_command_msg(
+                    &peer_map, &commands, local_addr, texts, &peer_name, &peer_addr, msg,
+                ),
+                _ => handle_unknown_msg(&peer_addr, msg),
+            }
+        }
+    };
+
+    #[cfg(feature = "chaos")]
+    let receiver = chaos::faulty(receiver);
+    let receive_from_others = batch::forward(receiver, outgoing, config.batch_window, |frames| {
+        batch_msg(local_addr, frames)
+    });
+    // Dropped without a close frame, as if the network had failed.
+    #[cfg(feature = "chaos")]
+    let receive_from_others = future::select(
+        Box::pin(receive_from_others),
+        Box::pin(chaos::cut_off(peer_addr)),
+    );
+
+    // Boxed rather than pinned on the stack, so both are dropped along with their borrow
+    // of 'state' once either is done.
+    future::select(Box::pin(broadcast_incoming), Box::pin(receive_from_others)).await;
+
+    peer_map.lock().unwrap().remove(&peer_addr);
+    peer_list_subscribers.lock().unwrap().remove(&peer_addr);
+    peer_locales.lock().unwrap().remove(&peer_addr);
+    admins.lock().unwrap().remove(&peer_addr);
+    // The peer's spam state stays until it has cooled down, whatever has by now goes.
+    spam_filters.lock().unwrap().prune(&config.spam);
+
+    // A bot's commands leave with it.
+    let unregistered = commands.lock().unwrap().unregister_all(&peer_addr);
+    if unregistered {
+        broadcast_commands_list_msg(&peer_map, &commands, local_addr);
+    }
+
+    // If the name has been taken over by a newer connection, this one no longer
+    // owns anything and the other peers never saw it leave.
+    match discon_peer_name(&peer_name_map, &peer_addr) {
+        Some(discon_peer_name) => {
+            let shadow_banned = is_shadow_banned(&shadow_bans, &discon_peer_name, &peer_addr);
+            // The session's shadow ban ends with it.
+            shadow_bans.lock().unwrap().names.remove(&discon_peer_name);
+            peer_name_map.lock().unwrap().remove(&discon_peer_name);
+            peer_badges.lock().unwrap().remove(&discon_peer_name);
+            {
+                let mut peer_token_map = peer_token_map.lock().unwrap();
+                peer_token_map.retain(|_, name| name != &discon_peer_name);
+                session_msg_ids
+                    .lock()
+                    .unwrap()
+                    .retain(|token, _| peer_token_map.contains_key(token));
+                accepted_rules
+                    .lock()
+                    .unwrap()
+                    .retain(|token| peer_token_map.contains_key(token));
+            }
+
+            if !shadow_banned {
+                broadcast_lost_peer_msg(
+                    &peer_map,
+                    &peer_locales,
+                    &config.catalog,
+                    local_addr,
+                    &peer_addr,
+                    &discon_peer_name,
+                );
+                broadcast_peer_list_delta(
+                    &peer_map,
+                    &peer_list_subscribers,
+                    local_addr,
+                    &peer_addr,
+                    PeerListDelta {
+                        joined: Vec::new(),
+                        left: vec![discon_peer_name.clone()],
+                        renamed: Vec::new(),
+                    },
+                );
+            }
+            events.record(
+                now_millis(),
+                Event::Disconnected {
+                    name: discon_peer_name.clone(),
+                },
+            );
+            println!("\n[Chat] {} ({}) has disconnected.", peer_name, peer_addr);
+        }
+        None => println!(
+            "\n[Chat] {} ({}) was closed after being taken over.",
+            peer_name, peer_addr
+        ),
+    }
+}
+
+// Sends 'challenge' to a freshly connected peer and checks the answer it sends back.
+async fn pass_challenge(
+    ws_stream: &mut WebSocketStream<TcpStream>,
+    challenge_config: &ChallengeConfig,
+    challenge: Challenge,
+    local_addr: &str,
+) -> bool {
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::Challenge(challenge.clone()),
+        text: String::from("Challenge"),
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    let msg = TungMessage::Text(serde_json::to_string(&msg).unwrap());
+    if ws_stream.send(msg).await.is_err() {
+        return false;
+    }
+
+    let reply = match timeout(challenge_config.timeout, ws_stream.next()).await {
+        Ok(Some(Ok(reply))) => reply,
+        _ => return false,
+    };
+
+    match serde_json::from_str::<Message>(reply.to_text().unwrap_or_default()) {
+        Ok(Message {
+            msg_type: MessageType::ChallengeAnswer(answer),
+            ..
+        }) => challenge_config.verify(&challenge, answer),
+        _ => false,
+    }
+}
+
+fn broadcast_msg(peers: &PeerMap, peer_addr: &SocketAddr, msg: Message) {
+    let peers = peers.lock().unwrap();
+    let msg = TungMessage::Text(serde_json::to_string(&msg).unwrap());
+
+    // We want to broadcast the message to everyone except ourselves.
+    let broadcast_recipients = peers
+        .iter()
+        .filter(|(addr, _)| addr != &peer_addr)
+        .map(|(_, ws_sink)| ws_sink);
+
+    // A connection that is ending drops its receiver before it leaves the peer map, so a
+    // send may fail in between. It is gone either way.
     for recp in broadcast_recipients {
-        recp.unbounded_send(msg.clone()).unwrap();
+        let _ = recp.unbounded_send(msg.clone());
     }
 }
 
@@ -209,68 +1689,328 @@ fn send_single_msg(peers: &PeerMap, peer_addr: &SocketAddr, msg: Message) {
     let peers = peers.lock().unwrap();
     let msg = TungMessage::Text(serde_json::to_string(&msg).unwrap());
 
-    // Send only to single peer!
-    let recp = peers.get(&peer_addr).unwrap();
-    recp.unbounded_send(msg.clone()).unwrap();
+    // Send only to single peer! It may have left or been taken over meanwhile.
+    if let Some(recp) = peers.get(peer_addr) {
+        let _ = recp.unbounded_send(msg);
+    }
+}
+
+// Wraps messages that have already been serialized into one Batch frame.
+fn batch_msg(local_addr: &str, frames: Vec<String>) -> TungMessage {
+    let batch = frames
+        .into_iter()
+        .filter_map(|frame| RawValue::from_string(frame).ok())
+        .collect();
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::Batch(batch),
+        text: String::from(""),
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    TungMessage::Text(serde_json::to_string(&msg).unwrap())
+}
+
+// Closes the connection at 'peer_addr' once everything queued for it has been sent.
+fn close_peer(peers: &PeerMap, peer_addr: &SocketAddr, reason: CloseReason) {
+    if let Some(recp) = peers.lock().unwrap().get(peer_addr) {
+        let _ = recp.unbounded_send(TungMessage::Close(Some(reason.frame())));
+    }
+}
+
+// Like broadcast_msg, but the text is 'key' rendered in the locale of each recipient.
+// Everyone but 'peer_addr' gets it, or everyone if it is None.
+fn broadcast_localized_msg(
+    peers: &PeerMap,
+    locales: &PeerLocales,
+    catalog: &Catalog,
+    peer_addr: Option<&SocketAddr>,
+    mut msg: Message,
+    key: &str,
+    args: &[&dyn std::fmt::Display],
+) {
+    let locales = locales.lock().unwrap().clone();
+    let peers = peers.lock().unwrap();
+    let mut rendered: HashMap<&str, TungMessage> = HashMap::new();
+
+    for (addr, recp) in peers.iter().filter(|(addr, _)| Some(*addr) != peer_addr) {
+        let locale = locales.get(addr).map(String::as_str).unwrap_or_default();
+        let rendered = rendered.entry(locale).or_insert_with(|| {
+            msg.text = catalog.text(locale, key, args);
+            TungMessage::Text(serde_json::to_string(&msg).unwrap())
+        });
+        let _ = recp.unbounded_send(rendered.clone());
+    }
 }
 
 fn broadcast_new_peer_msg(
     peers: &PeerMap,
+    locales: &PeerLocales,
+    catalog: &Catalog,
     local_addr: &str,
     peer_addr: &SocketAddr,
     peer_name: &str,
 ) {
     let msg = Message {
+        v: PROTOCOL_VERSION,
         src_addr: local_addr,
         src_name: LOCAL_NAME,
         msg_type: MessageType::NewPeer(peer_name),
-        text: format!("{} ({}) has connected.", peer_name, peer_addr),
+        text: String::from(""),
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
     };
 
-    broadcast_msg(peers, peer_addr, msg);
+    broadcast_localized_msg(
+        peers,
+        locales,
+        catalog,
+        Some(peer_addr),
+        msg,
+        "peer-connected",
+        &[&peer_name, peer_addr],
+    );
 }
 
 fn broadcast_lost_peer_msg(
     peers: &PeerMap,
+    locales: &PeerLocales,
+    catalog: &Catalog,
     local_addr: &str,
     peer_addr: &SocketAddr,
     peer_name: &str,
 ) {
     let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::DisconPeer(peer_name),
+        text: String::from(""),
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    broadcast_localized_msg(
+        peers,
+        locales,
+        catalog,
+        Some(peer_addr),
+        msg,
+        "peer-disconnected",
+        &[&peer_name, peer_addr],
+    );
+}
+
+// Sends 'delta' to every subscribed peer except the one at 'peer_addr'.
+fn broadcast_peer_list_delta(
+    peers: &PeerMap,
+    subscribers: &PeerListSubscribers,
+    local_addr: &str,
+    peer_addr: &SocketAddr,
+    delta: PeerListDelta,
+) {
+    let msg = Message {
+        v: PROTOCOL_VERSION,
         src_addr: local_addr,
         src_name: LOCAL_NAME,
-        msg_type: MessageType::DisconPeer(peer_name.clone()),
-        text: format!("{} ({}) has disconnected.", peer_name, peer_addr),
+        msg_type: MessageType::PeerListDelta(delta),
+        text: String::from(""),
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
     };
+    let msg = TungMessage::Text(serde_json::to_string(&msg).unwrap());
+
+    let subscribers = subscribers.lock().unwrap();
+    let peers = peers.lock().unwrap();
+    let recipients = peers
+        .iter()
+        .filter(|(addr, _)| *addr != peer_addr && subscribers.contains(addr))
+        .map(|(_, ws_sink)| ws_sink);
 
-    broadcast_msg(peers, peer_addr, msg);
+    for recp in recipients {
+        let _ = recp.unbounded_send(msg.clone());
+    }
 }
 
 fn send_name_assignment_msg(sender: &Sender, local_addr: &str, peer_name: &str) {
     let msg = Message {
+        v: PROTOCOL_VERSION,
         src_addr: local_addr,
         src_name: LOCAL_NAME,
-        msg_type: MessageType::PeerNameAssign(peer_name.clone()),
+        msg_type: MessageType::PeerNameAssign(peer_name),
         text: String::from("PeerName"),
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    let msg = TungMessage::Text(serde_json::to_string(&msg).unwrap());
+    let _ = sender.unbounded_send(msg);
+}
+
+fn send_time_sync_msg(sender: &Sender, local_addr: &str) {
+    let now = now_millis();
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::TimeSync(now),
+        text: String::from("TimeSync"),
+        timestamp: now,
+        msg_id: None,
+        badges: Vec::new(),
     };
 
     let msg = TungMessage::Text(serde_json::to_string(&msg).unwrap());
-    sender.unbounded_send(msg).unwrap();
+    let _ = sender.unbounded_send(msg);
 }
 
+fn send_backfill_msg(sender: &Sender, history: &HistoryBuffer, size: usize, local_addr: &str) {
+    let entries = history.lock().unwrap().latest(size);
+    if entries.is_empty() {
+        return;
+    }
+
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::Backfill(entries),
+        text: String::from("Backfill"),
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    let msg = TungMessage::Text(serde_json::to_string(&msg).unwrap());
+    let _ = sender.unbounded_send(msg);
+}
+
+fn send_commands_list_msg(sender: &Sender, commands: &CommandMap, local_addr: &str) {
+    let commands = commands.lock().unwrap().list();
+    if commands.is_empty() {
+        return;
+    }
+
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::CommandsList(commands),
+        text: String::from("CommandsList"),
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    let msg = TungMessage::Text(serde_json::to_string(&msg).unwrap());
+    let _ = sender.unbounded_send(msg);
+}
+
+fn send_emoji_list_msg(sender: &Sender, emoji: &EmojiMap, local_addr: &str) {
+    let emoji = emoji.lock().unwrap();
+    if emoji.is_empty() {
+        return;
+    }
+
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::EmojiList(emoji.list()),
+        text: String::from("EmojiList"),
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    let msg = TungMessage::Text(serde_json::to_string(&msg).unwrap());
+    let _ = sender.unbounded_send(msg);
+}
+
+// Sends the current list of commands to every peer, including when it has become empty.
+fn broadcast_commands_list_msg(peer_map: &PeerMap, commands: &CommandMap, local_addr: &str) {
+    let commands = commands.lock().unwrap().list();
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::CommandsList(commands),
+        text: String::from("CommandsList"),
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+    let msg = TungMessage::Text(serde_json::to_string(&msg).unwrap());
+
+    for recp in peer_map.lock().unwrap().values() {
+        let _ = recp.unbounded_send(msg.clone());
+    }
+}
+
+fn send_session_token_msg(sender: &Sender, local_addr: &str, session_token: &str) {
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::SessionToken(session_token),
+        text: String::from("SessionToken"),
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    let msg = TungMessage::Text(serde_json::to_string(&msg).unwrap());
+    let _ = sender.unbounded_send(msg);
+}
+
+#[allow(clippy::too_many_arguments)]
 fn create_peer_data(
     peer_name_map: &PeerNameMap,
+    shadow_bans: &ShadowBans,
+    peer_badges: &PeerBadges,
     names: &HashSet<String>,
     src_name: &str,
+    offset: usize,
+    limit: usize,
+    filter: Option<&str>,
 ) -> PeerInfo {
-    let peer_names: HashSet<String> = peer_name_map
-        .lock()
-        .unwrap()
-        .keys()
-        .filter(|&k| k != src_name)
-        .map(|k| k.to_string())
+    let peer_names = visible_peer_names(peer_name_map, shadow_bans, src_name);
+    let peers_online = peer_names.len() as i32 + 1;
+
+    let filter = filter.map(|filter| filter.to_lowercase());
+    let mut matching: Vec<String> = peer_names
+        .into_iter()
+        .filter(|name| {
+            filter
+                .as_ref()
+                .is_none_or(|filter| name.to_lowercase().contains(filter))
+        })
+        .collect();
+    matching.sort();
+
+    let peers_matching = matching.len() as i32;
+    let peer_names: Vec<String> = matching
+        .into_iter()
+        .skip(offset)
+        .take(limit.min(PEER_INFO_MAX_LIMIT))
         .collect();
 
+    let peer_badges = {
+        let peer_badges = peer_badges.lock().unwrap();
+        peer_names
+            .iter()
+            .filter_map(|name| Some((name.clone(), peer_badges.get(name)?.clone())))
+            .collect()
+    };
+
     let name_map = peer_name_map.lock().unwrap().clone();
     let curr_names: HashSet<String> = name_map.keys().map(|k| k.to_string()).collect();
     let available_names: Vec<String> = names
@@ -280,15 +2020,49 @@ fn create_peer_data(
 
     let peer_spots_left = available_names.len() as i32;
 
-    let peers_online = peer_name_map.lock().unwrap().keys().len() as i32;
+    PeerInfo {
+        peers_online,
+        peer_spots_left,
+        peers_matching,
+        peer_names,
+        peer_badges,
+    }
+}
+
+// The names of all connected peers except 'src_name'. Shadow banned peers are
+// left out, so nobody can tell they are still around.
+fn visible_peer_names(
+    peer_name_map: &PeerNameMap,
+    shadow_bans: &ShadowBans,
+    src_name: &str,
+) -> HashSet<String> {
+    // Locked after the name map, as in Server::peers.
+    let peer_name_map = peer_name_map.lock().unwrap();
+    let shadow_bans = shadow_bans.lock().unwrap();
 
-    let peer_data = PeerInfo {
-        peers_online: peers_online,
-        peer_spots_left: peer_spots_left,
-        peer_names: peer_names.clone(),
-    };
+    peer_name_map
+        .iter()
+        .filter(|&(k, addr)| k != src_name && !shadow_bans.covers(k, addr))
+        .map(|(k, _)| k.to_string())
+        .collect()
+}
+
+// Whether 'ip' is banned. Expired bans are lifted on the way.
+fn is_banned(bans: &Bans, ip: &IpAddr) -> bool {
+    let mut bans = bans.lock().unwrap();
+
+    match bans.get(ip) {
+        Some(Some(until)) if *until <= Instant::now() => {
+            bans.remove(ip);
+            false
+        }
+        Some(_) => true,
+        None => false,
+    }
+}
 
-    peer_data
+fn is_shadow_banned(shadow_bans: &ShadowBans, peer_name: &str, peer_addr: &SocketAddr) -> bool {
+    shadow_bans.lock().unwrap().covers(peer_name, peer_addr)
 }
 
 fn parse_peer_names() -> HashSet<String> {
@@ -323,6 +2097,100 @@ fn random_peer_name(available_peer_names: &[String]) -> String {
         .to_string()
 }
 
+fn new_session_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SESSION_TOKEN_LEN)
+        .collect()
+}
+
+fn query_param(query: Option<&str>, key: &str) -> Option<String> {
+    query?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, value)| *name == key && !value.is_empty())
+        .map(|(_, value)| value.to_string())
+}
+
+// Rebinds the name behind 'session_token' to 'peer_addr' and closes the stale
+// connection that held it. Returns the taken over name.
+fn take_over_peer(
+    peer_map: &PeerMap,
+    peer_name_map: &PeerNameMap,
+    peer_token_map: &PeerTokenMap,
+    session_token: &str,
+    peer_addr: &SocketAddr,
+    sender: &Sender,
+) -> Option<String> {
+    let peer_name = peer_token_map.lock().unwrap().get(session_token)?.clone();
+    // Only a name that is still bound is taken over. Right after its connection ended the
+    // token can outlive the name for a moment, and binding the name again then would
+    // leave it behind for good once this connection ends under a name of its own.
+    let mut peer_name_map = peer_name_map.lock().unwrap();
+    let bound_addr = peer_name_map.get_mut(&peer_name)?;
+    let stale_addr = std::mem::replace(bound_addr, *peer_addr);
+
+    // Swapped while the name map is still locked, so nothing that looks the name up ever
+    // finds an address without a sender.
+    let mut peer_map = peer_map.lock().unwrap();
+    peer_map.insert(*peer_addr, sender.clone());
+    // Dropping the stale sender ends its forwarding half, which closes the old connection.
+    if let Some(stale_sender) = peer_map.remove(&stale_addr) {
+        let _ =
+            stale_sender.unbounded_send(TungMessage::Close(Some(CloseReason::Replaced.frame())));
+    }
+
+    Some(peer_name)
+}
+
+fn send_ack_msg(peer_map: &PeerMap, peer_addr: &SocketAddr, local_addr: &str, msg_id: u64) {
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::Ack(msg_id),
+        text: String::from(""),
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    send_single_msg(peer_map, peer_addr, msg);
+}
+
+// The msg_ids seen in the session behind 'session_token'. A peer resends what it has not
+// seen acked after reconnecting, so they belong to the session rather than to a
+// connection, and a connection taking the session over shares them.
+fn session_seen_msg_ids(
+    session_msg_ids: &SessionMsgIds,
+    session_token: &str,
+    window: usize,
+) -> Arc<Mutex<SeenMsgIds>> {
+    session_msg_ids
+        .lock()
+        .unwrap()
+        .entry(session_token.to_string())
+        .or_insert_with(|| Arc::new(Mutex::new(SeenMsgIds::new(window))))
+        .clone()
+}
+
+// Records 'msg_id' as seen in the session. Returns false if it has been before.
+fn first_seen_msg_id(
+    seen_msg_ids: &Mutex<SeenMsgIds>,
+    peer_name: &str,
+    peer_addr: &SocketAddr,
+    msg_id: u64,
+) -> bool {
+    let first_seen = seen_msg_ids.lock().unwrap().insert(msg_id);
+    if !first_seen {
+        println!(
+            "\n[Dedup] Dropped repeated message {} from {} ({})",
+            msg_id, peer_name, peer_addr
+        );
+    }
+    first_seen
+}
+
 fn discon_peer_name(peer_name_map: &PeerNameMap, discon_peer_addr: &SocketAddr) -> Option<String> {
     let peer_names = peer_name_map.lock().unwrap();
 
@@ -335,29 +2203,525 @@ fn discon_peer_name(peer_name_map: &PeerNameMap, discon_peer_addr: &SocketAddr)
     None
 }
 
-fn handle_text_msg(peer_map: &PeerMap, peer_addr: &SocketAddr, msg: Message) {
-    if !msg.text.trim().is_empty() {
-        println!("\n[Chat] {} ({}): {}", msg.src_name, peer_addr, msg.text);
-        broadcast_msg(&peer_map, &peer_addr, msg);
+// Greets a new peer with WELCOME_TEXT and tells it to /accept the rules first if it
+// has to.
+fn send_welcome_msg(
+    peer_map: &PeerMap,
+    welcome: &WelcomeConfig,
+    local_addr: &str,
+    texts: Texts,
+    peer_name: &str,
+    peer_addr: &SocketAddr,
+) {
+    if let Some(greeting) = welcome.greeting(peer_name) {
+        let greeting = Message {
+            v: PROTOCOL_VERSION,
+            src_addr: local_addr,
+            src_name: LOCAL_NAME,
+            msg_type: if welcome.private {
+                MessageType::Private(peer_name)
+            } else {
+                MessageType::Text
+            },
+            text: greeting,
+            timestamp: now_millis(),
+            msg_id: None,
+            badges: Vec::new(),
+        };
+
+        if welcome.private {
+            send_single_msg(peer_map, peer_addr, greeting);
+        } else {
+            let greeting = TungMessage::Text(serde_json::to_string(&greeting).unwrap());
+            for recp in peer_map.lock().unwrap().values() {
+                let _ = recp.unbounded_send(greeting.clone());
+            }
+        }
+    }
+
+    if welcome.rules_accept {
+        let notice = Message {
+            v: PROTOCOL_VERSION,
+            src_addr: local_addr,
+            src_name: LOCAL_NAME,
+            msg_type: MessageType::Private(peer_name),
+            text: texts.get("rules-required", &[]),
+            timestamp: now_millis(),
+            msg_id: None,
+            badges: Vec::new(),
+        };
+        send_single_msg(peer_map, peer_addr, notice);
     }
 }
 
-fn handle_peer_info_request_msg(
+// With RULES_ACCEPT, holds back everything a peer posts until it has sent /accept, which
+// is not posted either. Returns whether the message may go on.
+#[allow(clippy::too_many_arguments)]
+fn screen_rules(
     peer_map: &PeerMap,
-    peer_name_map: &PeerNameMap,
-    names: &HashSet<String>,
+    welcome: &WelcomeConfig,
+    accepted_rules: &AcceptedRules,
     local_addr: &str,
-    peer_name: &String,
+    texts: Texts,
+    session_token: &str,
+    peer_name: &str,
     peer_addr: &SocketAddr,
-    msg: Message,
-) {
-    let peer_data = create_peer_data(&peer_name_map, &names, &msg.src_name);
+    text: &str,
+) -> bool {
+    if !welcome.rules_accept || accepted_rules.lock().unwrap().contains(session_token) {
+        return true;
+    }
 
-    let msg = Message {
+    let notice = if text.trim() == "/accept" {
+        println!(
+            "\n[Rules] {} ({}) has accepted the rules.",
+            peer_name, peer_addr
+        );
+        accepted_rules
+            .lock()
+            .unwrap()
+            .insert(session_token.to_string());
+        texts.get("rules-accepted", &[])
+    } else {
+        texts.get("rules-required", &[])
+    };
+    let notice = Message {
+        v: PROTOCOL_VERSION,
         src_addr: local_addr,
         src_name: LOCAL_NAME,
-        msg_type: MessageType::PeerInfoReply(peer_data.clone()),
-        text: String::from(""),
+        msg_type: MessageType::Private(peer_name),
+        text: notice,
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+    send_single_msg(peer_map, peer_addr, notice);
+
+    false
+}
+
+// Scores the message against the peer's spam filter and tells the peer when it is
+// dropped. A warning is left to the caller, it goes out after the message's Ack.
+#[allow(clippy::too_many_arguments)]
+fn screen_spam(
+    peer_map: &PeerMap,
+    spam_config: &SpamConfig,
+    spam_filters: &SpamState,
+    local_addr: &str,
+    texts: Texts,
+    peer_name: &str,
+    peer_addr: &SocketAddr,
+    text: &str,
+) -> SpamVerdict {
+    if text.trim().is_empty() {
+        return SpamVerdict::Deliver;
+    }
+
+    let (verdict, score) = spam_filters
+        .lock()
+        .unwrap()
+        .check(spam_config, peer_addr.ip(), text);
+    let (msg_type, notice) = match verdict {
+        SpamVerdict::Deliver => return verdict,
+        SpamVerdict::Warn => {
+            println!(
+                "\n[Spam] {} ({}) scored {:.1}: warned",
+                peer_name, peer_addr, score
+            );
+            return verdict;
+        }
+        SpamVerdict::SlowMode(retry_in) => (
+            MessageType::SlowModeWait(whole_secs(retry_in)),
+            texts.get("spam-slow-mode", &[]),
+        ),
+        SpamVerdict::Muted(muted_for) => (
+            MessageType::Private(peer_name),
+            texts.get("spam-muted", &[&whole_secs(muted_for)]),
+        ),
+    };
+
+    println!(
+        "\n[Spam] {} ({}) scored {:.1}: {} {:?}",
+        peer_name, peer_addr, score, notice, msg_type
+    );
+
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type,
+        text: notice,
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    send_single_msg(peer_map, peer_addr, msg);
+
+    verdict
+}
+
+fn send_spam_warning_msg(
+    peer_map: &PeerMap,
+    local_addr: &str,
+    texts: Texts,
+    peer_name: &str,
+    peer_addr: &SocketAddr,
+) {
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::Private(peer_name),
+        text: texts.get("spam-warning", &[]),
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    send_single_msg(peer_map, peer_addr, msg);
+}
+
+// Runs the message past the external filter, applying any rewrite of its text and
+// telling the peer when it is rejected. Returns whether the message should be delivered.
+async fn screen_filter(
+    peer_map: &PeerMap,
+    filter: &Filter,
+    local_addr: &str,
+    texts: Texts<'_>,
+    peer_name: &str,
+    peer_addr: &SocketAddr,
+    msg: &mut Message<'_>,
+) -> bool {
+    let (kind, to) = match msg.msg_type {
+        MessageType::Private(recv_peer_name) => ("private", Some(recv_peer_name)),
+        _ => ("text", None),
+    };
+
+    let request = FilterRequest {
+        kind,
+        src_name: peer_name,
+        src_addr: peer_addr.to_string(),
+        to,
+        text: &msg.text,
+    };
+
+    let reason = match filter.check(&request).await {
+        FilterVerdict::Allow => return true,
+        FilterVerdict::Modify { text } => {
+            println!(
+                "\n[Filter] Rewrote message from {} ({}): {} -> {}",
+                peer_name, peer_addr, msg.text, text
+            );
+            msg.text = text;
+            return true;
+        }
+        FilterVerdict::Reject { reason } => reason,
+    };
+
+    println!(
+        "\n[Filter] Rejected message from {} ({}): {} {:?}",
+        peer_name, peer_addr, msg.text, reason
+    );
+
+    let notice = match reason {
+        Some(reason) => texts.get("filter-rejected-reason", &[&reason]),
+        None => texts.get("filter-rejected", &[]),
+    };
+    let notice = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::Private(peer_name),
+        text: notice,
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    send_single_msg(peer_map, peer_addr, notice);
+
+    false
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn whole_secs(duration: Duration) -> u64 {
+    (duration.as_millis() as u64).div_ceil(1000)
+}
+
+// Pushes a message to the history and records it in the event log. Returns its id.
+fn add_to_history(
+    history: &HistoryBuffer,
+    events: &EventLog,
+    src_name: &str,
+    text: &str,
+    timestamp: u64,
+) -> u64 {
+    // Under the lock, so the log has the messages in the order of their ids.
+    let mut history = history.lock().unwrap();
+    events.record(
+        timestamp,
+        Event::Message {
+            src_name: src_name.to_string(),
+            text: text.to_string(),
+            timestamp,
+        },
+    );
+    history.push(src_name, text, timestamp)
+}
+
+fn handle_text_msg(
+    peer_map: &PeerMap,
+    history: &HistoryBuffer,
+    events: &EventLog,
+    activity: &ActivityLog,
+    peer_addr: &SocketAddr,
+    mut msg: Message,
+) {
+    if !msg.text.trim().is_empty() {
+        println!("\n[Chat] {} ({}): {}", msg.src_name, peer_addr, msg.text);
+        let id = add_to_history(history, events, msg.src_name, &msg.text, msg.timestamp);
+        activity.lock().unwrap().record(msg.src_name, &msg.text);
+        // The other peers see the message's id in the history, not the sender's msg_id.
+        msg.msg_id = Some(id);
+        broadcast_msg(peer_map, peer_addr, msg);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_code_snippet_msg(
+    peer_map: &PeerMap,
+    history: &HistoryBuffer,
+    events: &EventLog,
+    activity: &ActivityLog,
+    peer_addr: &SocketAddr,
+    language: Option<String>,
+    mut msg: Message,
+) {
+    if !msg.text.trim().is_empty() {
+        println!(
+            "\n[Code] {} ({}) shared {} line(s) of {}",
+            msg.src_name,
+            peer_addr,
+            msg.text.lines().count(),
+            language.as_deref().unwrap_or("code")
+        );
+        // Kept fenced, so Backfill, /find and the archive show it as a block.
+        let fenced = format!(
+            "```{}\n{}\n```",
+            language.as_deref().unwrap_or(""),
+            msg.text.trim_end()
+        );
+        let id = add_to_history(history, events, msg.src_name, &fenced, msg.timestamp);
+        activity.lock().unwrap().record(msg.src_name, &msg.text);
+        msg.msg_id = Some(id);
+        broadcast_msg(peer_map, peer_addr, msg);
+    }
+}
+
+fn handle_get_permalink_msg(
+    peer_map: &PeerMap,
+    history: &HistoryBuffer,
+    config: &Config,
+    local_addr: &str,
+    texts: Texts,
+    peer_addr: &SocketAddr,
+    msg_id: u64,
+) {
+    let (msg_type, text) = if history.lock().unwrap().has_assigned(msg_id) {
+        let server = config.public_addr.as_deref().unwrap_or(local_addr);
+        let permalink = Permalink {
+            msg_id,
+            uri: format!("chat://{}/{}/{}", server, ROOM_NAME, msg_id),
+            url: None,
+        };
+        (MessageType::Permalink(permalink), String::from("Permalink"))
+    } else {
+        (MessageType::Text, texts.get("message-unknown", &[&msg_id]))
+    };
+
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type,
+        text,
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    send_single_msg(peer_map, peer_addr, msg);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_fetch_context_msg(
+    peer_map: &PeerMap,
+    history: &HistoryBuffer,
+    local_addr: &str,
+    texts: Texts,
+    peer_addr: &SocketAddr,
+    msg_id: u64,
+    before: usize,
+    after: usize,
+) {
+    let (context, assigned) = {
+        let history = history.lock().unwrap();
+        let context = history.context(
+            msg_id,
+            before.min(CONTEXT_MAX_SIZE),
+            after.min(CONTEXT_MAX_SIZE),
+        );
+        (context, history.has_assigned(msg_id))
+    };
+
+    let (msg_type, text) = match context {
+        Some(entries) => (
+            MessageType::Context { msg_id, entries },
+            String::from("Context"),
+        ),
+        None if assigned => (MessageType::Text, texts.get("message-gone", &[&msg_id])),
+        None => (MessageType::Text, texts.get("message-unknown", &[&msg_id])),
+    };
+
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type,
+        text,
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    send_single_msg(peer_map, peer_addr, msg);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_history_request_msg(
+    peer_map: &PeerMap,
+    history: &HistoryBuffer,
+    local_addr: &str,
+    texts: Texts,
+    peer_addr: &SocketAddr,
+    room: Option<String>,
+    cursor: Option<String>,
+    direction: Direction,
+    limit: usize,
+) {
+    let from = cursor.as_deref().map(history::parse_cursor);
+    let (msg_type, text) = match (room, from) {
+        (Some(room), _) if room != ROOM_NAME => {
+            (MessageType::Text, texts.get("room-unknown", &[&room]))
+        }
+        (_, Some(None)) => (MessageType::Text, texts.get("history-bad-cursor", &[])),
+        (_, from) => {
+            let from = from.flatten();
+            let limit = match limit {
+                0 => HISTORY_PAGE_MAX,
+                limit => limit.min(HISTORY_PAGE_MAX),
+            };
+            let (messages, more) = history.lock().unwrap().page(from, direction, limit);
+
+            let next_cursor = match direction {
+                Direction::Older if more => messages.first().map(|entry| entry.id),
+                Direction::Older => None,
+                Direction::Newer => messages.last().map(|entry| entry.id).or(from),
+            };
+            (
+                MessageType::HistoryReply {
+                    messages,
+                    next_cursor: next_cursor.map(history::cursor),
+                },
+                String::from("HistoryReply"),
+            )
+        }
+    };
+
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type,
+        text,
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    send_single_msg(peer_map, peer_addr, msg);
+}
+
+// Pings are answered without logging, since clients send them periodically.
+fn handle_ping_msg(
+    peer_map: &PeerMap,
+    peer_addr: &SocketAddr,
+    local_addr: &str,
+    nonce: u64,
+    sent_at: u64,
+) {
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::Pong { nonce, sent_at },
+        text: String::from(""),
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    send_single_msg(peer_map, peer_addr, msg);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_peer_info_request_msg(
+    peer_map: &PeerMap,
+    peer_name_map: &PeerNameMap,
+    shadow_bans: &ShadowBans,
+    peer_badges: &PeerBadges,
+    names: &HashSet<String>,
+    local_addr: &str,
+    peer_name: &String,
+    peer_addr: &SocketAddr,
+    msg: Message,
+) {
+    let (offset, limit, filter) = match &msg.msg_type {
+        MessageType::PeerInfoRequest {
+            offset,
+            limit,
+            filter,
+        } => (*offset, *limit, filter.as_deref()),
+        _ => return,
+    };
+
+    let peer_data = create_peer_data(
+        peer_name_map,
+        shadow_bans,
+        peer_badges,
+        names,
+        peer_name,
+        offset,
+        limit,
+        filter,
+    );
+
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::PeerInfoReply(peer_data.clone()),
+        text: String::from(""),
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
     };
 
     println!(
@@ -365,17 +2729,50 @@ fn handle_peer_info_request_msg(
         LOCAL_NAME, local_addr, peer_name, peer_addr, peer_data
     );
 
-    send_single_msg(&peer_map, &peer_addr, msg);
+    send_single_msg(peer_map, peer_addr, msg);
 }
 
-fn handle_peer_info_reply_msg(peer_addr: &SocketAddr, peer_info: PeerInfo, msg: Message) {
+fn handle_subscribe_peer_list_msg(
+    peer_map: &PeerMap,
+    peer_name_map: &PeerNameMap,
+    shadow_bans: &ShadowBans,
+    subscribers: &PeerListSubscribers,
+    local_addr: &str,
+    peer_name: &str,
+    peer_addr: &SocketAddr,
+) {
+    subscribers.lock().unwrap().insert(*peer_addr);
+
+    // The first delta carries everyone that is already online.
+    let mut joined: Vec<String> = visible_peer_names(peer_name_map, shadow_bans, peer_name)
+        .into_iter()
+        .collect();
+    joined.sort();
+
     println!(
-        "\n[PeerDataReply] {} ({}): Server received PeerDataReply. Not further action is taken:
-    {}: {:?}",
-        msg.src_name, peer_addr, msg.text, peer_info
-    )
+        "\n[SubscribePeerList] {} ({}) subscribed to the peer list.",
+        peer_name, peer_addr
+    );
+
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::PeerListDelta(PeerListDelta {
+            joined,
+            left: Vec::new(),
+            renamed: Vec::new(),
+        }),
+        text: String::from(""),
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    send_single_msg(peer_map, peer_addr, msg);
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handle_private_msg(
     peer_map: &PeerMap,
     peer_name_map: &PeerNameMap,
@@ -383,6 +2780,7 @@ fn handle_private_msg(
     peer_name: &String,
     peer_addr: &SocketAddr,
     local_addr: &str,
+    texts: Texts,
     msg: Message,
 ) {
     if !msg.text.trim().is_empty() {
@@ -397,14 +2795,18 @@ fn handle_private_msg(
                     msg.src_name, peer_addr, recv_peer_name, recv_peer_addr, msg.text
                 );
 
-                send_single_msg(&peer_map, recv_peer_addr, msg);
+                send_single_msg(peer_map, recv_peer_addr, msg);
             }
         } else {
             let msg = Message {
+                v: PROTOCOL_VERSION,
                 src_addr: local_addr,
                 src_name: LOCAL_NAME,
                 msg_type: MessageType::Private(peer_name.as_str()),
-                text: format!("{} is not connected.", recv_peer_name),
+                text: texts.get("peer-not-connected", &[&recv_peer_name]),
+                timestamp: now_millis(),
+                msg_id: None,
+                badges: Vec::new(),
             };
 
             println!(
@@ -412,14 +2814,1109 @@ fn handle_private_msg(
                 LOCAL_NAME, local_addr, peer_name, peer_addr, &msg.text
             );
 
-            send_single_msg(&peer_map, &peer_addr, msg);
+            send_single_msg(peer_map, peer_addr, msg);
+        }
+    }
+}
+
+// Shadow bans the session of 'banned_name', or with 'by_ip' its whole IP address.
+#[allow(clippy::too_many_arguments)]
+fn handle_shadow_ban_msg(
+    peer_map: &PeerMap,
+    peer_name_map: &PeerNameMap,
+    shadow_bans: &ShadowBans,
+    events: &EventLog,
+    subscribers: &PeerListSubscribers,
+    banned_name: &str,
+    by_ip: bool,
+    peer_name: &str,
+    peer_addr: &SocketAddr,
+    local_addr: &str,
+    texts: Texts,
+) {
+    let banned = {
+        let peer_name_map = peer_name_map.lock().unwrap();
+        let mut shadow_bans = shadow_bans.lock().unwrap();
+        peer_name_map.get(banned_name).map(|banned_addr| {
+            // Everyone the ban hides that was not hidden before leaves the peer list.
+            let mut hidden: Vec<String> = peer_name_map
+                .iter()
+                .filter(|&(name, addr)| {
+                    (name == banned_name || by_ip && addr.ip() == banned_addr.ip())
+                        && !shadow_bans.covers(name, addr)
+                })
+                .map(|(name, _)| name.clone())
+                .collect();
+            hidden.sort();
+            if by_ip {
+                shadow_bans.ips.insert(banned_addr.ip());
+            } else {
+                shadow_bans.names.insert(banned_name.to_string());
+            }
+            (*banned_addr, hidden)
+        })
+    };
+
+    let text = match banned {
+        Some((banned_addr, hidden)) => {
+            let event = if by_ip {
+                Event::ShadowBanIp {
+                    ip: banned_addr.ip(),
+                }
+            } else {
+                Event::ShadowBan {
+                    name: banned_name.to_string(),
+                }
+            };
+            events.record(now_millis(), event);
+            if !hidden.is_empty() {
+                broadcast_peer_list_delta(
+                    peer_map,
+                    subscribers,
+                    local_addr,
+                    &banned_addr,
+                    PeerListDelta {
+                        joined: Vec::new(),
+                        left: hidden,
+                        renamed: Vec::new(),
+                    },
+                );
+            }
+            println!(
+                "\n[ShadowBan] {} ({}) shadow banned {} ({}){}",
+                peer_name,
+                peer_addr,
+                banned_name,
+                banned_addr,
+                if by_ip { " by IP" } else { "" }
+            );
+            if by_ip {
+                texts.get("shadow-banned-ip", &[&banned_name, &banned_addr.ip()])
+            } else {
+                texts.get("shadow-banned", &[&banned_name])
+            }
+        }
+        None => texts.get("peer-not-connected", &[&banned_name]),
+    };
+
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::Private(peer_name),
+        text,
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    send_single_msg(peer_map, peer_addr, msg);
+}
+
+// Returns false if there is no room 'room'.
+fn set_slow_mode(spam_filters: &SpamState, room: &str, interval: Duration) -> bool {
+    if room != ROOM_NAME {
+        return false;
+    }
+
+    spam_filters.lock().unwrap().slow_mode = interval;
+    println!("\n[SlowMode] {} is now {}s.", room, interval.as_secs());
+    true
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_set_slow_mode_msg(
+    peer_map: &PeerMap,
+    spam_filters: &SpamState,
+    room: &str,
+    interval: u64,
+    peer_name: &str,
+    peer_addr: &SocketAddr,
+    local_addr: &str,
+    texts: Texts,
+) {
+    let text = if !set_slow_mode(spam_filters, room, Duration::from_secs(interval)) {
+        texts.get("room-unknown", &[&room])
+    } else if interval == 0 {
+        texts.get("slow-mode-off", &[&room])
+    } else {
+        texts.get("slow-mode-set", &[&room, &interval])
+    };
+
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::Private(peer_name),
+        text,
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    send_single_msg(peer_map, peer_addr, msg);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_report_msg(
+    peer_map: &PeerMap,
+    peer_name_map: &PeerNameMap,
+    peer_locales: &PeerLocales,
+    admins: &Admins,
+    history: &HistoryBuffer,
+    reports: &ReportQueue,
+    events: &Events,
+    catalog: &Catalog,
+    local_addr: &str,
+    texts: Texts,
+    peer_name: &str,
+    peer_addr: &SocketAddr,
+    msg_id: u64,
+    reason: &str,
+) {
+    // What the message said is kept with the report, the history may drop it any time.
+    let reported = {
+        let history = history.lock().unwrap();
+        match history
+            .context(msg_id, 0, 0)
+            .and_then(|mut entries| entries.pop())
+        {
+            Some(entry) => Ok(entry),
+            None if history.has_assigned(msg_id) => Err("message-gone"),
+            None => Err("message-unknown"),
+        }
+    };
+    let text = match reported {
+        Ok(entry) => {
+            let report = reports.lock().unwrap().file(
+                msg_id,
+                peer_name,
+                &entry.src_name,
+                &entry.text,
+                reason,
+                now_millis(),
+            );
+            println!(
+                "\n[Report] {} ({}) reported message {} by {}: {}",
+                peer_name, peer_addr, msg_id, entry.src_name, reason
+            );
+            events.record(report.at, Event::Reported(report.clone()));
+            notify_admins(
+                peer_map,
+                peer_name_map,
+                peer_locales,
+                admins,
+                catalog,
+                local_addr,
+                "report-notice",
+                &[
+                    &report.id,
+                    &peer_name,
+                    &msg_id,
+                    &entry.src_name,
+                    &entry.text,
+                    &reason,
+                ],
+            );
+            texts.get("report-filed", &[&report.id])
+        }
+        Err(key) => texts.get(key, &[&msg_id]),
+    };
+
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::Private(peer_name),
+        text,
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    send_single_msg(peer_map, peer_addr, msg);
+}
+
+// Sends every admin online a Private from the server, in its own locale.
+#[allow(clippy::too_many_arguments)]
+fn notify_admins(
+    peer_map: &PeerMap,
+    peer_name_map: &PeerNameMap,
+    peer_locales: &PeerLocales,
+    admins: &Admins,
+    catalog: &Catalog,
+    local_addr: &str,
+    key: &str,
+    args: &[&dyn std::fmt::Display],
+) {
+    let admin_names: Vec<(String, SocketAddr)> = {
+        let peer_name_map = peer_name_map.lock().unwrap();
+        let admins = admins.lock().unwrap();
+        peer_name_map
+            .iter()
+            .filter(|(_, addr)| admins.contains(addr))
+            .map(|(name, addr)| (name.clone(), *addr))
+            .collect()
+    };
+    let locales = peer_locales.lock().unwrap().clone();
+
+    for (admin_name, admin_addr) in &admin_names {
+        let locale = locales
+            .get(admin_addr)
+            .map(String::as_str)
+            .unwrap_or_default();
+        let msg = Message {
+            v: PROTOCOL_VERSION,
+            src_addr: local_addr,
+            src_name: LOCAL_NAME,
+            msg_type: MessageType::Private(admin_name),
+            text: catalog.text(locale, key, args),
+            timestamp: now_millis(),
+            msg_id: None,
+            badges: Vec::new(),
+        };
+        send_single_msg(peer_map, admin_addr, msg);
+    }
+}
+
+fn handle_census_request_msg(
+    peer_map: &PeerMap,
+    census: Census,
+    peer_addr: &SocketAddr,
+    local_addr: &str,
+) {
+    let leaks = census.leaks().into_iter().map(String::from).collect();
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::CensusReply { census, leaks },
+        text: String::from(""),
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    send_single_msg(peer_map, peer_addr, msg);
+}
+
+fn handle_invite_msg(
+    peer_map: &PeerMap,
+    invites: &InviteMap,
+    peer_name: &str,
+    peer_addr: &SocketAddr,
+    local_addr: &str,
+    texts: Texts,
+    msg: Message,
+) {
+    let mut invites = invites.lock().unwrap();
+
+    let (msg_type, text) = match msg.msg_type {
+        MessageType::CreateInvite {
+            uses,
+            expires_in_secs,
+        } => match invites.create(uses, expires_in_secs.map(Duration::from_secs)) {
+            Some(invite) => {
+                println!(
+                    "\n[Invite] {} ({}) created invite {:?}",
+                    peer_name, peer_addr, invite
+                );
+                (MessageType::InviteList(vec![invite]), String::from(""))
+            }
+            None => (
+                MessageType::Private(peer_name),
+                texts.get("invite-expiry-too-far", &[]),
+            ),
+        },
+        MessageType::ListInvites => (MessageType::InviteList(invites.list()), String::from("")),
+        MessageType::RevokeInvite(token) => {
+            let text = if invites.revoke(token) {
+                println!(
+                    "\n[Invite] {} ({}) revoked invite {}",
+                    peer_name, peer_addr, token
+                );
+                texts.get("invite-revoked", &[&token])
+            } else {
+                texts.get("invite-unknown", &[&token])
+            };
+            (MessageType::Private(peer_name), text)
+        }
+        _ => return,
+    };
+
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type,
+        text,
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    send_single_msg(peer_map, peer_addr, msg);
+}
+
+fn is_command_invocation(commands: &CommandMap, text: &str) -> bool {
+    commands::parse_invocation(text)
+        .is_some_and(|(name, _)| commands.lock().unwrap().owner(name).is_some())
+}
+
+fn handle_command_invocation_msg(
+    peer_map: &PeerMap,
+    commands: &CommandMap,
+    local_addr: &str,
+    peer_name: &str,
+    peer_addr: &SocketAddr,
+    msg: Message,
+) {
+    let (name, args) = match commands::parse_invocation(&msg.text) {
+        Some(invocation) => invocation,
+        None => return,
+    };
+    let bot_addr = match commands.lock().unwrap().owner(name) {
+        Some(bot_addr) => bot_addr,
+        None => return,
+    };
+
+    println!(
+        "\n[Command] {} ({}) -> {}: /{} {}",
+        peer_name, peer_addr, bot_addr, name, args
+    );
+
+    let invocation = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: peer_name,
+        msg_type: MessageType::CommandInvocation {
+            name: name.to_string(),
+            args: args.to_string(),
+        },
+        text: msg.text.clone(),
+        timestamp: msg.timestamp,
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    send_single_msg(peer_map, &bot_addr, invocation);
+}
+
+fn is_content_command(content: &ContentConfig, text: &str) -> bool {
+    commands::parse_invocation(text).is_some_and(|(name, _)| content.handles(name))
+}
+
+// Posts what the operator's script found for the query as the peer's own Text, which
+// the peer is sent as well, since it did not write it.
+#[allow(clippy::too_many_arguments)]
+async fn handle_content_command_msg(
+    peer_map: &PeerMap,
+    content: &ContentConfig,
+    history: &HistoryBuffer,
+    events: &EventLog,
+    activity: &ActivityLog,
+    local_addr: &str,
+    texts: Texts<'_>,
+    peer_addr: &SocketAddr,
+    mut msg: Message<'_>,
+) {
+    let (name, query) = match commands::parse_invocation(&msg.text) {
+        Some((name, query)) => (name.to_string(), query.to_string()),
+        None => return,
+    };
+
+    println!(
+        "\n[Content] {} ({}): /{} {}",
+        msg.src_name, peer_addr, name, query
+    );
+    match content.look_up(&name, &query).await {
+        Ok(result) => {
+            msg.text = format!("/{} {}: {}", name, query, result);
+            let id = add_to_history(history, events, msg.src_name, &msg.text, msg.timestamp);
+            activity.lock().unwrap().record(msg.src_name, &msg.text);
+            msg.msg_id = Some(id);
+            broadcast_msg(peer_map, peer_addr, msg.clone());
+            send_single_msg(peer_map, peer_addr, msg);
+        }
+        Err(e) => {
+            println!("\n[Content] /{} failed for {}: {}", name, peer_addr, e);
+            let notice = Message {
+                v: PROTOCOL_VERSION,
+                src_addr: local_addr,
+                src_name: LOCAL_NAME,
+                msg_type: MessageType::Text,
+                text: texts.get("content-failed", &[&name]),
+                timestamp: now_millis(),
+                msg_id: None,
+                badges: Vec::new(),
+            };
+            send_single_msg(peer_map, peer_addr, notice);
+        }
+    }
+}
+
+fn handle_register_command_msg(
+    peer_map: &PeerMap,
+    commands: &CommandMap,
+    local_addr: &str,
+    texts: Texts,
+    peer_name: &str,
+    peer_addr: &SocketAddr,
+    msg: Message,
+) {
+    let (name, description) = match &msg.msg_type {
+        MessageType::RegisterCommand { name, description } => {
+            (name.trim_start_matches('/'), description.as_str())
+        }
+        _ => return,
+    };
+
+    let refusal = if !commands::is_valid_name(name) {
+        Some(texts.get("command-invalid", &[&name]))
+    } else {
+        let registered = commands
+            .lock()
+            .unwrap()
+            .register(name, description, peer_name, peer_addr);
+        registered
+            .err()
+            .map(|bot| texts.get("command-taken", &[&name, &bot]))
+    };
+
+    match refusal {
+        Some(text) => {
+            let msg = Message {
+                v: PROTOCOL_VERSION,
+                src_addr: local_addr,
+                src_name: LOCAL_NAME,
+                msg_type: MessageType::Private(peer_name),
+                text,
+                timestamp: now_millis(),
+                msg_id: None,
+                badges: Vec::new(),
+            };
+
+            send_single_msg(peer_map, peer_addr, msg);
+        }
+        None => {
+            println!(
+                "\n[Command] {} ({}) registered /{}: {}",
+                peer_name, peer_addr, name, description
+            );
+            broadcast_commands_list_msg(peer_map, commands, local_addr);
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
+fn handle_help_request_msg(
+    peer_map: &PeerMap,
+    commands: &CommandMap,
+    config: &Config,
+    is_admin: bool,
+    local_addr: &str,
+    texts: Texts,
+    peer_addr: &SocketAddr,
+    msg: Message,
+) {
+    let topic = match &msg.msg_type {
+        MessageType::HelpRequest { topic } => topic
+            .as_deref()
+            .map(|topic| topic.trim().trim_start_matches('/').to_lowercase()),
+        _ => return,
+    };
+
+    let mut commands = commands.lock().unwrap().list();
+    // Bot commands come first when peers invoke them, so content commands are only added
+    // under names no bot has taken.
+    let mut content: Vec<CommandInfo> = config
+        .content
+        .commands
+        .keys()
+        .filter(|name| !commands.iter().any(|command| &command.name == *name))
+        .map(|name| CommandInfo {
+            name: name.clone(),
+            description: String::from("Looks up what follows it and posts what is found"),
+            bot: LOCAL_NAME.to_string(),
+        })
+        .collect();
+    content.sort_by(|a, b| a.name.cmp(&b.name));
+    commands.extend(content);
+    let mut entries = help::entries(is_admin, config.invite_only, &commands, &config.help_topics);
+    if let Some(topic) = &topic {
+        entries.retain(|entry| &entry.topic == topic);
+    }
+
+    let text = match &topic {
+        Some(topic) if entries.is_empty() => texts.get("help-unknown", &[topic]),
+        Some(topic) => texts.get("help-topic", &[topic]),
+        None => texts.get("help-all", &[]),
+    };
+
+    let msg = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::HelpReply(entries),
+        text,
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+
+    send_single_msg(peer_map, peer_addr, msg);
+}
+
+// Tells the peer that its message of type 'msg_type' was not handled, and why.
+fn refuse_illegal_msg(
+    peer_map: &PeerMap,
+    local_addr: &str,
+    texts: Texts,
+    peer_name: &str,
+    peer_addr: &SocketAddr,
+    msg_type: &str,
+    illegal: IllegalMessage,
+) {
+    println!(
+        "\n[Chat] Refused {} from {} ({}): {}",
+        msg_type, peer_name, peer_addr, illegal
+    );
+    let notice = Message {
+        v: PROTOCOL_VERSION,
+        src_addr: local_addr,
+        src_name: LOCAL_NAME,
+        msg_type: MessageType::Private(peer_name),
+        text: texts.get(illegal.key(), &[&msg_type]),
+        timestamp: now_millis(),
+        msg_id: None,
+        badges: Vec::new(),
+    };
+    send_single_msg(peer_map, peer_addr, notice);
+}
+
 fn handle_unknown_msg(peer_addr: &SocketAddr, msg: Message) {
     println!(
         "\n[Chat: UNKNOWN MESSAGE] {} ({}): {}",
         msg.src_name, peer_addr, msg.text
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc::UnboundedReceiver;
+    use rand::{rngs::StdRng, SeedableRng};
+    use serde_json::Value;
+
+    use super::*;
+
+    const LOCAL_ADDR: &str = "127.0.0.1:8080";
+    const NAMES: [&str; 6] = ["Alice", "Bob", "Carol", "Dave", "Eve", "Frank"];
+    const ROUTING_RUNS: usize = 50;
+    const ROUTING_STEPS: usize = 200;
+
+    // A connected peer in the routing test.
+    struct TestPeer {
+        name: String,
+        token: String,
+        addr: SocketAddr,
+        receiver: UnboundedReceiver<TungMessage>,
+    }
+
+    // A peer's end of its channel, standing in for its socket.
+    fn connect(peer_map: &PeerMap, peer_addr: SocketAddr) -> UnboundedReceiver<TungMessage> {
+        let (sender, receiver) = unbounded();
+        peer_map.lock().unwrap().insert(peer_addr, sender);
+        receiver
+    }
+
+    // What has been sent to a peer so far.
+    fn received(receiver: &mut UnboundedReceiver<TungMessage>) -> Vec<Value> {
+        let mut received = Vec::new();
+        while let Ok(TungMessage::Text(frame)) = receiver.try_recv() {
+            received.push(serde_json::from_str(&frame).unwrap());
+        }
+        received
+    }
+
+    fn text_msg<'a>(src_name: &'a str, text: &str, msg_id: u64) -> Message<'a> {
+        Message {
+            v: PROTOCOL_VERSION,
+            src_addr: "",
+            src_name,
+            msg_type: MessageType::Text,
+            text: text.to_string(),
+            timestamp: now_millis(),
+            msg_id: Some(msg_id),
+            badges: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn a_resend_after_a_takeover_is_acked_but_not_broadcast() {
+        let peer_map = PeerMap::default();
+        let peer_name_map = PeerNameMap::default();
+        let peer_token_map = PeerTokenMap::default();
+        let session_msg_ids = SessionMsgIds::default();
+        let history = HistoryBuffer::new(Mutex::new(History::new(10, 0)));
+        let (events, _) = EventLog::open(None).unwrap();
+        let activity = ActivityLog::new(Mutex::new(Activity::new()));
+
+        let first_addr: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let successor_addr: SocketAddr = "10.0.0.1:1001".parse().unwrap();
+        let other_addr: SocketAddr = "10.0.0.2:1000".parse().unwrap();
+        let mut first = connect(&peer_map, first_addr);
+        let mut other = connect(&peer_map, other_addr);
+        peer_name_map
+            .lock()
+            .unwrap()
+            .insert(String::from("Alice"), first_addr);
+        peer_token_map
+            .lock()
+            .unwrap()
+            .insert(String::from("token"), String::from("Alice"));
+
+        // The first connection's message is acked and broadcast, but the connection is
+        // lost before the Ack gets through.
+        let seen_msg_ids = session_seen_msg_ids(&session_msg_ids, "token", 8);
+        assert!(first_seen_msg_id(&seen_msg_ids, "Alice", &first_addr, 7));
+        send_ack_msg(&peer_map, &first_addr, LOCAL_ADDR, 7);
+        let msg = text_msg("Alice", "Hello", 7);
+        handle_text_msg(&peer_map, &history, &events, &activity, &first_addr, msg);
+        assert_eq!(received(&mut other).len(), 1);
+        assert_eq!(received(&mut first)[0]["type"], "Ack");
+
+        let (sender, mut successor) = unbounded();
+        let taken_over = take_over_peer(
+            &peer_map,
+            &peer_name_map,
+            &peer_token_map,
+            "token",
+            &successor_addr,
+            &sender,
+        );
+        assert_eq!(taken_over.as_deref(), Some("Alice"));
+        let successor_msg_ids = session_seen_msg_ids(&session_msg_ids, "token", 8);
+        assert!(Arc::ptr_eq(&seen_msg_ids, &successor_msg_ids));
+
+        // The successor resends the message it never saw acked, which is only acked again.
+        assert!(!first_seen_msg_id(
+            &successor_msg_ids,
+            "Alice",
+            &successor_addr,
+            7
+        ));
+        send_ack_msg(&peer_map, &successor_addr, LOCAL_ADDR, 7);
+        let acks = received(&mut successor);
+        assert_eq!(acks.len(), 1);
+        assert_eq!(acks[0]["type"], "Ack");
+        assert_eq!(acks[0]["payload"], 7);
+        assert!(received(&mut other).is_empty());
+        assert_eq!(history.lock().unwrap().latest(10).len(), 1);
+    }
+
+    #[test]
+    fn a_message_to_a_peer_that_is_gone_is_dropped() {
+        let peer_map = PeerMap::default();
+        let gone_addr: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        send_ack_msg(&peer_map, &gone_addr, LOCAL_ADDR, 1);
+        assert!(peer_map.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_broadcast_skips_a_peer_that_is_leaving() {
+        let peer_map = PeerMap::default();
+        let src_addr: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let leaving_addr: SocketAddr = "10.0.0.2:1000".parse().unwrap();
+        let other_addr: SocketAddr = "10.0.0.3:1000".parse().unwrap();
+        drop(connect(&peer_map, leaving_addr));
+        let mut other = connect(&peer_map, other_addr);
+
+        broadcast_msg(&peer_map, &src_addr, text_msg("Alice", "Hello", 1));
+        assert_eq!(received(&mut other).len(), 1);
+    }
+
+    #[test]
+    fn a_report_is_queued_and_only_the_admins_online_are_told() {
+        let peer_map = PeerMap::default();
+        let peer_name_map = PeerNameMap::default();
+        let peer_locales = PeerLocales::default();
+        let admins = Admins::default();
+        let history = HistoryBuffer::new(Mutex::new(History::new(10, 0)));
+        let reports = ReportQueue::new(Mutex::new(Reports::new()));
+        let (events, _) = EventLog::open(None).unwrap();
+        let events = Events::new(events);
+        let catalog = Catalog::load("");
+        let texts = Texts {
+            catalog: &catalog,
+            locale: "",
+        };
+
+        let reporter_addr: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let admin_addr: SocketAddr = "10.0.0.2:1000".parse().unwrap();
+        let other_addr: SocketAddr = "10.0.0.3:1000".parse().unwrap();
+        let mut reporter = connect(&peer_map, reporter_addr);
+        let mut admin = connect(&peer_map, admin_addr);
+        let mut other = connect(&peer_map, other_addr);
+        for (name, addr) in [
+            ("Alice", reporter_addr),
+            ("Bob", admin_addr),
+            ("Carol", other_addr),
+        ] {
+            peer_name_map
+                .lock()
+                .unwrap()
+                .insert(String::from(name), addr);
+        }
+        admins.lock().unwrap().insert(admin_addr);
+        let msg_id = history.lock().unwrap().push("Carol", "Rude", 1);
+
+        let report = |msg_id| {
+            handle_report_msg(
+                &peer_map,
+                &peer_name_map,
+                &peer_locales,
+                &admins,
+                &history,
+                &reports,
+                &events,
+                &catalog,
+                LOCAL_ADDR,
+                texts,
+                "Alice",
+                &reporter_addr,
+                msg_id,
+                "Insults",
+            )
+        };
+        report(msg_id);
+        report(msg_id + 1);
+
+        let open = reports.lock().unwrap().open();
+        assert_eq!(open.len(), 1);
+        assert_eq!(
+            (open[0].author.as_str(), open[0].text.as_str()),
+            ("Carol", "Rude")
+        );
+        let replies = received(&mut reporter);
+        assert_eq!(replies[0]["text"], texts.get("report-filed", &[&1]));
+        assert_eq!(
+            replies[1]["text"],
+            texts.get("message-unknown", &[&(msg_id + 1)])
+        );
+        let notices = received(&mut admin);
+        assert_eq!(notices.len(), 1);
+        assert_eq!(notices[0]["type"], "Private");
+        assert_eq!(notices[0]["payload"], "Bob");
+        assert!(received(&mut other).is_empty());
+    }
+
+    #[test]
+    fn a_shadow_ban_hides_the_session_and_only_an_ip_ban_the_address() {
+        let peer_map = PeerMap::default();
+        let peer_name_map = PeerNameMap::default();
+        let shadow_bans = ShadowBans::default();
+        let subscribers = PeerListSubscribers::default();
+        let (events, _) = EventLog::open(None).unwrap();
+        let catalog = Catalog::load("");
+        let texts = Texts {
+            catalog: &catalog,
+            locale: "",
+        };
+
+        // Alice and Bob share an address.
+        let alice_addr: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let bob_addr: SocketAddr = "10.0.0.1:1001".parse().unwrap();
+        let admin_addr: SocketAddr = "10.0.0.2:1000".parse().unwrap();
+        for (name, addr) in [
+            ("Alice", alice_addr),
+            ("Bob", bob_addr),
+            ("Carol", admin_addr),
+        ] {
+            peer_name_map.lock().unwrap().insert(name.to_string(), addr);
+        }
+        let _alice = connect(&peer_map, alice_addr);
+        let _bob = connect(&peer_map, bob_addr);
+        let mut admin = connect(&peer_map, admin_addr);
+        subscribers.lock().unwrap().insert(admin_addr);
+
+        let shadow_ban = |banned_name, by_ip| {
+            handle_shadow_ban_msg(
+                &peer_map,
+                &peer_name_map,
+                &shadow_bans,
+                &events,
+                &subscribers,
+                banned_name,
+                by_ip,
+                "Carol",
+                &admin_addr,
+                LOCAL_ADDR,
+                texts,
+            )
+        };
+        let visible = || {
+            let mut visible: Vec<String> =
+                visible_peer_names(&peer_name_map, &shadow_bans, "Carol")
+                    .into_iter()
+                    .collect();
+            visible.sort();
+            visible
+        };
+
+        shadow_ban("Alice", false);
+        assert_eq!(visible(), vec!["Bob"]);
+        assert!(!is_shadow_banned(&shadow_bans, "Bob", &bob_addr));
+        let replies = received(&mut admin);
+        assert_eq!(replies[0]["payload"]["left"], serde_json::json!(["Alice"]));
+        assert_eq!(
+            replies[1]["text"],
+            "Alice is now shadow banned until the session ends."
+        );
+
+        // Alice was hidden already, so only Bob leaves the peer list.
+        shadow_ban("Bob", true);
+        assert!(visible().is_empty());
+        let replies = received(&mut admin);
+        assert_eq!(replies[0]["payload"]["left"], serde_json::json!(["Bob"]));
+
+        // A new connection from the address is banned along, one elsewhere is not.
+        let dave_addr: SocketAddr = "10.0.0.1:1002".parse().unwrap();
+        assert!(is_shadow_banned(&shadow_bans, "Dave", &dave_addr));
+        assert!(!is_shadow_banned(&shadow_bans, "Dave", &admin_addr));
+    }
+
+    #[test]
+    fn routing_holds_up_under_random_churn() {
+        for _ in 0..ROUTING_RUNS {
+            route_randomly(rand::thread_rng().gen());
+        }
+    }
+
+    // Joins, leaves, takeovers, Texts and PMs in a random order, checking after each step
+    // that every message reached exactly who it was meant for. A failing run can be
+    // repeated with the seed in its panic message.
+    fn route_randomly(seed: u64) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let peer_map = PeerMap::default();
+        let peer_name_map = PeerNameMap::default();
+        let peer_token_map = PeerTokenMap::default();
+        let history = HistoryBuffer::new(Mutex::new(History::new(10, 0)));
+        let (events, _) = EventLog::open(None).unwrap();
+        let activity = ActivityLog::new(Mutex::new(Activity::new()));
+        let catalog = Catalog::load("");
+        let texts = Texts {
+            catalog: &catalog,
+            locale: "",
+        };
+
+        let mut peers: Vec<TestPeer> = Vec::new();
+        let mut gone: Vec<UnboundedReceiver<TungMessage>> = Vec::new(); // Left or taken over.
+        let mut next_port = 1000;
+        let mut last_id = None;
+
+        for step in 0..ROUTING_STEPS {
+            let mut next_addr = || {
+                next_port += 1;
+                SocketAddr::from(([10, 0, 0, 1], next_port))
+            };
+
+            match rng.gen_range(0, 5) {
+                // Joins under a name nobody has.
+                0 => {
+                    let names = NAMES.iter().map(|name| name.to_string()).collect();
+                    let available = available_peer_names(&peer_name_map, &names);
+                    let name = match available.choose(&mut rng) {
+                        Some(name) => name.clone(),
+                        None => continue,
+                    };
+                    let addr = next_addr();
+                    let token = format!("token-{}", step);
+                    peer_name_map.lock().unwrap().insert(name.clone(), addr);
+                    peer_token_map
+                        .lock()
+                        .unwrap()
+                        .insert(token.clone(), name.clone());
+                    let receiver = connect(&peer_map, addr);
+                    peers.push(TestPeer {
+                        name,
+                        token,
+                        addr,
+                        receiver,
+                    });
+                }
+                // Leaves, cleaning up like on_peer_connect does.
+                1 if !peers.is_empty() => {
+                    let peer = peers.remove(rng.gen_range(0, peers.len()));
+                    peer_map.lock().unwrap().remove(&peer.addr);
+                    let name = discon_peer_name(&peer_name_map, &peer.addr);
+                    assert_eq!(name.as_deref(), Some(peer.name.as_str()), "seed {}", seed);
+                    peer_name_map.lock().unwrap().remove(&peer.name);
+                    peer_token_map
+                        .lock()
+                        .unwrap()
+                        .retain(|_, name| name != &peer.name);
+                    gone.push(peer.receiver);
+                }
+                // Is taken over by a new connection presenting its token.
+                2 if !peers.is_empty() => {
+                    let taken_over = rng.gen_range(0, peers.len());
+                    let peer = &mut peers[taken_over];
+                    let addr = next_addr();
+                    let (sender, receiver) = unbounded();
+                    let name = take_over_peer(
+                        &peer_map,
+                        &peer_name_map,
+                        &peer_token_map,
+                        &peer.token,
+                        &addr,
+                        &sender,
+                    );
+                    assert_eq!(name.as_deref(), Some(peer.name.as_str()), "seed {}", seed);
+                    assert!(
+                        peer_map.lock().unwrap().contains_key(&addr),
+                        "seed {}",
+                        seed
+                    );
+                    let mut stale = std::mem::replace(&mut peer.receiver, receiver);
+                    peer.addr = addr;
+                    assert!(
+                        matches!(stale.try_recv(), Ok(TungMessage::Close(_))),
+                        "seed {}: the stale connection was not closed",
+                        seed
+                    );
+                    gone.push(stale);
+                }
+                // Sends a Text, which everyone else receives with the next id.
+                3 if !peers.is_empty() => {
+                    let sender = rng.gen_range(0, peers.len());
+                    let text = format!("Text {}", step);
+                    let msg = text_msg(&peers[sender].name, &text, step as u64);
+                    let sender_addr = peers[sender].addr;
+                    handle_text_msg(&peer_map, &history, &events, &activity, &sender_addr, msg);
+
+                    let mut ids = Vec::new();
+                    for (i, peer) in peers.iter_mut().enumerate() {
+                        let received = received(&mut peer.receiver);
+                        if i == sender {
+                            assert!(received.is_empty(), "seed {}: echoed a Text", seed);
+                            continue;
+                        }
+                        assert_eq!(received.len(), 1, "seed {}: {:?}", seed, received);
+                        assert_eq!(received[0]["text"], text.as_str(), "seed {}", seed);
+                        ids.push(received[0]["msg_id"].as_u64().unwrap());
+                    }
+                    // Everyone got the same id, the one the history handed out.
+                    ids.dedup();
+                    assert!(ids.len() <= 1, "seed {}: ids {:?}", seed, ids);
+                    let id = history.lock().unwrap().latest(1)[0].id;
+                    assert!(ids.iter().all(|received_id| *received_id == id));
+                    assert!(
+                        last_id < Some(id),
+                        "seed {}: {} after {:?}",
+                        seed,
+                        id,
+                        last_id
+                    );
+                    last_id = Some(id);
+                }
+                // Sends a PM to any name, which only that peer receives if it is there.
+                4 if !peers.is_empty() => {
+                    let sender = rng.gen_range(0, peers.len());
+                    let target = NAMES.choose(&mut rng).unwrap();
+                    let text = format!("PM {}", step);
+                    let sender_name = peers[sender].name.clone();
+                    let sender_addr = peers[sender].addr;
+                    let msg = Message {
+                        msg_type: MessageType::Private(target),
+                        ..text_msg(&sender_name, &text, step as u64)
+                    };
+                    handle_private_msg(
+                        &peer_map,
+                        &peer_name_map,
+                        target,
+                        &sender_name,
+                        &sender_addr,
+                        LOCAL_ADDR,
+                        texts,
+                        msg,
+                    );
+
+                    let target_is_here = peers.iter().any(|peer| peer.name == *target);
+                    for (i, peer) in peers.iter_mut().enumerate() {
+                        let received = received(&mut peer.receiver);
+                        if peer.name == *target && i != sender {
+                            assert_eq!(received.len(), 1, "seed {}: {:?}", seed, received);
+                            assert_eq!(received[0]["src_name"], sender_name.as_str());
+                            assert_eq!(received[0]["text"], text.as_str(), "seed {}", seed);
+                        } else if i == sender && !target_is_here {
+                            // Told that the target is not connected.
+                            assert_eq!(received.len(), 1, "seed {}: {:?}", seed, received);
+                            assert_eq!(received[0]["src_name"], LOCAL_NAME, "seed {}", seed);
+                        } else {
+                            assert!(received.is_empty(), "seed {}: {:?}", seed, received);
+                        }
+                    }
+                }
+                _ => continue,
+            }
+
+            // Nothing reaches a connection once it is gone.
+            for stale in gone.iter_mut() {
+                assert!(
+                    stale.try_recv().is_err(),
+                    "seed {}: delivered after leaving",
+                    seed
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn a_history_page_is_clamped_and_its_cursor_stops_at_either_end() {
+        let peer_map = PeerMap::default();
+        let history = HistoryBuffer::new(Mutex::new(History::new(200, 0)));
+        for i in 0..150 {
+            history.lock().unwrap().push("Alice", "hi", i);
+        }
+        let catalog = Catalog::load("");
+        let texts = Texts {
+            catalog: &catalog,
+            locale: "",
+        };
+        let peer_addr: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let mut peer = connect(&peer_map, peer_addr);
+
+        let mut page = |cursor: Option<u64>, direction, limit| {
+            let cursor = cursor.map(history::cursor);
+            handle_history_request_msg(
+                &peer_map, &history, LOCAL_ADDR, texts, &peer_addr, None, cursor, direction, limit,
+            );
+            let mut reply = received(&mut peer);
+            assert_eq!(reply.len(), 1);
+            assert_eq!(reply[0]["type"], "HistoryReply");
+            reply.remove(0)["payload"].take()
+        };
+
+        // No limit and one over the maximum are both a page of HISTORY_PAGE_MAX.
+        for limit in [0, HISTORY_PAGE_MAX + 1] {
+            let reply = page(None, Direction::Older, limit);
+            let messages = reply["messages"].as_array().unwrap();
+            assert_eq!(messages.len(), HISTORY_PAGE_MAX);
+            assert_eq!(messages[0]["id"], 50);
+            assert_eq!(reply["next_cursor"], history::cursor(50));
+        }
+
+        // Past the oldest entry there is nothing more to page to.
+        let reply = page(Some(50), Direction::Older, 0);
+        assert_eq!(reply["messages"].as_array().unwrap().len(), 50);
+        assert_eq!(reply["next_cursor"], Value::Null);
+
+        // The newest entry is where new messages are polled for from.
+        let reply = page(Some(140), Direction::Newer, 0);
+        assert_eq!(reply["messages"].as_array().unwrap().len(), 9);
+        assert_eq!(reply["next_cursor"], history::cursor(149));
+        let reply = page(Some(149), Direction::Newer, 0);
+        assert!(reply["messages"].as_array().unwrap().is_empty());
+        assert_eq!(reply["next_cursor"], history::cursor(149));
+    }
+}
@@ -0,0 +1,316 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use crate::config::env_or;
+
+const MIN_CAPS_LEN: usize = 8;
+const CAPS_RATIO: f64 = 0.7;
+
+// Every setting can be overridden through the environment (e.g. SPAM_MUTE_AT=30).
+pub struct SpamConfig {
+    pub message_score: f64, // Added for every message, so plain flooding adds up too.
+    pub repeat_score: f64,  // Added when a message repeats the previous one.
+    pub caps_score: f64,    // Added when a message is mostly upper case.
+    pub link_score: f64,    // Added when a message has more than 'max_links' links.
+    pub max_links: usize,   // How many links a message may carry before it is scored.
+    pub decay_per_sec: f64, // How much of the score wears off every second.
+    pub warn_at: f64,       // Score at which the peer is warned.
+    pub slow_mode_at: f64,  // Score at which the peer may only post every 'slow_mode_interval'.
+    pub mute_at: f64,       // Score at which the peer is muted for 'mute_duration'.
+    pub slow_mode_interval: Duration,
+    pub mute_duration: Duration,
+    pub slow_mode: Duration, // The slow mode the server starts with, see SpamFilters::slow_mode.
+}
+
+impl SpamConfig {
+    pub fn from_env() -> Self {
+        Self {
+            message_score: env_or("SPAM_MESSAGE_SCORE", 1.0),
+            repeat_score: env_or("SPAM_REPEAT_SCORE", 3.0),
+            caps_score: env_or("SPAM_CAPS_SCORE", 2.0),
+            link_score: env_or("SPAM_LINK_SCORE", 2.0),
+            max_links: env_or("SPAM_MAX_LINKS", 2),
+            decay_per_sec: env_or("SPAM_DECAY_PER_SEC", 1.0),
+            warn_at: env_or("SPAM_WARN_AT", 8.0),
+            slow_mode_at: env_or("SPAM_SLOW_MODE_AT", 12.0),
+            mute_at: env_or("SPAM_MUTE_AT", 20.0),
+            slow_mode: Duration::from_secs(env_or("SLOW_MODE_SECS", 0)),
+            slow_mode_interval: Duration::from_secs(env_or("SPAM_SLOW_MODE_SECS", 5)),
+            mute_duration: Duration::from_secs(env_or("SPAM_MUTE_SECS", 60)),
+        }
+    }
+}
+
+pub enum SpamVerdict {
+    Deliver,
+    Warn,               // Deliver the message, but tell the peer to slow down.
+    SlowMode(Duration), // Drop the message. The parameter is how long the peer has to wait.
+    Muted(Duration),    // Drop the message. The parameter is how long the mute lasts.
+}
+
+// The spam state of every address that has sent something lately. It is kept by IP
+// rather than per connection, so reconnecting does not wipe a score or lift a mute,
+// and stays around after the last connection from the address is gone until it has
+// cooled down. Peers behind the same address share their state, like they share bans.
+#[derive(Default)]
+pub struct SpamFilters {
+    filters: HashMap<IpAddr, SpamFilter>,
+    pub warnings: usize, // How many Warn verdicts there have been since the start.
+    pub slowed: usize,   // How many messages slow mode has dropped.
+    pub mutes: usize,    // How many messages a mute has dropped, the muting one included.
+    // Interval every peer has to keep between messages. Zero turns it off. Admins change
+    // it at runtime with SetSlowMode or the console's slowmode.
+    pub slow_mode: Duration,
+}
+
+impl SpamFilters {
+    pub fn new(slow_mode: Duration) -> Self {
+        Self {
+            slow_mode,
+            ..Self::default()
+        }
+    }
+
+    // Checks a message from 'ip', returning the verdict and the score it left 'ip' with.
+    pub fn check(&mut self, config: &SpamConfig, ip: IpAddr, text: &str) -> (SpamVerdict, f64) {
+        let filter = self.filters.entry(ip).or_insert_with(SpamFilter::new);
+        let verdict = filter.check(config, self.slow_mode, text);
+        match verdict {
+            SpamVerdict::Deliver => {}
+            SpamVerdict::Warn => self.warnings += 1,
+            SpamVerdict::SlowMode(_) => self.slowed += 1,
+            SpamVerdict::Muted(_) => self.mutes += 1,
+        }
+        (verdict, filter.score())
+    }
+
+    // Forgets the addresses whose state has worn off, so they would start out the same.
+    pub fn prune(&mut self, config: &SpamConfig) {
+        let now = Instant::now();
+        let slow_mode = self.slow_mode;
+        self.filters
+            .retain(|_, filter| !filter.is_cool(config, slow_mode, now));
+    }
+
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+}
+
+// The spam state of a single address.
+pub struct SpamFilter {
+    score: f64,
+    scored_at: Instant,
+    last_text: String,
+    last_delivered_at: Option<Instant>,
+    muted_until: Option<Instant>,
+    warned: bool,
+}
+
+impl SpamFilter {
+    pub fn new() -> Self {
+        Self {
+            score: 0.0,
+            scored_at: Instant::now(),
+            last_text: String::new(),
+            last_delivered_at: None,
+            muted_until: None,
+            warned: false,
+        }
+    }
+
+    pub fn score(&self) -> f64 {
+        self.score
+    }
+
+    // Whether nothing is left that would treat a message differently from a new peer's.
+    // The last text is not, a repeat after that long is hardly flooding.
+    fn is_cool(&self, config: &SpamConfig, slow_mode: Duration, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.scored_at).as_secs_f64();
+        let interval = config.slow_mode_interval.max(slow_mode);
+
+        self.score - elapsed * config.decay_per_sec <= 0.0
+            && self
+                .muted_until
+                .is_none_or(|muted_until| muted_until <= now)
+            && self
+                .last_delivered_at
+                .is_none_or(|last_delivered_at| now.duration_since(last_delivered_at) >= interval)
+    }
+
+    // 'slow_mode' is the interval in force for everyone, see SpamFilters::slow_mode.
+    pub fn check(&mut self, config: &SpamConfig, slow_mode: Duration, text: &str) -> SpamVerdict {
+        let now = Instant::now();
+
+        if let Some(muted_until) = self.muted_until {
+            if now < muted_until {
+                return SpamVerdict::Muted(muted_until - now);
+            }
+            self.muted_until = None;
+        }
+
+        let elapsed = now.duration_since(self.scored_at).as_secs_f64();
+        self.score = (self.score - elapsed * config.decay_per_sec).max(0.0);
+        self.scored_at = now;
+
+        let text = text.trim();
+        let normalized = text.to_lowercase();
+        self.score += config.message_score;
+        if normalized == self.last_text {
+            self.score += config.repeat_score;
+        }
+        if is_shouting(text) {
+            self.score += config.caps_score;
+        }
+        if count_links(&normalized) > config.max_links {
+            self.score += config.link_score;
+        }
+        self.last_text = normalized;
+
+        if self.score >= config.mute_at {
+            self.muted_until = Some(now + config.mute_duration);
+            return SpamVerdict::Muted(config.mute_duration);
+        }
+
+        let interval = if self.score >= config.slow_mode_at {
+            config.slow_mode_interval.max(slow_mode)
+        } else {
+            slow_mode
+        };
+
+        if let Some(last_delivered_at) = self.last_delivered_at {
+            let since_last = now.duration_since(last_delivered_at);
+            if since_last < interval {
+                return SpamVerdict::SlowMode(interval - since_last);
+            }
+        }
+
+        self.last_delivered_at = Some(now);
+
+        if self.score < config.warn_at {
+            self.warned = false;
+        } else if !self.warned {
+            self.warned = true;
+            return SpamVerdict::Warn;
+        }
+
+        SpamVerdict::Deliver
+    }
+}
+
+fn is_shouting(text: &str) -> bool {
+    let letters: Vec<char> = text.chars().filter(|c| c.is_alphabetic()).collect();
+    let upper = letters.iter().filter(|c| c.is_uppercase()).count();
+
+    letters.len() >= MIN_CAPS_LEN && upper as f64 >= letters.len() as f64 * CAPS_RATIO
+}
+
+fn count_links(text: &str) -> usize {
+    text.split_whitespace()
+        .filter(|word| {
+            word.starts_with("http://") || word.starts_with("https://") || word.starts_with("www.")
+        })
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> SpamConfig {
+        SpamConfig {
+            message_score: 1.0,
+            repeat_score: 0.0,
+            caps_score: 0.0,
+            link_score: 0.0,
+            max_links: 2,
+            decay_per_sec: 0.0,
+            warn_at: 100.0,
+            slow_mode_at: 100.0,
+            mute_at: 3.0,
+            slow_mode_interval: Duration::from_secs(5),
+            mute_duration: Duration::from_secs(60),
+            slow_mode: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn a_mute_outlasts_reconnecting() {
+        let config = config();
+        let mut filters = SpamFilters::default();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                filters.check(&config, ip, "hi").0,
+                SpamVerdict::Deliver
+            ));
+        }
+        assert!(matches!(
+            filters.check(&config, ip, "hi").0,
+            SpamVerdict::Muted(_)
+        ));
+
+        // A new connection from the address finds the state where the last one left it.
+        filters.prune(&config);
+        assert!(matches!(
+            filters.check(&config, ip, "hi").0,
+            SpamVerdict::Muted(_)
+        ));
+        assert_eq!(filters.mutes, 2);
+
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        assert!(matches!(
+            filters.check(&config, other, "hi").0,
+            SpamVerdict::Deliver
+        ));
+    }
+
+    #[test]
+    fn only_cooled_down_state_is_pruned() {
+        let config = SpamConfig {
+            decay_per_sec: 1000.0,
+            ..config()
+        };
+        let mut filters = SpamFilters::default();
+        filters.check(&config, "10.0.0.1".parse().unwrap(), "hi");
+        filters.prune(&config);
+        assert_eq!(filters.len(), 1); // Delivered just now, slow mode would still count it.
+
+        let config = SpamConfig {
+            slow_mode_interval: Duration::ZERO,
+            ..config
+        };
+        std::thread::sleep(Duration::from_millis(5));
+        filters.prune(&config);
+        assert_eq!(filters.len(), 0);
+    }
+
+    #[test]
+    fn slow_mode_set_at_runtime_holds_everyone_back() {
+        let config = SpamConfig {
+            mute_at: 100.0,
+            ..config()
+        };
+        let mut filters = SpamFilters::new(Duration::ZERO);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(matches!(
+            filters.check(&config, ip, "hi").0,
+            SpamVerdict::Deliver
+        ));
+
+        filters.slow_mode = Duration::from_secs(30);
+        assert!(matches!(
+            filters.check(&config, ip, "hi").0,
+            SpamVerdict::SlowMode(retry_in) if retry_in > Duration::from_secs(29)
+        ));
+
+        filters.slow_mode = Duration::ZERO;
+        assert!(matches!(
+            filters.check(&config, ip, "hi").0,
+            SpamVerdict::Deliver
+        ));
+    }
+}
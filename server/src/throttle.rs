@@ -0,0 +1,82 @@
+use std::time::{Duration, Instant};
+
+// The longest a turned away connection is asked to wait.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+// Lets at most ACCEPT_RATE connections a second through to the handshake, so that
+// everyone reconnecting at once after a restart does not swamp it. The rest are
+// told when to come back, each a slot later than the one before, so they return
+// spread out at the same rate instead of all in the next second.
+pub struct AcceptThrottle {
+    rate: u32, // Connections per second, 0 for no limit.
+    window_start: Instant,
+    accepted: u32,     // Connections let through since window_start.
+    retry_at: Instant, // The latest retry slot handed out.
+}
+
+impl AcceptThrottle {
+    pub fn new(rate: u32) -> Self {
+        let now = Instant::now();
+
+        Self {
+            rate,
+            window_start: now,
+            accepted: 0,
+            retry_at: now,
+        }
+    }
+
+    // Ok if the connection may go ahead, otherwise how long it should wait.
+    pub fn admit(&mut self) -> Result<(), Duration> {
+        if self.rate == 0 {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.accepted = 0;
+        }
+        if self.accepted < self.rate {
+            self.accepted += 1;
+            return Ok(());
+        }
+
+        let next_window = self.window_start + Duration::from_secs(1);
+        self.retry_at = self.retry_at.max(next_window) + Duration::from_secs(1) / self.rate;
+        Err(self.retry_at.duration_since(now).min(MAX_RETRY_AFTER))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_rate_of_zero_lets_everyone_through() {
+        let mut throttle = AcceptThrottle::new(0);
+        assert!((0..1000).all(|_| throttle.admit().is_ok()));
+    }
+
+    #[test]
+    fn connections_over_the_rate_are_spread_out_from_the_next_second() {
+        let mut throttle = AcceptThrottle::new(4);
+        assert!((0..4).all(|_| throttle.admit().is_ok()));
+
+        // A quarter of a second apart, starting a slot into the next window.
+        let first = throttle.admit().unwrap_err();
+        let second = throttle.admit().unwrap_err();
+        assert!(first > Duration::from_secs(1) && first <= Duration::from_millis(1250));
+        assert!(second > first + Duration::from_millis(200));
+        assert!(second <= first + Duration::from_millis(250));
+    }
+
+    #[test]
+    fn the_wait_is_capped() {
+        let mut throttle = AcceptThrottle::new(1);
+        assert!(throttle.admit().is_ok());
+
+        let last = (0..400).map(|_| throttle.admit().unwrap_err()).last();
+        assert_eq!(last, Some(MAX_RETRY_AFTER));
+    }
+}
@@ -0,0 +1,36 @@
+use std::{env, fs};
+
+use crate::config::env_or;
+
+// What a new peer is greeted with. WELCOME_TEXT, or the contents of WELCOME_FILE, is
+// sent with {name} replaced by the peer's name, e.g. a greeting with links to the rules.
+// With RULES_ACCEPT a peer may not post until it has answered with /accept.
+pub struct WelcomeConfig {
+    pub text: Option<String>,
+    pub private: bool, // Sent to the peer as a PM, otherwise greeted in front of everyone.
+    pub rules_accept: bool,
+}
+
+impl WelcomeConfig {
+    pub fn from_env() -> Self {
+        let text = env::var("WELCOME_TEXT").ok().or_else(|| {
+            env::var("WELCOME_FILE")
+                .ok()
+                .and_then(|path| fs::read_to_string(path).ok())
+        });
+
+        Self {
+            text: text
+                .map(|text| text.trim().to_string())
+                .filter(|text| !text.is_empty()),
+            private: env_or("WELCOME_PRIVATE", true),
+            rules_accept: env_or("RULES_ACCEPT", false),
+        }
+    }
+
+    pub fn greeting(&self, peer_name: &str) -> Option<String> {
+        self.text
+            .as_ref()
+            .map(|text| text.replace("{name}", peer_name))
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    env,
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+// The pause between the rings of a pattern, so that three rings are heard as three.
+pub const RING_GAP: Duration = Duration::from_millis(200);
+
+// Why a message rings the bell. A message counts as the first of these that applies.
+#[derive(Debug, Clone, Copy)]
+pub enum Alert {
+    Private, // A private message to us.
+    Mention, // A message mentioning our name.
+    Keyword, // A message with one of the WATCH keywords in it.
+    Text,    // Any other message.
+}
+
+impl Alert {
+    fn name(self) -> &'static str {
+        match self {
+            Alert::Private => "private",
+            Alert::Mention => "mention",
+            Alert::Keyword => "keyword",
+            Alert::Text => "text",
+        }
+    }
+}
+
+// When the client makes a sound. BELL=1 rings once for every message, BELL_PRIVATE,
+// BELL_MENTION and BELL_KEYWORD set how many times those ring instead, so they can be
+// told apart by ear, and WATCH ("deploy,outage") lists the keywords. With BELL_COMMAND
+// set it is run instead of ringing, with the alert ("private", "mention", "keyword" or
+// "text") as its last argument, e.g. a script playing a sound file for each.
+#[derive(Debug, Clone)]
+pub struct Bell {
+    text: u32,
+    private: u32,
+    mention: u32,
+    keyword: u32,
+    keywords: Vec<String>, // Lowercase.
+    command: Option<String>,
+    muted: Arc<AtomicBool>, // /mute-sounds, the same for every clone.
+}
+
+impl Bell {
+    pub fn from_env() -> Self {
+        let text = match env::var("BELL")
+            .map(|value| value.to_lowercase())
+            .as_deref()
+        {
+            Ok("1") | Ok("true") | Ok("on") | Ok("yes") => 1,
+            _ => 0,
+        };
+
+        Self {
+            text,
+            private: env_rings("BELL_PRIVATE", text),
+            mention: env_rings("BELL_MENTION", text),
+            keyword: env_rings("BELL_KEYWORD", text),
+            keywords: env::var("WATCH")
+                .unwrap_or_default()
+                .split(',')
+                .map(|keyword| keyword.trim().to_lowercase())
+                .filter(|keyword| !keyword.is_empty())
+                .collect(),
+            command: env::var("BELL_COMMAND")
+                .ok()
+                .filter(|command| !command.trim().is_empty()),
+            muted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // What a message with 'text' rings for.
+    pub fn alert(&self, private: bool, mentioned: bool, text: &str) -> Alert {
+        let text = text.to_lowercase();
+        if private {
+            Alert::Private
+        } else if mentioned {
+            Alert::Mention
+        } else if self.keywords.iter().any(|keyword| text.contains(keyword)) {
+            Alert::Keyword
+        } else {
+            Alert::Text
+        }
+    }
+
+    // How many times 'alert' rings, 0 while muted.
+    pub fn rings(&self, alert: Alert) -> u32 {
+        if self.muted.load(Ordering::Relaxed) {
+            return 0;
+        }
+
+        match alert {
+            Alert::Private => self.private,
+            Alert::Mention => self.mention,
+            Alert::Keyword => self.keyword,
+            Alert::Text => self.text,
+        }
+    }
+
+    // Runs BELL_COMMAND for 'alert' if it is set. Returns whether it was.
+    pub fn play(&self, alert: Alert) -> bool {
+        let command = match &self.command {
+            Some(command) => command,
+            None => return false,
+        };
+
+        let mut words = command.split_whitespace();
+        let program = match words.next() {
+            Some(program) => program,
+            None => return false,
+        };
+        let child = Command::new(program)
+            .args(words)
+            .arg(alert.name())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        // Waited for elsewhere, so a slow sound does not hold up the chat.
+        if let Ok(mut child) = child {
+            thread::spawn(move || child.wait());
+        }
+        true
+    }
+
+    // Turns sounds off or back on, returning whether they are muted now.
+    pub fn toggle_mute(&self) -> bool {
+        !self.muted.fetch_xor(true, Ordering::Relaxed)
+    }
+}
+
+fn env_rings(key: &str, default: u32) -> u32 {
+    env::var(key)
+        .ok()
+        .and_then(|rings| rings.trim().parse().ok())
+        .unwrap_or(default)
+}
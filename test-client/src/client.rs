@@ -1,236 +1,2081 @@
-use futures::{future, pin_mut, StreamExt};
+use futures::{future, pin_mut, FutureExt, SinkExt, Stream, StreamExt};
 
-use std::collections::HashSet;
+use std::{
+    cell::Cell,
+    collections::{HashMap, VecDeque},
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use async_std::io;
 use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
 
 use async_std::prelude::*;
 use async_std::task;
 use async_tungstenite::async_std::connect_async;
-use async_tungstenite::tungstenite::protocol::Message as TungMessage;
+use async_tungstenite::tungstenite::{
+    protocol::{CloseFrame, Message as TungMessage},
+    Error as WsError,
+};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 
+use crate::{
+    clock::{Clock, TimeFormat},
+    close::{self, CloseReason},
+    emoji::Emoji,
+    group::Grouper,
+    latency::Latency,
+    outbox::{Outbox, Pending},
+    output::Output,
+    roster::{Presence, Roster, RosterChange},
+    scrollback::{Found, HistoryCursor, Scrollback},
+    session::Session,
+    sink::LogSink,
+    theme::Style,
+};
+
+pub mod headless;
+mod stdio;
+
+const PROTOCOL_VERSION: u32 = 3; // 2 introduced the { v, type, payload } envelope, 3 has the server stamp the sender.
+
+const ROOM_NAME: &str = "main"; // The server's only room.
+
+// The name the server sends its own texts under, which are styled as system messages.
+const SERVER_NAME: &str = "Server";
+
+// How many messages /context asks for on either side unless told otherwise.
+const CONTEXT_SIZE: usize = 5;
+
+// How many older messages /history asks for unless told otherwise.
+const HISTORY_PAGE_SIZE: usize = 20;
+
+// How often the client pings the server to keep the rolling RTT up to date.
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+// How many reconnects in a row may fail before the client gives up, and the
+// longest it waits between two of them.
+const MAX_RECONNECT_ATTEMPTS: u32 = 5;
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+// How many redirects in a row the client follows, so two servers sending it back and
+// forth cannot keep it busy. A connection that lasted REDIRECT_RESET starts the count over.
+const MAX_REDIRECTS: u32 = 3;
+const REDIRECT_RESET: Duration = Duration::from_secs(60);
+
+// How long the server has to assign a name once connected, or once a challenge has
+// been answered, before the connection counts as failed.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// The hardest proof of work the client takes on, the most the server can be set to ask.
+// Solving it can still take longer than HANDSHAKE_TIMEOUT, at which point it gives up.
+const MAX_POW_DIFFICULTY: u32 = 32;
+
+// What this client does by itself, which the server's HelpReply knows nothing about, as
+// (topic, usage, description). /help lists it along with the server's entries.
+const CLIENT_HELP: &[(&str, &str, &str)] = &[
+    (
+        "find",
+        "/find <text>",
+        "Searches the messages seen so far, /next and /prev move between the matches.",
+    ),
+    (
+        "mute-sounds",
+        "/mute-sounds",
+        "Mutes the bell and BELL_COMMAND until it is used again.",
+    ),
+    (
+        "copy",
+        "/copy [id]",
+        "Copies a message to the clipboard, by default the one /find is on or else the latest.",
+    ),
+    (
+        "paste",
+        "/paste <file> [language]",
+        "Shares a file as a code snippet, the language defaulting to its extension.",
+    ),
+];
+
+// On the wire a message is an envelope: { "v", "type", "payload", ... } where "type" and
+// "payload" come from MessageType. Unknown fields are ignored and unknown types end up as
+// MessageType::Unknown, so a peer on an older version keeps working when new types are added.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Message<'a> {
-    src_name: &'a str,
-    src_addr: &'a str,
+    v: u32, // The PROTOCOL_VERSION of the sender.
+    #[serde(default, skip_serializing)]
+    src_name: &'a str, // Who sent the message, stamped by the server on everything it sends or relays. Peers leave it out.
+    #[serde(flatten)]
     msg_type: MessageType<'a>,
     text: String,
+    #[serde(default)]
+    timestamp: u64, // Milliseconds since the UNIX epoch by the server's clock. The server stamps every message it sends or relays.
+    #[serde(default)]
+    msg_id: Option<u64>, // Set by peers on Text and Private messages, unique per session. A message resent with the same msg_id, also from a connection that has taken over the session, is acked but not delivered again. On a broadcast Text it is the message's id in the history instead.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    badges: Vec<Badge>, // The sender's badges, stamped by the server on every Text, Private and CodeSnippet message it relays. Whatever a peer sends is replaced.
+}
+
+impl<'a> Message<'a> {
+    // A message to the server, which fills in who it is from.
+    fn outgoing(msg_type: MessageType<'a>, text: String, msg_id: Option<u64>) -> Self {
+        Self {
+            v: PROTOCOL_VERSION,
+            src_name: "",
+            msg_type,
+            text,
+            timestamp: 0,
+            msg_id,
+            badges: Vec::new(),
+        }
+    }
+}
+
+// Marks an operator has given a peer. Only the server can set them.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Badge {
+    Staff,    // Runs this server.
+    Bot,      // An automated peer.
+    Verified, // The operator knows who is behind the name.
+    #[serde(other)]
+    Unknown, // A badge added in a later version.
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", content = "payload")]
 enum MessageType<'a> {
+    Challenge(Challenge), // Sent before anything else when the server wants a new peer to prove itself. The peer has to reply with ChallengeAnswer or it is disconnected.
+    ChallengeAnswer(&'a str), // A peer's answer to a Challenge. The parameter is the answer.
+    TimeSync(u64), // Sent right before SessionToken. The parameter is the server's clock in milliseconds since the UNIX epoch, so peers can correct for their own clock when rendering timestamps.
     NewPeer(&'a str), // Broadcast this message to all peers when a new peer has connected. The parameter is the name of the new peer that has connected.
     DisconPeer(&'a str), // Broadcast this message to all peers when a peer has disconnected. The parameter is the name of the peer that has disconnected.
     PeerNameAssign(&'a str), // The server sends this message to a peer when it has first connected, giving it a random name. The name is the parameter.
-    PeerInfoRequest, // A peer sends this message to the server if the peer wants to retrieve peer info (PeerDataReply message is sent back to the peer).
+    Backfill(Vec<HistoryEntry>), // Sent right after PeerNameAssign with the latest BACKFILL_SIZE Text messages, oldest first, so a new peer has context. Render it as history, not as new messages.
+    SessionToken(&'a str), // Sent right before PeerNameAssign. Presenting the token as `?token=` on a new connection takes over the name while the old connection is still bound to it.
+    SubscribePeerList, // A peer sends this message to keep a roster in sync. The server replies with a PeerListDelta of everyone online and sends further deltas as peers come and go.
+    PeerListDelta(PeerListDelta), // The changes to the peer list since the last delta. Only sent to peers that have sent SubscribePeerList.
+    // A peer sends this message to the server if the peer wants to retrieve peer info (PeerDataReply message is sent back to the peer).
+    // Only the counts are filled in unless 'limit' is set, in which case up to 'limit' names matching 'filter' are included, starting at 'offset'.
+    PeerInfoRequest {
+        #[serde(default)]
+        offset: usize,
+        #[serde(default)]
+        limit: usize,
+        #[serde(default)]
+        filter: Option<String>,
+    },
     PeerInfoReply(PeerInfo), // If the server has received a PeerDataRequest message, a peer is asking to retrieve data about all connected peers. This resides in the PeerInfo struct.
     Private(&'a str), // A private message to the given peer. The parameter is the name of the peer receiving the message.
-    Text,             // Standard broadcasted text message to all peers.
+    // A snippet of code, broadcast like a Text with the code as 'text'. 'language' names what it is written in, e.g. "rust", for the client to render it by. It is kept in the history as a Markdown fenced code block.
+    CodeSnippet {
+        #[serde(default)]
+        language: Option<String>,
+    },
+    // An admin sends this message to create an invite token. The server replies with an InviteList holding only the new invite.
+    CreateInvite {
+        uses: u32,
+        expires_in_secs: Option<u64>,
+    },
+    ListInvites, // An admin sends this message to list all valid invite tokens. The server replies with an InviteList.
+    RevokeInvite(&'a str), // An admin sends this message to revoke the given invite token.
+    InviteList(Vec<InviteInfo>), // The server's reply to CreateInvite and ListInvites.
+    ShadowBan(&'a str), // An admin sends this message to shadow ban the given peer's session. Its messages are silently dropped from then on and it is left out of PeerInfoReply, until the session ends.
+    ShadowBanIp(&'a str), // An admin sends this message to shadow ban the given peer's IP address, every connection from it and for as long as the server keeps its state.
+    Ack(u64), // The server's reply to every Text, Private and CodeSnippet message carrying a msg_id, including repeats. The parameter is the msg_id. It comes after any notice that the message was refused and before anything else about it. A peer that has not seen the Ack may resend the message.
+    // An admin sends this message to set the slow mode of 'room': every peer has to wait 'interval' seconds between two messages there, 0 turns it off. SLOW_MODE_SECS is what the server starts with.
+    SetSlowMode {
+        room: String,
+        interval: u64,
+    },
+    SlowModeWait(u64), // The server sends this message to a peer whose message was dropped by slow mode. The parameter is how many seconds the peer has to wait.
+    // A peer sends this message to flag the Text with id 'msg_id' for the admins, saying why in 'reason'. The report is queued for the console and every admin online is told with a Private.
+    Report {
+        msg_id: u64,
+        reason: String,
+    },
+    // A bot (a peer connected with the admin key) sends this message to register `/name`. Text messages starting with it are routed to the bot as CommandInvocation instead of being broadcast.
+    RegisterCommand {
+        name: String,
+        description: String,
+    },
+    CommandsList(Vec<CommandInfo>), // Every registered command, sent after Backfill when there are any and to everyone whenever the list changes.
+    EmojiList(EmojiList), // The server's custom emoji, sent before Backfill when there are any and to everyone whenever they change. Render `:name:` in a message as the emoji's value.
+    // A peer sends this message to learn what this server supports. The server replies with a HelpReply of every entry, or only those on 'topic' when it is set.
+    HelpRequest {
+        #[serde(default)]
+        topic: Option<String>,
+    },
+    HelpReply(Vec<HelpEntry>), // The server's reply to HelpRequest. Only lists admin features to admins and includes the commands bots have registered.
+    // A peer sends this message to get a stable reference to the Text with id 'msg_id', as seen in the msg_id of broadcast Texts and in Backfill.
+    GetPermalink {
+        msg_id: u64,
+    },
+    Permalink(Permalink), // The server's reply to GetPermalink, unless no message has that id.
+    // A peer sends this message to load the Texts around the one with id 'msg_id', e.g. to jump to a permalink. At most CONTEXT_MAX_SIZE are sent on either side.
+    FetchContext {
+        msg_id: u64,
+        #[serde(default)]
+        before: usize,
+        #[serde(default)]
+        after: usize,
+    },
+    // The server's reply to FetchContext, unless the message is no longer kept. 'entries' are oldest first and include the message itself.
+    Context {
+        msg_id: u64,
+        entries: Vec<HistoryEntry>,
+    },
+    // A peer sends this message to page through the history, e.g. for infinite scroll. Without a 'cursor' it starts at the newest message going "older" or the oldest going "newer", otherwise pass the 'next_cursor' of the previous HistoryReply. Pages go by message, so they do not shift as new messages arrive. At most HISTORY_PAGE_MAX are sent, and that many if 'limit' is 0.
+    HistoryRequest {
+        #[serde(default)]
+        room: Option<String>,
+        #[serde(default)]
+        cursor: Option<String>,
+        #[serde(default)]
+        direction: Direction,
+        #[serde(default)]
+        limit: usize,
+    },
+    // The server's reply to HistoryRequest. 'messages' are oldest first. 'next_cursor' continues in the same direction. Going older it is None once the oldest message kept has been sent, going newer it is always set, so it can be used to poll for messages sent since.
+    HistoryReply {
+        messages: Vec<HistoryEntry>,
+        next_cursor: Option<String>,
+    },
+    // The server sends this message to every peer when it is drained for maintenance with an alternate address. Reconnect to 'addr' to keep chatting, presenting 'token' as `?token=` there when set and the current session token otherwise, unless 'addr' is on another host: that gets neither the session token nor an admin key or invite.
+    Redirect {
+        addr: String,
+        #[serde(default)]
+        token: Option<String>,
+    },
+    // The server sends this message to the bot that registered 'name' when a peer invokes it. 'src_name' is the invoking peer and 'args' is the rest of the line.
+    CommandInvocation {
+        name: String,
+        args: String,
+    },
+    // A peer sends this message to measure its round trip time to the server. 'sent_at' is the peer's own clock in milliseconds and is never read by the server.
+    Ping {
+        nonce: u64,
+        sent_at: u64,
+    },
+    // The server's reply to Ping, echoing its 'nonce' and 'sent_at'.
+    Pong {
+        nonce: u64,
+        sent_at: u64,
+    },
+    Batch(Vec<serde_json::Value>), // Several messages to the same peer in one frame, oldest first, when BATCH_WINDOW_MS is set. Handle each as if it had arrived on its own.
+    Text,                          // Standard broadcasted text message to all peers.
+    #[serde(untagged)]
+    Unknown(serde_json::Value), // Any type this version does not know about, e.g. one added later. The parameter is the raw type and payload.
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum Challenge {
+    // Find an answer such that SHA-1(nonce + answer) starts with 'difficulty' zero bits.
+    ProofOfWork { nonce: String, difficulty: u32 },
+    // A question configured by the operator.
+    Question(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PeerListDelta {
+    joined: Vec<String>,            // Names of the peers that have connected.
+    left: Vec<String>,              // Names of the peers that have disconnected.
+    renamed: Vec<(String, String)>, // (old, new) names. Names never change yet, so this is always empty.
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Permalink {
+    msg_id: u64,
+    uri: String,         // chat://<server>/<room>/<msg_id>
+    url: Option<String>, // Where a web UI shows the message. Always None, the server has no web UI yet.
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct HistoryEntry {
+    id: u64, // Counts up from 0 in the order messages were sent. Permalinks point at it.
+    src_name: String,
+    text: String,
+    timestamp: u64,
+}
+
+// Which way a HistoryRequest pages from its cursor.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum Direction {
+    #[default]
+    Older,
+    Newer,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CommandInfo {
+    name: String, // Without the slash, e.g. "deploy" for `/deploy`.
+    description: String,
+    bot: String, // The name of the bot the command is routed to.
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct EmojiInfo {
+    name: String,  // Without the colons, e.g. "shipit".
+    value: String, // The Unicode sequence it stands for, e.g. "🐿️".
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct EmojiList {
+    version: u64, // Goes up whenever the emoji change, so a client can keep what it has.
+    emoji: Vec<EmojiInfo>, // Sorted by name.
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct HelpEntry {
+    topic: String,
+    usage: String, // How to use it in this client. Empty for the operator's own topics.
+    description: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct InviteInfo {
+    token: String,
+    uses_left: u32,
+    expires_in_secs: Option<u64>, // None if the invite never expires.
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct PeerInfo {
-    peers_online: i32,           // How many peers are currently online?
-    peer_spots_left: i32,        // How many available spots are left for connections?
-    peer_names: HashSet<String>, // What are the names of the connected peers? excluding the requesting peers name.
+    peers_online: i32,       // How many peers are currently online?
+    peer_spots_left: i32,    // How many available spots are left for connections?
+    peers_matching: i32,     // How many of the other peers match the request's filter?
+    peer_names: Vec<String>, // The requested page of matching peer names, sorted and excluding the requesting peers name.
+    #[serde(default)]
+    peer_badges: HashMap<String, Vec<Badge>>, // The badges of the peers in 'peer_names' that have any.
+}
+
+// How a connection came to an end.
+pub enum Disconnect {
+    Quit,                // The user has closed stdin.
+    Closed(CloseReason), // The server has closed the connection with one of its close codes.
+    Lost,                // The connection failed or dropped without a known close code.
+    Throttled(Duration), // The server has turned the connection away until it is less busy.
+    // The server has asked the client to move to 'addr', presenting 'token' there if set.
+    Redirected { addr: String, token: Option<String> },
+    Handshake(HandshakeError), // The connection never got as far as a name.
+}
+
+// Why the server never assigned a name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    TimedOut,          // No PeerNameAssign within HANDSHAKE_TIMEOUT.
+    Malformed(String), // The server sent something that is not a message. The parameter is why.
+    ProofOfWork(u32), // The proof of work was too hard or took too long. The parameter is its difficulty.
+}
+
+impl HandshakeError {
+    pub fn explanation(&self) -> String {
+        match self {
+            HandshakeError::TimedOut => format!(
+                "The server did not assign a name within {} seconds.",
+                HANDSHAKE_TIMEOUT.as_secs()
+            ),
+            HandshakeError::Malformed(e) => {
+                format!(
+                    "The server sent an invalid message before assigning a name: {}",
+                    e
+                )
+            }
+            HandshakeError::ProofOfWork(difficulty) if *difficulty > MAX_POW_DIFFICULTY => {
+                format!(
+                    "The server asks for a proof of work of difficulty {}, this client solves up to {}.",
+                    difficulty, MAX_POW_DIFFICULTY
+                )
+            }
+            HandshakeError::ProofOfWork(difficulty) => format!(
+                "Could not solve the server's proof of work of difficulty {} within {} seconds.",
+                difficulty,
+                HANDSHAKE_TIMEOUT.as_secs()
+            ),
+        }
+    }
+}
+
+// What the client is doing, for applications that show connection status.
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    Connecting,                    // Opening the WebSocket connection.
+    Handshaking,                   // Connected, waiting for the server to assign a name.
+    NameAssigned { name: String }, // The connection is ready to chat.
+    Reconnecting { attempt: u32 }, // The connection has ended and the client will try again.
+    Redirected { addr: String },   // The server has sent the client to another address.
+    GaveUp { error: String },      // The connection has ended for good.
 }
 
 pub struct Client {
     addr: String,
     name: String,
+    session_token: Option<String>,
+    admin_key: Option<String>,
+    invite: Option<String>,
+    locale: Option<String>, // Asks the server to send its texts in this language, e.g. "de".
+    output: Output,
+    roster: Arc<Mutex<Roster>>,
+    latency: Arc<Mutex<Latency>>,
+    clock: Arc<Mutex<Clock>>,
+    commands: Arc<Mutex<Vec<CommandInfo>>>, // The bot commands the server has advertised.
+    emoji: Arc<Mutex<Emoji>>,               // Kept across reconnects.
+    scrollback: Arc<Mutex<Scrollback>>,     // Kept across reconnects.
+    outbox: Arc<Mutex<Outbox>>,             // Kept across reconnects.
+    session_file: Option<String>,           // Where the session is saved on exit, see restore.
+    log: Option<LogSink>,                   // Where everything received is streamed to as well.
+    event_listeners: Vec<UnboundedSender<ClientEvent>>,
 }
 
 impl Client {
-    pub fn new(addr: String) -> Self {
+    pub fn new(
+        addr: String,
+        session_token: Option<String>,
+        admin_key: Option<String>,
+        invite: Option<String>,
+        locale: Option<String>,
+        time_format: TimeFormat,
+        output: Output,
+    ) -> Self {
         Self {
             addr,
             name: String::new(),
+            session_token,
+            admin_key,
+            invite,
+            locale,
+            output,
+            roster: Arc::new(Mutex::new(Roster::new())),
+            latency: Arc::new(Mutex::new(Latency::new())),
+            clock: Arc::new(Mutex::new(Clock::new(time_format))),
+            commands: Arc::new(Mutex::new(Vec::new())),
+            emoji: Arc::new(Mutex::new(Emoji::new())),
+            scrollback: Arc::new(Mutex::new(Scrollback::from_env())),
+            outbox: Arc::new(Mutex::new(Outbox::new())),
+            session_file: None,
+            log: LogSink::from_env(),
+            event_listeners: Vec::new(),
         }
     }
 
-    pub async fn connect(&mut self) {
-        let (sender, receiver) = futures::channel::mpsc::unbounded::<TungMessage>();
+    // The other peers online, kept in sync through PeerListDelta once connected.
+    pub fn roster(&self) -> Arc<Mutex<Roster>> {
+        self.roster.clone()
+    }
 
-        let (ws_stream, _) = connect_async(format!("ws://{}/socket", &self.addr))
-            .await
-            .expect("Failed to connect");
+    pub fn roster_changes(&self) -> UnboundedReceiver<RosterChange> {
+        self.roster.lock().unwrap().subscribe()
+    }
 
-        println!("WebSocket handshake has been successfully completed.");
+    // Round trip times to the server, measured every PING_INTERVAL once connected.
+    pub fn latency(&self) -> Arc<Mutex<Latency>> {
+        self.latency.clone()
+    }
+
+    // Picks up the session saved in 'path' by the last run and saves this one there on
+    // exit. A session token given to new is kept over the saved one.
+    pub fn restore(&mut self, path: String) {
+        let session = Session::load(&path);
+        if self.session_token.is_none() {
+            self.session_token = session.session_token;
+        }
+        let mut outbox = self.outbox.lock().unwrap();
+        outbox.continue_from(session.next_msg_id);
+        for line in session.unsent {
+            outbox.hold(line);
+        }
+        self.scrollback.lock().unwrap().last_read = session.last_read;
+        drop(outbox);
+        self.session_file = Some(path);
+    }
 
-        let local_addr = ws_stream.get_ref().local_addr().unwrap().to_string();
+    fn save_session(&self) {
+        let path = match &self.session_file {
+            Some(path) => path,
+            None => return,
+        };
 
-        let (write, mut read) = ws_stream.split();
+        // Everything up to now has been shown, unless the Backfill marking it is yet to come.
+        let last_read = self.scrollback.lock().unwrap().last_read;
+        let (unsent, next_msg_id) = {
+            let outbox = self.outbox.lock().unwrap();
+            (outbox.unsent(), outbox.next_msg_id())
+        };
+        let session = Session {
+            session_token: self.session_token.clone(),
+            unsent,
+            last_read: last_read.or_else(|| Some(self.clock.lock().unwrap().server_now())),
+            next_msg_id,
+        };
+        if let Err(err) = session.save(path) {
+            println!(
+                "\n[Session] Could not save the session to {}: {}",
+                path, err
+            );
+        }
+    }
 
-        let stdin_to_ws = receiver.map(Ok).forward(write);
+    // Returns a stream of every ClientEvent from now on.
+    pub fn events(&mut self) -> UnboundedReceiver<ClientEvent> {
+        let (sender, receiver) = unbounded();
+        self.event_listeners.push(sender);
+        receiver
+    }
 
-        // Wait until name message has been received.
+    fn emit(&mut self, event: ClientEvent) {
+        self.event_listeners
+            .retain(|listener| listener.unbounded_send(event.clone()).is_ok());
+    }
+
+    // Connects and reconnects, backing off exponentially, until the user quits, the
+    // server closes the connection for good or MAX_RECONNECT_ATTEMPTS in a row fail.
+    // The session token is presented again, so a quick reconnect keeps the name, and
+    // whatever was typed meanwhile or never acked is sent once connected again.
+    pub async fn run(&mut self) {
+        self.reconnect().await;
+        self.save_session();
+    }
+
+    async fn reconnect(&mut self) {
+        let (line_sender, mut lines) = unbounded();
+        task::spawn(read_stdin(line_sender));
+
+        let mut attempt = 0;
+        let mut redirects = 0;
         loop {
-            if let Some(msg) = read.next().await {
-                let msg = msg.unwrap().to_string();
-                let msg: Message = serde_json::from_str(&msg).unwrap();
-                let msg_type = msg.msg_type.clone();
+            let connected_at = Instant::now();
+            let disconnect = self.connect(&mut lines).await;
+            if !self.name.is_empty() {
+                attempt = 0;
+            }
+            if connected_at.elapsed() >= REDIRECT_RESET {
+                redirects = 0;
+            }
 
-                match msg_type {
-                    MessageType::PeerNameAssign(new_name) => {
-                        async_std::io::stdout()
-                            .write_all(
-                                format!("\n[Chat] Welcome to Rust-Chat, {}!", new_name).as_bytes(),
-                            )
-                            .await
-                            .unwrap();
-                        self.name = new_name.to_string();
-                        async_std::io::stdout().flush().await.unwrap();
-                        break;
+            let retry_after = match disconnect {
+                Disconnect::Quit => return,
+                Disconnect::Closed(reason) => {
+                    println!("\n[Chat] {}", reason.explanation());
+                    if !reason.should_reconnect() {
+                        self.emit(ClientEvent::GaveUp {
+                            error: reason.explanation().to_string(),
+                        });
+                        return;
                     }
-                    _ => continue,
+                    None
+                }
+                Disconnect::Lost => {
+                    println!("\n[Chat] Lost the connection to the server.");
+                    None
+                }
+                Disconnect::Handshake(error) => {
+                    println!("\n[Chat] {}", error.explanation());
+                    None
+                }
+                // Moving to another server is not a failed attempt either, and the
+                // server is waiting for us to leave, so there is no delay.
+                Disconnect::Redirected { addr, token } => {
+                    redirects += 1;
+                    if redirects > MAX_REDIRECTS {
+                        let error = format!(
+                            "Not following the redirect to {}, the servers have sent this client on {} times in a row.",
+                            addr, MAX_REDIRECTS
+                        );
+                        println!("\n[Chat] {}", error);
+                        self.emit(ClientEvent::GaveUp { error });
+                        return;
+                    }
+
+                    println!("\n[Chat] The server has sent this client to {}.", addr);
+                    // What was meant for this host is not handed to another one.
+                    if host(&addr) != host(&self.addr) {
+                        let admin_key = self.admin_key.take();
+                        let invite = self.invite.take();
+                        if admin_key.is_some() || invite.is_some() {
+                            println!(
+                                "[Chat] {} is another host, the admin key and invite stay behind.",
+                                host(&addr)
+                            );
+                        }
+                        if token.is_none() {
+                            self.session_token = None;
+                        }
+                    }
+                    self.addr = addr.clone();
+                    // Message ids and cursors only mean something to the server that made them.
+                    self.scrollback.lock().unwrap().history_cursor = HistoryCursor::Newest;
+                    if token.is_some() {
+                        self.session_token = token;
+                    }
+                    self.emit(ClientEvent::Redirected { addr });
+                    continue;
+                }
+                Disconnect::Throttled(after) => {
+                    println!("\n[Chat] {}", CloseReason::RetryAfter.explanation());
+                    Some(after)
+                }
+            };
+
+            // Being turned away by a busy server is not a failed attempt: the server
+            // has said when to come back, and giving up would defeat the point.
+            let delay = match retry_after {
+                Some(after) => {
+                    println!("[Chat] Reconnecting in {} seconds...", after.as_secs());
+                    after
+                }
+                None => {
+                    attempt += 1;
+                    if attempt > MAX_RECONNECT_ATTEMPTS {
+                        let error = format!("Giving up after {} attempts.", MAX_RECONNECT_ATTEMPTS);
+                        println!("[Chat] {}", error);
+                        self.emit(ClientEvent::GaveUp { error });
+                        return;
+                    }
+
+                    let delay =
+                        (Duration::from_secs(1) * 2u32.pow(attempt - 1)).min(MAX_RECONNECT_DELAY);
+                    println!(
+                        "[Chat] Reconnecting in {} seconds (attempt {}/{})...",
+                        delay.as_secs(),
+                        attempt,
+                        MAX_RECONNECT_ATTEMPTS
+                    );
+                    delay
                 }
             };
+            self.emit(ClientEvent::Reconnecting { attempt });
+            hold_offline_lines(&mut lines, &self.outbox, &self.output, delay).await;
         }
+    }
+
+    // Where to connect, with everything the server is to know about the session.
+    fn socket_url(&self) -> String {
+        self.url_with_token(self.session_token.as_deref())
+    }
+
+    // Where headless commands connect. They join as peers of their own: presenting the
+    // session token would take the session over from the client that is using it.
+    fn headless_socket_url(&self) -> String {
+        self.url_with_token(None)
+    }
+
+    fn url_with_token(&self, session_token: Option<&str>) -> String {
+        let mut params = Vec::new();
+        if let Some(token) = session_token {
+            params.push(format!("token={}", token));
+        }
+        if let Some(admin_key) = &self.admin_key {
+            params.push(format!("admin_key={}", admin_key));
+        }
+        if let Some(invite) = &self.invite {
+            params.push(format!("invite={}", invite));
+        }
+        if let Some(locale) = &self.locale {
+            params.push(format!("locale={}", locale));
+        }
+
+        format!("ws://{}/socket?{}", &self.addr, params.join("&"))
+    }
+
+    // Runs a single connection until it ends. 'lines' are the lines read from stdin.
+    pub async fn connect(&mut self, lines: &mut UnboundedReceiver<String>) -> Disconnect {
+        let (sender, receiver) = unbounded::<TungMessage>();
+        self.name.clear();
+        self.roster.lock().unwrap().clear();
+        self.commands.lock().unwrap().clear();
 
-        task::spawn(read_stdin(sender, local_addr, self.name.clone()));
+        self.emit(ClientEvent::Connecting);
+
+        let ws_stream = match connect_async(self.socket_url()).await {
+            Ok((ws_stream, _)) => ws_stream,
+            Err(e) => {
+                println!("Failed to connect: {}", e);
+                return Disconnect::Lost;
+            }
+        };
+
+        println!("WebSocket handshake has been successfully completed.");
+        self.emit(ClientEvent::Handshaking);
+
+        let output = self.output.clone();
+
+        let (mut write, mut read) = ws_stream.split();
+        let mut unbatched = VecDeque::new();
+        // What arrives before the name is handled once it is there, in order.
+        let mut held = VecDeque::new();
+        let mut deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+
+        // Wait until name message has been received.
+        loop {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            let next = next_unbatched(&mut read, &mut unbatched);
+            let frame = match async_std::future::timeout(wait, next).await {
+                Ok(Some(Ok(TungMessage::Text(frame)))) => frame,
+                Ok(Some(Ok(TungMessage::Close(frame)))) => return closed(frame),
+                Ok(Some(Ok(_))) => continue,
+                Ok(Some(Err(_))) | Ok(None) => return Disconnect::Lost,
+                Err(_) => return Disconnect::Handshake(HandshakeError::TimedOut),
+            };
+            let msg: Message = match serde_json::from_str(&frame) {
+                Ok(msg) => msg,
+                Err(e) => return Disconnect::Handshake(HandshakeError::Malformed(e.to_string())),
+            };
+            let msg_type = msg.msg_type.clone();
+            // Held messages are recorded when they are handled.
+            if let Some(log) = &self.log {
+                if is_handshake(&msg_type) {
+                    log.record(&frame);
+                }
+            }
+
+            match msg_type {
+                MessageType::Challenge(challenge) => {
+                    let answer = match answer_challenge(&challenge, lines, &output).await {
+                        Ok(answer) => answer,
+                        Err(error) => return Disconnect::Handshake(error),
+                    };
+                    let msg_struct = Message::outgoing(
+                        MessageType::ChallengeAnswer(answer.as_str()),
+                        String::new(),
+                        None,
+                    );
+
+                    if write
+                        .send(TungMessage::Text(
+                            serde_json::to_string(&msg_struct).unwrap(),
+                        ))
+                        .await
+                        .is_err()
+                    {
+                        return Disconnect::Lost;
+                    }
+                    // However long the answer took, the server gets its full time again.
+                    deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+                }
+                MessageType::TimeSync(server_now) => {
+                    self.clock.lock().unwrap().sync(server_now);
+                }
+                MessageType::SessionToken(token) => {
+                    self.session_token = Some(token.to_string());
+                    // Saved right away too, so a client that crashes can take its name back.
+                    self.save_session();
+                }
+                MessageType::PeerNameAssign(new_name) => {
+                    output
+                        .line(&format!(
+                            "[Chat] Welcome to Rust-Chat, {}! (SESSION_TOKEN={})",
+                            new_name,
+                            self.session_token.as_deref().unwrap_or_default()
+                        ))
+                        .await;
+                    self.name = new_name.to_string();
+                    self.emit(ClientEvent::NameAssigned {
+                        name: self.name.clone(),
+                    });
+                    break;
+                }
+                _ => held.push_back(frame.clone()),
+            }
+        }
+        // Ahead of the rest of a Batch that PeerNameAssign came in.
+        while let Some(frame) = held.pop_back() {
+            unbatched.push_front(frame);
+        }
+
+        let msg_struct = Message::outgoing(MessageType::SubscribePeerList, String::new(), None);
+
+        if write
+            .send(TungMessage::Text(
+                serde_json::to_string(&msg_struct).unwrap(),
+            ))
+            .await
+            .is_err()
+        {
+            return Disconnect::Lost;
+        }
+
+        let resend = self.outbox.lock().unwrap().resend();
+        if !resend.is_empty() {
+            output
+                .styled(
+                    Style::System,
+                    &format!(
+                        "[Outbox] Resending {} message(s) the server has not acked.",
+                        resend.len()
+                    ),
+                )
+                .await;
+        }
+        for pending in &resend {
+            send_pending(&sender, pending);
+        }
+
+        let stdin_to_ws = async {
+            let _ = receiver.map(Ok).forward(write).await;
+            Disconnect::Lost
+        };
+
+        task::spawn(keep_pinging(sender.clone(), self.latency.clone()));
+
+        // Once stdin is closed, closing the channel lets stdin_to_ws send a close frame.
+        let quit = Cell::new(false);
+        let input = async {
+            send_input(
+                lines,
+                sender.clone(),
+                &self.name,
+                &self.roster,
+                &self.latency,
+                &self.clock,
+                &self.commands,
+                &self.scrollback,
+                &self.outbox,
+                &output,
+            )
+            .await;
+            quit.set(true);
+            sender.close_channel();
+            future::pending::<Disconnect>().await
+        };
 
         let ws_to_stdout = async {
-            while let Some(msg) = read.next().await {
-                let msg = msg.unwrap().to_string();
-                let msg: Message = serde_json::from_str(&msg).unwrap();
+            // The day of the last message shown, so a separator goes between days.
+            let mut shown_day = {
+                let clock = self.clock.lock().unwrap();
+                clock.day(clock.server_now())
+            };
+
+            let mut grouper = Grouper::new(&output);
+
+            loop {
+                // While peers are connecting or disconnecting, wait only until the burst is over.
+                let next = match grouper.burst_ends() {
+                    Some(ends) => {
+                        let wait = ends.saturating_duration_since(Instant::now());
+                        let next = next_unbatched(&mut read, &mut unbatched);
+                        match async_std::future::timeout(wait, next).await {
+                            Ok(next) => next,
+                            Err(_) => {
+                                show_burst(&mut grouper, &output).await;
+                                continue;
+                            }
+                        }
+                    }
+                    None => next_unbatched(&mut read, &mut unbatched).await,
+                };
+                let msg = match next {
+                    Some(Ok(TungMessage::Text(msg))) => msg,
+                    Some(Ok(TungMessage::Close(frame))) => return closed(frame),
+                    Some(Ok(_)) => continue,
+                    Some(Err(_)) | None => return Disconnect::Lost,
+                };
+                if let Some(log) = &self.log {
+                    log.record(&msg);
+                }
+                // One frame the client cannot make sense of is no reason to drop the rest.
+                let msg: Message = match serde_json::from_str(&msg) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        println!("\n[Chat] Skipped a message that could not be read: {}", e);
+                        continue;
+                    }
+                };
                 let msg_type = msg.msg_type.clone();
 
+                // Everything else shown ends the burst and the current block first.
+                if shows_line(&msg_type) {
+                    show_burst(&mut grouper, &output).await;
+                    if !matches!(msg_type, MessageType::Text) || msg.src_name == SERVER_NAME {
+                        grouper.interrupt();
+                    }
+                }
+
+                if let MessageType::Text
+                | MessageType::Private(_)
+                | MessageType::CodeSnippet { .. } = msg_type
+                {
+                    self.roster
+                        .lock()
+                        .unwrap()
+                        .record_message(msg.src_name, msg.timestamp);
+                    self.scrollback.lock().unwrap().push(
+                        history_id(&msg),
+                        msg.timestamp,
+                        msg.src_name,
+                        &msg.text,
+                    );
+
+                    let separator =
+                        date_separator(&self.clock.lock().unwrap(), &mut shown_day, msg.timestamp);
+                    if let Some(separator) = separator {
+                        grouper.interrupt();
+                        output.styled(Style::System, &separator).await;
+                    }
+                    let alert = output.bell.alert(
+                        matches!(msg_type, MessageType::Private(_)),
+                        mentions(&msg.text, &self.name),
+                        &msg.text,
+                    );
+                    output.ring(alert);
+                }
+
                 match msg_type {
-                    MessageType::NewPeer(peer_name) => async_std::io::stdout()
-                        .write_all(
-                            format!("\n[Chat] {}: {} has connected.", &msg.src_name, peer_name)
-                                .as_bytes(),
-                        )
-                        .await
-                        .unwrap(),
-                    MessageType::DisconPeer(peer_name) => async_std::io::stdout()
-                        .write_all(
-                            format!(
-                                "\n[Chat] {}: {} has disconnected.",
-                                &msg.src_name, peer_name
+                    MessageType::NewPeer(_) | MessageType::DisconPeer(_) if !output.join_leave => {}
+                    MessageType::NewPeer(peer_name) => {
+                        grouper.joined(peer_name);
+                        if output.join_leave_window.is_zero() {
+                            show_burst(&mut grouper, &output).await;
+                        }
+                    }
+                    MessageType::DisconPeer(peer_name) => {
+                        grouper.left(peer_name);
+                        if output.join_leave_window.is_zero() {
+                            show_burst(&mut grouper, &output).await;
+                        }
+                    }
+                    MessageType::Text if msg.src_name == SERVER_NAME => {
+                        output
+                            .styled(
+                                Style::System,
+                                &format!("[Chat] {}: {}", &msg.src_name, &msg.text),
                             )
-                            .as_bytes(),
-                        )
-                        .await
-                        .unwrap(),
-                    MessageType::Text => async_std::io::stdout()
-                        .write_all(format!("\n[Chat] {}: {}", &msg.src_name, &msg.text).as_bytes())
-                        .await
-                        .unwrap(),
-                    MessageType::PeerInfoRequest => async_std::io::stdout()
-                        .write_all(
-                            format!("\n[PeerDataRequest] {}: {}", &msg.src_name, &msg.text)
-                                .as_bytes(),
-                        )
-                        .await
-                        .unwrap(),
-                    MessageType::PeerInfoReply(peer_data) => async_std::io::stdout()
-                        .write_all(
-                            format!("\n[PeerDataReply] {}: {:?}", &msg.src_name, peer_data)
-                                .as_bytes(),
-                        )
-                        .await
-                        .unwrap(),
+                            .await
+                    }
+                    MessageType::Text => {
+                        let text = self.emoji.lock().unwrap().expand(&msg.text);
+                        let text = if mentions(&msg.text, &self.name) {
+                            output.theme.paint(Style::Mention, &text)
+                        } else {
+                            text
+                        };
+                        if grouper.continues(msg.src_name, msg.timestamp) {
+                            // Lined up under the text of the block's first line.
+                            let indent = "[Chat] : ".len() + msg.src_name.chars().count();
+                            output
+                                .line(&format!("{}{}", " ".repeat(indent), text))
+                                .await
+                        } else {
+                            output
+                                .line(&format!(
+                                    "[Chat] {}{}: {}",
+                                    output.theme.sender(msg.src_name),
+                                    describe_badges(&msg.badges),
+                                    text
+                                ))
+                                .await
+                        }
+                    }
+                    MessageType::CodeSnippet { language } => {
+                        output
+                            .line(&format!(
+                                "[Code] {}{}: {}",
+                                output.theme.sender(msg.src_name),
+                                describe_badges(&msg.badges),
+                                describe_snippet(language.as_deref(), &msg.text)
+                            ))
+                            .await
+                    }
+                    MessageType::PeerInfoRequest { .. } => {
+                        output
+                            .styled(
+                                Style::System,
+                                &format!("[PeerDataRequest] {}: {}", &msg.src_name, &msg.text),
+                            )
+                            .await
+                    }
+                    MessageType::PeerInfoReply(peer_data) => {
+                        output
+                            .styled(
+                                Style::System,
+                                &format!("[PeerDataReply] {}: {:?}", &msg.src_name, peer_data),
+                            )
+                            .await
+                    }
                     MessageType::PeerNameAssign(name) => {
-                        async_std::io::stdout()
-                            .write_all(
-                                format!("\n[PeerName] {}: {}, {}", &msg.src_name, &msg.text, name)
-                                    .as_bytes(),
+                        output
+                            .styled(
+                                Style::System,
+                                &format!("[PeerName] {}: {}, {}", &msg.src_name, &msg.text, name),
+                            )
+                            .await;
+                    }
+                    MessageType::SlowModeWait(retry_in) => {
+                        output
+                            .styled(
+                                Style::System,
+                                &format!(
+                                    "[Slow mode] {}: {} Try again in {} seconds.",
+                                    &msg.src_name, &msg.text, retry_in
+                                ),
+                            )
+                            .await;
+                        task::spawn(slow_mode_countdown(retry_in, output.clone()));
+                    }
+                    MessageType::Pong { nonce, sent_at } => {
+                        let report = {
+                            let mut latency = self.latency.lock().unwrap();
+                            match latency.finish(nonce, sent_at) {
+                                Some((rtt, true)) => Some(format!(
+                                    "[Ping] Round trip to the server: {} ms (average {} ms)",
+                                    rtt.as_millis(),
+                                    latency.rtt().unwrap_or(rtt).as_millis()
+                                )),
+                                _ => None,
+                            }
+                        };
+                        if let Some(text) = report {
+                            output.styled(Style::System, &text).await;
+                        }
+                    }
+                    MessageType::Backfill(entries) => {
+                        let last_read = {
+                            // On reconnect the server sends history we have already seen.
+                            let mut scrollback = self.scrollback.lock().unwrap();
+                            let newest = scrollback.newest();
+                            for entry in &entries {
+                                if newest.is_none_or(|newest| entry.timestamp > newest) {
+                                    scrollback.push(
+                                        Some(entry.id),
+                                        entry.timestamp,
+                                        &entry.src_name,
+                                        &entry.text,
+                                    );
+                                }
+                            }
+                            scrollback.last_read.take()
+                        };
+                        let text: String = {
+                            let clock = self.clock.lock().unwrap();
+                            let emoji = self.emoji.lock().unwrap();
+                            // History may start days ago, so it is grouped by day.
+                            let mut history_day = None;
+                            let mut lines = Vec::new();
+                            let mut unread_marked = false;
+                            for entry in &entries {
+                                if !unread_marked
+                                    && last_read
+                                        .is_some_and(|last_read| entry.timestamp > last_read)
+                                {
+                                    lines.push(String::from("--- New since you last left ---"));
+                                    unread_marked = true;
+                                }
+                                let day = clock.day(entry.timestamp);
+                                if history_day != Some(day) {
+                                    lines.push(format!("--- {} ---", clock.date(entry.timestamp)));
+                                    history_day = Some(day);
+                                }
+                                // Earlier days already have their date above them.
+                                let ago = if day == clock.day(clock.server_now()) {
+                                    format!(" ({})", clock.ago(entry.timestamp))
+                                } else {
+                                    String::new()
+                                };
+                                lines.push(format!(
+                                    "[History] {} {}{}: {}",
+                                    clock.time(entry.timestamp),
+                                    entry.src_name,
+                                    ago,
+                                    emoji.expand(&entry.text)
+                                ));
+                            }
+                            if let Some(day) = history_day {
+                                shown_day = day;
+                            }
+                            lines.join("\n")
+                        };
+                        output.styled(Style::History, &text).await;
+                    }
+                    MessageType::Ack(msg_id) => {
+                        let acked = self.outbox.lock().unwrap().ack(msg_id);
+                        if let Some(pending) = acked.filter(|pending| pending.queued) {
+                            output
+                                .styled(Style::System, &format!("[Outbox] Sent: {}", pending.text))
+                                .await;
+                        }
+                    }
+                    MessageType::Permalink(permalink) => {
+                        let mut text = format!("[Permalink] {}", permalink.uri);
+                        if let Some(url) = &permalink.url {
+                            text.push_str(&format!(" ({})", url));
+                        }
+                        if output.copy(&permalink.uri).await {
+                            text.push_str(", copied to the clipboard");
+                        }
+                        output.styled(Style::System, &text).await;
+                    }
+                    MessageType::HistoryReply {
+                        messages,
+                        next_cursor,
+                    } => {
+                        self.scrollback.lock().unwrap().history_cursor = match next_cursor {
+                            Some(cursor) => HistoryCursor::At(cursor),
+                            None => HistoryCursor::Oldest,
+                        };
+                        let text = describe_history_page(&self.clock.lock().unwrap(), &messages);
+                        output.styled(Style::History, &text).await;
+                    }
+                    MessageType::Context { msg_id, entries } => {
+                        let text = describe_context(&self.clock.lock().unwrap(), msg_id, &entries);
+                        output.styled(Style::History, &text).await;
+                    }
+                    MessageType::PeerListDelta(delta) => {
+                        self.roster.lock().unwrap().apply_delta(
+                            delta.joined,
+                            delta.left,
+                            delta.renamed,
+                        );
+                    }
+                    MessageType::InviteList(invites) => {
+                        let mut text =
+                            format!("[Invites] {}: {} invite(s)", &msg.src_name, invites.len());
+                        for invite in invites {
+                            text.push_str(&format!(
+                                "\n    {} ({} use(s) left, {})",
+                                invite.token,
+                                invite.uses_left,
+                                match invite.expires_in_secs {
+                                    Some(secs) => format!("expires in {} seconds", secs),
+                                    None => String::from("never expires"),
+                                }
+                            ));
+                        }
+                        output.styled(Style::System, &text).await;
+                    }
+                    MessageType::CommandsList(commands) => {
+                        let text = format!("[Commands] {}", describe_commands(&commands));
+                        *self.commands.lock().unwrap() = commands;
+                        output.styled(Style::System, &text).await;
+                    }
+                    MessageType::EmojiList(list) => {
+                        let count = list.emoji.len();
+                        let emoji = list
+                            .emoji
+                            .into_iter()
+                            .map(|emoji| (emoji.name, emoji.value));
+                        if self.emoji.lock().unwrap().update(list.version, emoji) {
+                            output
+                                .styled(
+                                    Style::System,
+                                    &format!("[Emoji] The server has {} custom emoji.", count),
+                                )
+                                .await;
+                        }
+                    }
+                    MessageType::HelpReply(entries) => {
+                        let mut text = format!("[Help] {}: {}", &msg.src_name, &msg.text);
+                        for entry in entries {
+                            if entry.usage.is_empty() {
+                                text.push_str(&format!(
+                                    "\n    {}: {}",
+                                    entry.topic, entry.description
+                                ));
+                            } else {
+                                text.push_str(&format!(
+                                    "\n    {} - {}",
+                                    entry.usage, entry.description
+                                ));
+                            }
+                        }
+                        output.styled(Style::System, &text).await;
+                    }
+                    MessageType::Redirect { addr, token } => {
+                        return Disconnect::Redirected { addr, token }
+                    }
+                    MessageType::CommandInvocation { name, args } => {
+                        output
+                            .styled(
+                                Style::System,
+                                &format!("[Command] {} invoked /{} {}", &msg.src_name, name, args),
+                            )
+                            .await
+                    }
+                    MessageType::Challenge(_)
+                    | MessageType::ChallengeAnswer(_)
+                    | MessageType::RegisterCommand { .. }
+                    | MessageType::HelpRequest { .. }
+                    | MessageType::GetPermalink { .. }
+                    | MessageType::FetchContext { .. }
+                    | MessageType::HistoryRequest { .. }
+                    | MessageType::CreateInvite { .. }
+                    | MessageType::ListInvites
+                    | MessageType::Ping { .. }
+                    | MessageType::Unknown(_)
+                    | MessageType::SubscribePeerList
+                    | MessageType::RevokeInvite(_)
+                    | MessageType::SessionToken(_)
+                    | MessageType::TimeSync(_)
+                    | MessageType::ShadowBan(_)
+                    | MessageType::ShadowBanIp(_)
+                    | MessageType::SetSlowMode { .. }
+                    | MessageType::Report { .. }
+                    | MessageType::Batch(_) => (),
+                    MessageType::Private(name) => {
+                        output
+                            .styled(
+                                Style::Private,
+                                &format!(
+                                    "[PM] {}{}: {}: {}",
+                                    &msg.src_name,
+                                    describe_badges(&msg.badges),
+                                    self.emoji.lock().unwrap().expand(&msg.text),
+                                    name
+                                ),
                             )
                             .await
-                            .unwrap();
                     }
-                    MessageType::Private(name) => async_std::io::stdout()
-                        .write_all(
-                            format!("\n[PM] {}: {}: {}", &msg.src_name, &msg.text, name).as_bytes(),
-                        )
-                        .await
-                        .unwrap(),
                 }
-                async_std::io::stdout().flush().await.unwrap();
             }
         };
 
-        pin_mut!(stdin_to_ws, ws_to_stdout);
-        future::select(stdin_to_ws, ws_to_stdout).await;
+        pin_mut!(input, stdin_to_ws, ws_to_stdout);
+        let connection =
+            future::select(stdin_to_ws, ws_to_stdout).map(|either| either.factor_first().0);
+        let disconnect = future::select(input, connection).await.factor_first().0;
+
+        if quit.get() {
+            Disconnect::Quit
+        } else {
+            disconnect
+        }
+    }
+}
+
+// Whether Client::connect handles 'msg_type' itself while waiting for a name.
+fn is_handshake(msg_type: &MessageType) -> bool {
+    matches!(
+        msg_type,
+        MessageType::Challenge(_)
+            | MessageType::TimeSync(_)
+            | MessageType::SessionToken(_)
+            | MessageType::PeerNameAssign(_)
+    )
+}
+
+// Reads the next message from the server. The messages in a Batch are handed out one
+// at a time, 'unbatched' holding those still to come.
+async fn next_unbatched<S>(
+    read: &mut S,
+    unbatched: &mut VecDeque<String>,
+) -> Option<Result<TungMessage, WsError>>
+where
+    S: Stream<Item = Result<TungMessage, WsError>> + Unpin,
+{
+    loop {
+        if let Some(msg) = unbatched.pop_front() {
+            return Some(Ok(TungMessage::Text(msg)));
+        }
+
+        let next = read.next().await;
+        if let Some(Ok(TungMessage::Text(frame))) = &next {
+            if let Ok(Message {
+                msg_type: MessageType::Batch(batch),
+                ..
+            }) = serde_json::from_str(frame)
+            {
+                unbatched.extend(batch.iter().map(|msg| msg.to_string()));
+                continue;
+            }
+        }
+        return next;
     }
 }
 
-// Our helper method which will read data from stdin and send it along the
-// sender provided.
-async fn read_stdin(
+fn closed(frame: Option<CloseFrame>) -> Disconnect {
+    let Some(frame) = frame else {
+        return Disconnect::Lost;
+    };
+
+    match CloseReason::from_code(frame.code.into()) {
+        Some(CloseReason::RetryAfter) => match close::retry_after(&frame) {
+            Some(after) => Disconnect::Throttled(after),
+            None => Disconnect::Closed(CloseReason::RetryAfter),
+        },
+        Some(reason) => Disconnect::Closed(reason),
+        None => Disconnect::Lost,
+    }
+}
+
+// Solves a proof of work, or asks the user when the server poses a question.
+async fn answer_challenge(
+    challenge: &Challenge,
+    lines: &mut UnboundedReceiver<String>,
+    output: &Output,
+) -> Result<String, HandshakeError> {
+    match challenge {
+        Challenge::ProofOfWork { nonce, difficulty } => {
+            println!(
+                "[Challenge] Solving a proof of work of difficulty {}...",
+                difficulty
+            );
+
+            solve_proof_of_work(nonce, *difficulty)
+                .await
+                .ok_or(HandshakeError::ProofOfWork(*difficulty))
+        }
+        Challenge::Question(question) => {
+            output.line(&format!("[Challenge] {} ", question)).await;
+
+            Ok(lines.next().await.unwrap_or_default().trim().to_string())
+        }
+    }
+}
+
+// Solves a proof of work on a thread of its own, so the connection is not held up.
+// None if the difficulty is above MAX_POW_DIFFICULTY or solving it takes longer than
+// HANDSHAKE_TIMEOUT.
+async fn solve_proof_of_work(nonce: &str, difficulty: u32) -> Option<String> {
+    if difficulty > MAX_POW_DIFFICULTY {
+        return None;
+    }
+
+    let nonce = nonce.to_string();
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    task::spawn_blocking(move || {
+        (0u64..)
+            .take_while(|_| Instant::now() < deadline)
+            .map(|n| n.to_string())
+            .find(|answer| {
+                leading_zero_bits(&Sha1::digest(format!("{}{}", nonce, answer).as_bytes()))
+                    >= difficulty
+            })
+    })
+    .await
+}
+
+// The host part of host:port, e.g. "[::1]" of "[::1]:8080".
+fn host(addr: &str) -> &str {
+    addr.rsplit_once(':').map_or(addr, |(host, _)| host)
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+
+    bits
+}
+
+// Counts down a slow mode wait and tells the user once they can send again.
+// The countdown itself is left out in accessible mode.
+async fn slow_mode_countdown(retry_in: u64, output: Output) {
+    for left in (1..=retry_in).rev() {
+        if left <= 3 && !output.accessible {
+            output
+                .styled(Style::System, &format!("[Slow mode] {}...", left))
+                .await;
+        }
+        task::sleep(Duration::from_secs(1)).await;
+    }
+
+    output
+        .styled(Style::System, "[Slow mode] You can send messages again.")
+        .await;
+}
+
+// Pings the server every PING_INTERVAL until the connection is gone.
+async fn keep_pinging(
     sender: futures::channel::mpsc::UnboundedSender<TungMessage>,
-    local_addr: String,
-    peer_name: String,
+    latency: Arc<Mutex<Latency>>,
 ) {
-    let mut stdin = io::stdin();
-
     loop {
-        async_std::io::stdout()
-            .write_all(format!("\n[Chat] {}: ", peer_name).as_bytes())
-            .await
-            .unwrap();
-        async_std::io::stdout().flush().await.unwrap();
+        task::sleep(PING_INTERVAL).await;
 
-        let mut buf = vec![0; 1024];
-        let n = match stdin.read(&mut buf).await {
-            Err(_) | Ok(0) => break,
-            Ok(n) => n,
+        if send_ping(&sender, &latency, false).is_err() {
+            break;
+        }
+    }
+}
+
+fn send_ping(
+    sender: &futures::channel::mpsc::UnboundedSender<TungMessage>,
+    latency: &Mutex<Latency>,
+    requested: bool,
+) -> Result<(), futures::channel::mpsc::TrySendError<TungMessage>> {
+    let (nonce, sent_at) = latency.lock().unwrap().start(requested);
+    let msg_struct = Message::outgoing(MessageType::Ping { nonce, sent_at }, String::new(), None);
+
+    sender.unbounded_send(TungMessage::Text(
+        serde_json::to_string(&msg_struct).unwrap(),
+    ))
+}
+
+// The line to show before a message at 'timestamp' if it is on another
+// day than the last message shown.
+fn date_separator(clock: &Clock, shown_day: &mut i64, timestamp: u64) -> Option<String> {
+    let day = clock.day(timestamp);
+    if day == *shown_day {
+        return None;
+    }
+
+    *shown_day = day;
+    Some(format!("--- {} ---", clock.date(timestamp)))
+}
+
+// Whether receiving a message of this type writes a line.
+fn shows_line(msg_type: &MessageType) -> bool {
+    !matches!(
+        msg_type,
+        MessageType::NewPeer(_)
+            | MessageType::DisconPeer(_)
+            | MessageType::PeerListDelta(_)
+            | MessageType::Pong { .. }
+            | MessageType::Ack(_)
+            | MessageType::TimeSync(_)
+            | MessageType::SessionToken(_)
+            | MessageType::Unknown(_)
+    )
+}
+
+// Shows the peers that have connected and disconnected since the last time, if any.
+async fn show_burst(grouper: &mut Grouper, output: &Output) {
+    if let Some(summary) = grouper.take_burst() {
+        output
+            .styled(
+                Style::System,
+                &format!("[Chat] {}: {}", SERVER_NAME, summary),
+            )
+            .await;
+    }
+}
+
+// e.g. " [staff, verified]", or nothing for a peer without badges.
+fn describe_badges(badges: &[Badge]) -> String {
+    if badges.is_empty() {
+        return String::new();
+    }
+
+    let badges: Vec<&str> = badges
+        .iter()
+        .map(|badge| match badge {
+            Badge::Staff => "staff",
+            Badge::Bot => "bot",
+            Badge::Verified => "verified",
+            Badge::Unknown => "?",
+        })
+        .collect();
+    format!(" [{}]", badges.join(", "))
+}
+
+// Whether 'text' mentions 'name' as a word of its own, ignoring case.
+fn mentions(text: &str, name: &str) -> bool {
+    !name.is_empty()
+        && text
+            .split(|c: char| !c.is_alphanumeric() && c != '_' && c != '-')
+            .any(|word| word.eq_ignore_ascii_case(name))
+}
+
+// The Context of 'msg_id', marking the message itself with ">".
+fn describe_context(clock: &Clock, msg_id: u64, entries: &[HistoryEntry]) -> String {
+    let mut text = format!("[Context] The messages around #{}:", msg_id);
+    for entry in entries {
+        text.push_str(&format!(
+            "\n  {} #{} {} {}: {}",
+            if entry.id == msg_id { ">" } else { " " },
+            entry.id,
+            clock.time(entry.timestamp),
+            entry.src_name,
+            entry.text
+        ));
+    }
+    text
+}
+
+// A page of older messages, grouped by day since it may go back days.
+fn describe_history_page(clock: &Clock, entries: &[HistoryEntry]) -> String {
+    if entries.is_empty() {
+        return String::from("[History] No older messages are kept.");
+    }
+
+    let mut history_day = None;
+    let mut lines = Vec::new();
+    for entry in entries {
+        let day = clock.day(entry.timestamp);
+        if history_day != Some(day) {
+            lines.push(format!("--- {} ---", clock.date(entry.timestamp)));
+            history_day = Some(day);
+        }
+        lines.push(format!(
+            "[History] #{} {} {}: {}",
+            entry.id,
+            clock.time(entry.timestamp),
+            entry.src_name,
+            entry.text
+        ));
+    }
+    lines.join("\n")
+}
+
+// A CodeSnippet as a fenced block under its header, e.g. "rust snippet, 3 line(s)".
+fn describe_snippet(language: Option<&str>, code: &str) -> String {
+    let code = code.trim_end();
+    format!(
+        "{} snippet, {} line(s)\n```{}\n{}\n```",
+        language.unwrap_or("code"),
+        code.lines().count(),
+        language.unwrap_or(""),
+        code
+    )
+}
+
+// The id the server gave a message in its history. Only broadcast Texts from peers have one.
+fn history_id(msg: &Message) -> Option<u64> {
+    match msg.msg_type {
+        MessageType::Text | MessageType::CodeSnippet { .. } if msg.src_name != SERVER_NAME => {
+            msg.msg_id
+        }
+        _ => None,
+    }
+}
+
+// A /find result, e.g. "[Find] 2/5 #41 14:05 Tanya: hello there".
+fn describe_found(clock: &Clock, found: Found, output: &Output) -> String {
+    let id = match found.line.msg_id {
+        Some(msg_id) => format!(" #{}", msg_id),
+        None => String::new(),
+    };
+
+    format!(
+        "[Find] {}/{}{} {} {}: {}",
+        found.index,
+        found.count,
+        id,
+        clock.time(found.line.timestamp),
+        output.theme.highlight(&found.line.sender, found.needle),
+        output.theme.highlight(&found.line.text, found.needle)
+    )
+}
+
+fn describe_commands(commands: &[CommandInfo]) -> String {
+    let mut text = format!("{} bot command(s) available", commands.len());
+    for command in commands {
+        text.push_str(&format!(
+            "\n    /{} - {} ({})",
+            command.name, command.description, command.bot
+        ));
+    }
+    text
+}
+
+// Reads stdin line by line for as long as the client runs, so typed lines
+// are not tied to a single connection.
+async fn read_stdin(sender: futures::channel::mpsc::UnboundedSender<String>) {
+    let mut lines = io::BufReader::new(io::stdin()).lines();
+
+    while let Some(Ok(line)) = lines.next().await {
+        if sender.unbounded_send(line).is_err() {
+            break;
+        }
+    }
+}
+
+// Our helper method which will read lines from stdin and send them along the
+// sender provided. Returns once stdin is closed.
+#[allow(clippy::too_many_arguments)]
+async fn send_input(
+    lines: &mut UnboundedReceiver<String>,
+    sender: futures::channel::mpsc::UnboundedSender<TungMessage>,
+    peer_name: &str,
+    roster: &Mutex<Roster>,
+    latency: &Mutex<Latency>,
+    clock: &Mutex<Clock>,
+    commands: &Mutex<Vec<CommandInfo>>,
+    scrollback: &Mutex<Scrollback>,
+    outbox: &Mutex<Outbox>,
+    output: &Output,
+) {
+    loop {
+        // What was typed while offline goes first.
+        let held = outbox.lock().unwrap().take_held();
+        let (msg, queued) = match held {
+            Some(msg) => (msg, true),
+            None => {
+                output.prompt(peer_name).await;
+                match lines.next().await {
+                    Some(msg) => (msg, false),
+                    None => break,
+                }
+            }
         };
-        buf.truncate(n);
 
-        let mut msg = String::from_utf8(buf).unwrap();
+        if let Some(args) = msg.strip_prefix("pm: ") {
+            let (recv_name, msg) = args.split_once(' ').unwrap_or((args, ""));
 
-        if msg.ends_with('\n') {
-            msg.pop();
-            if msg.ends_with('\r') {
-                msg.pop();
+            let pending = outbox.lock().unwrap().add(Some(recv_name), msg, queued);
+            send_pending(&sender, &pending);
+        } else if let Some(args) = msg.strip_prefix("invite: ") {
+            let mut args = args.split_whitespace();
+            let uses = args.next().and_then(|uses| uses.parse().ok()).unwrap_or(1);
+            let expires_in_secs = args.next().and_then(|secs| secs.parse().ok());
+
+            let msg_struct = Message::outgoing(
+                MessageType::CreateInvite {
+                    uses,
+                    expires_in_secs,
+                },
+                String::new(),
+                None,
+            );
+
+            sender
+                .unbounded_send(TungMessage::Text(
+                    serde_json::to_string(&msg_struct).unwrap(),
+                ))
+                .unwrap();
+        } else if let Some(interval) = msg.strip_prefix("slowmode: ") {
+            match interval.trim().parse() {
+                Ok(interval) => {
+                    let msg_struct = Message::outgoing(
+                        MessageType::SetSlowMode {
+                            room: ROOM_NAME.to_string(),
+                            interval,
+                        },
+                        String::new(),
+                        None,
+                    );
+
+                    sender
+                        .unbounded_send(TungMessage::Text(
+                            serde_json::to_string(&msg_struct).unwrap(),
+                        ))
+                        .unwrap();
+                }
+                Err(_) => {
+                    output
+                        .styled(
+                            Style::System,
+                            "[SlowMode] slowmode: needs a number of seconds, 0 to turn it off.",
+                        )
+                        .await
+                }
             }
-        }
+        } else if msg.starts_with("invites") {
+            let msg_struct = Message::outgoing(MessageType::ListInvites, String::new(), None);
 
-        if msg.starts_with("pm: ") {
-            let split: Vec<&str> = msg.split(" ").collect();
-            let (recv_name, msg) = (split[1].to_string(), split[2].to_string());
+            sender
+                .unbounded_send(TungMessage::Text(
+                    serde_json::to_string(&msg_struct).unwrap(),
+                ))
+                .unwrap();
+        } else if let Some(token) = msg.strip_prefix("revokeinvite: ") {
+            let msg_struct =
+                Message::outgoing(MessageType::RevokeInvite(token.trim()), String::new(), None);
 
-            let msg_struct = Message {
-                src_addr: local_addr.as_str(),
-                src_name: peer_name.as_str(),
-                msg_type: MessageType::Private(recv_name.as_str()),
-                text: msg,
-            };
+            sender
+                .unbounded_send(TungMessage::Text(
+                    serde_json::to_string(&msg_struct).unwrap(),
+                ))
+                .unwrap();
+        } else if let Some(banned_name) = msg.strip_prefix("shadowban: ") {
+            let msg_struct = Message::outgoing(
+                MessageType::ShadowBan(banned_name.trim()),
+                String::new(),
+                None,
+            );
 
             sender
                 .unbounded_send(TungMessage::Text(
                     serde_json::to_string(&msg_struct).unwrap(),
                 ))
                 .unwrap();
-        } else if msg.starts_with("peerdatarequest") {
-            let msg_struct = Message {
-                src_addr: local_addr.as_str(),
-                src_name: peer_name.as_str(),
-                msg_type: MessageType::PeerInfoRequest,
-                text: String::from(""),
-            };
+        } else if let Some(banned_name) = msg.strip_prefix("shadowban-ip: ") {
+            let msg_struct = Message::outgoing(
+                MessageType::ShadowBanIp(banned_name.trim()),
+                String::new(),
+                None,
+            );
 
             sender
                 .unbounded_send(TungMessage::Text(
                     serde_json::to_string(&msg_struct).unwrap(),
                 ))
                 .unwrap();
-        } else {
-            let msg_struct = Message {
-                src_addr: local_addr.as_str(),
-                src_name: peer_name.as_str(),
-                msg_type: MessageType::Text,
-                text: msg,
+        } else if let Some(args) = msg.strip_prefix("register: ") {
+            // register: <name> <description>, for bots connected with ADMIN_KEY
+            let (name, description) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+
+            let msg_struct = Message::outgoing(
+                MessageType::RegisterCommand {
+                    name: name.to_string(),
+                    description: description.trim().to_string(),
+                },
+                String::new(),
+                None,
+            );
+
+            sender
+                .unbounded_send(TungMessage::Text(
+                    serde_json::to_string(&msg_struct).unwrap(),
+                ))
+                .unwrap();
+        } else if let Some(topic) = msg.strip_prefix("/help") {
+            let topic = topic.trim();
+            // The client's own topics are answered here, the server does not know them.
+            let client_topic = topic.trim_start_matches('/').to_lowercase();
+            match CLIENT_HELP
+                .iter()
+                .find(|(topic, _, _)| *topic == client_topic)
+            {
+                Some((topic, usage, description)) => {
+                    let text = format!(
+                        "[Help] Help on {} in this client:\n    {} - {}",
+                        topic, usage, description
+                    );
+                    output.styled(Style::System, &text).await;
+                }
+                None => {
+                    if topic.is_empty() {
+                        let mut text = String::from("[Help] This client adds:");
+                        for (_, usage, description) in CLIENT_HELP {
+                            text.push_str(&format!("\n    {} - {}", usage, description));
+                        }
+                        output.styled(Style::System, &text).await;
+                    }
+
+                    let msg_struct = Message::outgoing(
+                        MessageType::HelpRequest {
+                            topic: (!topic.is_empty()).then(|| topic.to_string()),
+                        },
+                        String::new(),
+                        None,
+                    );
+
+                    sender
+                        .unbounded_send(TungMessage::Text(
+                            serde_json::to_string(&msg_struct).unwrap(),
+                        ))
+                        .unwrap();
+                }
+            }
+        } else if let Some(needle) = msg.strip_prefix("/find ") {
+            let text = {
+                let clock = clock.lock().unwrap();
+                match scrollback.lock().unwrap().find(needle.trim()) {
+                    Some(found) => describe_found(&clock, found, output),
+                    None => format!("[Find] No message matches \"{}\".", needle.trim()),
+                }
+            };
+            // Not styled as a whole, a style would end at the first highlighted match.
+            output.line(&text).await;
+        } else if msg == "/next" || msg == "/prev" {
+            let text = {
+                let clock = clock.lock().unwrap();
+                let mut scrollback = scrollback.lock().unwrap();
+                let searching = scrollback.searching();
+                let found = if msg == "/next" {
+                    scrollback.next()
+                } else {
+                    scrollback.previous()
+                };
+                match found {
+                    Some(found) => describe_found(&clock, found, output),
+                    None if searching => String::from("[Find] No more matches."),
+                    None => String::from("[Find] Search with /find <text> first."),
+                }
+            };
+            output.line(&text).await;
+        } else if let Some(args) = msg.strip_prefix("/context") {
+            let mut args = args.split_whitespace();
+            let msg_id = match args.next() {
+                Some(id) => id.trim_start_matches('#').parse().ok(),
+                None => scrollback.lock().unwrap().selected_id(),
+            };
+            let size = args
+                .next()
+                .and_then(|size| size.parse().ok())
+                .unwrap_or(CONTEXT_SIZE);
+
+            match msg_id {
+                Some(msg_id) => {
+                    let msg_struct = Message::outgoing(
+                        MessageType::FetchContext {
+                            msg_id,
+                            before: size,
+                            after: size,
+                        },
+                        String::new(),
+                        None,
+                    );
+
+                    sender
+                        .unbounded_send(TungMessage::Text(
+                            serde_json::to_string(&msg_struct).unwrap(),
+                        ))
+                        .unwrap();
+                }
+                None => {
+                    output
+                        .styled(
+                            Style::System,
+                            "[Context] No message to show, give its id or /find it first.",
+                        )
+                        .await
+                }
+            }
+        } else if let Some(count) = msg.strip_prefix("/history") {
+            let limit = count.trim().parse().unwrap_or(HISTORY_PAGE_SIZE);
+            let cursor = match &scrollback.lock().unwrap().history_cursor {
+                HistoryCursor::Newest => Some(None),
+                HistoryCursor::At(cursor) => Some(Some(cursor.clone())),
+                HistoryCursor::Oldest => None,
             };
 
+            match cursor {
+                Some(cursor) => {
+                    let msg_struct = Message::outgoing(
+                        MessageType::HistoryRequest {
+                            room: None,
+                            cursor,
+                            direction: Direction::Older,
+                            limit,
+                        },
+                        String::new(),
+                        None,
+                    );
+
+                    sender
+                        .unbounded_send(TungMessage::Text(
+                            serde_json::to_string(&msg_struct).unwrap(),
+                        ))
+                        .unwrap();
+                }
+                None => {
+                    output
+                        .styled(Style::History, "[History] No older messages are kept.")
+                        .await
+                }
+            }
+        } else if let Some(args) = msg.strip_prefix("/paste ") {
+            // /paste <file> [language], the language defaulting to the file's extension.
+            let mut args = args.split_whitespace();
+            let path = args.next().unwrap_or_default();
+            let language = args.next().map(str::to_string).or_else(|| {
+                Path::new(path)
+                    .extension()
+                    .map(|extension| extension.to_string_lossy().to_lowercase())
+            });
+
+            match fs::read_to_string(path) {
+                Ok(code) if !code.trim().is_empty() => {
+                    let msg_struct =
+                        Message::outgoing(MessageType::CodeSnippet { language }, code, None);
+
+                    sender
+                        .unbounded_send(TungMessage::Text(
+                            serde_json::to_string(&msg_struct).unwrap(),
+                        ))
+                        .unwrap();
+                }
+                Ok(_) => {
+                    output
+                        .styled(Style::System, &format!("[Code] {} is empty.", path))
+                        .await
+                }
+                Err(err) => {
+                    output
+                        .styled(
+                            Style::System,
+                            &format!("[Code] Could not read {}: {}", path, err),
+                        )
+                        .await
+                }
+            }
+        } else if msg == "/mute-sounds" {
+            let text = if output.bell.toggle_mute() {
+                "[Bell] Sounds are muted, /mute-sounds again to turn them back on."
+            } else {
+                "[Bell] Sounds are on again."
+            };
+            output.styled(Style::System, text).await;
+        } else if let Some(id) = msg.strip_prefix("/copy") {
+            let msg_id = id.trim().trim_start_matches('#').parse().ok();
+            let line = scrollback.lock().unwrap().selected(msg_id).cloned();
+
+            let text = match line {
+                Some(line) if output.copy(&line.text).await => {
+                    format!("[Copy] Copied {}'s message to the clipboard.", line.sender)
+                }
+                Some(_) => String::from("[Copy] This terminal cannot be copied to."),
+                None => String::from("[Copy] No message to copy."),
+            };
+            output.styled(Style::System, &text).await;
+        } else if let Some(id) = msg.strip_prefix("/permalink") {
+            let msg_id = match id.trim() {
+                "" => scrollback.lock().unwrap().selected_id(),
+                id => id.trim_start_matches('#').parse().ok(),
+            };
+
+            match msg_id {
+                Some(msg_id) => {
+                    let msg_struct = Message::outgoing(
+                        MessageType::GetPermalink { msg_id },
+                        String::new(),
+                        None,
+                    );
+
+                    sender
+                        .unbounded_send(TungMessage::Text(
+                            serde_json::to_string(&msg_struct).unwrap(),
+                        ))
+                        .unwrap();
+                }
+                None => {
+                    output
+                        .styled(
+                            Style::System,
+                            "[Permalink] No message to link to, give its id or /find it first.",
+                        )
+                        .await
+                }
+            }
+        } else if let Some(args) = msg.strip_prefix("/report ") {
+            // The id can be left out, the reason cannot.
+            let (msg_id, reason) = match args.trim().split_once(' ') {
+                Some((id, reason)) => match id.trim_start_matches('#').parse() {
+                    Ok(msg_id) => (Some(msg_id), reason.trim()),
+                    Err(_) => (scrollback.lock().unwrap().selected_id(), args.trim()),
+                },
+                None => (scrollback.lock().unwrap().selected_id(), args.trim()),
+            };
+
+            match msg_id {
+                Some(msg_id) => {
+                    let msg_struct = Message::outgoing(
+                        MessageType::Report {
+                            msg_id,
+                            reason: reason.to_string(),
+                        },
+                        String::new(),
+                        None,
+                    );
+
+                    sender
+                        .unbounded_send(TungMessage::Text(
+                            serde_json::to_string(&msg_struct).unwrap(),
+                        ))
+                        .unwrap();
+                }
+                None => {
+                    output
+                        .styled(
+                            Style::System,
+                            "[Report] No message to report, give its id or /find it first.",
+                        )
+                        .await
+                }
+            }
+        } else if msg.starts_with("/commands") {
+            let text = describe_commands(&commands.lock().unwrap());
+            output
+                .styled(Style::System, &format!("[Commands] {}", text))
+                .await;
+        } else if msg.starts_with("/ping") {
+            send_ping(&sender, latency, true).unwrap();
+        } else if msg.starts_with("roster") {
+            let names: Vec<String> = {
+                let clock = clock.lock().unwrap();
+                let entries = roster.lock().unwrap().entries(clock.server_now());
+                entries
+                    .iter()
+                    .map(|entry| match (entry.presence, entry.last_message_at) {
+                        (Presence::Active, Some(at)) => {
+                            format!("{} (active, {})", entry.name, clock.ago(at))
+                        }
+                        _ => entry.name.clone(),
+                    })
+                    .collect()
+            };
+
+            output
+                .styled(
+                    Style::System,
+                    &format!(
+                        "[Roster] {} peer(s) online: {}",
+                        names.len(),
+                        names.join(", ")
+                    ),
+                )
+                .await;
+        } else if let Some(args) = msg.strip_prefix("peerdatarequest") {
+            // peerdatarequest [offset] [limit] [filter]
+            let mut args = args.split_whitespace();
+            let offset = args.next().and_then(|n| n.parse().ok()).unwrap_or(0);
+            let limit = args.next().and_then(|n| n.parse().ok()).unwrap_or(0);
+            let filter = args.next().map(|filter| filter.to_string());
+
+            let msg_struct = Message::outgoing(
+                MessageType::PeerInfoRequest {
+                    offset,
+                    limit,
+                    filter,
+                },
+                String::new(),
+                None,
+            );
+
             sender
                 .unbounded_send(TungMessage::Text(
                     serde_json::to_string(&msg_struct).unwrap(),
                 ))
                 .unwrap();
+        } else {
+            let pending = outbox.lock().unwrap().add(None, &msg, queued);
+            send_pending(&sender, &pending);
+        }
+    }
+}
+
+// Sends a Text or Private message with the msg_id the outbox gave it.
+fn send_pending(sender: &UnboundedSender<TungMessage>, pending: &Pending) {
+    let msg_struct = Message::outgoing(
+        match &pending.to {
+            Some(recv_name) => MessageType::Private(recv_name),
+            None => MessageType::Text,
+        },
+        pending.text.clone(),
+        Some(pending.msg_id),
+    );
+
+    sender
+        .unbounded_send(TungMessage::Text(
+            serde_json::to_string(&msg_struct).unwrap(),
+        ))
+        .unwrap();
+}
+
+// Waits out 'delay' before the next reconnect, holding on to the lines typed meanwhile.
+async fn hold_offline_lines(
+    lines: &mut UnboundedReceiver<String>,
+    outbox: &Mutex<Outbox>,
+    output: &Output,
+    delay: Duration,
+) {
+    let reconnect_at = Instant::now() + delay;
+    loop {
+        let wait = reconnect_at.saturating_duration_since(Instant::now());
+        match async_std::future::timeout(wait, lines.next()).await {
+            Ok(Some(line)) => {
+                let held = outbox.lock().unwrap().hold(line);
+                output
+                    .styled(
+                        Style::System,
+                        &format!(
+                            "[Outbox] Not connected, {} line(s) will be sent once reconnected.",
+                            held
+                        ),
+                    )
+                    .await;
+            }
+            Ok(None) => {
+                task::sleep(wait).await;
+                return;
+            }
+            Err(_) => return,
         }
     }
 }
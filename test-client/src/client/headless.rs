@@ -0,0 +1,300 @@
+use std::{collections::VecDeque, time::Duration};
+
+use async_std::future::timeout;
+use async_tungstenite::async_std::connect_async;
+use async_tungstenite::tungstenite::protocol::Message as TungMessage;
+use futures::{Sink, SinkExt, StreamExt};
+use serde::Serialize;
+
+use crate::clock::now_millis;
+
+use super::{
+    next_unbatched, solve_proof_of_work, stdio, Challenge, Client, HandshakeError, Message,
+    MessageType, ROOM_NAME, SERVER_NAME,
+};
+
+// How long send waits for the server to acknowledge the message.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+const SEND_MSG_ID: u64 = 0;
+// The Ping sent ahead of the message. Its Pong comes after the greeting, so a notice
+// from the server between the Pong and the Ack is about the message.
+const SEND_PING_NONCE: u64 = 0;
+
+// What the client exits with in headless mode.
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_TIMED_OUT: i32 = 1; // wait-for saw no match in time, or send got no Ack.
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_DISCONNECTED: i32 = 3; // Could not connect or join, or the connection ended.
+pub const EXIT_REFUSED: i32 = 4; // The server did not take the message send posted.
+
+pub const USAGE: &str = "Usage:
+  test-client send [--room main] <text>
+  test-client tail [--room main] [--json]
+  test-client wait-for --pattern <text> [--room main] [--timeout <seconds>] [--json]
+  test-client --stdio-json";
+
+// Runs the client without a prompt, for scripts: `send` posts one message, `tail`
+// prints every message until the connection ends and `wait-for` returns once one
+// containing the pattern arrives. Messages go to stdout and anything else to stderr.
+// `--stdio-json` bridges the protocol to JSON lines on stdin and stdout, see stdio.
+pub enum Command {
+    Send {
+        text: String,
+    },
+    Tail {
+        json: bool,
+    },
+    // Only messages sent after joining are matched, not the backfill.
+    WaitFor {
+        pattern: String,
+        timeout: Option<Duration>,
+        json: bool,
+    },
+    StdioJson,
+}
+
+impl Command {
+    // None unless 'args', without the program name, start with a headless command.
+    pub fn parse(args: &[String]) -> Option<Result<Self, String>> {
+        let (command, args) = args.split_first()?;
+        match command.as_str() {
+            "send" | "tail" | "wait-for" => Some(Self::parse_options(command, args)),
+            "--stdio-json" if args.is_empty() => Some(Ok(Self::StdioJson)),
+            "--stdio-json" => Some(Err(String::from(USAGE))),
+            _ => None,
+        }
+    }
+
+    fn parse_options(command: &str, args: &[String]) -> Result<Self, String> {
+        let mut words = Vec::new();
+        let mut json = false;
+        let mut pattern = None;
+        let mut timeout = None;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--json" => json = true,
+                "--room" => {
+                    let room = args.next().ok_or("--room needs the name of a room.")?;
+                    if room != ROOM_NAME {
+                        return Err(format!(
+                            "There is no room {}, the server only has {}.",
+                            room, ROOM_NAME
+                        ));
+                    }
+                }
+                "--pattern" => {
+                    pattern = Some(args.next().ok_or("--pattern needs a text.")?.clone());
+                }
+                "--timeout" => {
+                    let secs = args
+                        .next()
+                        .and_then(|secs| secs.parse::<f64>().ok())
+                        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+                        .ok_or("--timeout needs a number of seconds.")?;
+                    timeout = Some(Duration::from_secs_f64(secs));
+                }
+                option if option.starts_with("--") => {
+                    return Err(format!("Unknown option {}.\n{}", option, USAGE))
+                }
+                word => words.push(word),
+            }
+        }
+
+        match command {
+            "send" if !words.is_empty() => Ok(Self::Send {
+                text: words.join(" "),
+            }),
+            "tail" if words.is_empty() => Ok(Self::Tail { json }),
+            "wait-for" if words.is_empty() => match pattern {
+                Some(pattern) => Ok(Self::WaitFor {
+                    pattern,
+                    timeout,
+                    json,
+                }),
+                None => Err(format!("wait-for needs a --pattern.\n{}", USAGE)),
+            },
+            _ => Err(String::from(USAGE)),
+        }
+    }
+}
+
+// A message as tail and wait-for print it, on one line.
+#[derive(Serialize)]
+struct Line<'a> {
+    id: Option<u64>, // The message's id in the history, None for private messages.
+    sender: &'a str,
+    text: &'a str,
+    timestamp: u64,
+    private: bool,
+}
+
+impl Line<'_> {
+    fn print(&self, json: bool) {
+        if json {
+            println!("{}", serde_json::to_string(self).unwrap());
+        } else if self.private {
+            println!("{} (private): {}", self.sender, self.text);
+        } else {
+            println!("{}: {}", self.sender, self.text);
+        }
+    }
+}
+
+// Runs 'command' against the server 'client' is set up for, returning the exit code.
+pub async fn run(client: &Client, command: Command) -> i32 {
+    if let Command::StdioJson = command {
+        return stdio::run(client).await;
+    }
+
+    let ws_stream = match connect_async(client.headless_socket_url()).await {
+        Ok((ws_stream, _)) => ws_stream,
+        Err(e) => {
+            eprintln!("Failed to connect: {}", e);
+            return EXIT_DISCONNECTED;
+        }
+    };
+
+    let (mut write, mut read) = ws_stream.split();
+    let mut unbatched = VecDeque::new();
+
+    let session = async {
+        let mut screening = false; // Whether the server is screening the message.
+        while let Some(Ok(frame)) = next_unbatched(&mut read, &mut unbatched).await {
+            let frame = match frame {
+                TungMessage::Text(frame) => frame,
+                TungMessage::Close(_) => break,
+                _ => continue,
+            };
+            let msg: Message = match serde_json::from_str(&frame) {
+                Ok(msg) => msg,
+                Err(_) => continue,
+            };
+
+            let sent = match &msg.msg_type {
+                MessageType::Challenge(Challenge::ProofOfWork { nonce, difficulty }) => {
+                    let answer = match solve_proof_of_work(nonce, *difficulty).await {
+                        Some(answer) => answer,
+                        None => {
+                            eprintln!("{}", HandshakeError::ProofOfWork(*difficulty).explanation());
+                            return EXIT_DISCONNECTED;
+                        }
+                    };
+                    let answer = MessageType::ChallengeAnswer(&answer);
+                    send(&mut write, answer, String::new(), None).await
+                }
+                MessageType::Challenge(Challenge::Question(question)) => {
+                    eprintln!(
+                        "The server asks \"{}\", start the client without a command to answer it.",
+                        question
+                    );
+                    return EXIT_DISCONNECTED;
+                }
+                MessageType::PeerNameAssign(_) => match &command {
+                    Command::Send { text } => {
+                        let ping = MessageType::Ping {
+                            nonce: SEND_PING_NONCE,
+                            sent_at: now_millis(),
+                        };
+                        let text = text.clone();
+                        send(&mut write, ping, String::new(), None).await
+                            && send(&mut write, MessageType::Text, text, Some(SEND_MSG_ID)).await
+                    }
+                    _ => true,
+                },
+                MessageType::Pong {
+                    nonce: SEND_PING_NONCE,
+                    ..
+                } => {
+                    screening = true;
+                    true
+                }
+                MessageType::Ack(SEND_MSG_ID) => {
+                    let _ = write.send(TungMessage::Close(None)).await;
+                    return EXIT_OK;
+                }
+                MessageType::SlowModeWait(_) | MessageType::Private(_)
+                    if screening && msg.src_name == SERVER_NAME =>
+                {
+                    eprintln!("The message was not sent: {}", msg.text);
+                    let _ = write.send(TungMessage::Close(None)).await;
+                    return EXIT_REFUSED;
+                }
+                MessageType::Backfill(entries) => {
+                    if let Command::Tail { json } = &command {
+                        for entry in entries {
+                            Line {
+                                id: Some(entry.id),
+                                sender: &entry.src_name,
+                                text: &entry.text,
+                                timestamp: entry.timestamp,
+                                private: false,
+                            }
+                            .print(*json);
+                        }
+                    }
+                    true
+                }
+                MessageType::Text | MessageType::Private(_) | MessageType::CodeSnippet { .. } => {
+                    let private = matches!(msg.msg_type, MessageType::Private(_));
+                    let line = Line {
+                        id: if private { None } else { msg.msg_id },
+                        sender: msg.src_name,
+                        text: &msg.text,
+                        timestamp: msg.timestamp,
+                        private,
+                    };
+                    match &command {
+                        Command::Tail { json } => line.print(*json),
+                        Command::WaitFor { pattern, json, .. } if msg.text.contains(pattern) => {
+                            line.print(*json);
+                            let _ = write.send(TungMessage::Close(None)).await;
+                            return EXIT_OK;
+                        }
+                        _ => {}
+                    }
+                    true
+                }
+                _ => true,
+            };
+            if !sent {
+                break;
+            }
+        }
+
+        eprintln!("The connection to the server has ended.");
+        EXIT_DISCONNECTED
+    };
+
+    let limit = match &command {
+        Command::Send { .. } => Some(SEND_TIMEOUT),
+        Command::WaitFor { timeout, .. } => *timeout,
+        Command::Tail { .. } | Command::StdioJson => None,
+    };
+    match limit {
+        Some(limit) => timeout(limit, session).await.unwrap_or_else(|_| {
+            eprintln!("Timed out after {:.1} seconds.", limit.as_secs_f64());
+            EXIT_TIMED_OUT
+        }),
+        None => session.await,
+    }
+}
+
+// Returns false if the connection has ended.
+pub(super) async fn send<S>(
+    write: &mut S,
+    msg_type: MessageType<'_>,
+    text: String,
+    msg_id: Option<u64>,
+) -> bool
+where
+    S: Sink<TungMessage> + Unpin,
+{
+    let msg = Message::outgoing(msg_type, text, msg_id);
+
+    write
+        .send(TungMessage::Text(serde_json::to_string(&msg).unwrap()))
+        .await
+        .is_ok()
+}
@@ -0,0 +1,249 @@
+use std::collections::VecDeque;
+
+use async_std::task;
+use async_tungstenite::async_std::connect_async;
+use async_tungstenite::tungstenite::protocol::Message as TungMessage;
+use futures::{
+    channel::mpsc::unbounded,
+    future::{self, Either},
+    pin_mut, SinkExt, StreamExt,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    headless::{send, EXIT_DISCONNECTED, EXIT_OK},
+    next_unbatched, read_stdin, solve_proof_of_work, Challenge, Client, HandshakeError, Message,
+    MessageType,
+};
+
+// A command read from stdin with --stdio-json, one JSON object per line, e.g.
+// {"command": "send", "text": "hi", "id": 1}. With an 'id', unique per session, the Ack is
+// reported as an "acked" event. Commands sent before joining are held until then.
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Request {
+    Send {
+        text: String,
+        #[serde(default)]
+        id: Option<u64>,
+    },
+    Private {
+        to: String,
+        text: String,
+        #[serde(default)]
+        id: Option<u64>,
+    },
+    Answer {
+        text: String, // The answer to a "challenge" event.
+    },
+    Quit,
+}
+
+// What --stdio-json writes to stdout, one JSON object per line tagged with "event",
+// e.g. {"event": "joined", "name": "Miya"}.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum ChatEvent<'a> {
+    Connected {
+        name: &'a str, // The name the server has given this client.
+    },
+    // The server wants a question answered before letting the client in.
+    Challenge {
+        question: &'a str,
+    },
+    Message {
+        id: Option<u64>, // The message's id in the history, None for private messages.
+        sender: &'a str,
+        text: &'a str,
+        timestamp: u64,
+        private: bool,
+        backfill: bool, // Sent before this client joined.
+    },
+    Joined {
+        name: &'a str,
+    },
+    Left {
+        name: &'a str,
+    },
+    Acked {
+        id: u64,
+    },
+    Error {
+        error: String, // E.g. a command that could not be read.
+    },
+    Disconnected,
+}
+
+impl ChatEvent<'_> {
+    fn emit(&self) {
+        println!("{}", serde_json::to_string(self).unwrap());
+    }
+}
+
+enum Incoming {
+    Frame(Option<TungMessage>),
+    Line(Option<String>),
+}
+
+// Bridges the protocol to stdin and stdout until stdin is closed or a quit command
+// arrives, which exits with EXIT_OK, or the connection ends.
+pub async fn run(client: &Client) -> i32 {
+    let ws_stream = match connect_async(client.headless_socket_url()).await {
+        Ok((ws_stream, _)) => ws_stream,
+        Err(e) => {
+            ChatEvent::Error {
+                error: format!("Failed to connect: {}", e),
+            }
+            .emit();
+            ChatEvent::Disconnected.emit();
+            return EXIT_DISCONNECTED;
+        }
+    };
+
+    let (mut write, mut read) = ws_stream.split();
+    let mut unbatched = VecDeque::new();
+
+    let (line_sender, mut lines) = unbounded();
+    task::spawn(read_stdin(line_sender));
+
+    let mut name = String::new();
+    let mut held = VecDeque::new();
+    loop {
+        // Once joined, commands held back until then go first. They are taken one by one
+        // without a select, which could drop one in favour of a frame.
+        let incoming = if !name.is_empty() && !held.is_empty() {
+            Incoming::Line(held.pop_front())
+        } else {
+            let frame = next_unbatched(&mut read, &mut unbatched);
+            pin_mut!(frame);
+            match future::select(frame, lines.next()).await {
+                Either::Left((frame, _)) => Incoming::Frame(frame.and_then(Result::ok)),
+                Either::Right((line, _)) => Incoming::Line(line),
+            }
+        };
+
+        let sent = match incoming {
+            Incoming::Frame(Some(TungMessage::Text(frame))) => {
+                let msg: Message = match serde_json::from_str(&frame) {
+                    Ok(msg) => msg,
+                    Err(_) => continue,
+                };
+
+                match &msg.msg_type {
+                    MessageType::Challenge(Challenge::ProofOfWork { nonce, difficulty }) => {
+                        let answer = match solve_proof_of_work(nonce, *difficulty).await {
+                            Some(answer) => answer,
+                            None => {
+                                ChatEvent::Error {
+                                    error: HandshakeError::ProofOfWork(*difficulty).explanation(),
+                                }
+                                .emit();
+                                ChatEvent::Disconnected.emit();
+                                return EXIT_DISCONNECTED;
+                            }
+                        };
+                        let answer = MessageType::ChallengeAnswer(&answer);
+                        send(&mut write, answer, String::new(), None).await
+                    }
+                    MessageType::Challenge(Challenge::Question(question)) => {
+                        ChatEvent::Challenge { question }.emit();
+                        true
+                    }
+                    MessageType::PeerNameAssign(assigned) => {
+                        name = assigned.to_string();
+                        ChatEvent::Connected { name: &name }.emit();
+                        true
+                    }
+                    MessageType::Backfill(entries) => {
+                        for entry in entries {
+                            ChatEvent::Message {
+                                id: Some(entry.id),
+                                sender: &entry.src_name,
+                                text: &entry.text,
+                                timestamp: entry.timestamp,
+                                private: false,
+                                backfill: true,
+                            }
+                            .emit();
+                        }
+                        true
+                    }
+                    MessageType::Text
+                    | MessageType::Private(_)
+                    | MessageType::CodeSnippet { .. } => {
+                        let private = matches!(msg.msg_type, MessageType::Private(_));
+                        ChatEvent::Message {
+                            id: if private { None } else { msg.msg_id },
+                            sender: msg.src_name,
+                            text: &msg.text,
+                            timestamp: msg.timestamp,
+                            private,
+                            backfill: false,
+                        }
+                        .emit();
+                        true
+                    }
+                    MessageType::NewPeer(name) => {
+                        ChatEvent::Joined { name }.emit();
+                        true
+                    }
+                    MessageType::DisconPeer(name) => {
+                        ChatEvent::Left { name }.emit();
+                        true
+                    }
+                    MessageType::Ack(id) => {
+                        ChatEvent::Acked { id: *id }.emit();
+                        true
+                    }
+                    _ => true,
+                }
+            }
+            Incoming::Frame(Some(TungMessage::Close(_)) | None) => false,
+            Incoming::Frame(Some(_)) => true,
+            Incoming::Line(None) => {
+                let _ = write.send(TungMessage::Close(None)).await;
+                return EXIT_OK;
+            }
+            Incoming::Line(Some(line)) => {
+                let request = match serde_json::from_str(&line) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        ChatEvent::Error {
+                            error: format!("Could not read the command {}: {}", line, e),
+                        }
+                        .emit();
+                        continue;
+                    }
+                };
+
+                match request {
+                    Request::Answer { text } => {
+                        let answer = MessageType::ChallengeAnswer(&text);
+                        send(&mut write, answer, String::new(), None).await
+                    }
+                    Request::Quit => {
+                        let _ = write.send(TungMessage::Close(None)).await;
+                        return EXIT_OK;
+                    }
+                    _ if name.is_empty() => {
+                        held.push_back(line);
+                        true
+                    }
+                    Request::Send { text, id } => {
+                        send(&mut write, MessageType::Text, text, id).await
+                    }
+                    Request::Private { to, text, id } => {
+                        let msg_type = MessageType::Private(&to);
+                        send(&mut write, msg_type, text, id).await
+                    }
+                }
+            }
+        };
+        if !sent {
+            break;
+        }
+    }
+
+    ChatEvent::Disconnected.emit();
+    EXIT_DISCONNECTED
+}
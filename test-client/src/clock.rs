@@ -0,0 +1,236 @@
+use std::{
+    env,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// How dates are written, following LOCALE.
+#[derive(Debug, Clone, Copy)]
+pub enum DateStyle {
+    Iso,     // 2026-10-14
+    Us,      // Oct 14, 2026
+    Day,     // 14 Oct 2026
+    Dotted,  // 14.10.2026
+    Slashed, // 14/10/2026
+}
+
+// How the client shows timestamps. Set through LOCALE (e.g. "en-US" or "de"),
+// TIME_FORMAT ("12" or "24", by default what LOCALE uses) and UTC_OFFSET (e.g.
+// "+02:00"), since there is no portable way to look up the local time zone.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeFormat {
+    pub utc_offset_secs: i64,
+    pub hour12: bool,
+    pub date_style: DateStyle,
+}
+
+impl TimeFormat {
+    pub fn from_env() -> Self {
+        let locale = env::var("LOCALE")
+            .unwrap_or_default()
+            .to_lowercase()
+            .replace('_', "-");
+        let language = locale.split('-').next().unwrap_or_default();
+
+        let date_style = match language {
+            _ if locale == "en-us" => DateStyle::Us,
+            "en" => DateStyle::Day,
+            "de" | "da" | "fi" | "nb" | "no" | "pl" | "cs" | "sk" | "ru" | "uk" | "tr" | "ro" => {
+                DateStyle::Dotted
+            }
+            "fr" | "es" | "it" | "pt" | "nl" | "el" => DateStyle::Slashed,
+            _ => DateStyle::Iso,
+        };
+
+        let hour12 = match env::var("TIME_FORMAT").ok().as_deref() {
+            Some("12") => true,
+            Some("24") => false,
+            _ => matches!(date_style, DateStyle::Us),
+        };
+
+        Self {
+            utc_offset_secs: env::var("UTC_OFFSET")
+                .ok()
+                .and_then(|offset| parse_utc_offset(&offset))
+                .unwrap_or(0),
+            hour12,
+            date_style,
+        }
+    }
+}
+
+// The server's clock as seen from here. Every timestamp on the wire is the
+// server's, so relative times are worked out against it rather than our own clock.
+pub struct Clock {
+    offset_ms: i64, // Server clock minus our clock.
+    format: TimeFormat,
+}
+
+impl Clock {
+    pub fn new(format: TimeFormat) -> Self {
+        Self {
+            offset_ms: 0,
+            format,
+        }
+    }
+
+    // Called with the server's clock from TimeSync. The message's transit time
+    // ends up in the offset, which is far below what relative times show.
+    pub fn sync(&mut self, server_now: u64) {
+        self.offset_ms = server_now as i64 - now_millis() as i64;
+    }
+
+    // The server's current time in milliseconds since the UNIX epoch.
+    pub fn server_now(&self) -> u64 {
+        (now_millis() as i64 + self.offset_ms).max(0) as u64
+    }
+
+    // Renders a server timestamp relative to now, e.g. "2m ago". Anything
+    // before yesterday is shown as its date instead.
+    pub fn ago(&self, timestamp: u64) -> String {
+        let secs = self.server_now().saturating_sub(timestamp) / 1000;
+        let days = self.day(self.server_now()) - self.day(timestamp);
+
+        match secs {
+            0..=9 => String::from("just now"),
+            10..=59 => format!("{}s ago", secs),
+            60..=3599 => format!("{}m ago", secs / 60),
+            3600..=86399 if days == 0 => format!("{}h ago", secs / 3600),
+            _ if days == 1 => format!("yesterday, {}", self.time(timestamp)),
+            _ => format!("{}, {}", self.date(timestamp), self.time(timestamp)),
+        }
+    }
+
+    // The local day a server timestamp falls on, counted from the UNIX epoch.
+    // Messages on different days get a date separator between them.
+    pub fn day(&self, timestamp: u64) -> i64 {
+        self.local_secs(timestamp).div_euclid(86400)
+    }
+
+    // The clock time of a server timestamp, e.g. "14:05" or "2:05 PM".
+    pub fn time(&self, timestamp: u64) -> String {
+        let secs_of_day = self.local_secs(timestamp).rem_euclid(86400);
+        let (hour, minute) = (secs_of_day / 3600, secs_of_day % 3600 / 60);
+
+        if self.format.hour12 {
+            let suffix = if hour < 12 { "AM" } else { "PM" };
+            let hour = match hour % 12 {
+                0 => 12,
+                hour => hour,
+            };
+            format!("{}:{:02} {}", hour, minute, suffix)
+        } else {
+            format!("{:02}:{:02}", hour, minute)
+        }
+    }
+
+    // The date of a server timestamp, e.g. "2026-10-14" or "14.10.2026".
+    pub fn date(&self, timestamp: u64) -> String {
+        let (year, month, day) = civil_from_days(self.day(timestamp));
+
+        match self.format.date_style {
+            DateStyle::Iso => format!("{}-{:02}-{:02}", year, month, day),
+            DateStyle::Us => format!("{} {}, {}", MONTHS[month as usize - 1], day, year),
+            DateStyle::Day => format!("{} {} {}", day, MONTHS[month as usize - 1], year),
+            DateStyle::Dotted => format!("{:02}.{:02}.{}", day, month, year),
+            DateStyle::Slashed => format!("{:02}/{:02}/{}", day, month, year),
+        }
+    }
+
+    fn local_secs(&self, timestamp: u64) -> i64 {
+        (timestamp / 1000) as i64 + self.format.utc_offset_secs
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// Parses "+02:00", "-0530", "+2" or "Z" into seconds east of UTC.
+fn parse_utc_offset(offset: &str) -> Option<i64> {
+    let offset = offset.trim();
+    if offset.eq_ignore_ascii_case("z") || offset.eq_ignore_ascii_case("utc") {
+        return Some(0);
+    }
+
+    let (sign, offset) = match (offset.strip_prefix('+'), offset.strip_prefix('-')) {
+        (Some(offset), _) => (1, offset),
+        (_, Some(offset)) => (-1, offset),
+        _ => (1, offset),
+    };
+    let (hours, minutes) = match offset.split_once(':') {
+        Some((hours, minutes)) => (hours, minutes),
+        None if offset.len() == 4 && offset.is_ascii() => offset.split_at(2),
+        None => (offset, "0"),
+    };
+
+    // Unsigned, so a second sign is not taken for one.
+    let hours: u32 = hours.parse().ok()?;
+    let minutes: u32 = minutes.parse().ok()?;
+    if hours > 14 || minutes > 59 {
+        return None;
+    }
+    Some(sign * i64::from(hours * 3600 + minutes * 60))
+}
+
+// Turns days since the UNIX epoch into (year, month, day) of the proleptic
+// Gregorian calendar, after Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utc_offsets_are_parsed() {
+        assert_eq!(parse_utc_offset("+02:00"), Some(7200));
+        assert_eq!(parse_utc_offset("-0530"), Some(-19800));
+        assert_eq!(parse_utc_offset("+2"), Some(7200));
+        assert_eq!(parse_utc_offset("9"), Some(32400));
+        assert_eq!(parse_utc_offset(" Z "), Some(0));
+        assert_eq!(parse_utc_offset("UTC"), Some(0));
+    }
+
+    #[test]
+    fn malformed_utc_offsets_are_refused() {
+        assert_eq!(parse_utc_offset(""), None);
+        assert_eq!(parse_utc_offset("+"), None);
+        assert_eq!(parse_utc_offset("\u{2212}02:00"), None); // A Unicode minus.
+        assert_eq!(parse_utc_offset("1\u{20ac}"), None);
+        assert_eq!(parse_utc_offset("+-2"), None);
+        assert_eq!(parse_utc_offset("+15"), None);
+        assert_eq!(parse_utc_offset("+02:60"), None);
+    }
+
+    #[test]
+    fn days_are_turned_into_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11017), (2000, 3, 1));
+        assert_eq!(civil_from_days(20740), (2026, 10, 14));
+    }
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use async_tungstenite::tungstenite::protocol::CloseFrame;
+
+// The close codes the server uses, see server/src/close.rs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    Kicked,
+    Banned,
+    Idle,
+    ServerFull,
+    ProtocolError,
+    Shutdown,
+    ChallengeFailed,
+    InviteRequired,
+    Replaced,
+    RetryAfter,
+}
+
+impl CloseReason {
+    pub fn from_code(code: u16) -> Option<Self> {
+        match code {
+            4000 => Some(CloseReason::Kicked),
+            4001 => Some(CloseReason::Banned),
+            4002 => Some(CloseReason::Idle),
+            4003 => Some(CloseReason::ServerFull),
+            4004 => Some(CloseReason::ProtocolError),
+            4005 => Some(CloseReason::Shutdown),
+            4006 => Some(CloseReason::ChallengeFailed),
+            4007 => Some(CloseReason::InviteRequired),
+            4008 => Some(CloseReason::Replaced),
+            4009 => Some(CloseReason::RetryAfter),
+            _ => None,
+        }
+    }
+
+    pub fn explanation(self) -> &'static str {
+        match self {
+            CloseReason::Kicked => "You have been kicked by an admin.",
+            CloseReason::Banned => "You are banned from this server.",
+            CloseReason::Idle => "You have been disconnected for being idle.",
+            CloseReason::ServerFull => "The server is full.",
+            CloseReason::ProtocolError => {
+                "The server did not understand a message from this client."
+            }
+            CloseReason::Shutdown => "The server is shutting down.",
+            CloseReason::ChallengeFailed => "You did not pass the server's connect challenge.",
+            CloseReason::InviteRequired => {
+                "The server is invite-only. Set INVITE to a valid invite token."
+            }
+            CloseReason::Replaced => "Your session has been taken over by another connection.",
+            CloseReason::RetryAfter => "The server is busy, try again later.",
+        }
+    }
+
+    // Whether trying again later can help. Reconnecting after a kick, a ban or a
+    // takeover would only undo what the server or the user wanted.
+    pub fn should_reconnect(self) -> bool {
+        matches!(
+            self,
+            CloseReason::ServerFull | CloseReason::Shutdown | CloseReason::RetryAfter
+        )
+    }
+}
+
+// How long a RetryAfter close asks to wait, from a reason like "retry-after 3".
+pub fn retry_after(frame: &CloseFrame) -> Option<Duration> {
+    let secs = frame
+        .reason
+        .strip_prefix("retry-after ")?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(secs))
+}
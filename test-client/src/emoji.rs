@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+
+// The server's custom emoji, kept across reconnects and replaced only when the
+// server's EmojiList has another version.
+pub struct Emoji {
+    version: Option<u64>,
+    emoji: HashMap<String, String>, // Name without colons -> value.
+}
+
+impl Emoji {
+    pub fn new() -> Self {
+        Self {
+            version: None,
+            emoji: HashMap::new(),
+        }
+    }
+
+    // Takes over the server's emoji. Returns false if they are the ones already kept.
+    pub fn update(&mut self, version: u64, emoji: impl Iterator<Item = (String, String)>) -> bool {
+        if self.version == Some(version) {
+            return false;
+        }
+
+        self.version = Some(version);
+        self.emoji = emoji.collect();
+        true
+    }
+
+    // Replaces every `:name:` of a known emoji in 'text' with its value. Anything
+    // else between colons, e.g. a time like 12:30:45, is left as it is.
+    pub fn expand(&self, text: &str) -> String {
+        if self.emoji.is_empty() {
+            return text.to_string();
+        }
+
+        let mut expanded = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find(':') {
+            expanded.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            match after
+                .find(':')
+                .and_then(|end| Some((end, self.emoji.get(&after[..end])?)))
+            {
+                Some((end, value)) => {
+                    expanded.push_str(value);
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    expanded.push(':');
+                    rest = after;
+                }
+            }
+        }
+        expanded.push_str(rest);
+        expanded
+    }
+}
@@ -0,0 +1,141 @@
+use std::time::{Duration, Instant};
+
+use crate::output::Output;
+
+// Folds a busy room into fewer lines: consecutive messages of one sender become a
+// block under a single name, and a burst of peers connecting and disconnecting is
+// shown as one line once it is over.
+pub struct Grouper {
+    group_window_ms: u64,
+    join_leave_window: Duration,
+    last_text: Option<(String, u64)>, // Sender and server timestamp of the last line shown, if it was a Text.
+    joined: Vec<String>,
+    left: Vec<String>,
+    burst_ends: Option<Instant>,
+}
+
+impl Grouper {
+    pub fn new(output: &Output) -> Self {
+        Self {
+            // A line without the sender's name would be read out without it.
+            group_window_ms: if output.accessible {
+                0
+            } else {
+                output.group_window.as_millis() as u64
+            },
+            join_leave_window: output.join_leave_window,
+            last_text: None,
+            joined: Vec::new(),
+            left: Vec::new(),
+            burst_ends: None,
+        }
+    }
+
+    // Records a Text about to be shown and tells whether it continues the block of
+    // the one before it, so its sender can be left out.
+    pub fn continues(&mut self, sender: &str, timestamp: u64) -> bool {
+        let continues = match &self.last_text {
+            Some((last_sender, last_at)) => {
+                last_sender == sender && timestamp.saturating_sub(*last_at) < self.group_window_ms
+            }
+            None => false,
+        };
+
+        self.last_text = Some((sender.to_string(), timestamp));
+        continues
+    }
+
+    // Ends the current block. Called for every other line shown.
+    pub fn interrupt(&mut self) {
+        self.last_text = None;
+    }
+
+    pub fn joined(&mut self, name: &str) {
+        self.joined.push(name.to_string());
+        self.extend_burst();
+    }
+
+    pub fn left(&mut self, name: &str) {
+        self.left.push(name.to_string());
+        self.extend_burst();
+    }
+
+    // The burst is over once nobody has connected or disconnected for a while.
+    fn extend_burst(&mut self) {
+        self.burst_ends = Some(Instant::now() + self.join_leave_window);
+    }
+
+    // When the pending burst should be shown, if there is one.
+    pub fn burst_ends(&self) -> Option<Instant> {
+        self.burst_ends
+    }
+
+    // Takes the pending burst as one line, e.g. "3 peers have connected: a, b, c.".
+    pub fn take_burst(&mut self) -> Option<String> {
+        self.burst_ends.take()?;
+        self.interrupt();
+
+        let joined = std::mem::take(&mut self.joined);
+        let left = std::mem::take(&mut self.left);
+        let summary = [(joined, "connected"), (left, "disconnected")]
+            .iter()
+            .filter(|(names, _)| !names.is_empty())
+            .map(|(names, what)| match names.len() {
+                1 => format!("{} has {}.", names[0], what),
+                n => format!("{} peers have {}: {}.", n, what, names.join(", ")),
+            })
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        Some(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grouper(accessible: bool) -> Grouper {
+        let mut output = Output::from_env();
+        output.accessible = accessible;
+        output.group_window = Duration::from_secs(60);
+        output.join_leave_window = Duration::from_secs(3);
+        Grouper::new(&output)
+    }
+
+    #[test]
+    fn messages_of_one_sender_within_the_window_are_one_block() {
+        let mut grouper = grouper(false);
+        assert!(!grouper.continues("Alice", 0));
+        assert!(grouper.continues("Alice", 59_000));
+        assert!(!grouper.continues("Alice", 120_000));
+        assert!(!grouper.continues("Bob", 120_001));
+
+        grouper.interrupt();
+        assert!(!grouper.continues("Bob", 120_002));
+    }
+
+    #[test]
+    fn accessible_output_never_leaves_the_sender_out() {
+        let mut grouper = grouper(true);
+        assert!(!grouper.continues("Alice", 0));
+        assert!(!grouper.continues("Alice", 1));
+    }
+
+    #[test]
+    fn a_burst_is_summed_up_in_one_line() {
+        let mut grouper = grouper(false);
+        assert!(grouper.take_burst().is_none());
+
+        grouper.joined("Alice");
+        grouper.joined("Bob");
+        grouper.left("Carol");
+        assert!(grouper.burst_ends().is_some());
+        assert_eq!(
+            grouper.take_burst().as_deref(),
+            Some("2 peers have connected: Alice, Bob. Carol has disconnected.")
+        );
+        assert!(grouper.burst_ends().is_none());
+        assert!(grouper.take_burst().is_none());
+    }
+}
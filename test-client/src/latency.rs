@@ -0,0 +1,57 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use crate::clock::now_millis;
+
+// How many of the latest round trips the rolling RTT is averaged over.
+const MAX_SAMPLES: usize = 8;
+
+// Round trip times to the server, measured through Ping and Pong.
+pub struct Latency {
+    next_nonce: u64,
+    pending: HashMap<u64, bool>, // Nonce -> whether the user asked for this ping.
+    samples: VecDeque<Duration>,
+}
+
+impl Latency {
+    pub fn new() -> Self {
+        Self {
+            next_nonce: 0,
+            pending: HashMap::new(),
+            samples: VecDeque::new(),
+        }
+    }
+
+    // Returns the (nonce, sent_at) to put in the next Ping.
+    pub fn start(&mut self, requested: bool) -> (u64, u64) {
+        let nonce = self.next_nonce;
+        self.next_nonce += 1;
+        self.pending.insert(nonce, requested);
+        (nonce, now_millis())
+    }
+
+    // Records the round trip of a Pong. Returns the round trip time and whether
+    // the user asked for it, or None if the Pong does not answer one of our pings.
+    pub fn finish(&mut self, nonce: u64, sent_at: u64) -> Option<(Duration, bool)> {
+        let requested = self.pending.remove(&nonce)?;
+        let rtt = Duration::from_millis(now_millis().saturating_sub(sent_at));
+
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+
+        Some((rtt, requested))
+    }
+
+    // The average of the latest round trips, or None before the first Pong.
+    pub fn rtt(&self) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+
+        Some(self.samples.iter().sum::<Duration>() / self.samples.len() as u32)
+    }
+}
@@ -1,21 +1,144 @@
-use async_std::task;
-use client::Client;
+use async_std::{io::prelude::WriteExt, task};
+use client::{headless, Client, ClientEvent};
+use clock::TimeFormat;
 use dotenv::dotenv;
-use std::env;
+use futures::{channel::mpsc::UnboundedReceiver, stream, StreamExt};
+use latency::Latency;
+use output::Output;
+use roster::{Roster, RosterChange};
+use std::{
+    env,
+    io::IsTerminal,
+    process,
+    sync::{Arc, Mutex},
+};
 
+mod bell;
 mod client;
+mod clock;
+mod close;
+mod emoji;
+mod group;
+mod latency;
+mod outbox;
+mod output;
+mod roster;
+mod scrollback;
+mod session;
+mod setup;
+mod sink;
+mod theme;
 
 fn main() {
     dotenv().ok();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let headless = match headless::Command::parse(&args) {
+        Some(Ok(command)) => Some(command),
+        Some(Err(usage)) => {
+            eprintln!("{}", usage);
+            process::exit(headless::EXIT_USAGE);
+        }
+        // The wizard would get in the way of a script's output.
+        None => {
+            setup::run_if_needed();
+            None
+        }
+    };
 
-    let host = env::var("HOST")
-        .ok()
-        .expect("Failed to parse HOST environment variable!");
-    let port = env::var("PORT")
-        .ok()
-        .expect("Failed to parse PORT environment variable!");
+    let host = env::var("HOST").expect("Failed to parse HOST environment variable!");
+    let port = env::var("PORT").expect("Failed to parse PORT environment variable!");
 
-    let mut client = Client::new(format!("{}:{}", host, port));
+    let session_token = env::var("SESSION_TOKEN").ok();
+    let admin_key = env::var("ADMIN_KEY").ok();
+    let invite = env::var("INVITE").ok();
+    let output = Output::from_env();
+    let locale = env::var("LOCALE").ok();
 
-    task::block_on(client.connect());
+    let mut client = Client::new(
+        format!("{}:{}", host, port),
+        session_token,
+        admin_key,
+        invite,
+        locale,
+        TimeFormat::from_env(),
+        output.clone(),
+    );
+
+    if let Some(command) = headless {
+        process::exit(task::block_on(headless::run(&client, command)));
+    }
+
+    // Title updates are escape codes a screen reader would stumble over.
+    if std::io::stdout().is_terminal() && !output.accessible {
+        task::spawn(show_status_in_title(
+            client.roster(),
+            client.roster_changes(),
+            client.events(),
+            client.latency(),
+        ));
+    }
+
+    if let Ok(path) = env::var("SESSION_FILE") {
+        client.restore(path);
+    }
+
+    task::block_on(client.run());
+}
+
+// What the terminal title gets updated on.
+enum TitleUpdate {
+    Roster(RosterChange),
+    Client(ClientEvent),
+}
+
+// Keeps the terminal title showing the connection status or, once connected, how
+// many peers are online, the round trip time to the server and what happened last.
+async fn show_status_in_title(
+    roster: Arc<Mutex<Roster>>,
+    changes: UnboundedReceiver<RosterChange>,
+    events: UnboundedReceiver<ClientEvent>,
+    latency: Arc<Mutex<Latency>>,
+) {
+    let mut updates = stream::select(
+        changes.map(TitleUpdate::Roster),
+        events.map(TitleUpdate::Client),
+    );
+
+    while let Some(update) = updates.next().await {
+        let title = match update {
+            TitleUpdate::Roster(change) => {
+                let last_change = match change {
+                    RosterChange::Joined(name) => format!("{} joined", name),
+                    RosterChange::Left(name) => format!("{} left", name),
+                    RosterChange::Renamed(old_name, new_name) => {
+                        format!("{} is now {}", old_name, new_name)
+                    }
+                    RosterChange::Spoke(name) => format!("new message from {}", name),
+                };
+
+                let online = roster.lock().unwrap().len();
+                let rtt = match latency.lock().unwrap().rtt() {
+                    Some(rtt) => format!(", {} ms", rtt.as_millis()),
+                    None => String::new(),
+                };
+                format!("Rust-Chat ({} online{}) - {}", online, rtt, last_change)
+            }
+            TitleUpdate::Client(event) => match event {
+                ClientEvent::Connecting => String::from("Rust-Chat - connecting..."),
+                ClientEvent::Handshaking => String::from("Rust-Chat - joining..."),
+                ClientEvent::NameAssigned { name } => format!("Rust-Chat - {}", name),
+                ClientEvent::Reconnecting { attempt } => {
+                    format!("Rust-Chat - reconnecting (attempt {})...", attempt)
+                }
+                ClientEvent::Redirected { addr } => format!("Rust-Chat - moving to {}...", addr),
+                ClientEvent::GaveUp { error } => format!("Rust-Chat - disconnected: {}", error),
+            },
+        };
+
+        async_std::io::stdout()
+            .write_all(format!("\x1b]0;{}\x07", title).as_bytes())
+            .await
+            .unwrap();
+        async_std::io::stdout().flush().await.unwrap();
+    }
 }
@@ -0,0 +1,152 @@
+use std::collections::VecDeque;
+
+// A Text or Private message the server has not acked yet.
+#[derive(Debug, Clone)]
+pub struct Pending {
+    pub msg_id: u64,
+    pub to: Option<String>, // The receiver of a Private message, None for a Text.
+    pub text: String,
+    pub queued: bool, // Held back by a lost connection, so the user is told once it is sent.
+}
+
+// What the user has sent that may not have reached the server: messages awaiting
+// their Ack, which are resent with the same msg_id after a reconnect, and lines
+// typed while offline, which are run once the client is connected again.
+pub struct Outbox {
+    next_msg_id: u64, // Kept across reconnects, since the server dedups per session.
+    unacked: VecDeque<Pending>,
+    offline_lines: VecDeque<String>,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self {
+            next_msg_id: 0,
+            unacked: VecDeque::new(),
+            offline_lines: VecDeque::new(),
+        }
+    }
+
+    pub fn next_msg_id(&self) -> u64 {
+        self.next_msg_id
+    }
+
+    // Goes on from a session restored from SESSION_FILE, whose msg_ids up to
+    // 'next_msg_id' the server may still have seen.
+    pub fn continue_from(&mut self, next_msg_id: u64) {
+        self.next_msg_id = self.next_msg_id.max(next_msg_id);
+    }
+
+    // Records a message about to be sent and returns it with its msg_id.
+    pub fn add(&mut self, to: Option<&str>, text: &str, queued: bool) -> Pending {
+        let pending = Pending {
+            msg_id: self.next_msg_id,
+            to: to.map(str::to_string),
+            text: text.to_string(),
+            queued,
+        };
+
+        self.next_msg_id += 1;
+        self.unacked.push_back(pending.clone());
+        pending
+    }
+
+    // Removes the message 'msg_id' once the server has acked it.
+    pub fn ack(&mut self, msg_id: u64) -> Option<Pending> {
+        let i = self
+            .unacked
+            .iter()
+            .position(|pending| pending.msg_id == msg_id)?;
+        self.unacked.remove(i)
+    }
+
+    // The messages to resend after reconnecting, oldest first.
+    pub fn resend(&mut self) -> Vec<Pending> {
+        self.unacked
+            .iter_mut()
+            .map(|pending| {
+                pending.queued = true;
+                pending.clone()
+            })
+            .collect()
+    }
+
+    // Keeps a line typed while offline and returns how many are waiting.
+    pub fn hold(&mut self, line: String) -> usize {
+        self.offline_lines.push_back(line);
+        self.offline_lines.len()
+    }
+
+    // Everything not known to have reached the server, as the lines that would send it
+    // again, oldest first.
+    pub fn unsent(&self) -> Vec<String> {
+        self.unacked
+            .iter()
+            .map(|pending| match &pending.to {
+                Some(recv_name) => format!("pm: {} {}", recv_name, pending.text),
+                None => pending.text.clone(),
+            })
+            .chain(self.offline_lines.iter().cloned())
+            .collect()
+    }
+
+    // The next line typed while offline, to be run before anything typed since.
+    pub fn take_held(&mut self) -> Option<String> {
+        self.offline_lines.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn msg_ids_go_on_from_a_restored_session_and_never_back() {
+        let mut outbox = Outbox::new();
+        outbox.continue_from(5);
+        assert_eq!(outbox.add(None, "hi", false).msg_id, 5);
+        outbox.continue_from(2);
+        assert_eq!(outbox.next_msg_id(), 6);
+    }
+
+    #[test]
+    fn acked_messages_are_not_resent_and_the_rest_are_marked_queued() {
+        let mut outbox = Outbox::new();
+        let first = outbox.add(None, "hi", false);
+        let second = outbox.add(Some("Bob"), "psst", false);
+        outbox.add(None, "bye", false);
+
+        assert_eq!(
+            outbox.ack(first.msg_id).map(|pending| pending.text),
+            Some(first.text)
+        );
+        assert!(outbox.ack(first.msg_id).is_none());
+
+        let resent = outbox.resend();
+        assert_eq!(
+            resent
+                .iter()
+                .map(|pending| pending.msg_id)
+                .collect::<Vec<u64>>(),
+            vec![second.msg_id, second.msg_id + 1]
+        );
+        assert!(resent.iter().all(|pending| pending.queued));
+    }
+
+    #[test]
+    fn unsent_lists_the_unacked_messages_before_the_held_lines() {
+        let mut outbox = Outbox::new();
+        outbox.add(Some("Bob"), "psst", false);
+        outbox.add(None, "hi", false);
+        assert_eq!(outbox.hold(String::from("/nick Alice")), 1);
+        assert_eq!(outbox.hold(String::from("later")), 2);
+
+        assert_eq!(
+            outbox.unsent(),
+            vec!["pm: Bob psst", "hi", "/nick Alice", "later"]
+        );
+        assert_eq!(outbox.take_held().as_deref(), Some("/nick Alice"));
+        assert_eq!(outbox.take_held().as_deref(), Some("later"));
+        assert_eq!(outbox.take_held(), None);
+    }
+}
@@ -0,0 +1,138 @@
+use std::{env, io::IsTerminal, time::Duration};
+
+use async_std::{
+    io::{prelude::WriteExt, stdout},
+    task,
+};
+
+use crate::{
+    bell::{Alert, Bell, RING_GAP},
+    theme::{Style, Theme},
+};
+
+// How the client writes to the terminal. Set through --accessible (or ACCESSIBLE=1),
+// JOIN_LEAVE=off, the BELL variables, GROUP_WINDOW and JOIN_LEAVE_WINDOW (in seconds, 0 turns
+// grouping off) and the THEME variables.
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub accessible: bool, // Plain lines for screen readers: no prompt, title or countdown.
+    pub join_leave: bool, // Whether peers connecting and disconnecting are shown.
+    pub bell: Bell,       // When messages ring the terminal bell.
+    // How long after a sender's last message its next one still joins the same block.
+    pub group_window: Duration,
+    // How long peers connecting and disconnecting are collected into one line.
+    pub join_leave_window: Duration,
+    pub theme: Theme, // Always without colors in accessible mode.
+}
+
+impl Output {
+    pub fn from_env() -> Self {
+        let accessible =
+            env::args().any(|arg| arg == "--accessible") || env_flag("ACCESSIBLE", false);
+
+        Self {
+            accessible,
+            join_leave: env_flag("JOIN_LEAVE", true),
+            bell: Bell::from_env(),
+            group_window: env_secs("GROUP_WINDOW", 120),
+            join_leave_window: env_secs("JOIN_LEAVE_WINDOW", 3),
+            theme: Theme::from_env(accessible),
+        }
+    }
+
+    // Writes one line. Normally a line starts by leaving the prompt it interrupts,
+    // while in accessible mode every line is simply terminated, in order.
+    pub async fn line(&self, text: &str) {
+        let text = if self.accessible {
+            format!("{}\n", text)
+        } else {
+            format!("\n{}", text)
+        };
+        write(&text).await;
+    }
+
+    // Writes one line in the theme's 'style'.
+    pub async fn styled(&self, style: Style, text: &str) {
+        self.line(&self.theme.paint(style, text)).await;
+    }
+
+    // Shows the input prompt. Left out in accessible mode, where it would be read
+    // out after every single line.
+    pub async fn prompt(&self, peer_name: &str) {
+        if !self.accessible {
+            write(&format!("\n[Chat] {}: ", self.theme.sender(peer_name))).await;
+        }
+    }
+
+    // Copies 'text' to the clipboard through the terminal (OSC 52), which not every
+    // terminal supports. Returns whether it was tried.
+    pub async fn copy(&self, text: &str) -> bool {
+        if self.accessible || !std::io::stdout().is_terminal() {
+            return false;
+        }
+
+        write(&format!("\x1b]52;c;{}\x07", base64(text.as_bytes()))).await;
+        true
+    }
+
+    // Rings the terminal bell as often as the bell policy says for 'alert', or plays
+    // BELL_COMMAND instead. Rung in the background, so the pattern's pauses do not hold
+    // up the lines after it.
+    pub fn ring(&self, alert: Alert) {
+        let rings = self.bell.rings(alert);
+        if rings == 0 || self.bell.play(alert) {
+            return;
+        }
+
+        task::spawn(async move {
+            for ring in 0..rings {
+                if ring > 0 {
+                    task::sleep(RING_GAP).await;
+                }
+                write("\x07").await;
+            }
+        });
+    }
+}
+
+async fn write(text: &str) {
+    stdout().write_all(text.as_bytes()).await.unwrap();
+    stdout().flush().await.unwrap();
+}
+
+fn env_flag(key: &str, default: bool) -> bool {
+    match env::var(key).map(|value| value.to_lowercase()).as_deref() {
+        Ok("1") | Ok("true") | Ok("on") | Ok("yes") => true,
+        Ok("0") | Ok("false") | Ok("off") | Ok("no") => false,
+        _ => default,
+    }
+}
+
+fn env_secs(key: &str, default: u64) -> Duration {
+    Duration::from_secs(
+        env::var(key)
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(default),
+    )
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,196 @@
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::Entry, HashMap},
+};
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+
+// Peers that have sent a message within this window count as active.
+const ACTIVE_WINDOW_MS: u64 = 5 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+    Active, // Has sent a message within the last few minutes.
+    Idle,   // Online, but quiet.
+}
+
+#[derive(Debug, Clone)]
+pub struct RosterEntry {
+    pub name: String,
+    pub presence: Presence,
+    pub last_message_at: Option<u64>, // Server timestamp of the last message.
+}
+
+#[derive(Debug, Clone)]
+pub enum RosterChange {
+    Joined(String),
+    Left(String),
+    Renamed(String, String), // (old, new)
+    Spoke(String),           // The peer has sent a message.
+}
+
+// The other peers online, fed by PeerListDelta and the messages we receive.
+pub struct Roster {
+    peers: HashMap<String, Option<u64>>, // Name -> server timestamp of the last message we saw from it.
+    listeners: Vec<UnboundedSender<RosterChange>>,
+}
+
+impl Roster {
+    pub fn new() -> Self {
+        Self {
+            peers: HashMap::new(),
+            listeners: Vec::new(),
+        }
+    }
+
+    // Returns a stream of every change made to the roster from now on.
+    pub fn subscribe(&mut self) -> UnboundedReceiver<RosterChange> {
+        let (sender, receiver) = unbounded();
+        self.listeners.push(sender);
+        receiver
+    }
+
+    pub fn apply_delta(
+        &mut self,
+        joined: Vec<String>,
+        left: Vec<String>,
+        renamed: Vec<(String, String)>,
+    ) {
+        for name in left {
+            if self.peers.remove(&name).is_some() {
+                self.notify(RosterChange::Left(name));
+            }
+        }
+
+        for (old_name, new_name) in renamed {
+            let last_message_at = self.peers.remove(&old_name).flatten();
+            self.peers.insert(new_name.clone(), last_message_at);
+            self.notify(RosterChange::Renamed(old_name, new_name));
+        }
+
+        for name in joined {
+            if let Entry::Vacant(entry) = self.peers.entry(name.clone()) {
+                entry.insert(None);
+                self.notify(RosterChange::Joined(name));
+            }
+        }
+    }
+
+    // Forgets everyone, e.g. before reconnecting and subscribing again.
+    pub fn clear(&mut self) {
+        let names: Vec<String> = self.peers.drain().map(|(name, _)| name).collect();
+        for name in names {
+            self.notify(RosterChange::Left(name));
+        }
+    }
+
+    pub fn record_message(&mut self, name: &str, timestamp: u64) {
+        if let Some(last_message_at) = self.peers.get_mut(name) {
+            *last_message_at = Some(timestamp);
+            self.notify(RosterChange::Spoke(name.to_string()));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    // Active peers come first, most recently heard from at the top, followed
+    // by idle peers in alphabetical order. 'now' is the server's current time.
+    pub fn entries(&self, now: u64) -> Vec<RosterEntry> {
+        let mut entries: Vec<RosterEntry> = self
+            .peers
+            .iter()
+            .map(|(name, last_message_at)| RosterEntry {
+                name: name.clone(),
+                presence: match last_message_at {
+                    Some(at) if now.saturating_sub(*at) < ACTIVE_WINDOW_MS => Presence::Active,
+                    _ => Presence::Idle,
+                },
+                last_message_at: *last_message_at,
+            })
+            .collect();
+
+        entries.sort_by(|a, b| {
+            (a.presence != Presence::Active)
+                .cmp(&(b.presence != Presence::Active))
+                .then_with(|| match a.presence {
+                    Presence::Active => Reverse(a.last_message_at).cmp(&Reverse(b.last_message_at)),
+                    Presence::Idle => a.name.cmp(&b.name),
+                })
+        });
+
+        entries
+    }
+
+    fn notify(&mut self, change: RosterChange) {
+        self.listeners
+            .retain(|listener| listener.unbounded_send(change.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(entries: &[RosterEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn active_peers_come_first_by_their_last_message_then_idle_ones_by_name() {
+        let mut roster = Roster::new();
+        let peers = ["Dave", "Carol", "Bob", "Alice"].map(String::from);
+        roster.apply_delta(peers.to_vec(), Vec::new(), Vec::new());
+
+        let now = ACTIVE_WINDOW_MS * 2;
+        roster.record_message("Bob", now - 10);
+        roster.record_message("Dave", now - 5);
+        roster.record_message("Carol", now - ACTIVE_WINDOW_MS);
+
+        let entries = roster.entries(now);
+        assert_eq!(names(&entries), vec!["Dave", "Bob", "Alice", "Carol"]);
+        assert_eq!(entries[1].presence, Presence::Active);
+        assert_eq!(entries[3].presence, Presence::Idle);
+        assert_eq!(entries[3].last_message_at, Some(now - ACTIVE_WINDOW_MS));
+    }
+
+    #[test]
+    fn a_rename_keeps_the_last_message_and_only_real_changes_are_told() {
+        let mut roster = Roster::new();
+        let mut changes = roster.subscribe();
+        roster.apply_delta(vec![String::from("Alice")], Vec::new(), Vec::new());
+        roster.record_message("Alice", 7);
+        roster.record_message("Nobody", 8);
+
+        // Joining twice or leaving without having joined changes nothing.
+        roster.apply_delta(
+            vec![String::from("Alice")],
+            vec![String::from("Bob")],
+            Vec::new(),
+        );
+        roster.apply_delta(
+            Vec::new(),
+            Vec::new(),
+            vec![(String::from("Alice"), String::from("Alicia"))],
+        );
+        assert_eq!(roster.entries(10)[0].last_message_at, Some(7));
+
+        roster.clear();
+        assert_eq!(roster.len(), 0);
+
+        let mut told = Vec::new();
+        while let Ok(change) = changes.try_recv() {
+            told.push(format!("{:?}", change));
+        }
+        assert_eq!(
+            told,
+            vec![
+                "Joined(\"Alice\")",
+                "Spoke(\"Alice\")",
+                "Renamed(\"Alice\", \"Alicia\")",
+                "Left(\"Alicia\")",
+            ]
+        );
+    }
+}
@@ -0,0 +1,250 @@
+use std::{collections::VecDeque, env};
+
+// How many messages the scrollback keeps unless SCROLLBACK says otherwise.
+const DEFAULT_CAPACITY: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub msg_id: Option<u64>, // The message's id in the server's history, for permalinks.
+    pub timestamp: u64,      // Server timestamp of the message.
+    pub sender: String,
+    pub text: String,
+}
+
+// Where /history goes on from.
+pub enum HistoryCursor {
+    Newest,     // Nothing paged yet, so the first page has the latest messages.
+    At(String), // The next_cursor of the last HistoryReply.
+    Oldest,     // The oldest message the server keeps has been shown.
+}
+
+// One search result: the line and where it is among all matches, counted from 1.
+pub struct Found<'a> {
+    pub line: &'a Line,
+    pub needle: &'a str,
+    pub index: usize,
+    pub count: usize,
+}
+
+struct Search {
+    needle: String,
+    matches: Vec<u64>, // Sequence numbers of the matching lines, oldest first.
+    at: usize,         // The match shown last.
+}
+
+// The messages shown since the client started, for /find. Lines are numbered in the
+// order they came in, so a search keeps its place while old lines are dropped.
+pub struct Scrollback {
+    lines: VecDeque<Line>,
+    first_seq: u64, // Sequence number of lines[0].
+    capacity: usize,
+    search: Option<Search>,
+    pub history_cursor: HistoryCursor,
+    pub last_read: Option<u64>, // From the last run, until the Backfill has marked what is new.
+}
+
+impl Scrollback {
+    pub fn from_env() -> Self {
+        Self {
+            lines: VecDeque::new(),
+            first_seq: 0,
+            capacity: env::var("SCROLLBACK")
+                .ok()
+                .and_then(|capacity| capacity.trim().parse().ok())
+                .unwrap_or(DEFAULT_CAPACITY),
+            search: None,
+            history_cursor: HistoryCursor::Newest,
+            last_read: None,
+        }
+    }
+
+    pub fn push(&mut self, msg_id: Option<u64>, timestamp: u64, sender: &str, text: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+            self.first_seq += 1;
+        }
+        self.lines.push_back(Line {
+            msg_id,
+            timestamp,
+            sender: sender.to_string(),
+            text: text.to_string(),
+        });
+    }
+
+    // The newest timestamp kept, so history sent again on reconnect is not stored twice.
+    pub fn newest(&self) -> Option<u64> {
+        self.lines.back().map(|line| line.timestamp)
+    }
+
+    // Starts a search for 'needle', ignoring case, at the newest match.
+    pub fn find(&mut self, needle: &str) -> Option<Found<'_>> {
+        let needle = needle.to_lowercase();
+        let matches: Vec<u64> = self
+            .lines
+            .iter()
+            .zip(self.first_seq..)
+            .filter(|(line, _)| {
+                line.text.to_lowercase().contains(&needle)
+                    || line.sender.to_lowercase().contains(&needle)
+            })
+            .map(|(_, seq)| seq)
+            .collect();
+
+        self.search = Some(Search {
+            needle,
+            at: matches.len().saturating_sub(1),
+            matches,
+        });
+        self.current()
+    }
+
+    // Moves to the match before the current one, wrapping around to the newest.
+    pub fn previous(&mut self) -> Option<Found<'_>> {
+        self.step(|at, count| (at + count - 1) % count)
+    }
+
+    // Moves to the match after the current one, wrapping around to the oldest.
+    pub fn next(&mut self) -> Option<Found<'_>> {
+        self.step(|at, count| (at + 1) % count)
+    }
+
+    fn step(&mut self, step: impl Fn(usize, usize) -> usize) -> Option<Found<'_>> {
+        let first_seq = self.first_seq;
+        let search = self.search.as_mut()?;
+
+        // Matches that have been dropped from the scrollback cannot be shown anymore.
+        let dropped = search
+            .matches
+            .iter()
+            .filter(|seq| **seq < first_seq)
+            .count();
+        search.matches.drain(..dropped);
+        search.at = search.at.saturating_sub(dropped);
+
+        if !search.matches.is_empty() {
+            search.at = step(search.at, search.matches.len());
+        }
+        self.current()
+    }
+
+    fn current(&self) -> Option<Found<'_>> {
+        let search = self.search.as_ref()?;
+        let seq = *search.matches.get(search.at)?;
+
+        Some(Found {
+            line: self
+                .lines
+                .get((seq.checked_sub(self.first_seq)?) as usize)?,
+            needle: &search.needle,
+            index: search.at + 1,
+            count: search.matches.len(),
+        })
+    }
+
+    // The message /permalink links to: the one /find is on, or else the latest with an id.
+    pub fn selected_id(&self) -> Option<u64> {
+        match self.current() {
+            Some(found) => found.line.msg_id,
+            None => self.lines.iter().rev().find_map(|line| line.msg_id),
+        }
+    }
+
+    // The message /copy copies: the one with 'msg_id' if given, else the one /find is on,
+    // else the latest.
+    pub fn selected(&self, msg_id: Option<u64>) -> Option<&Line> {
+        match msg_id {
+            Some(msg_id) => self
+                .lines
+                .iter()
+                .rev()
+                .find(|line| line.msg_id == Some(msg_id)),
+            None => self
+                .current()
+                .map(|found| found.line)
+                .or_else(|| self.lines.back()),
+        }
+    }
+
+    // Whether a search has been started, so "no match" can be told from "no search".
+    pub fn searching(&self) -> bool {
+        self.search.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scrollback(capacity: usize) -> Scrollback {
+        let mut scrollback = Scrollback::from_env();
+        scrollback.capacity = capacity;
+        scrollback
+    }
+
+    fn found_text(found: Option<Found<'_>>) -> Option<(String, usize, usize)> {
+        found.map(|found| (found.line.text.clone(), found.index, found.count))
+    }
+
+    #[test]
+    fn a_search_starts_at_the_newest_match_and_wraps_around() {
+        let mut scrollback = scrollback(10);
+        scrollback.push(Some(0), 1, "Alice", "Hello there");
+        scrollback.push(Some(1), 2, "Bob", "bye");
+        scrollback.push(Some(2), 3, "Carol", "hello again");
+
+        let newest = (String::from("hello again"), 2, 2);
+        let oldest = (String::from("Hello there"), 1, 2);
+        assert_eq!(found_text(scrollback.find("HELLO")), Some(newest.clone()));
+        assert_eq!(found_text(scrollback.previous()), Some(oldest.clone()));
+        assert_eq!(found_text(scrollback.previous()), Some(newest.clone()));
+        assert_eq!(found_text(scrollback.next()), Some(oldest));
+
+        // Senders are searched too.
+        assert_eq!(found_text(scrollback.find("bob")).unwrap().0, "bye");
+        assert!(scrollback.find("nobody").is_none());
+        assert!(scrollback.searching());
+    }
+
+    #[test]
+    fn matches_dropped_from_the_scrollback_are_skipped() {
+        let mut scrollback = scrollback(2);
+        scrollback.push(Some(0), 1, "Alice", "one");
+        scrollback.push(Some(1), 2, "Alice", "two");
+        assert_eq!(found_text(scrollback.find("o")).unwrap().1, 2);
+
+        scrollback.push(Some(2), 3, "Bob", "three");
+        assert_eq!(
+            found_text(scrollback.next()),
+            Some((String::from("two"), 1, 1))
+        );
+        assert_eq!(scrollback.newest(), Some(3));
+    }
+
+    #[test]
+    fn the_selection_is_the_search_or_else_the_latest_line() {
+        let mut scrollback = scrollback(10);
+        scrollback.push(Some(0), 1, "Alice", "first");
+        scrollback.push(Some(1), 2, "Bob", "second");
+        scrollback.push(None, 3, "Server", "notice");
+
+        assert!(!scrollback.searching());
+        assert_eq!(scrollback.selected_id(), Some(1));
+        assert_eq!(scrollback.selected(None).unwrap().text, "notice");
+        assert_eq!(scrollback.selected(Some(0)).unwrap().text, "first");
+        assert!(scrollback.selected(Some(7)).is_none());
+
+        scrollback.find("first");
+        assert_eq!(scrollback.selected_id(), Some(0));
+        assert_eq!(scrollback.selected(None).unwrap().text, "first");
+    }
+
+    #[test]
+    fn nothing_is_kept_with_a_capacity_of_zero() {
+        let mut scrollback = scrollback(0);
+        scrollback.push(Some(0), 1, "Alice", "hi");
+        assert_eq!(scrollback.newest(), None);
+    }
+}
@@ -0,0 +1,31 @@
+use std::{fs, io::Error as IoError};
+
+use serde::{Deserialize, Serialize};
+
+// What the client keeps in SESSION_FILE between runs, so that a restart picks up where
+// the last run left off: the name through the session token, what was typed but never
+// acked, and where the user had read up to.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Session {
+    pub session_token: Option<String>,
+    #[serde(default)]
+    pub unsent: Vec<String>, // Lines to run again once connected, oldest first.
+    #[serde(default)]
+    pub last_read: Option<u64>, // Timestamp of the newest message shown.
+    #[serde(default)]
+    pub next_msg_id: u64, // The server remembers the msg_ids of a session, so they go on from here.
+}
+
+impl Session {
+    // A missing or unreadable file is an empty session, as on the very first run.
+    pub fn load(path: &str) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|session| serde_json::from_str(&session).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), IoError> {
+        fs::write(path, serde_json::to_string_pretty(self).unwrap())
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    env, fs,
+    io::{self, BufRead, IsTerminal, Write},
+};
+
+const ENV_FILE: &str = ".env";
+
+// Walks the user through the settings the client needs, on the first run (HOST or PORT
+// are not set) or whenever it is started with --setup, and writes them to .env, where
+// dotenv picks them up from then on. Other settings already in .env are kept.
+pub fn run_if_needed() {
+    let asked = env::args().any(|arg| arg == "--setup");
+    let missing = env::var("HOST").is_err() || env::var("PORT").is_err();
+    // Piped input is meant for the chat, so it is only asked for when missing on a terminal.
+    let first_run = missing && io::stdin().is_terminal();
+    if !asked && !first_run {
+        return;
+    }
+
+    println!("Setting up the chat client, press enter to keep what is in [brackets].");
+    let current_addr = format!(
+        "{}:{}",
+        env::var("HOST").unwrap_or_else(|_| String::from("127.0.0.1")),
+        env::var("PORT").unwrap_or_else(|_| String::from("8080"))
+    );
+    let (host, port) = loop {
+        let addr = ask("Server address", &current_addr);
+        match addr.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                break (host.to_string(), port.to_string())
+            }
+            _ => println!("That is not an address like 127.0.0.1:8080."),
+        }
+    };
+    println!("The server gives out names, so there is no nickname to pick or account to register.");
+    let invite = ask(
+        "Invite token, if the server is invite-only",
+        &env::var("INVITE").unwrap_or_default(),
+    );
+    let theme = loop {
+        let theme = ask(
+            "Colors: dark, light or none",
+            &env::var("THEME").unwrap_or_else(|_| String::from("dark")),
+        )
+        .to_lowercase();
+        match theme.as_str() {
+            "dark" | "light" | "none" => break theme,
+            _ => println!("Pick one of dark, light or none."),
+        }
+    };
+    let keep_session = ask(
+        "Keep the name and unsent lines across restarts (yes or no)",
+        if env::var("SESSION_FILE").is_ok() {
+            "yes"
+        } else {
+            "no"
+        },
+    );
+    let session_file = match keep_session.to_lowercase().as_str() {
+        "y" | "yes" => env::var("SESSION_FILE").unwrap_or_else(|_| String::from(".chat-session")),
+        _ => String::new(),
+    };
+
+    let settings = [
+        ("HOST", host),
+        ("PORT", port),
+        ("INVITE", invite),
+        ("THEME", theme),
+        ("SESSION_FILE", session_file),
+    ];
+    for (key, value) in &settings {
+        if value.is_empty() {
+            env::remove_var(key);
+        } else {
+            env::set_var(key, value);
+        }
+    }
+    match write_env_file(&settings) {
+        Ok(()) => println!("Saved to {}, run with --setup to change it.\n", ENV_FILE),
+        Err(e) => println!("Could not save {}: {}\n", ENV_FILE, e),
+    }
+}
+
+// Prints 'question' and reads the answer, 'default' if it is left empty.
+fn ask(question: &str, default: &str) -> String {
+    if default.is_empty() {
+        print!("{}: ", question);
+    } else {
+        print!("{} [{}]: ", question, default);
+    }
+    io::stdout().flush().unwrap();
+
+    let mut answer = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .unwrap_or_default();
+    match answer.trim() {
+        "" => default.to_string(),
+        answer => answer.to_string(),
+    }
+}
+
+// Replaces the lines of 'settings' in .env, leaving out the empty ones.
+fn write_env_file(settings: &[(&str, String)]) -> io::Result<()> {
+    let existing = fs::read_to_string(ENV_FILE).unwrap_or_default();
+    let mut lines: Vec<String> = existing
+        .lines()
+        .filter(|line| {
+            let key = line.split('=').next().unwrap_or_default().trim();
+            !settings.iter().any(|(setting, _)| *setting == key)
+        })
+        .map(str::to_string)
+        .collect();
+    lines.extend(
+        settings
+            .iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(key, value)| format!("{}={}", key, value)),
+    );
+
+    fs::write(ENV_FILE, lines.join("\n") + "\n")
+}
@@ -0,0 +1,180 @@
+use std::{env, time::Duration};
+
+#[cfg(unix)]
+use async_std::os::unix::net::UnixStream;
+use async_std::{
+    fs::OpenOptions,
+    io::{self, prelude::*, BufReader},
+    net::TcpStream,
+    task,
+};
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    StreamExt,
+};
+
+use crate::clock::now_millis;
+
+// How long an HTTP endpoint has to answer a line.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Streams everything the client receives to LOG_SINK, one JSON object per line:
+// {"received_at": <milliseconds by our clock>, "message": <the message as it came>}.
+// "file:<path>" appends to a file, "unix:<path>" writes to a Unix socket (on unix) and
+// "http://<host>[:port]/<path>" POSTs each line, plain HTTP only. Lines are written
+// on their own task, so a slow sink never holds up the chat. A line that cannot be
+// written is dropped and the sink is tried again with the next one.
+#[derive(Clone)]
+pub struct LogSink {
+    sender: UnboundedSender<String>,
+}
+
+enum Target {
+    File(String),
+    #[cfg(unix)]
+    Unix(String),
+    Http {
+        addr: String, // What to connect to, host:port.
+        host: String, // As in the Host header.
+        path: String,
+    },
+}
+
+impl LogSink {
+    pub fn from_env() -> Option<Self> {
+        let sink = env::var("LOG_SINK").ok()?;
+        let target = match Target::parse(&sink) {
+            Ok(target) => target,
+            Err(e) => {
+                println!("[Log] Ignoring LOG_SINK {}: {}", sink, e);
+                return None;
+            }
+        };
+
+        let (sender, lines) = unbounded();
+        task::spawn(write_lines(sink, target, lines));
+        Some(Self { sender })
+    }
+
+    // 'frame' is a message as received, before it is parsed.
+    pub fn record(&self, frame: &str) {
+        let line = format!("{{\"received_at\":{},\"message\":{}}}", now_millis(), frame);
+        let _ = self.sender.unbounded_send(line);
+    }
+}
+
+impl Target {
+    fn parse(sink: &str) -> Result<Self, &'static str> {
+        if let Some(path) = sink.strip_prefix("file:") {
+            return Ok(Target::File(path.to_string()));
+        }
+        #[cfg(unix)]
+        if let Some(path) = sink.strip_prefix("unix:") {
+            return Ok(Target::Unix(path.to_string()));
+        }
+        #[cfg(not(unix))]
+        if sink.starts_with("unix:") {
+            return Err("unix: sinks are only supported on unix");
+        }
+        if let Some(rest) = sink.strip_prefix("http://") {
+            let (host, path) = match rest.find('/') {
+                Some(slash) => rest.split_at(slash),
+                None => (rest, "/"),
+            };
+            if host.is_empty() {
+                return Err("the URL has no host");
+            }
+            let addr = if host.contains(':') {
+                host.to_string()
+            } else {
+                format!("{}:80", host)
+            };
+            return Ok(Target::Http {
+                addr,
+                host: host.to_string(),
+                path: path.to_string(),
+            });
+        }
+
+        Err("use file:<path>, unix:<path> or http://<host>[:port]/<path>")
+    }
+
+    async fn open(&self) -> io::Result<Box<dyn Write + Send + Unpin>> {
+        match self {
+            Target::File(path) => Ok(Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?,
+            )),
+            #[cfg(unix)]
+            Target::Unix(path) => Ok(Box::new(UnixStream::connect(path).await?)),
+            Target::Http { .. } => unreachable!("every line is posted on its own connection"),
+        }
+    }
+}
+
+async fn write_lines(sink: String, target: Target, mut lines: UnboundedReceiver<String>) {
+    let mut connection = None;
+    let mut failing = false; // Only the first of failures in a row is reported.
+
+    while let Some(line) = lines.next().await {
+        let written = match &target {
+            Target::Http { addr, host, path } => {
+                io::timeout(HTTP_TIMEOUT, post(addr, host, path, &line)).await
+            }
+            _ => write_line(&target, &mut connection, &line).await,
+        };
+
+        match written {
+            Ok(()) => failing = false,
+            Err(e) => {
+                connection = None;
+                if !failing {
+                    println!("\n[Log] Could not write to {}: {}", sink, e);
+                }
+                failing = true;
+            }
+        }
+    }
+}
+
+async fn write_line(
+    target: &Target,
+    connection: &mut Option<Box<dyn Write + Send + Unpin>>,
+    line: &str,
+) -> io::Result<()> {
+    if connection.is_none() {
+        *connection = Some(target.open().await?);
+    }
+
+    let connection = connection.as_mut().unwrap();
+    connection
+        .write_all(format!("{}\n", line).as_bytes())
+        .await?;
+    connection.flush().await
+}
+
+// Fails unless the endpoint answers with a 2xx status.
+async fn post(addr: &str, host: &str, path: &str, line: &str) -> io::Result<()> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        line.len(),
+        line
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status).await?;
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "the endpoint answered {}",
+            status.trim()
+        ))),
+    }
+}
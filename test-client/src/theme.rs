@@ -0,0 +1,154 @@
+use std::{env, io::IsTerminal};
+
+// What a line is, so it can be styled apart from ordinary chat.
+#[derive(Debug, Clone, Copy)]
+pub enum Style {
+    Private, // Private messages, to and from us.
+    Mention, // Text messages that mention our name.
+    System,  // Everything the server or the client itself says.
+    History, // Backfilled messages.
+    Match,   // What /find has found.
+}
+
+// ANSI colors for the client's output. THEME picks the "dark" (default) or "light"
+// preset or "none", and THEME_SENDERS ("31,32,34"), THEME_PRIVATE, THEME_MENTION,
+// THEME_SYSTEM, THEME_HISTORY and THEME_MATCH override parts of it with SGR codes such as "1;35".
+// Colors are left out when NO_COLOR is set, stdout is not a terminal or 'plain' is set.
+#[derive(Debug, Clone)]
+pub struct Theme {
+    enabled: bool,
+    senders: Vec<String>, // Every sender gets one of these, always the same for a name.
+    private: String,
+    mention: String,
+    system: String,
+    history: String,
+    matched: String,
+}
+
+impl Theme {
+    pub fn from_env(plain: bool) -> Self {
+        let preset = env::var("THEME").unwrap_or_default().to_lowercase();
+        let no_color = env::var("NO_COLOR").is_ok_and(|value| !value.is_empty());
+
+        let mut theme = match preset.as_str() {
+            "light" => Self::light(),
+            _ => Self::dark(),
+        };
+        theme.enabled = !plain && preset != "none" && !no_color && std::io::stdout().is_terminal();
+
+        if let Ok(senders) = env::var("THEME_SENDERS") {
+            let senders: Vec<String> = senders
+                .split(',')
+                .map(|code| code.trim().to_string())
+                .filter(|code| !code.is_empty())
+                .collect();
+            if !senders.is_empty() {
+                theme.senders = senders;
+            }
+        }
+        for (key, style) in [
+            ("THEME_PRIVATE", &mut theme.private),
+            ("THEME_MENTION", &mut theme.mention),
+            ("THEME_SYSTEM", &mut theme.system),
+            ("THEME_HISTORY", &mut theme.history),
+            ("THEME_MATCH", &mut theme.matched),
+        ] {
+            if let Ok(code) = env::var(key) {
+                *style = code.trim().to_string();
+            }
+        }
+
+        theme
+    }
+
+    // Bright colors that read well on a dark background.
+    fn dark() -> Self {
+        Self {
+            enabled: true,
+            senders: codes(&["91", "92", "93", "94", "95", "96", "31", "32", "36"]),
+            private: String::from("1;35"),
+            mention: String::from("1;93"),
+            system: String::from("90"),
+            history: String::from("2"),
+            matched: String::from("7"),
+        }
+    }
+
+    // Darker colors without yellow, which is hard to read on a light background.
+    fn light() -> Self {
+        Self {
+            enabled: true,
+            senders: codes(&["31", "32", "34", "35", "36", "91", "94"]),
+            private: String::from("1;35"),
+            mention: String::from("1;31"),
+            system: String::from("90"),
+            history: String::from("2"),
+            matched: String::from("7"),
+        }
+    }
+
+    pub fn paint(&self, style: Style, text: &str) -> String {
+        let code = match style {
+            Style::Private => &self.private,
+            Style::Mention => &self.mention,
+            Style::System => &self.system,
+            Style::History => &self.history,
+            Style::Match => &self.matched,
+        };
+        self.apply(code, text)
+    }
+
+    // 'name' in the color it always gets.
+    pub fn sender(&self, name: &str) -> String {
+        if self.senders.is_empty() {
+            return name.to_string();
+        }
+
+        // FNV-1a, so a name keeps its color across runs and builds.
+        let hash = name.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+        let code = &self.senders[(hash % self.senders.len() as u64) as usize];
+        self.apply(code, name)
+    }
+
+    // 'text' with every occurrence of 'needle', which is lowercase, in the Match style.
+    pub fn highlight(&self, text: &str, needle: &str) -> String {
+        let lower = text.to_lowercase();
+        // Offsets into the lowercase text only hold for the original if lowercasing kept its length.
+        if needle.is_empty() || lower.len() != text.len() {
+            return text.to_string();
+        }
+
+        let mut highlighted = String::new();
+        let mut shown = 0;
+        for (start, _) in lower.match_indices(needle) {
+            let end = start + needle.len();
+            if start < shown || !text.is_char_boundary(start) || !text.is_char_boundary(end) {
+                continue;
+            }
+            highlighted.push_str(&text[shown..start]);
+            highlighted.push_str(&self.paint(Style::Match, &text[start..end]));
+            shown = end;
+        }
+        highlighted.push_str(&text[shown..]);
+
+        highlighted
+    }
+
+    fn apply(&self, code: &str, text: &str) -> String {
+        if !self.enabled || code.is_empty() {
+            return text.to_string();
+        }
+
+        // Styled per line, so a reset never has to cross a line break.
+        text.split('\n')
+            .map(|line| format!("\x1b[{}m{}\x1b[0m", code, line))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+fn codes(codes: &[&str]) -> Vec<String> {
+    codes.iter().map(|code| code.to_string()).collect()
+}